@@ -0,0 +1,55 @@
+// How long parsing commands takes, as a server does for every line a
+// client sends.
+//
+//     cargo bench --bench parse
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use utils::commands::Command;
+
+const COMMANDS: usize = 100_000;
+
+const INPUTS: [&str; 8] = [
+    "list",
+    "reqs",
+    "glide notes.txt @bob",
+    "glide notes.txt @bob @carol",
+    "ok @alice",
+    "ok @alice report.pdf into ~/in",
+    "no @alice",
+    "peek @alice report.pdf 64",
+];
+
+// The fastest of a few runs of `COMMANDS` calls to `parse`
+fn bench(name: &str, parse: impl Fn(&str)) {
+    let fastest = (0..5)
+        .map(|_| {
+            let started = Instant::now();
+            for input in INPUTS.iter().cycle().take(COMMANDS) {
+                parse(black_box(input));
+            }
+            started.elapsed()
+        })
+        .min()
+        .unwrap_or(Duration::ZERO);
+
+    println!(
+        "{:<20} {} commands in {:?} ({:?} each)",
+        name,
+        COMMANDS,
+        fastest,
+        fastest / COMMANDS as u32
+    );
+}
+
+fn main() {
+    bench("parse", |input| {
+        black_box(Command::parse(input).unwrap());
+    });
+    bench("parse_with_trailer", |input| {
+        black_box(Command::parse_with_trailer(input).unwrap());
+    });
+}
@@ -0,0 +1,93 @@
+// How long many users' commands take against the shared state at once, as a
+// busy server's are. Run it both ways to compare the single mutex with the
+// sharded map:
+//
+//     cargo bench --bench state
+//     cargo bench --bench state --features sharded-state
+
+use std::{
+    hint::black_box,
+    time::{Duration, Instant, SystemTime},
+};
+
+use utils::data::{Request, UserData};
+use utils::state::{self, SharedState};
+
+const USERS: usize = 256;
+const OPERATIONS: usize = 2_000;
+
+#[cfg(not(feature = "sharded-state"))]
+const BACKING: &str = "single mutex";
+#[cfg(feature = "sharded-state")]
+const BACKING: &str = "sharded";
+
+fn user() -> UserData {
+    UserData {
+        socket: String::new(),
+        incoming_requests: Vec::new(),
+        public_key: None,
+    }
+}
+
+// What one user's connection does: glide to the next user along, check
+// its own requests and look someone up
+async fn busy(state: SharedState, me: usize) {
+    let (name, next) = (format!("user{}", me), format!("user{}", (me + 1) % USERS));
+    for i in 0..OPERATIONS {
+        state::with_user(&state, &next, |client| {
+            client.incoming_requests.push(Request {
+                sender: name.clone(),
+                filename: format!("{}.txt", i),
+                size: 0,
+                offered_at: SystemTime::UNIX_EPOCH,
+            });
+            client.incoming_requests.truncate(16);
+        })
+        .await;
+        black_box(state::with_user(&state, &name, |client| client.incoming_requests.len()).await);
+        black_box(state::contains_user(&state, &next).await);
+    }
+}
+
+// The fastest of a few runs of every user being busy at once
+fn bench(name: &str, workers: usize) {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(workers)
+        .build()
+        .unwrap();
+    let fastest = (0..5)
+        .map(|_| {
+            runtime.block_on(async {
+                let state = state::new_state();
+                for me in 0..USERS {
+                    state::insert_user(&state, &format!("user{}", me), user()).await;
+                }
+                let started = Instant::now();
+                let tasks: Vec<_> = (0..USERS)
+                    .map(|me| tokio::spawn(busy(state.clone(), me)))
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+                started.elapsed()
+            })
+        })
+        .min()
+        .unwrap_or(Duration::ZERO);
+
+    let operations = USERS * OPERATIONS * 3;
+    println!(
+        "{:<20} {} operations in {:?} ({:?} each)",
+        name,
+        operations,
+        fastest,
+        fastest / operations as u32
+    );
+}
+
+fn main() {
+    println!("{}", BACKING);
+    for workers in [1, 4, 8] {
+        bench(&format!("{} threads", workers), workers);
+    }
+}
@@ -0,0 +1,100 @@
+// A server and a client talking over TLS.
+//
+// The server needs a certificate and its private key in PEM. For trying
+// things out locally, a self-signed one for "localhost" will do:
+//
+//     openssl req -x509 -newkey rsa:2048 -nodes -days 365 \
+//         -keyout key.pem -out cert.pem -subj "/CN=localhost" \
+//         -addext "subjectAltName=DNS:localhost" \
+//         -addext "basicConstraints=critical,CA:FALSE"
+//
+// Clients have to trust whatever signed it. Here that's the self-signed
+// certificate itself. For a real deployment, use one from a CA and build the
+// client's root store from the system's roots instead.
+//
+//     cargo run --example tls --features tls -- cert.pem key.pem
+
+use std::{error::Error, sync::Arc};
+
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+use utils::{
+    commands::Command,
+    connection,
+    protocol::Transmission,
+    state,
+    tls::{
+        self,
+        rustls::{
+            self,
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        },
+    },
+};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut args = std::env::args().skip(1);
+    let (Some(cert_path), Some(key_path)) = (args.next(), args.next()) else {
+        return Err("usage: tls <cert.pem> <key.pem>".into());
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)?.collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(&key_path)?;
+
+    let server_config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs.clone(), key)?;
+    let server_config = Arc::new(server_config);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let shared = state::new_state();
+
+    tokio::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let config = server_config.clone();
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                let stream = match tls::accept_tls(stream, config).await {
+                    Ok(stream) => stream,
+                    Err(e) => return eprintln!("Handshake failed: {}", e),
+                };
+                if let Err(e) = connection::run(stream, &shared, std::future::pending()).await {
+                    eprintln!("Connection ended: {}", e);
+                }
+            });
+        }
+    });
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in certs {
+        roots.add(cert)?;
+    }
+    let client_config = rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let mut stream = tls::connect_tls(addr, "localhost", Arc::new(client_config)).await?;
+
+    stream
+        .write_all(&Transmission::Username("alice".to_string()).to_bytes())
+        .await?;
+    println!("{:?}", Transmission::from_stream(&mut stream).await?);
+
+    stream
+        .write_all(
+            &Transmission::Command(Command::List {
+                filter: None,
+                page: None,
+                page_size: None,
+            })
+            .to_bytes(),
+        )
+        .await?;
+    println!("{:?}", Transmission::from_stream(&mut stream).await?);
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+use log::warn;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc};
+
+use crate::data::FileHash;
+
+// One finished (or failed) transfer, as written to the audit file
+#[derive(Clone, Debug)]
+pub struct AuditRecord {
+    pub sender: String,
+    pub recipient: String,
+    pub filename: String,
+    pub bytes: u64,
+    // What happened, e.g. "staged", "delivered", "failed" or
+    // "cleanup-failed" for a staged file the server couldn't delete
+    pub outcome: String,
+    pub hash: Option<FileHash>,
+}
+
+// Most lines waiting to be written before new ones are dropped
+pub const AUDIT_QUEUE: usize = 1024;
+
+// Appends a JSON line per transfer to a file. Writes happen on a background
+// task so logging never holds up a transfer; cloning shares the same writer.
+// Each line is flushed as it's written, so the file is complete up to the
+// last transfer logged even if the server dies.
+#[derive(Clone, Debug)]
+pub struct AuditLogger {
+    sender: mpsc::Sender<String>,
+}
+
+impl AuditLogger {
+    // Must be called from within a tokio runtime
+    pub fn new(path: &str) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<String>(AUDIT_QUEUE);
+        let path = path.to_string();
+
+        tokio::spawn(async move {
+            let mut file = match OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .await
+            {
+                Ok(file) => file,
+                Err(e) => {
+                    warn!("Unable to open audit log '{}': {}", path, e);
+                    return;
+                }
+            };
+
+            while let Some(line) = receiver.recv().await {
+                let written = async {
+                    file.write_all(line.as_bytes()).await?;
+                    file.flush().await
+                };
+                if let Err(e) = written.await {
+                    warn!("Unable to write to audit log '{}': {}", path, e);
+                }
+            }
+        });
+
+        Self { sender }
+    }
+
+    pub fn log(&self, record: AuditRecord) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let hash = match record.hash {
+            Some(hash) => format!("\"{}\"", hex(&hash)),
+            None => "null".to_string(),
+        };
+
+        let line = format!(
+            "{{\"timestamp\":{},\"sender\":{},\"recipient\":{},\"filename\":{},\"bytes\":{},\"outcome\":{},\"hash\":{}}}\n",
+            timestamp,
+            json_string(&record.sender),
+            json_string(&record.recipient),
+            json_string(&record.filename),
+            record.bytes,
+            json_string(&record.outcome),
+            hash
+        );
+
+        // Rather than hold up the transfer, a line is dropped if the writer
+        // has fallen this far behind. Closed only once the writer has given
+        // up, which it already logged.
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(line) {
+            warn!("Audit log is {} lines behind, dropping one", AUDIT_QUEUE);
+        }
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
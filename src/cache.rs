@@ -0,0 +1,92 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use crate::data::FileHash;
+
+// How many files' size and hash `send_file` remembers
+pub const METADATA_CACHE_SIZE: usize = 64;
+
+type Key = (PathBuf, SystemTime);
+
+// Size and hash of recently sent files, keyed by path and modification time
+// so that a file that has changed since is never served from the cache.
+// Evicts the least recently used entry once full.
+pub struct MetadataCache {
+    capacity: usize,
+    entries: HashMap<Key, (u64, FileHash)>,
+    // Least recently used at the front
+    order: VecDeque<Key>,
+}
+
+impl MetadataCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn get(&mut self, path: &Path, modified: SystemTime) -> Option<(u64, FileHash)> {
+        let key = (path.to_path_buf(), modified);
+        let found = *self.entries.get(&key)?;
+
+        self.order.retain(|k| k != &key);
+        self.order.push_back(key);
+
+        Some(found)
+    }
+
+    pub fn insert(&mut self, path: &Path, modified: SystemTime, size: u64, hash: FileHash) {
+        // Drop anything remembered about older versions of the file
+        self.entries.retain(|(p, _), _| p != path);
+        self.order.retain(|(p, _)| p != path);
+
+        while self.order.len() >= self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => self.entries.remove(&oldest),
+                None => break,
+            };
+        }
+
+        let key = (path.to_path_buf(), modified);
+        self.entries.insert(key.clone(), (size, hash));
+        self.order.push_back(key);
+    }
+}
+
+// A `MetadataCache` a sender keeps across sends, see `SendOptions`. Clones
+// share it.
+#[derive(Clone)]
+pub struct SentFiles(Arc<Mutex<MetadataCache>>);
+
+impl SentFiles {
+    pub fn new(capacity: usize) -> Self {
+        Self(Arc::new(Mutex::new(MetadataCache::new(capacity))))
+    }
+
+    pub fn get(&self, path: &Path, modified: SystemTime) -> Option<(u64, FileHash)> {
+        self.0.lock().unwrap().get(path, modified)
+    }
+
+    pub fn insert(&self, path: &Path, modified: SystemTime, size: u64, hash: FileHash) {
+        self.0.lock().unwrap().insert(path, modified, size, hash)
+    }
+}
+
+impl Default for SentFiles {
+    fn default() -> Self {
+        Self::new(METADATA_CACHE_SIZE)
+    }
+}
+
+impl fmt::Debug for SentFiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SentFiles({})", self.0.lock().unwrap().entries.len())
+    }
+}
@@ -1,218 +1,1863 @@
 use crate::{
-    data::{Request, UserData},
-    protocol::Transmission,
+    audit::AuditRecord,
+    connection::Socket,
+    data::{Request, RetryPolicy, ServerConfig, UserData, PARTIAL_SUFFIX},
+    dedup,
+    error::GlideError,
+    protocol::{ProtocolVersion, Transmission},
+    state::{self, SharedState},
     transfers,
 };
+use log::warn;
 use regex::Regex;
-use std::{collections::HashMap, path::Path, sync::Arc};
-use tokio::{io::AsyncWriteExt, net::TcpStream, sync::Mutex};
-
-type SharedState = Arc<Mutex<HashMap<String, UserData>>>;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::io::AsyncWriteExt;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Command {
-    List,
+    // Connected users in name order, a page at a time. Without any of
+    // these, just the first page as `ConnectedUsers`.
+    List {
+        // Only names with this in them, or matching it if it's a glob, in
+        // any case
+        filter: Option<String>,
+        // Counting from 0
+        page: Option<u32>,
+        page_size: Option<u16>,
+    },
     Requests,
-    Glide { path: String, to: String },
-    Ok(String),
-    No(String),
+    Glide {
+        path: String,
+        to: String,
+        // Deliver under this name rather than the file's own
+        as_name: Option<String>,
+    },
+    // Optionally naming the file, for a sender with several pending.
+    // Without one, the first they sent.
+    Ok(String, Option<String>),
+    // Accept, saving into this directory rather than wherever the client
+    // would. The server only sees an `ok`; see `transfers::save_dir`.
+    OkInto {
+        from: String,
+        filename: Option<String>,
+        into: String,
+    },
+    // Optionally naming the file, for a sender with several pending
+    No(String, Option<String>),
+    // A bare `ok` or `no`, for the glide that arrived last
+    OkLatest,
+    NoLatest,
+    Capabilities,
+    SetName(String),
+    // Fetch a user's published end-to-end encryption key
+    Key(String),
+    // Preview the first `bytes` of a pending glide without accepting it
+    Peek {
+        from: String,
+        filename: String,
+        bytes: u32,
+    },
+    // Refuse every pending glide at once and clear out the staging area
+    Purge,
+    // Call off a glide of this file the user sent, whether it's still
+    // waiting or being delivered
+    Cancel(String),
+    // The same file to several users, sent once
+    GlideMany {
+        path: String,
+        to: Vec<String>,
+    },
+    // The username the server knows the connection by, and how many glides
+    // are waiting for it
+    Whoami,
+    // Every file under a directory, keeping the tree
+    GlideDir {
+        path: String,
+        to: String,
+    },
 }
 
+// Every command the server knows how to run, by its typed name
+pub const COMMAND_NAMES: [&str; 12] = [
+    "list", "reqs", "glide", "ok", "no", "caps", "nick", "key", "peek", "purge", "cancel", "whoami",
+];
+
+// Most users in one answer to `list`, and how many a page has when it
+// isn't said. A plain `list` with more to show is answered with its first
+// page, so the total says some were left out.
+pub const LIST_PAGE_SIZE: u16 = 1000;
+
 impl Command {
-    pub fn parse(input: &str) -> Command {
-        let glide_re = Regex::new(r"^glide\s+(.+)\s+@(.+)$").unwrap();
-        let ok_re = Regex::new(r"^ok\s+@(.+)$").unwrap();
-        let no_re = Regex::new(r"^no\s+@(.+)$").unwrap();
+    pub fn parse(input: &str) -> Result<Command, ParseError> {
+        // Built the first time anything is parsed, rather than on every call
+        static GLIDE_DIR_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^glide\s+-r\s+(.+)\s+@(\S+)$").unwrap());
+        static GLIDE_MANY_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^glide\s+(.+?)((?:\s+@\S+){2,})$").unwrap());
+        static GLIDE_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^glide\s+(.+)\s+@(.+?)(?:\s+as\s+(.+))?$").unwrap());
+        static LIST_RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(
+                r"^list(?:\s+([^-\s]\S*))?(?:\s+-p\s+([0-9]{1,9}))?(?:\s+-n\s+([0-9]{1,9}))?$",
+            )
+            .unwrap()
+        });
+        static OK_RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"^ok\s+@(\S+)(?:\s+(.+?))??(?:\s+into\s+(.+))?$").unwrap()
+        });
+        static NO_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^no\s+@(\S+)(?:\s+(.+))?$").unwrap());
+        static NICK_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^nick\s+(.+)$").unwrap());
+        static KEY_RE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^key\s+@(.+)$").unwrap());
+        static PEEK_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^peek\s+@(\S+)\s+(.+)\s+([0-9]{1,9})$").unwrap());
+        static CANCEL_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^cancel\s+(.+)$").unwrap());
 
-        if input == "list" {
-            Command::List
+        let command = if let Some(caps) = LIST_RE.captures(input) {
+            let filter = list_filter(caps.get(1))?;
+            let (page, page_size) = list_page(caps.get(2), caps.get(3))?;
+            Command::List {
+                filter,
+                page,
+                page_size,
+            }
         } else if input == "reqs" {
             Command::Requests
-        } else if let Some(caps) = glide_re.captures(input) {
+        } else if input == "caps" {
+            Command::Capabilities
+        } else if input == "purge" {
+            Command::Purge
+        } else if input == "whoami" {
+            Command::Whoami
+        } else if input == "ok" {
+            Command::OkLatest
+        } else if input == "no" {
+            Command::NoLatest
+        } else if let Some(caps) = GLIDE_DIR_RE.captures(input) {
+            let path = caps[1].to_string();
+            let to = clean_username(&caps[2])?;
+            Command::GlideDir { path, to }
+        } else if let Some(caps) = GLIDE_MANY_RE.captures(input) {
             let path = caps[1].to_string();
-            let to = caps[2].to_string();
-            Command::Glide { path, to }
-        } else if let Some(caps) = ok_re.captures(input) {
-            let username = caps[1].to_string();
-            Command::Ok(username)
-        } else if let Some(caps) = no_re.captures(input) {
-            let username = caps[1].to_string();
-            Command::No(username)
+            let to = caps[2]
+                .split_whitespace()
+                .map(clean_username)
+                .collect::<Result<Vec<_>, _>>()?;
+            Command::GlideMany {
+                path,
+                to: recipients(to),
+            }
+        } else if let Some(caps) = GLIDE_RE.captures(input) {
+            let path = caps[1].to_string();
+            let to = clean_username(&caps[2])?;
+            let as_name = match caps.get(3).map(|m| m.as_str()) {
+                Some(name) if !is_valid_filename(name) => {
+                    return Err(ParseError::InvalidFilename(name.to_string()))
+                }
+                name => name.map(String::from),
+            };
+            Command::Glide { path, to, as_name }
+        } else if let Some(caps) = OK_RE.captures(input) {
+            let username = clean_username(&caps[1])?;
+            let filename = reply_filename(caps.get(2))?;
+            match caps.get(3) {
+                Some(into) => Command::OkInto {
+                    from: username,
+                    filename,
+                    into: into.as_str().to_string(),
+                },
+                None => Command::Ok(username, filename),
+            }
+        } else if let Some(caps) = NO_RE.captures(input) {
+            let username = clean_username(&caps[1])?;
+            Command::No(username, reply_filename(caps.get(2))?)
+        } else if let Some(caps) = NICK_RE.captures(input) {
+            if !is_valid_username(&caps[1]) {
+                return Err(ParseError::InvalidUsername(caps[1].to_string()));
+            }
+            Command::SetName(caps[1].to_string())
+        } else if let Some(caps) = KEY_RE.captures(input) {
+            Command::Key(clean_username(&caps[1])?)
+        } else if let Some(caps) = PEEK_RE.captures(input) {
+            Command::Peek {
+                from: clean_username(&caps[1])?,
+                filename: caps[2].to_string(),
+                bytes: parse_number(&caps[3])?,
+            }
+        } else if let Some(caps) = CANCEL_RE.captures(input) {
+            if !is_valid_filename(&caps[1]) {
+                return Err(ParseError::InvalidFilename(caps[1].to_string()));
+            }
+            Command::Cancel(caps[1].to_string())
+        } else if input.split_whitespace().any(|word| word == "@") {
+            return Err(ParseError::MissingUsername);
+        } else if input.split_whitespace().next() == Some("glide") {
+            return Err(ParseError::MalformedGlide(input.to_string()));
         } else {
-            unreachable!("oh no")
-        }
+            return Err(ParseError::UnknownCommand(input.to_string()));
+        };
+
+        Ok(command)
     }
 
-    pub fn to_string(&self) -> String {
+    // Like `parse`, but allows arbitrary text after the command, which is
+    // returned alongside it. Usernames can't contain whitespace here, since
+    // the first whitespace after the username starts the trailer. After
+    // `ok @user` or `no @user` the next word names the file, so names with
+    // spaces in them can only be given to `parse`.
+    pub fn parse_with_trailer(input: &str) -> Result<(Command, Option<String>), ParseError> {
+        // Built the first time anything is parsed, as in `parse`
+        static LIST_RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(
+                r"^list(?:\s+([^-\s]\S*))?(?:\s+-p\s+([0-9]{1,9}))?(?:\s+-n\s+([0-9]{1,9}))?(?:\s+(.*))?$",
+            )
+            .unwrap()
+        });
+        static REQS_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^reqs(?:\s+(.*))?$").unwrap());
+        static CAPS_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^caps(?:\s+(.*))?$").unwrap());
+        static PURGE_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^purge(?:\s+(.*))?$").unwrap());
+        static WHOAMI_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^whoami(?:\s+(.*))?$").unwrap());
+        static GLIDE_DIR_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^glide\s+-r\s+(.+?)\s+@(\S+)(?:\s+(.*))?$").unwrap());
+        static GLIDE_MANY_RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"^glide\s+(.+?)((?:\s+@\S+){2,})(?:\s+([^@\s].*))?$").unwrap()
+        });
+        static GLIDE_RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"^glide\s+(.+?)\s+@(\S+)(?:\s+as\s+(\S+))?(?:\s+(.*))?$").unwrap()
+        });
+        // `into` straight after the username is the directory, not a file
+        static OK_RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(
+                r"^ok\s+@(\S+)(?:\s+into\s+(\S+)|\s+(\S+)(?:\s+into\s+(\S+))?)?(?:\s+(.*))?$",
+            )
+            .unwrap()
+        });
+        static NO_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^no\s+@(\S+)(?:\s+(\S+))?(?:\s+(.*))?$").unwrap());
+        static REPLY_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^(?:ok|no)(?:$|\s+(\S*))").unwrap());
+        static NICK_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^nick\s+(\S+)(?:\s+(.*))?$").unwrap());
+        static KEY_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^key\s+@(\S+)(?:\s+(.*))?$").unwrap());
+        static PEEK_RE: LazyLock<Regex> = LazyLock::new(|| {
+            Regex::new(r"^peek\s+@(\S+)\s+(.+?)\s+([0-9]{1,9})(?:\s+(.*))?$").unwrap()
+        });
+        static CANCEL_RE: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"^cancel\s+(\S+)(?:\s+(.*))?$").unwrap());
+
+        let (command, trailer) = if let Some(caps) = LIST_RE.captures(input) {
+            let filter = list_filter(caps.get(1))?;
+            let (page, page_size) = list_page(caps.get(2), caps.get(3))?;
+            (
+                Command::List {
+                    filter,
+                    page,
+                    page_size,
+                },
+                caps.get(4),
+            )
+        } else if let Some(caps) = REQS_RE.captures(input) {
+            (Command::Requests, caps.get(1))
+        } else if let Some(caps) = CAPS_RE.captures(input) {
+            (Command::Capabilities, caps.get(1))
+        } else if let Some(caps) = PURGE_RE.captures(input) {
+            (Command::Purge, caps.get(1))
+        } else if let Some(caps) = WHOAMI_RE.captures(input) {
+            (Command::Whoami, caps.get(1))
+        } else if let Some(caps) = GLIDE_DIR_RE.captures(input) {
+            let path = caps[1].to_string();
+            let to = clean_username(&caps[2])?;
+            (Command::GlideDir { path, to }, caps.get(3))
+        } else if let Some(caps) = GLIDE_MANY_RE.captures(input) {
+            let path = caps[1].to_string();
+            let to = caps[2]
+                .split_whitespace()
+                .map(clean_username)
+                .collect::<Result<Vec<_>, _>>()?;
+            (
+                Command::GlideMany {
+                    path,
+                    to: recipients(to),
+                },
+                caps.get(3),
+            )
+        } else if let Some(caps) = GLIDE_RE.captures(input) {
+            let path = caps[1].to_string();
+            let to = clean_username(&caps[2])?;
+            let as_name = match caps.get(3).map(|m| m.as_str()) {
+                Some(name) if !is_valid_filename(name) => {
+                    return Err(ParseError::InvalidFilename(name.to_string()))
+                }
+                name => name.map(String::from),
+            };
+            (Command::Glide { path, to, as_name }, caps.get(4))
+        } else if let Some(caps) = OK_RE.captures(input) {
+            let from = clean_username(&caps[1])?;
+            let filename = reply_filename(caps.get(3))?;
+            let command = match caps.get(2).or(caps.get(4)) {
+                Some(into) => Command::OkInto {
+                    from,
+                    filename,
+                    into: into.as_str().to_string(),
+                },
+                None => Command::Ok(from, filename),
+            };
+            (command, caps.get(5))
+        } else if let Some(caps) = NO_RE.captures(input) {
+            let from = clean_username(&caps[1])?;
+            (Command::No(from, reply_filename(caps.get(2))?), caps.get(3))
+        } else if let Some(caps) = NICK_RE.captures(input) {
+            if !is_valid_username(&caps[1]) {
+                return Err(ParseError::InvalidUsername(caps[1].to_string()));
+            }
+            (Command::SetName(caps[1].to_string()), caps.get(2))
+        } else if let Some(caps) = KEY_RE.captures(input) {
+            (Command::Key(clean_username(&caps[1])?), caps.get(2))
+        } else if let Some(caps) = PEEK_RE.captures(input) {
+            let from = clean_username(&caps[1])?;
+            let filename = caps[2].to_string();
+            let bytes = parse_number(&caps[3])?;
+            (
+                Command::Peek {
+                    from,
+                    filename,
+                    bytes,
+                },
+                caps.get(4),
+            )
+        } else if let Some(caps) = CANCEL_RE.captures(input) {
+            if !is_valid_filename(&caps[1]) {
+                return Err(ParseError::InvalidFilename(caps[1].to_string()));
+            }
+            (Command::Cancel(caps[1].to_string()), caps.get(2))
+        } else if let Some(caps) = REPLY_RE.captures(input) {
+            // An ok/no on its own is for the latest glide, but anything else
+            // after it is missing the `@` or the username
+            match caps.get(1).map(|m| m.as_str()) {
+                None if input.starts_with("ok") => (Command::OkLatest, None),
+                None => (Command::NoLatest, None),
+                Some("") | Some("@") => return Err(ParseError::MissingUsername),
+                Some(arg) => return Err(ParseError::InvalidUsername(arg.to_string())),
+            }
+        } else {
+            return Err(ParseError::UnknownCommand(input.to_string()));
+        };
+
+        let trailer = trailer
+            .map(|m| m.as_str().trim().to_string())
+            .filter(|t| !t.is_empty());
+
+        Ok((command, trailer))
+    }
+
+    // Whether the command can run alongside others on the same connection.
+    // Ones that stream a file after answering, or rename the connection,
+    // can't.
+    pub fn can_pipeline(&self) -> bool {
+        !matches!(
+            self,
+            Command::Glide { .. }
+                | Command::GlideMany { .. }
+                | Command::GlideDir { .. }
+                | Command::Ok(..)
+                | Command::OkInto { .. }
+                | Command::OkLatest
+                | Command::Peek { .. }
+                | Command::SetName(_)
+        )
+    }
+
+    // The name the command is typed as
+    pub fn name(&self) -> &'static str {
         match self {
-            Command::List => "list".to_string(),
-            Command::Requests => "reqs".to_string(),
-            Command::Glide { path, to } => format!("glide {} @{}", path, to),
-            Command::Ok(user) => format!("ok @{}", user),
-            Command::No(user) => format!("no @{}", user),
+            Command::List { .. } => "list",
+            Command::Requests => "reqs",
+            Command::Glide { .. } | Command::GlideMany { .. } | Command::GlideDir { .. } => "glide",
+            Command::Ok(..) | Command::OkInto { .. } | Command::OkLatest => "ok",
+            Command::No(..) | Command::NoLatest => "no",
+            Command::Capabilities => "caps",
+            Command::SetName(_) => "nick",
+            Command::Key(_) => "key",
+            Command::Peek { .. } => "peek",
+            Command::Purge => "purge",
+            Command::Cancel(_) => "cancel",
+            Command::Whoami => "whoami",
+        }
+    }
+
+    // Expands a glide whose path contains glob metacharacters into one glide
+    // per matching file. Any other command is returned as is.
+    pub fn expand_glob(self) -> Result<Vec<Command>, ParseError> {
+        let Command::Glide {
+            ref path,
+            ref to,
+            ref as_name,
+        } = self
+        else {
+            return Ok(vec![self]);
+        };
+
+        if !path.contains(['*', '?', '[']) {
+            return Ok(vec![self]);
+        }
+
+        let paths =
+            glob::glob(path).map_err(|e| ParseError::InvalidGlob(path.clone(), e.to_string()))?;
+
+        // A path that isn't UTF-8 can't be named in a glide, and mangling it
+        // would glide a file that doesn't exist
+        let commands = paths
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.is_file())
+            .map(|entry| match entry.to_str() {
+                Some(matched) => Ok(Command::Glide {
+                    path: matched.to_string(),
+                    to: to.clone(),
+                    as_name: as_name.clone(),
+                }),
+                None => Err(ParseError::InvalidGlob(
+                    path.clone(),
+                    format!("matched '{}', which isn't valid UTF-8", entry.display()),
+                )),
+            })
+            .collect::<Result<Vec<Command>, ParseError>>()?;
+
+        if commands.is_empty() {
+            return Err(ParseError::NoGlobMatches(path.clone()));
+        }
+
+        // They'd all be staged under the same name
+        if as_name.is_some() && commands.len() > 1 {
+            return Err(ParseError::InvalidGlob(
+                path.clone(),
+                format!("{} files match, but 'as' can only name one", commands.len()),
+            ));
         }
+
+        Ok(commands)
     }
 
     pub async fn execute(&self, state: &SharedState, username: &str) -> Transmission {
+        self.execute_with_config(state, username, &ServerConfig::default())
+            .await
+    }
+
+    pub async fn execute_with_config(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        if !allowed(config, self.name()) {
+            return Transmission::Error(format!("'{}' isn't allowed here", self.name()));
+        }
+
         match self {
-            Command::List => self.cmd_list(state, username).await,
+            Command::List { .. } => self.cmd_list(state, username).await,
             Command::Requests => self.cmd_reqs(state, username).await,
-            Command::Glide { path: _, to: _ } => self.cmd_glide(state, username).await,
-            Command::Ok(_) => self.cmd_ok(state, username).await,
-            Command::No(_) => self.cmd_no(state, username).await,
+            Command::Glide { .. } | Command::GlideDir { .. } => {
+                self.cmd_glide(state, username, config).await
+            }
+            Command::GlideMany { .. } => self.cmd_glide_many(state, username, config).await,
+            Command::Ok(..) | Command::OkInto { .. } => self.cmd_ok(state, username, config).await,
+            Command::No(..) => self.cmd_no(state, username, config).await,
+            Command::OkLatest | Command::NoLatest => {
+                match self.clone().resolve_latest(state, username).await {
+                    Command::OkLatest | Command::NoLatest => {
+                        Transmission::Error("No glides are pending".to_string())
+                    }
+                    resolved @ Command::Ok(..) => resolved.cmd_ok(state, username, config).await,
+                    resolved => resolved.cmd_no(state, username, config).await,
+                }
+            }
+            Command::Capabilities => self.cmd_caps(config).await,
+            Command::SetName(_) => self.cmd_nick(state, username, config).await,
+            Command::Key(_) => self.cmd_key(state).await,
+            Command::Peek { .. } => self.cmd_peek(state, username, config).await,
+            Command::Purge => self.cmd_purge(state, username, config).await,
+            Command::Cancel(_) => self.cmd_cancel(state, username, config).await,
+            Command::Whoami => self.cmd_whoami(state, username).await,
         }
     }
 
-    // Executes and prints the output of a command to a user
-    pub async fn handle(
+    // A bare `ok` or `no` as if it named the sender and file of the glide
+    // that arrived last. Left as it is if nothing's pending, or for any other
+    // command.
+    async fn resolve_latest(self, state: &SharedState, username: &str) -> Command {
+        if !matches!(self, Command::OkLatest | Command::NoLatest) {
+            return self;
+        }
+        let latest = state::with_user(state, username, |client| {
+            client
+                .incoming_requests
+                .last()
+                .map(|req| (req.sender.clone(), req.filename.clone()))
+        })
+        .await
+        .flatten();
+
+        match (self, latest) {
+            (Command::OkLatest, Some((from, filename))) => Command::Ok(from, Some(filename)),
+            (Command::NoLatest, Some((from, filename))) => Command::No(from, Some(filename)),
+            (command, _) => command,
+        }
+    }
+
+    // Executes and prints the output of a command to a user, returning the
+    // response that was sent
+    pub async fn handle<S: Socket>(
         command: Command,
         username: &str,
-        stream: &mut TcpStream,
+        stream: &mut S,
         state: &SharedState,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let response = command.execute(state, username).await;
-        stream.write_all(response.to_bytes().as_slice()).await?;
-
-        // If the reponse was GlideRequestSent, receive file
-        if matches!(response, Transmission::GlideRequestSent) {
-            // Create a directory to save the incoming data
-            let Command::Glide { to, .. } = command else {
-                unreachable!("the command should always be glide")
-            };
-            let file_path = format!("clients/{}/{}", username, to);
+    ) -> Result<Transmission, GlideError> {
+        Self::handle_with_config(command, username, stream, state, &ServerConfig::default()).await
+    }
+
+    // Same as `handle`, with a custom wait for the sender to start a glide
+    pub async fn handle_with_timeout<S: Socket>(
+        command: Command,
+        username: &str,
+        stream: &mut S,
+        state: &SharedState,
+        receive_timeout: Duration,
+    ) -> Result<Transmission, GlideError> {
+        let config = ServerConfig {
+            glide_receive_timeout: receive_timeout,
+            ..ServerConfig::default()
+        };
+        Self::handle_with_config(command, username, stream, state, &config).await
+    }
 
-            // Ensure the parent directories exist
-            if let Some(parent_dir) = std::path::Path::new(&file_path).parent() {
-                tokio::fs::create_dir_all(parent_dir).await?;
+    // Same as `handle`, following the server's configuration
+    pub async fn handle_with_config<S: Socket>(
+        command: Command,
+        username: &str,
+        stream: &mut S,
+        state: &SharedState,
+        config: &ServerConfig,
+    ) -> Result<Transmission, GlideError> {
+        Self::handle_for(
+            command,
+            username,
+            stream,
+            state,
+            config,
+            ProtocolVersion::V1,
+        )
+        .await
+    }
+
+    // Same as `handle_with_config`, on a connection that agreed on `version`
+    // of the wire format
+    pub async fn handle_for<S: Socket>(
+        command: Command,
+        username: &str,
+        stream: &mut S,
+        state: &SharedState,
+        config: &ServerConfig,
+        version: ProtocolVersion,
+    ) -> Result<Transmission, GlideError> {
+        let started = Instant::now();
+        // Settled now, so the glide accepted is the one delivered even if
+        // another arrives in between
+        let command = command.resolve_latest(state, username).await;
+        let mut response = command.execute_with_config(state, username, config).await;
+        if let Some(metrics) = &config.metrics {
+            metrics.record(command.name(), started.elapsed());
+        }
+
+        // The name a glide the sender's been told to go ahead with is staged
+        // under, and everyone it was queued for
+        let staging = match (&response, &command) {
+            (Transmission::GlideRequestSent, Command::Glide { path, to, as_name }) => {
+                Some((staged_name(path, as_name), vec![to.clone()]))
+            }
+            (Transmission::GlideRequestSent, Command::GlideDir { path, to }) => {
+                Some((staged_name(path, &None), vec![to.clone()]))
+            }
+            (Transmission::GlideRequestsSent { unknown }, Command::GlideMany { path, to }) => {
+                let queued = to.iter().filter(|user| !unknown.contains(user)).cloned();
+                Some((staged_name(path, &None), queued.collect()))
             }
+            _ => None,
+        };
+
+        // Create a directory to save the incoming data before telling the
+        // sender to go ahead, so they hear about it if we can't
+        if let Some((filename, recipients)) = &staging {
+            for to in recipients {
+                let file_path = format!("{}/{}/{}", config.staging_dir, username, to);
+                if let Err(e) = tokio::fs::create_dir_all(&file_path).await {
+                    for to in recipients {
+                        withdraw_glide(state, username, filename, to, config).await;
+                    }
+                    response = Transmission::Error(staging_error_message(&file_path, &e));
+                    break;
+                }
+            }
+        }
+
+        // The file an `ok` is for. The sender may have withdrawn it since the
+        // command ran, so look now, before the user's told to expect it.
+        let accepted = match (&response, &command) {
+            (
+                Transmission::OkSuccess,
+                Command::Ok(from, filename) | Command::OkInto { from, filename, .. },
+            ) => {
+                let filename = state::with_user(state, username, |client| {
+                    accepted_request(client, from, filename.as_deref())
+                        .map(|req| req.filename.clone())
+                })
+                .await
+                .flatten();
+                if filename.is_none() {
+                    response = Transmission::OkFailed;
+                }
+                filename.map(|filename| (from.clone(), filename))
+            }
+            _ => None,
+        };
+
+        // Flush so the client isn't left waiting on a buffered response
+        stream
+            .write_all(response.to_bytes_for(version).as_slice())
+            .await?;
+        stream.flush().await?;
+
+        // If the sender was told to go ahead, receive the file once, for
+        // the first recipient
+        let staging = staging.filter(|_| !matches!(response, Transmission::Error(_)));
+        if let Some((filename, recipients)) = staging {
+            let to = &recipients[0];
+            let file_path = format!("{}/{}/{}", config.staging_dir, username, to);
 
-            transfers::receive_file(stream, &file_path).await?;
-        } else if matches!(response, Transmission::OkSuccess) {
-            // Get the request
-            let Command::Ok(from) = command else {
-                unreachable!();
+            let options = transfers::ReceiveOptions {
+                metadata_timeout: Some(config.glide_receive_timeout),
+                retry: RetryPolicy {
+                    read_timeout: config.read_timeout,
+                    ..RetryPolicy::default()
+                },
+                extensions: Some(&config.extensions),
+                max_dir_files: config.max_dir_files,
+                max_dir_bytes: config.max_dir_bytes,
+                version,
+                ..transfers::ReceiveOptions::default()
             };
+            let whole_dir = matches!(command, Command::GlideDir { .. });
+            let result = match whole_dir {
+                true => transfers::receive_dir_with(stream, &file_path, &options).await,
+                false => transfers::receive_file_with(stream, &file_path, &options)
+                    .await
+                    .map(|file| vec![file]),
+            };
+            let size = result
+                .as_ref()
+                .map_or(0, |files| files.iter().map(|file| file.size as u64).sum());
+
+            // However it went wrong, whether the sender never started, gave
+            // up part way, called it off, sent something we won't keep or we
+            // ran out of room, nothing's staged, so withdraw the request we
+            // queued for them
+            if result.is_err() {
+                for to in &recipients {
+                    withdraw_glide(state, username, &filename, to, config).await;
+                }
+            }
 
-            let filename = {
-                let clients = state.lock().await;
-
-                if let Some(requests) = clients.get(username).map(|c| &c.incoming_requests) {
-                    // use a labeled loop for breaking with a value
-                    'outer: loop {
-                        for Request {
-                            sender: from_username,
-                            filename,
-                        } in requests.iter()
-                        {
-                            if from_username == &from {
-                                // break with the value
-                                break 'outer filename.clone();
-                            }
+            if let Some(audit) = &config.audit {
+                for to in &recipients {
+                    audit.log(AuditRecord {
+                        sender: username.to_string(),
+                        recipient: to.clone(),
+                        filename: filename.clone(),
+                        bytes: size,
+                        outcome: match &result {
+                            Ok(_) => "staged",
+                            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => "cancelled",
+                            Err(_) => "failed",
                         }
+                        .to_string(),
+                        // A directory has no one hash for all of it
+                        hash: match result.as_deref() {
+                            Ok([file]) if !whole_dir => Some(file.hash),
+                            _ => None,
+                        },
+                    });
+                }
+            }
 
-                        unreachable!()
+            // The sender meant to stop, so there's no reason to drop them
+            if matches!(&result, Err(e) if e.kind() == std::io::ErrorKind::Interrupted) {
+                return Ok(response);
+            }
+            let received = result?;
+
+            // Everyone else gets their own copy, since each is cleaned up on
+            // its own when it's accepted or refused. Only a glide to several
+            // users has anyone else, and it's always one file.
+            for other in &recipients[1..] {
+                for file in &received {
+                    let staged = format!("{}/{}", file_path, file.filename);
+                    let copy = format!(
+                        "{}/{}/{}/{}",
+                        config.staging_dir, username, other, file.filename
+                    );
+                    let copied = match tokio::fs::copy(&staged, &copy).await {
+                        Ok(_) if config.dedup_staging => {
+                            dedup::store(
+                                Path::new(&config.staging_dir),
+                                Path::new(&copy),
+                                file.hash,
+                            )
+                            .await
+                        }
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = copied {
+                        warn!(
+                            "Couldn't stage a copy of '{}' for @{}: {}",
+                            filename, other, e
+                        );
+                        withdraw_glide(state, username, &filename, other, config).await;
+                        break;
                     }
-                } else {
-                    unreachable!()
                 }
+            }
+
+            if config.dedup_staging {
+                for file in &received {
+                    let staged = format!("{}/{}", file_path, file.filename);
+                    if let Err(e) = dedup::store(
+                        Path::new(&config.staging_dir),
+                        Path::new(&staged),
+                        file.hash,
+                    )
+                    .await
+                    {
+                        for to in &recipients {
+                            withdraw_glide(state, username, &filename, to, config).await;
+                        }
+                        return Err(e.into());
+                    }
+                }
+            }
+
+            // Now there's a file, `reqs` can say how big it is
+            for to in &recipients {
+                state::with_user(state, to, |client| {
+                    client
+                        .incoming_requests
+                        .iter_mut()
+                        .filter(|req| req.sender == username && req.filename == filename)
+                        .for_each(|req| req.size = size);
+                })
+                .await;
+            }
+        } else if let (
+            Transmission::OkSuccess,
+            Command::Peek {
+                from,
+                filename,
+                bytes,
+            },
+        ) = (&response, &command)
+        {
+            // Only a preview, so the request and the staged file stay put
+            let path = format!("{}/{}/{}/{}", config.staging_dir, from, username, filename);
+            let options = transfers::SendOptions {
+                limit: Some(*bytes),
+                version,
+                ..transfers::SendOptions::default()
+            };
+            transfers::send_file_with(stream, &path, &options).await?;
+        } else if let Some((from, filename)) = accepted {
+            let path = format!("{}/{}/{}/{}", config.staging_dir, from, username, filename);
+
+            let staged = tokio::fs::metadata(&path).await;
+            let whole_dir = staged.as_ref().is_ok_and(|m| m.is_dir());
+            let bytes = staged.ok().filter(|m| m.is_file()).map_or(0, |m| m.len());
+            // Dropped however this ends, even if the handler is
+            let delivery = config.deliveries.start(&from, username, &filename);
+            let options = transfers::SendOptions {
+                cancel: Some(delivery.cancel.clone()),
+                version,
+                ..transfers::SendOptions::default()
             };
+            // A directory goes file by file, with no one hash for all of it
+            let result = match whole_dir {
+                true => transfers::send_dir_with(stream, &path, &options)
+                    .await
+                    .map(|sent| (sent.iter().map(|file| file.stats.bytes).sum(), None)),
+                false => transfers::send_file_with(stream, &path, &options)
+                    .await
+                    .map(|hash| (bytes, Some(hash))),
+            };
+            drop(delivery);
+            let cancelled =
+                matches!(&result, Err(e) if e.kind() == std::io::ErrorKind::Interrupted);
 
-            let path = format!("clients/{}/{}/{}", from, username, filename);
+            if let Some(audit) = &config.audit {
+                audit.log(AuditRecord {
+                    sender: from.clone(),
+                    recipient: username.to_string(),
+                    filename: filename.clone(),
+                    bytes: result.as_ref().map_or(bytes, |(sent, _)| *sent),
+                    outcome: match &result {
+                        Ok(_) => "delivered",
+                        Err(_) if cancelled => "cancelled",
+                        Err(_) => "failed",
+                    }
+                    .to_string(),
+                    hash: result.as_ref().ok().and_then(|(_, hash)| *hash),
+                });
+            }
 
-            transfers::send_file(stream, &path).await?;
+            // Called off by either end, which isn't the recipient's fault
+            if cancelled {
+                withdraw_glide(state, &from, &filename, username, config).await;
+                return Ok(response);
+            }
+            result?;
 
-            // Remove the file after sending
-            tokio::fs::remove_file(&path).await?;
+            // Delivered, so it's no longer pending, and another `ok` moves on
+            // to the next one
+            state::with_user(state, username, |client| {
+                let pos = client
+                    .incoming_requests
+                    .iter()
+                    .position(|req| req.sender == from && req.filename == filename);
+                if let Some(pos) = pos {
+                    client.incoming_requests.remove(pos);
+                }
+            })
+            .await;
+
+            // Remove the file after sending. It's been delivered either way,
+            // so a failure here is the server's problem, not the user's.
+            let _ = cleanup_file(&from, username, &filename, config).await;
         }
-        Ok(())
+        Ok(response)
     }
 
     // -- Command implementations --
 
+    async fn cmd_caps(&self, config: &ServerConfig) -> Transmission {
+        let enabled = COMMAND_NAMES.iter().filter(|name| allowed(config, name));
+        Transmission::Capabilities(enabled.map(|name| name.to_string()).collect())
+    }
+
+    // Moves the user to a new name. On `UsernameOk` the caller must carry on
+    // as the new name.
+    async fn cmd_nick(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let Command::SetName(new) = self else {
+            unreachable!()
+        };
+
+        if !is_valid_username(new) {
+            return Transmission::UsernameInvalid;
+        }
+
+        if new == username {
+            return Transmission::UsernameOk;
+        }
+
+        // Checked before anything's moved, so nobody else's staged files are
+        // touched. A user may change the case of their own name.
+        let folded = new.to_lowercase();
+        let taken = state::usernames(state)
+            .await
+            .iter()
+            .any(|name| name != username && name.to_lowercase() == folded);
+        if taken {
+            return Transmission::UsernameTaken;
+        }
+
+        // Staged files live under clients/<sender>/<recipient>/, so move
+        // both the ones waiting for this user and the ones they sent, before
+        // the name changes. If any can't be, the rest are put back.
+        let mut moves = Vec::new();
+        if let Ok(mut senders) = tokio::fs::read_dir(&config.staging_dir).await {
+            while let Ok(Some(sender)) = senders.next_entry().await {
+                moves.push((sender.path().join(username), sender.path().join(new)));
+            }
+        }
+        moves.push((
+            Path::new(&config.staging_dir).join(username),
+            Path::new(&config.staging_dir).join(new),
+        ));
+        let mut moved = Vec::new();
+        for (old_dir, new_dir) in moves {
+            match tokio::fs::rename(&old_dir, &new_dir).await {
+                Ok(()) => moved.push((old_dir, new_dir)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(
+                        "Couldn't move '{}' to '{}': {}",
+                        old_dir.display(),
+                        new_dir.display(),
+                        e
+                    );
+                    move_back(&moved).await;
+                    return Transmission::Error(format!(
+                        "Couldn't move your staged files to '{}'",
+                        new
+                    ));
+                }
+            }
+        }
+
+        if !state::rename_user(state, username, new).await {
+            move_back(&moved).await;
+            return Transmission::UsernameTaken;
+        }
+
+        // Requests this user has sent others now come from the new name
+        state::for_each_user(state, |_, client| {
+            client
+                .incoming_requests
+                .iter_mut()
+                .filter(|req| req.sender == username)
+                .for_each(|req| req.sender = new.clone());
+        })
+        .await;
+        // And so do answers waiting for them
+        config.outcomes.rename(username, new);
+
+        Transmission::UsernameOk
+    }
+
+    async fn cmd_key(&self, state: &SharedState) -> Transmission {
+        let Command::Key(user) = self else {
+            unreachable!()
+        };
+
+        match state::with_user(state, user, |client| client.public_key).await {
+            Some(Some(key)) => Transmission::PublicKey(user.clone(), key),
+            Some(None) => Transmission::Error(format!("@{} hasn't published a key", user)),
+            None => Transmission::UsernameInvalid,
+        }
+    }
+
     async fn cmd_list(&self, state: &SharedState, username: &str) -> Transmission {
-        let clients = state.lock().await;
-        let user_list: Vec<String> = clients.keys().cloned().filter(|x| x != username).collect();
+        let Command::List {
+            filter,
+            page,
+            page_size,
+        } = self
+        else {
+            unreachable!()
+        };
 
-        Transmission::ConnectedUsers(user_list)
+        let glob = match filter.as_deref().filter(|filter| is_glob(filter)) {
+            Some(filter) => match glob::Pattern::new(filter) {
+                Ok(glob) => Some(glob),
+                Err(e) => {
+                    return Transmission::Error(format!("Invalid pattern '{}': {}", filter, e))
+                }
+            },
+            None => None,
+        };
+        let options = glob::MatchOptions {
+            case_sensitive: false,
+            ..glob::MatchOptions::new()
+        };
+        let folded = filter.as_ref().map(|filter| filter.to_lowercase());
+
+        let mut user_list: Vec<String> = state::usernames(state)
+            .await
+            .into_iter()
+            .filter(|x| x != username)
+            .filter(|x| match (&glob, &folded) {
+                (Some(glob), _) => glob.matches_with(x, options),
+                (None, Some(folded)) => x.to_lowercase().contains(folded),
+                (None, None) => true,
+            })
+            .collect();
+        // Sorted so pages don't overlap or miss anyone between requests
+        user_list.sort();
+
+        let size = match page_size {
+            Some(0) | None => LIST_PAGE_SIZE,
+            Some(size) => (*size).min(LIST_PAGE_SIZE),
+        } as usize;
+        let start = (page.unwrap_or(0) as usize).saturating_mul(size);
+        let total = user_list.len() as u32;
+        let users: Vec<String> = user_list.into_iter().skip(start).take(size).collect();
+
+        // A plain `list` gets the answer it always has, for older clients,
+        // unless that would leave users out without saying so
+        let whole = users.len() as u32 == total;
+        if filter.is_none() && page.is_none() && page_size.is_none() && whole {
+            Transmission::ConnectedUsers(users)
+        } else {
+            Transmission::UsersPage { total, users }
+        }
     }
 
     async fn cmd_reqs(&self, state: &SharedState, username: &str) -> Transmission {
-        let clients = state.lock().await;
-        let incoming_user_list: Vec<Request> =
-            clients.get(username).unwrap().incoming_requests.clone();
+        // Gone already, say if they were dropped while this was queued
+        let Some(incoming_user_list) =
+            state::with_user(state, username, |client| client.incoming_requests.clone()).await
+        else {
+            return Transmission::UsernameInvalid;
+        };
+        if u16::try_from(incoming_user_list.len()).is_err() {
+            return Transmission::Error(format!(
+                "Too many glides are pending to list ({}), answer some first",
+                incoming_user_list.len()
+            ));
+        }
 
         Transmission::IncomingRequests(incoming_user_list)
     }
 
-    async fn cmd_glide(&self, state: &SharedState, username: &str) -> Transmission {
-        let Command::Glide { path, to } = self else {
+    async fn cmd_whoami(&self, state: &SharedState, username: &str) -> Transmission {
+        let Some(pending) =
+            state::with_user(state, username, |client| client.incoming_requests.len()).await
+        else {
+            return Transmission::UsernameInvalid;
+        };
+
+        Transmission::UserStatus {
+            username: username.to_string(),
+            pending: pending as u32,
+        }
+    }
+
+    async fn cmd_glide(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let (to, filename, whole_dir) = match self {
+            Command::Glide { path, to, as_name } => (to, staged_name(path, as_name), false),
+            Command::GlideDir { path, to } => (to, staged_name(path, &None), true),
+            _ => unreachable!(),
+        };
+
+        if username == to {
+            return Transmission::UsernameInvalid;
+        }
+
+        // Which kinds of file are allowed is checked on each file in a
+        // directory as it arrives, rather than on the directory's name
+        let refused = match whole_dir {
+            true if !is_valid_filename(&filename) => Some(Transmission::GlideRefused(format!(
+                "'{}' isn't a valid directory name",
+                filename
+            ))),
+            true => None,
+            false => refuse_filename(&filename, config),
+        };
+        if let Some(refused) = refused {
+            return refused;
+        }
+
+        let request = Request {
+            sender: username.to_string(),
+            filename,
+            size: 0,
+            offered_at: SystemTime::now(),
+        };
+
+        // Add request, if the user exists and this sender hasn't filled their inbox
+        let added = state::with_user(state, to, |client| {
+            let staged = client
+                .incoming_requests
+                .iter()
+                .filter(|req| req.sender == username)
+                .count();
+            if config
+                .max_staged_per_sender
+                .is_some_and(|max| staged >= max)
+            {
+                return false;
+            }
+
+            client.incoming_requests.push(request);
+            true
+        })
+        .await;
+
+        match added {
+            Some(true) => Transmission::GlideRequestSent,
+            Some(false) => {
+                Transmission::GlideRefused(format!("Too many files already waiting for @{}", to))
+            }
+            None => Transmission::UsernameInvalid,
+        }
+    }
+
+    async fn cmd_glide_many(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let Command::GlideMany { path, to } = self else {
             unreachable!()
         };
 
-        // Check if user exists
-        let mut clients = state.lock().await;
-        if !clients.contains_key(to) || username == to {
+        if to.iter().any(|user| user == username) {
             return Transmission::UsernameInvalid;
         }
 
-        // Add request
-        clients
-            .get_mut(to)
-            .unwrap()
-            .incoming_requests
-            .push(Request {
+        let filename = staged_name(path, &None);
+        if let Some(refused) = refuse_filename(&filename, config) {
+            return refused;
+        }
+
+        // Check everyone has room first, so it's all or nothing
+        let mut unknown = Vec::new();
+        for user in to {
+            let staged = state::with_user(state, user, |client| {
+                client
+                    .incoming_requests
+                    .iter()
+                    .filter(|req| req.sender == username)
+                    .count()
+            })
+            .await;
+
+            match staged {
+                None => unknown.push(user.clone()),
+                Some(staged)
+                    if config
+                        .max_staged_per_sender
+                        .is_some_and(|max| staged >= max) =>
+                {
+                    return Transmission::GlideRefused(format!(
+                        "Too many files already waiting for @{}",
+                        user
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+
+        let known: Vec<&String> = to.iter().filter(|user| !unknown.contains(user)).collect();
+        let mut queued = 0;
+        for user in known {
+            let request = Request {
                 sender: username.to_string(),
-                filename: Path::new(path)
-                    .file_name()
-                    .unwrap()
-                    .to_str()
-                    .unwrap()
-                    .to_string(),
-            });
+                filename: filename.clone(),
+                size: 0,
+                offered_at: SystemTime::now(),
+            };
+            match state::with_user(state, user, |client| client.incoming_requests.push(request))
+                .await
+            {
+                Some(()) => queued += 1,
+                // Left since we looked
+                None => unknown.push(user.clone()),
+            }
+        }
 
-        Transmission::GlideRequestSent
+        if queued == 0 {
+            return Transmission::UsernameInvalid;
+        }
+        Transmission::GlideRequestsSent { unknown }
     }
 
-    async fn cmd_ok(&self, state: &SharedState, username: &str) -> Transmission {
-        let Command::Ok(from) = self else {
+    async fn cmd_ok(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let (Command::Ok(from, filename) | Command::OkInto { from, filename, .. }) = self else {
             unreachable!()
         };
 
-        let clients = state.lock().await;
+        let request = state::with_user(state, username, |client| {
+            accepted_request(client, from, filename.as_deref()).cloned()
+        })
+        .await
+        .flatten();
+
+        let request = match (request, filename) {
+            (Some(request), _) => request,
+            (None, Some(filename)) => {
+                return Transmission::Error(format!(
+                    "@{} has no pending glide of '{}'",
+                    from, filename
+                ))
+            }
+            (None, None) => return Transmission::OkFailed,
+        };
 
-        if let Some(client) = clients.get(username) {
-            let valid_request = client
+        // The sender went offline after gliding, let them know later
+        if !state::contains_user(state, from).await {
+            config
+                .outcomes
+                .queue(from, username, &request.filename, true);
+        }
+
+        Transmission::OkSuccess
+    }
+
+    async fn cmd_peek(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let Command::Peek { from, filename, .. } = self else {
+            unreachable!()
+        };
+
+        let pending = state::with_user(state, username, |client| {
+            client
                 .incoming_requests
                 .iter()
-                .any(|req| &req.sender == from);
+                .any(|req| &req.sender == from && &req.filename == filename)
+        })
+        .await
+        .unwrap_or(false);
 
-            if valid_request {
-                return Transmission::OkSuccess;
-            }
+        if !pending {
+            return Transmission::OkFailed;
         }
 
-        Transmission::OkFailed
+        // Only a single file can be previewed
+        let path = format!("{}/{}/{}/{}", config.staging_dir, from, username, filename);
+        if tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_dir()) {
+            return Transmission::Error(format!("Can't peek at the directory '{}'", filename));
+        }
+
+        Transmission::OkSuccess
     }
 
-    async fn cmd_no(&self, state: &SharedState, username: &str) -> Transmission {
-        let Command::No(from) = self else {
+    async fn cmd_no(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let Command::No(from, filename) = self else {
             unreachable!()
         };
 
-        let mut clients = state.lock().await;
-
-        if let Some(client) = clients.get_mut(username) {
-            if let Some(pos) = client
+        // Without a filename, refuse to guess between several files from
+        // the same sender
+        let request = state::with_user(state, username, |client| {
+            let matching: Vec<usize> = client
                 .incoming_requests
                 .iter()
-                .position(|req| &req.sender == from)
-            {
-                let request = client.incoming_requests.remove(pos);
-                let file_path = format!("clients/{}/{}/{}", from, username, request.filename);
-                let _ = tokio::fs::remove_file(file_path).await; // ignore errors
+                .enumerate()
+                .filter(|(_, req)| &req.sender == from)
+                .filter(|(_, req)| filename.as_ref().is_none_or(|f| &req.filename == f))
+                .map(|(pos, _)| pos)
+                .collect();
+
+            match matching[..] {
+                [] => Ok(None),
+                [pos] => Ok(Some(client.incoming_requests.remove(pos))),
+                _ => Err(matching
+                    .iter()
+                    .map(|&pos| client.incoming_requests[pos].filename.clone())
+                    .collect::<Vec<_>>()),
+            }
+        })
+        .await
+        .unwrap_or(Ok(None));
+
+        let request = match request {
+            Ok(request) => request,
+            Err(filenames) => {
+                return Transmission::Error(format!(
+                    "@{} has sent {} files, pick one with 'no @{} <file>': {}",
+                    from,
+                    filenames.len(),
+                    from,
+                    filenames.join(", ")
+                ))
             }
+        };
+
+        if let Some(request) = request {
+            if !state::contains_user(state, from).await {
+                config
+                    .outcomes
+                    .queue(from, username, &request.filename, false);
+            }
+            let _ = cleanup_file(from, username, &request.filename, config).await;
         }
 
         Transmission::NoSuccess
     }
+
+    async fn cmd_purge(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let requests = state::with_user(state, username, |client| {
+            std::mem::take(&mut client.incoming_requests)
+        })
+        .await
+        .unwrap_or_default();
+
+        // Same as refusing each one with `no`
+        let mut purged = 0;
+        for request in &requests {
+            if !state::contains_user(state, &request.sender).await {
+                config
+                    .outcomes
+                    .queue(&request.sender, username, &request.filename, false);
+            }
+            let _ = cleanup_file(&request.sender, username, &request.filename, config).await;
+            purged += 1;
+        }
+
+        // Then sweep up anything staged for this user that no request points
+        // at any more. Only clients/<sender>/<username>/ is ever touched, and
+        // files still on their way in are left to their transfers.
+        if let Ok(mut senders) = tokio::fs::read_dir(&config.staging_dir).await {
+            while let Ok(Some(sender)) = senders.next_entry().await {
+                if sender.path() == Path::new(&config.staging_dir).join(dedup::BLOB_DIR) {
+                    continue;
+                }
+                let Ok(mut staged) = tokio::fs::read_dir(sender.path().join(username)).await else {
+                    continue;
+                };
+                let sender = sender.file_name().to_string_lossy().to_string();
+                while let Ok(Some(file)) = staged.next_entry().await {
+                    let filename = file.file_name().to_string_lossy().to_string();
+                    let staged = !filename.ends_with(PARTIAL_SUFFIX)
+                        && file
+                            .file_type()
+                            .await
+                            .is_ok_and(|t| t.is_file() || t.is_dir());
+                    if staged
+                        && cleanup_file(&sender, username, &filename, config)
+                            .await
+                            .is_ok()
+                    {
+                        purged += 1;
+                    }
+                }
+            }
+        }
+
+        Transmission::Purged(purged)
+    }
+
+    async fn cmd_cancel(
+        &self,
+        state: &SharedState,
+        username: &str,
+        config: &ServerConfig,
+    ) -> Transmission {
+        let Command::Cancel(filename) = self else {
+            unreachable!()
+        };
+
+        // Deliveries under way stop at their next chunk, and tell the
+        // recipient themselves
+        let mut cancelled = config.deliveries.cancel(username, filename);
+
+        // Glides still waiting are withdrawn from whoever they were sent to
+        for recipient in state::usernames(state).await {
+            let withdrawn = state::with_user(state, &recipient, |client| {
+                let before = client.incoming_requests.len();
+                client
+                    .incoming_requests
+                    .retain(|req| req.sender != username || &req.filename != filename);
+                before - client.incoming_requests.len()
+            })
+            .await
+            .unwrap_or(0);
+
+            if withdrawn > 0 {
+                let _ = cleanup_file(username, &recipient, filename, config).await;
+                cancelled += withdrawn;
+            }
+        }
+
+        if cancelled == 0 {
+            return Transmission::Error(format!("No glide of '{}' to cancel", filename));
+        }
+        Transmission::TransferCancelled(filename.clone())
+    }
+}
+
+// Why a glide under `filename` can't be staged, if it can't
+fn refuse_filename(filename: &str, config: &ServerConfig) -> Option<Transmission> {
+    if !is_valid_filename(filename) {
+        return Some(Transmission::GlideRefused(format!(
+            "'{}' isn't a valid filename",
+            filename
+        )));
+    }
+
+    if !config.extensions.permits(filename) {
+        return Some(Transmission::GlideRefused(format!(
+            "The server doesn't accept files like '{}'",
+            filename
+        )));
+    }
+
+    None
+}
+
+// Whether the server lets clients use the command with this name
+fn allowed(config: &ServerConfig, name: &str) -> bool {
+    config
+        .allowed_commands
+        .as_ref()
+        .is_none_or(|allowed| allowed.iter().any(|allowed| allowed == name))
+}
+
+// Recipients in the order given, each once
+fn recipients(users: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut to: Vec<String> = Vec::new();
+    for user in users {
+        if !to.contains(&user) {
+            to.push(user);
+        }
+    }
+    to
+}
+
+fn staging_error_message(dir: &str, e: &std::io::Error) -> String {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied => {
+            format!(
+                "Server can't stage files: no permission to create '{}'",
+                dir
+            )
+        }
+        std::io::ErrorKind::AlreadyExists | std::io::ErrorKind::NotADirectory => {
+            format!(
+                "Server can't stage files: a file is in the way of '{}'",
+                dir
+            )
+        }
+        _ => format!("Server can't stage files in '{}': {}", dir, e),
+    }
+}
+
+// The name a glided file is staged under: the one given with `as`, or the
+// file's own. Empty for a path like ".." that doesn't name a file, which
+// is then refused as an invalid filename.
+pub(crate) fn staged_name(path: &str, as_name: &Option<String>) -> String {
+    match as_name {
+        Some(name) => name.clone(),
+        None => Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+// Undoes a glide that never completed: drops the request from the
+// recipient's list and deletes whatever was staged for it
+pub(crate) async fn withdraw_glide(
+    state: &SharedState,
+    sender: &str,
+    filename: &str,
+    to: &str,
+    config: &ServerConfig,
+) {
+    state::with_user(state, to, |client| {
+        client
+            .incoming_requests
+            .retain(|req| req.sender != sender || req.filename != filename)
+    })
+    .await;
+
+    let _ = cleanup_file(sender, to, filename, config).await;
+}
+
+// Takes a user who's gone out of the shared state and refuses whatever was
+// waiting for them, deleting the staged files. Call it once their stream
+// hits EOF or is dropped; `connection::run` and `serve` already do. The
+// state's lock is let go before any file is touched.
+pub async fn cleanup_user(state: &SharedState, username: &str) -> Option<UserData> {
+    cleanup_user_with(state, username, &ServerConfig::default()).await
+}
+
+// Same as `cleanup_user`, auditing with `config`
+pub async fn cleanup_user_with(
+    state: &SharedState,
+    username: &str,
+    config: &ServerConfig,
+) -> Option<UserData> {
+    let data = state::remove_user(state, username).await?;
+
+    // Same as refusing each one with `no`
+    for request in &data.incoming_requests {
+        if !state::contains_user(state, &request.sender).await {
+            config
+                .outcomes
+                .queue(&request.sender, username, &request.filename, false);
+        }
+        let _ = cleanup_file(&request.sender, username, &request.filename, config).await;
+    }
+
+    Some(data)
+}
+
+// Undoes the directory moves of a rename that didn't go through, last first
+async fn move_back(moved: &[(PathBuf, PathBuf)]) {
+    for (old_dir, new_dir) in moved.iter().rev() {
+        if let Err(e) = tokio::fs::rename(new_dir, old_dir).await {
+            warn!(
+                "Couldn't move '{}' back to '{}': {}",
+                new_dir.display(),
+                old_dir.display(),
+                e
+            );
+        }
+    }
+}
+
+// Deletes a staged file that's no longer wanted. A file that's already gone
+// counts as cleaned up. Any other failure is logged and audited, but it's
+// up to the caller whether it matters; the user's command went through.
+pub(crate) async fn cleanup_file(
+    sender: &str,
+    recipient: &str,
+    filename: &str,
+    config: &ServerConfig,
+) -> Result<(), CleanupError> {
+    let path = format!(
+        "{}/{}/{}/{}",
+        config.staging_dir, sender, recipient, filename
+    );
+    // What's left of a glide that was cut off while it was being staged
+    let _ = tokio::fs::remove_file(transfers::partial_path(&path)).await;
+    let released = match tokio::fs::symlink_metadata(&path).await {
+        Ok(m) if m.is_dir() => {
+            dedup::release_dir(Path::new(&config.staging_dir), Path::new(&path)).await
+        }
+        _ => dedup::release(Path::new(&config.staging_dir), Path::new(&path)).await,
+    };
+    let error = match released {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => CleanupError { path, source: e },
+    };
+
+    warn!("{}", error);
+    if let Some(audit) = &config.audit {
+        audit.log(AuditRecord {
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            filename: filename.to_string(),
+            bytes: 0,
+            outcome: "cleanup-failed".to_string(),
+            hash: None,
+        });
+    }
+
+    Err(error)
+}
+
+// The file named after `ok @user` or `no @user`
+fn reply_filename(filename: Option<regex::Match>) -> Result<Option<String>, ParseError> {
+    match filename.map(|m| m.as_str()) {
+        Some(name) if !is_valid_filename(name) => {
+            Err(ParseError::InvalidFilename(name.to_string()))
+        }
+        name => Ok(name.map(String::from)),
+    }
+}
+
+// The request `ok @from [filename]` accepts: the named file, or else the
+// first one `from` sent
+fn accepted_request<'a>(
+    client: &'a UserData,
+    from: &str,
+    filename: Option<&str>,
+) -> Option<&'a Request> {
+    client
+        .incoming_requests
+        .iter()
+        .filter(|req| req.sender == from)
+        .find(|req| filename.is_none_or(|f| req.filename == f))
+}
+
+// Usernames never have these in them, so a filter that does is a glob
+fn is_glob(filter: &str) -> bool {
+    filter.contains(['*', '?', '['])
+}
+
+// The filter typed after `list`, checked if it's a glob
+fn list_filter(filter: Option<regex::Match>) -> Result<Option<String>, ParseError> {
+    let Some(filter) = filter.map(|m| m.as_str()) else {
+        return Ok(None);
+    };
+    if is_glob(filter) {
+        glob::Pattern::new(filter)
+            .map_err(|e| ParseError::InvalidGlob(filter.to_string(), e.to_string()))?;
+    }
+
+    Ok(Some(filter.to_string()))
+}
+
+// The page and page size typed after `list -p` and `-n`. Sizes past what
+// fits on the wire are cut down, and the server cuts them down further.
+fn list_page(
+    page: Option<regex::Match>,
+    size: Option<regex::Match>,
+) -> Result<(Option<u32>, Option<u16>), ParseError> {
+    let page = page.map(|m| parse_number(m.as_str())).transpose()?;
+    let size = size
+        .map(|m| parse_number::<u32>(m.as_str()))
+        .transpose()?
+        .map(|size| size.min(u16::MAX as u32) as u16);
+    Ok((page, size))
+}
+
+// A count typed into a command, such as how many bytes to `peek` at
+fn parse_number<T: std::str::FromStr>(digits: &str) -> Result<T, ParseError> {
+    digits
+        .parse()
+        .map_err(|_| ParseError::InvalidNumber(digits.to_string()))
+}
+
+// Strips stray leading `@`s off a username typed after an `@`, and checks
+// what's left is a plausible username
+fn clean_username(raw: &str) -> Result<String, ParseError> {
+    let username = raw.trim_start_matches('@');
+
+    if username.is_empty() {
+        return Err(ParseError::MissingUsername);
+    }
+
+    if !is_valid_username(username) {
+        return Err(ParseError::InvalidUsername(raw.to_string()));
+    }
+
+    Ok(username.to_string())
+}
+
+// A name a file can be staged and saved under: a single path component
+// with nothing the filesystem would treat specially
+pub fn is_valid_filename(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control)
+}
+
+// Longest username, in characters
+pub const MAX_USERNAME_LEN: usize = 32;
+
+// Names no one can take, in any case. Some would clash with the staging
+// directories under clients/, the rest would only confuse people.
+pub const RESERVED_USERNAMES: [&str; 6] = [".", "..", ".blobs", "server", "admin", "everyone"];
+
+pub fn is_valid_username(username: &str) -> bool {
+    validate_username(username).is_ok()
+}
+
+// Same as `is_valid_username`, saying what's wrong with the name
+pub fn validate_username(username: &str) -> Result<(), UsernameError> {
+    if username.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+
+    let len = username.chars().count();
+    if len > MAX_USERNAME_LEN {
+        return Err(UsernameError::TooLong(len));
+    }
+
+    if let Some(c) = username
+        .chars()
+        .find(|&c| !(c.is_alphanumeric() || c == '_' || c == '-' || c == '.'))
+    {
+        return Err(UsernameError::InvalidChar(c));
+    }
+
+    if RESERVED_USERNAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(username))
+    {
+        return Err(UsernameError::Reserved(username.to_string()));
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Command::List {
+                filter,
+                page,
+                page_size,
+            } => {
+                write!(f, "list")?;
+                if let Some(filter) = filter {
+                    write!(f, " {}", filter)?;
+                }
+                if page.is_some() || page_size.is_some() {
+                    write!(f, " -p {}", page.unwrap_or(0))?;
+                }
+                match page_size {
+                    Some(size) => write!(f, " -n {}", size),
+                    None => Ok(()),
+                }
+            }
+            Command::Requests => write!(f, "reqs"),
+            Command::Glide {
+                path,
+                to,
+                as_name: None,
+            } => write!(f, "glide {} @{}", path, to),
+            Command::Glide {
+                path,
+                to,
+                as_name: Some(name),
+            } => write!(f, "glide {} @{} as {}", path, to, name),
+            Command::Ok(user, None) => write!(f, "ok @{}", user),
+            Command::Ok(user, Some(filename)) => write!(f, "ok @{} {}", user, filename),
+            Command::OkInto {
+                from,
+                filename,
+                into,
+            } => {
+                write!(f, "ok @{}", from)?;
+                if let Some(filename) = filename {
+                    write!(f, " {}", filename)?;
+                }
+                write!(f, " into {}", into)
+            }
+            Command::No(user, None) => write!(f, "no @{}", user),
+            Command::No(user, Some(filename)) => write!(f, "no @{} {}", user, filename),
+            Command::Capabilities => write!(f, "caps"),
+            Command::SetName(name) => write!(f, "nick {}", name),
+            Command::Key(user) => write!(f, "key @{}", user),
+            Command::Peek {
+                from,
+                filename,
+                bytes,
+            } => write!(f, "peek @{} {} {}", from, filename, bytes),
+            Command::Purge => write!(f, "purge"),
+            Command::Cancel(filename) => write!(f, "cancel {}", filename),
+            Command::Whoami => write!(f, "whoami"),
+            Command::OkLatest => write!(f, "ok"),
+            Command::NoLatest => write!(f, "no"),
+            Command::GlideDir { path, to } => write!(f, "glide -r {} @{}", path, to),
+            Command::GlideMany { path, to } => {
+                write!(f, "glide {}", path)?;
+                for user in to {
+                    write!(f, " @{}", user)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    UnknownCommand(String),
+    // A glide that isn't `glide <path> @<username>` or one of its forms
+    MalformedGlide(String),
+    InvalidGlob(String, String),
+    NoGlobMatches(String),
+    MissingUsername,
+    InvalidUsername(String),
+    InvalidFilename(String),
+    InvalidNumber(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnknownCommand(input) => write!(f, "Unknown command '{}'", input),
+            ParseError::MalformedGlide(input) => {
+                write!(
+                    f,
+                    "Malformed glide '{}', expected 'glide <path> @<username>'",
+                    input
+                )
+            }
+            ParseError::InvalidGlob(pattern, reason) => {
+                write!(f, "Invalid pattern '{}': {}", pattern, reason)
+            }
+            ParseError::NoGlobMatches(pattern) => write!(f, "No files match '{}'", pattern),
+            ParseError::MissingUsername => write!(f, "Missing username, expected '@<username>'"),
+            ParseError::InvalidUsername(name) => {
+                write!(f, "Invalid username '{}', expected '@<username>'", name)
+            }
+            ParseError::InvalidFilename(name) => write!(f, "Invalid filename '{}'", name),
+            ParseError::InvalidNumber(digits) => write!(f, "Invalid number '{}'", digits),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsernameError {
+    Empty,
+    // How many characters it was
+    TooLong(usize),
+    InvalidChar(char),
+    Reserved(String),
+}
+
+impl fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "Usernames can't be empty"),
+            UsernameError::TooLong(len) => write!(
+                f,
+                "Usernames are at most {} characters, not {}",
+                MAX_USERNAME_LEN, len
+            ),
+            UsernameError::InvalidChar(c) => {
+                write!(f, "Usernames can't contain {:?}", c)
+            }
+            UsernameError::Reserved(name) => write!(f, "'{}' is reserved", name),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+// A staged file that couldn't be deleted
+#[derive(Debug)]
+pub struct CleanupError {
+    pub path: String,
+    pub source: std::io::Error,
+}
+
+impl fmt::Display for CleanupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unable to delete '{}': {}", self.path, self.source)
+    }
+}
+
+impl std::error::Error for CleanupError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
 }
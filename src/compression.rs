@@ -0,0 +1,195 @@
+use flate2::{
+    read::GzDecoder, write::GzEncoder, Compress, Decompress, FlushCompress, FlushDecompress, Status,
+};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+use crate::data::CHUNK_SIZE;
+
+// Compresses a whole file as one deflate stream, fed a piece at a time, so
+// the window spans chunk boundaries
+pub struct Deflater(Compress);
+
+impl Deflater {
+    pub fn new() -> Self {
+        Self(Compress::new(flate2::Compression::default(), false))
+    }
+
+    // Compresses the next piece of the file, returning whatever output is
+    // ready. Often nothing, while the compressor builds up a block.
+    pub fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        self.run(data, FlushCompress::None)
+    }
+
+    // Ends the stream, returning the rest of the output
+    pub fn finish(mut self) -> Vec<u8> {
+        self.run(&[], FlushCompress::Finish)
+    }
+
+    fn run(&mut self, mut input: &[u8], flush: FlushCompress) -> Vec<u8> {
+        let mut out = Vec::with_capacity(CHUNK_SIZE);
+        loop {
+            let before = self.0.total_in();
+            let status = self
+                .0
+                .compress_vec(input, &mut out, flush)
+                .expect("deflate never fails on valid arguments");
+            input = &input[(self.0.total_in() - before) as usize..];
+
+            let finished = match flush {
+                FlushCompress::Finish => status == Status::StreamEnd,
+                _ => input.is_empty(),
+            };
+            if finished && out.len() < out.capacity() {
+                return out;
+            }
+            out.reserve(CHUNK_SIZE);
+        }
+    }
+}
+
+impl Default for Deflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Undoes `Deflater`, however the stream was split into chunks
+pub struct Inflater {
+    inner: Decompress,
+    done: bool,
+}
+
+impl Inflater {
+    pub fn new() -> Self {
+        Self {
+            inner: Decompress::new(false),
+            done: false,
+        }
+    }
+
+    // Whether the end of the stream has arrived
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Decompresses the next piece of the stream, refusing one that comes out
+    // to more than `max_len` bytes
+    pub fn push(&mut self, mut input: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        // Room for one byte past the cap is enough to tell it was passed
+        let limit = max_len.saturating_add(1);
+        let mut out = Vec::with_capacity(input.len().saturating_mul(4).min(limit));
+        while !self.done {
+            let before = self.inner.total_in();
+            let status = self
+                .inner
+                .decompress_vec(input, &mut out, FlushDecompress::None)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            input = &input[(self.inner.total_in() - before) as usize..];
+
+            if out.len() > max_len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "A chunk decompressed to more than the rest of the file",
+                ));
+            }
+            if status == Status::StreamEnd {
+                self.done = true;
+            } else if input.is_empty() && out.len() < out.capacity() {
+                break;
+            } else {
+                let grow = out.capacity().max(CHUNK_SIZE * 4);
+                out.reserve_exact(grow.min(limit - out.len()));
+            }
+        }
+
+        if self.done && !input.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Data after the end of the compressed stream",
+            ));
+        }
+        Ok(out)
+    }
+}
+
+impl Default for Inflater {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Most of the file a chunk compressed on its own may hold, leaving room for
+// the codec's overhead on data that doesn't shrink, within a chunk's 2 byte
+// length
+pub const MAX_CODEC_INPUT: usize = 60 * 1024;
+
+// Compresses each chunk on its own, so it comes out without the ones before
+// it. Worse ratios than `Deflater`, but no state carried across chunks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkCodec {
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl ChunkCodec {
+    // The value of the compression attribute naming it
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChunkCodec::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            ChunkCodec::Zstd => "zstd",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(ChunkCodec::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(ChunkCodec::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            ChunkCodec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(data)
+                    .and_then(|_| encoder.finish())
+                    .expect("gzip never fails writing to memory")
+            }
+            #[cfg(feature = "zstd")]
+            ChunkCodec::Zstd => {
+                zstd::bulk::compress(data, 0).expect("zstd never fails on valid arguments")
+            }
+        }
+    }
+
+    // Decompresses a chunk, refusing one that comes out to more than
+    // `max_len` bytes
+    pub fn decompress(&self, data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+        let out = match self {
+            ChunkCodec::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(data)
+                    .take(max_len as u64 + 1)
+                    .read_to_end(&mut out)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                out
+            }
+            #[cfg(feature = "zstd")]
+            ChunkCodec::Zstd => zstd::bulk::decompress(data, max_len)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+        };
+
+        if out.len() > max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "A chunk decompressed to more than the rest of the file",
+            ));
+        }
+        Ok(out)
+    }
+}
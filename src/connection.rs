@@ -0,0 +1,498 @@
+use futures::{future::BoxFuture, stream::FuturesUnordered, FutureExt, StreamExt};
+use log::info;
+use std::{error::Error, fmt, future::Future, net::SocketAddr, time::Instant};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::{self, Interval},
+};
+
+use crate::{
+    commands::{self, Command},
+    data::{ServerConfig, UserData},
+    error::GlideError,
+    protocol::{ProtocolError, ProtocolVersion, Transmission},
+    state::{self, SharedState},
+};
+
+// What a connection runs over: a plain TcpStream, or one wrapped in TLS with
+// the `tls` feature
+pub trait Socket: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr>;
+
+    // Resolves once there's something to read, without reading it
+    fn readable(&mut self) -> BoxFuture<'_, std::io::Result<()>>;
+}
+
+impl Socket for TcpStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn readable(&mut self) -> BoxFuture<'_, std::io::Result<()>> {
+        async move {
+            let mut first_byte = [0; 1];
+            self.peek(&mut first_byte).await.map(drop)
+        }
+        .boxed()
+    }
+}
+
+// Where a connection is in its lifetime. Commands are only accepted once
+// registered, and a transfer always belongs to a registered user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnState {
+    Unauthenticated,
+    Registered(String),
+    // Running a glide or ok, which streams a file after the response
+    Transferring(String),
+}
+
+impl ConnState {
+    pub fn username(&self) -> Option<&str> {
+        match self {
+            ConnState::Unauthenticated => None,
+            ConnState::Registered(username) | ConnState::Transferring(username) => Some(username),
+        }
+    }
+
+    pub fn register(self, username: String) -> Result<Self, InvalidTransition> {
+        match self {
+            ConnState::Unauthenticated => Ok(ConnState::Registered(username)),
+            state => Err(InvalidTransition::new(state, "register")),
+        }
+    }
+
+    pub fn begin_transfer(self) -> Result<Self, InvalidTransition> {
+        match self {
+            ConnState::Registered(username) => Ok(ConnState::Transferring(username)),
+            state => Err(InvalidTransition::new(state, "begin a transfer")),
+        }
+    }
+
+    pub fn finish_transfer(self) -> Result<Self, InvalidTransition> {
+        match self {
+            ConnState::Transferring(username) => Ok(ConnState::Registered(username)),
+            state => Err(InvalidTransition::new(state, "finish a transfer")),
+        }
+    }
+
+    pub fn rename(self, new: String) -> Result<Self, InvalidTransition> {
+        match self {
+            ConnState::Registered(_) => Ok(ConnState::Registered(new)),
+            state => Err(InvalidTransition::new(state, "rename")),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidTransition {
+    pub state: ConnState,
+    pub attempted: &'static str,
+}
+
+impl InvalidTransition {
+    fn new(state: ConnState, attempted: &'static str) -> Self {
+        Self { state, attempted }
+    }
+}
+
+impl fmt::Display for InvalidTransition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Can't {} while {:?}", self.attempted, self.state)
+    }
+}
+
+impl Error for InvalidTransition {}
+
+// Runs a connection from the username exchange until the user disconnects
+// or `shutdown` resolves. See `serve`.
+pub async fn run<S: Socket>(
+    stream: S,
+    state: &SharedState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    run_with_config(stream, state, &ServerConfig::default(), shutdown).await
+}
+
+pub async fn run_with_config<S: Socket>(
+    stream: S,
+    state: &SharedState,
+    config: &ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    drive(stream, ConnState::Unauthenticated, state, config, shutdown).await
+}
+
+// Runs a logged in user's command loop until they disconnect or `shutdown`
+// resolves. However it ends, the user is removed from the shared state, any
+// glide they were in the middle of is withdrawn and the socket is closed.
+pub async fn serve<S: Socket>(
+    stream: S,
+    username: String,
+    state: &SharedState,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = ServerConfig::default();
+    drive(
+        stream,
+        ConnState::Registered(username),
+        state,
+        &config,
+        shutdown,
+    )
+    .await
+}
+
+async fn drive<S: Socket>(
+    mut stream: S,
+    mut conn: ConnState,
+    state: &SharedState,
+    config: &ServerConfig,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    tokio::pin!(shutdown);
+
+    let mut heartbeat = config.keepalive.as_ref().map(|keepalive| {
+        let start = time::Instant::now() + keepalive.interval;
+        let mut interval = time::interval_at(start, keepalive.interval);
+        // Don't fire a burst of pings after a long command
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        interval
+    });
+    let mut missed = 0;
+    // The wire format, settled by the first thing the client sends. Only a
+    // `Version` can change it from version 1.
+    let mut version = None;
+    // Tagged commands still running, each giving back its tag and answer
+    let mut pipelined: FuturesUnordered<BoxFuture<'_, (u32, Transmission)>> =
+        FuturesUnordered::new();
+
+    let result = loop {
+        // Wait for the start of a frame rather than reading it here, so a
+        // heartbeat can't interrupt a frame part way through
+        tokio::select! {
+            _ = &mut shutdown => break Ok(()),
+            _ = next_heartbeat(&mut heartbeat) => {
+                let max_missed = config.keepalive.as_ref().map_or(0, |k| k.max_missed);
+                if missed >= max_missed {
+                    info!("{:?} missed {} pings, dropping them", conn, missed);
+                    break Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("No pong after {} pings", missed),
+                    )
+                    .into());
+                }
+                let ping = Transmission::Ping.to_bytes_for(version.unwrap_or_default());
+                if let Err(e) = write_now(&mut stream, &ping).await {
+                    break Err(e.into());
+                }
+                missed += 1;
+                continue;
+            }
+            Some((id, response)) = pipelined.next(), if !pipelined.is_empty() => {
+                if let Err(e) = write_tagged(&mut stream, id, response, version.unwrap_or_default()).await {
+                    break Err(e.into());
+                }
+                continue;
+            }
+            // Stop reading while too many tagged commands are running
+            peeked = stream.readable(), if pipelined.len() < config.max_pipelined.max(1) => {
+                if let Err(e) = peeked {
+                    break Err(e.into());
+                }
+            }
+        }
+
+        let read = Transmission::from_stream_with_limit_for(
+            &mut stream,
+            config.max_field_len,
+            version.unwrap_or_default(),
+        );
+        // Once something's arrived, the rest of it has to follow
+        let read = async {
+            match config.read_timeout {
+                Some(timeout) => time::timeout(timeout, read).await.unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "Timed out part way through a transmission",
+                    )
+                    .into())
+                }),
+                None => read.await,
+            }
+        };
+        let transmission = tokio::select! {
+            _ = &mut shutdown => break Ok(()),
+            transmission = read => transmission,
+        };
+        // Anything from them shows they're still there
+        missed = 0;
+
+        let transmission = match transmission {
+            Ok(Transmission::ClientDisconnected) => break Ok(()),
+            Ok(transmission) => transmission,
+            // Hung up between frames, as opposed to part way through one
+            Err(GlideError::Protocol(ProtocolError::UnexpectedEof)) => break Ok(()),
+            Err(e) => break Err(e.into()),
+        };
+        let negotiating = version.is_none() && matches!(transmission, Transmission::Version(_));
+        let wire = match negotiating {
+            true => ProtocolVersion::V1,
+            false => *version.get_or_insert(ProtocolVersion::V1),
+        };
+
+        match (conn, transmission) {
+            // Always answered in version 1, with the highest we speak
+            (ConnState::Unauthenticated, Transmission::Version(theirs)) if negotiating => {
+                conn = ConnState::Unauthenticated;
+                let ours = config.max_version.number();
+                let offer = Transmission::Version(ours).to_bytes();
+                if let Err(e) = write_now(&mut stream, &offer).await {
+                    break Err(e.into());
+                }
+                match ProtocolVersion::from_number(theirs.min(ours)) {
+                    Some(agreed) => version = Some(agreed),
+                    None => {
+                        break Err(std::io::Error::new(
+                            std::io::ErrorKind::Unsupported,
+                            format!("Client speaks protocol version {}", theirs),
+                        )
+                        .into())
+                    }
+                }
+            }
+            (ConnState::Unauthenticated, Transmission::Username(username)) => {
+                let response = register(&stream, state, config, &username).await;
+                if let Err(e) = write_now(&mut stream, &response.to_bytes_for(wire)).await {
+                    if matches!(response, Transmission::UsernameOk) {
+                        state::remove_user(state, &username).await;
+                    }
+                    conn = ConnState::Unauthenticated;
+                    break Err(e.into());
+                }
+
+                // Free the slot they'd be waiting on for someone else
+                if matches!(response, Transmission::Busy { .. }) {
+                    conn = ConnState::Unauthenticated;
+                    break Ok(());
+                }
+
+                conn = if matches!(response, Transmission::UsernameOk) {
+                    // Let them know what happened to their glides while they were away
+                    if let Err(e) = config.outcomes.flush(&mut stream, &username, wire).await {
+                        conn = ConnState::Registered(username);
+                        break Err(e.into());
+                    }
+                    ConnState::Unauthenticated.register(username)?
+                } else {
+                    ConnState::Unauthenticated
+                };
+            }
+            (conn_state, Transmission::Pong) => conn = conn_state,
+            (conn_state, Transmission::Ping) => {
+                conn = conn_state;
+                if let Err(e) = write_now(&mut stream, &Transmission::Pong.to_bytes_for(wire)).await
+                {
+                    break Err(e.into());
+                }
+            }
+            // Publishing a key for end-to-end encryption, which needs no reply
+            (ConnState::Registered(username), Transmission::PublicKey(owner, key))
+                if owner == username =>
+            {
+                state::with_user(state, &username, |client| client.public_key = Some(key)).await;
+                conn = ConnState::Registered(username);
+            }
+            (ConnState::Registered(username), Transmission::Tagged(id, inner)) => {
+                match *inner {
+                    Transmission::Command(command) if command.can_pipeline() => {
+                        let username = username.clone();
+                        pipelined.push(
+                            async move {
+                                let started = Instant::now();
+                                let response =
+                                    command.execute_with_config(state, &username, config).await;
+                                if let Some(metrics) = &config.metrics {
+                                    metrics.record(command.name(), started.elapsed());
+                                }
+                                (id, response)
+                            }
+                            .boxed(),
+                        );
+                    }
+                    other => {
+                        let message = match other {
+                            Transmission::Command(command) => {
+                                format!("'{}' can't be tagged", command.name())
+                            }
+                            _ => "Only commands can be tagged".to_string(),
+                        };
+                        let refusal = Transmission::Error(message);
+                        if let Err(e) = write_tagged(&mut stream, id, refusal, wire).await {
+                            conn = ConnState::Registered(username);
+                            break Err(e.into());
+                        }
+                    }
+                }
+                conn = ConnState::Registered(username);
+            }
+            (ConnState::Registered(username), Transmission::Command(command)) => {
+                // Answer everything tagged first, so nothing runs alongside
+                // a transfer or a rename
+                let mut drained = Ok(());
+                while let Some((id, response)) = pipelined.next().await {
+                    drained = write_tagged(&mut stream, id, response, wire).await;
+                    if drained.is_err() {
+                        break;
+                    }
+                }
+                if let Err(e) = drained {
+                    conn = ConnState::Registered(username);
+                    break Err(e.into());
+                }
+
+                let transfers = matches!(
+                    command,
+                    Command::Glide { .. }
+                        | Command::GlideMany { .. }
+                        | Command::GlideDir { .. }
+                        | Command::Ok(..)
+                        | Command::OkInto { .. }
+                        | Command::OkLatest
+                        | Command::Peek { .. }
+                );
+                conn = ConnState::Registered(username.clone());
+                if transfers {
+                    conn = conn.begin_transfer()?;
+                }
+
+                let handled = tokio::select! {
+                    _ = &mut shutdown => None,
+                    handled = Command::handle_for(
+                        command.clone(),
+                        &username,
+                        &mut stream,
+                        state,
+                        config,
+                        wire,
+                    ) => Some(handled),
+                };
+
+                match (handled, command) {
+                    // Cancelled mid-command, don't leave a half received glide behind
+                    (None, Command::Glide { path, to, as_name }) => {
+                        let filename = commands::staged_name(&path, &as_name);
+                        commands::withdraw_glide(state, &username, &filename, &to, config).await;
+                        break Ok(());
+                    }
+                    (None, Command::GlideDir { path, to }) => {
+                        let filename = commands::staged_name(&path, &None);
+                        commands::withdraw_glide(state, &username, &filename, &to, config).await;
+                        break Ok(());
+                    }
+                    (None, Command::GlideMany { path, to }) => {
+                        let filename = commands::staged_name(&path, &None);
+                        for to in to {
+                            commands::withdraw_glide(state, &username, &filename, &to, config)
+                                .await;
+                        }
+                        break Ok(());
+                    }
+                    (None, _) => break Ok(()),
+                    (Some(Err(e)), _) => break Err(e.into()),
+                    (Some(Ok(Transmission::UsernameOk)), Command::SetName(new)) => {
+                        conn = conn.rename(new)?;
+                    }
+                    (Some(Ok(_)), _) => {
+                        if transfers {
+                            conn = conn.finish_transfer()?;
+                        }
+                    }
+                }
+            }
+            (state, other) => {
+                let attempted = match other {
+                    Transmission::Version(_) => "negotiate a version",
+                    Transmission::Username(_) => "register",
+                    Transmission::Command(_) => "run a command",
+                    _ => "handle an unexpected transmission",
+                };
+                conn = state.clone();
+                break Err(InvalidTransition::new(state, attempted).into());
+            }
+        }
+    };
+
+    if let Some(username) = conn.username() {
+        info!("Closing connection for {}", username);
+        commands::cleanup_user_with(state, username, config).await;
+    }
+    let _ = stream.shutdown().await;
+
+    result
+}
+
+// Answers a tagged command
+async fn write_tagged<S: Socket>(
+    stream: &mut S,
+    id: u32,
+    response: Transmission,
+    version: ProtocolVersion,
+) -> std::io::Result<()> {
+    let tagged = Transmission::Tagged(id, Box::new(response));
+    write_now(stream, &tagged.to_bytes_for(version)).await
+}
+
+// Writes something the client may be waiting on, flushing it so it isn't
+// left sitting in a buffered or TLS stream
+async fn write_now<S: Socket>(stream: &mut S, bytes: &[u8]) -> std::io::Result<()> {
+    stream.write_all(bytes).await?;
+    stream.flush().await
+}
+
+// Resolves on the next heartbeat tick, or never without keepalive
+async fn next_heartbeat(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+// Claims `username` for this connection if it's valid and free, and the
+// server has room
+async fn register<S: Socket>(
+    stream: &S,
+    state: &SharedState,
+    config: &ServerConfig,
+    username: &str,
+) -> Transmission {
+    if !commands::is_valid_username(username) {
+        return Transmission::UsernameInvalid;
+    }
+
+    let data = UserData {
+        socket: stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default(),
+        incoming_requests: Vec::new(),
+        public_key: None,
+    };
+
+    let Some(cap) = &config.connection_cap else {
+        return match state::insert_user(state, username, data).await {
+            true => Transmission::UsernameOk,
+            false => Transmission::UsernameTaken,
+        };
+    };
+    match state::insert_user_capped(state, username, data, cap.max_users).await {
+        state::Inserted::Yes => Transmission::UsernameOk,
+        state::Inserted::Taken => Transmission::UsernameTaken,
+        state::Inserted::Full => Transmission::Busy {
+            retry_after_secs: cap.retry_after.as_secs() as u32,
+        },
+    }
+}
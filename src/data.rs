@@ -1,15 +1,445 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    audit::AuditLogger,
+    outcomes::Outcomes,
+    protocol::{ProtocolVersion, MAX_FIELD_LEN},
+};
+
 pub const CHUNK_SIZE: usize = 1024;
 
+// SHA-256 digest of a file's contents
+pub type FileHash = [u8; 32];
+
+// How long the server waits for a sender to start transmitting after a glide
+pub const GLIDE_RECEIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a client may go quiet part way through sending something
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Where glides are staged, as clients/<sender>/<recipient>/<file>
+pub const STAGING_DIR: &str = "clients";
+
+// How many tagged commands one connection may have running at once
+pub const MAX_PIPELINED: usize = 8;
+
+// Most files, and bytes all told, the server stages for one directory glide
+pub const MAX_DIR_FILES: u32 = 10_000;
+pub const MAX_DIR_BYTES: u64 = 10 * 1024 * 1024 * 1024;
+
+// Server-side knobs for how commands are handled
 #[derive(Clone, Debug)]
+pub struct ServerConfig {
+    // How long to wait for a sender to start transmitting after a glide
+    pub glide_receive_timeout: Duration,
+    // How long a client may go quiet once it's started a transmission, or
+    // between the chunks of a file it's sending, before it's given up on.
+    // An idle connection between commands is `keepalive`'s to deal with.
+    pub read_timeout: Option<Duration>,
+    // Where glides are staged, each under <sender>/<recipient>/. A relative
+    // one is taken from the working directory.
+    pub staging_dir: String,
+    // Most files one sender may have staged for one recipient at a time
+    pub max_staged_per_sender: Option<usize>,
+    // Most files, and bytes all told, a directory glide may bring
+    pub max_dir_files: Option<u32>,
+    pub max_dir_bytes: Option<u64>,
+    // Where to record every transfer the server takes part in
+    pub audit: Option<AuditLogger>,
+    // Store identical staged files once, see `dedup`
+    pub dedup_staging: bool,
+    // Told how long each command took to execute
+    pub metrics: Option<CommandMetrics>,
+    // Ping idle connections and drop the ones that stop answering
+    pub keepalive: Option<KeepAlive>,
+    // Which kinds of file may be glided
+    pub extensions: ExtensionPolicy,
+    // Commands clients may use, by name as in `COMMAND_NAMES`. Anything else
+    // is answered with `Transmission::Error` and left out of `caps`.
+    pub allowed_commands: Option<Vec<String>>,
+    // How many tagged commands one connection may have running at once.
+    // Past that, the server stops reading until one finishes.
+    pub max_pipelined: usize,
+    // Longest username, filename or other string a client may send
+    pub max_field_len: usize,
+    // Highest wire format offered to a client that starts with `Version`.
+    // One that doesn't speaks version 1.
+    pub max_version: ProtocolVersion,
+    // Turn away logins once this many users are connected
+    pub connection_cap: Option<ConnectionCap>,
+    // Deliveries under way, so `cancel` can stop one part way. Only the ones
+    // on connections given this config, or a clone of it, can be reached.
+    pub deliveries: Deliveries,
+    // Answers to glides waiting for their sender to log back in
+    pub outcomes: Outcomes,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            glide_receive_timeout: GLIDE_RECEIVE_TIMEOUT,
+            read_timeout: Some(READ_TIMEOUT),
+            staging_dir: STAGING_DIR.to_string(),
+            max_staged_per_sender: None,
+            max_dir_files: Some(MAX_DIR_FILES),
+            max_dir_bytes: Some(MAX_DIR_BYTES),
+            audit: None,
+            dedup_staging: false,
+            metrics: None,
+            keepalive: None,
+            extensions: ExtensionPolicy::default(),
+            allowed_commands: None,
+            max_pipelined: MAX_PIPELINED,
+            max_field_len: MAX_FIELD_LEN,
+            max_version: ProtocolVersion::V3,
+            connection_cap: None,
+            deliveries: Deliveries::default(),
+            outcomes: Outcomes::default(),
+        }
+    }
+}
+
+// Sender, recipient and filename
+type Delivery = (String, String, String);
+
+// Deliveries a server has under way, each with the flag that stops it.
+// Clones share them.
+#[derive(Clone, Default)]
+pub struct Deliveries(Arc<Mutex<HashMap<Delivery, Arc<AtomicBool>>>>);
+
+impl Deliveries {
+    // Records a delivery for as long as the guard is kept
+    pub fn start(&self, sender: &str, recipient: &str, filename: &str) -> DeliveryGuard {
+        let delivery = (
+            sender.to_string(),
+            recipient.to_string(),
+            filename.to_string(),
+        );
+        let cancel = Arc::new(AtomicBool::new(false));
+        self.0
+            .lock()
+            .unwrap()
+            .insert(delivery.clone(), cancel.clone());
+        DeliveryGuard {
+            deliveries: self.clone(),
+            delivery,
+            cancel,
+        }
+    }
+
+    // Stops every delivery of `filename` from `sender` at its next chunk,
+    // returning how many there were
+    pub fn cancel(&self, sender: &str, filename: &str) -> usize {
+        let mut cancelled = 0;
+        for ((from, _, delivering), cancel) in self.0.lock().unwrap().iter() {
+            if from == sender && delivering == filename {
+                cancel.store(true, Ordering::Relaxed);
+                cancelled += 1;
+            }
+        }
+        cancelled
+    }
+}
+
+impl fmt::Debug for Deliveries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Deliveries({})", self.0.lock().unwrap().len())
+    }
+}
+
+// A delivery under way, forgotten when this is dropped, however the
+// delivery ended
+pub struct DeliveryGuard {
+    deliveries: Deliveries,
+    delivery: Delivery,
+    pub cancel: Arc<AtomicBool>,
+}
+
+impl Drop for DeliveryGuard {
+    fn drop(&mut self) {
+        let mut deliveries = self.deliveries.0.lock().unwrap();
+        // A newer delivery of the same file may have taken the entry over
+        if deliveries
+            .get(&self.delivery)
+            .is_some_and(|cancel| Arc::ptr_eq(cancel, &self.cancel))
+        {
+            deliveries.remove(&self.delivery);
+        }
+    }
+}
+
+// A hook called with each command's name and how long it took to execute,
+// e.g. to feed a Prometheus histogram
+#[derive(Clone)]
+pub struct CommandMetrics(Arc<MetricsHook>);
+
+type MetricsHook = dyn Fn(&str, Duration) + Send + Sync;
+
+impl CommandMetrics {
+    pub fn new(hook: impl Fn(&str, Duration) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub fn record(&self, command: &str, elapsed: Duration) {
+        (self.0)(command, elapsed)
+    }
+}
+
+impl fmt::Debug for CommandMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CommandMetrics(..)")
+    }
+}
+
+// A hook called after each chunk of a transfer with how many bytes have gone
+// through so far and how many there are in all, e.g. to drive a progress bar
+#[derive(Clone)]
+pub struct Progress(Arc<ProgressHook>);
+
+type ProgressHook = dyn Fn(u64, u64) + Send + Sync;
+
+impl Progress {
+    pub fn new(hook: impl Fn(u64, u64) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(hook))
+    }
+
+    pub fn report(&self, done: u64, total: u64) {
+        (self.0)(done, total)
+    }
+}
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress(..)")
+    }
+}
+
+// What `send_file` does when asked to send a symlink
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    #[default]
+    Reject,
+    Follow,
+    // Follow only links that resolve to somewhere under this directory
+    Within(PathBuf),
+}
+
+// Which file extensions are accepted. Extensions are given without the
+// leading dot and match case-insensitively against the end of the name, so
+// "gz" and "tar.gz" both match "backup.TAR.GZ".
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ExtensionPolicy {
+    #[default]
+    Any,
+    // Only files with one of these
+    Allow(Vec<String>),
+    // Anything but files with one of these
+    Deny(Vec<String>),
+}
+
+impl ExtensionPolicy {
+    pub fn permits(&self, filename: &str) -> bool {
+        // Windows ignores trailing dots and spaces, so "run.exe." is an exe
+        let name = filename.trim_end_matches(['.', ' ']).to_lowercase();
+        let matches = |extensions: &[String]| {
+            extensions.iter().any(|extension| {
+                let extension = extension.trim_start_matches('.').to_lowercase();
+                name.strip_suffix(&extension)
+                    .is_some_and(|stem| stem.ends_with('.'))
+            })
+        };
+
+        match self {
+            ExtensionPolicy::Any => true,
+            ExtensionPolicy::Allow(extensions) => matches(extensions),
+            ExtensionPolicy::Deny(extensions) => !matches(extensions),
+        }
+    }
+}
+
+// How often the server pings an idle connection, and how many pings in a
+// row may go unanswered before the user is dropped as gone
+#[derive(Clone, Debug)]
+pub struct KeepAlive {
+    pub interval: Duration,
+    pub max_missed: u32,
+}
+
+// How long a glide may go unanswered before the server drops it, and how
+// often it looks for ones that have. See `expiry`.
+#[derive(Clone, Debug)]
+pub struct RequestExpiry {
+    pub ttl: Duration,
+    pub interval: Duration,
+}
+
+impl RequestExpiry {
+    // Looks twice per `ttl`, but at least once a minute
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            interval: (ttl / 2).clamp(Duration::from_millis(10), Duration::from_secs(60)),
+        }
+    }
+}
+
+// How many users may be logged in at once, and how long a user turned away
+// is told to wait before trying again
+#[derive(Clone, Debug)]
+pub struct ConnectionCap {
+    pub max_users: usize,
+    pub retry_after: Duration,
+}
+
+// How `receive_file` copes with transient read errors mid-transfer. The
+// default never retries and never times out, matching plain `receive_file`.
+#[derive(Clone, Debug, Default)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    // Doubled after every failed attempt
+    pub backoff: Duration,
+    // Treat a chunk that takes longer than this as a transient failure
+    pub read_timeout: Option<Duration>,
+}
+
+// The metadata attribute saying whether the sender thinks the file is text
+// ("true") or binary ("false")
+pub const TEXT_ATTRIBUTE: &str = "text";
+
+// The metadata attribute naming how the chunks are compressed. The size in
+// the metadata is always the size of the file once decompressed.
+pub const COMPRESSION_ATTRIBUTE: &str = "compression";
+
+// How `send_file` compresses what it sends
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    // The whole file as one deflate stream, split across chunks
+    Deflate,
+    // Each chunk gzipped on its own
+    Gzip,
+    // Each chunk a zstd frame of its own
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+// The metadata attribute marking a sparse transfer, holding how many bytes
+// of data will be sent. The rest of the file is holes.
+pub const SPARSE_ATTRIBUTE: &str = "sparse";
+
+// The metadata attribute marking a window of a file, holding the offset it
+// starts at. The size in the metadata is the window's length, and the
+// receiver writes it into the file in place.
+pub const RANGE_ATTRIBUTE: &str = "range";
+
+// The metadata attribute marking a transfer that picks up where an earlier
+// one was cut off, holding how many bytes of the file it skips. The size in
+// the metadata is still the whole file's, and so is the digest.
+pub const RESUME_ATTRIBUTE: &str = "resume";
+
+// The metadata attribute naming the hash of the whole file that follows the
+// last chunk as `Transmission::Digest`. Only "sha256" so far.
+pub const DIGEST_ATTRIBUTE: &str = "digest";
+
+// Added to a received file's name while it's being written. A transfer that
+// was cut off leaves it there for `resume_file` to pick up from.
+pub const PARTIAL_SUFFIX: &str = ".part";
+
+// Line endings `receive_file` can rewrite text files to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    pub fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+// What `receive_file` got, as described by the sender's metadata
+#[derive(Clone, Debug)]
+pub struct ReceivedFile {
+    pub filename: String,
+    pub size: u32,
+    pub hash: FileHash,
+    pub attributes: HashMap<String, String>,
+    pub stats: TransferStats,
+}
+
+// What `send_file_stats` sent
+#[derive(Clone, Debug)]
+pub struct SentFile {
+    pub hash: FileHash,
+    pub stats: TransferStats,
+}
+
+// How a transfer went. `bytes` counts the file's data that went over the
+// wire, before compression and leaving out holes and anything resumed past.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransferStats {
+    pub bytes: u64,
+    pub chunks: u64,
+    pub duration: Duration,
+}
+
+impl TransferStats {
+    // Bytes per second, or 0 for a transfer too quick to measure
+    pub fn throughput(&self) -> f64 {
+        match self.duration.as_secs_f64() {
+            secs if secs > 0.0 => self.bytes as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+impl ReceivedFile {
+    // Whether the sender flagged the file as text
+    pub fn is_text(&self) -> bool {
+        is_text(&self.attributes)
+    }
+}
+
+pub(crate) fn is_text(attributes: &HashMap<String, String>) -> bool {
+    attributes.get(TEXT_ATTRIBUTE).is_some_and(|v| v == "true")
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Request {
     pub sender: String,
     pub filename: String,
+    // Bytes staged so far, 0 until the sender's file has arrived
+    pub size: u64,
+    // When the glide was sent. `UNIX_EPOCH` when the peer didn't say, as
+    // before protocol version 3.
+    pub offered_at: SystemTime,
 }
 
+// An X25519 public key, as published for end-to-end encryption
+pub type PublicKey = [u8; 32];
+
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UserData {
     pub socket: String,
     pub incoming_requests: Vec<Request>,
+    // Set once the user publishes one with `Transmission::PublicKey`
+    pub public_key: Option<PublicKey>,
 }
 
 // #[derive(Debug)]
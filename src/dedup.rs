@@ -0,0 +1,115 @@
+use std::{
+    io::{ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+use crate::{audit::hex, data::FileHash};
+
+// Where staged content lives when deduplication is on, named by its hash,
+// under the staging directory
+pub const BLOB_DIR: &str = ".blobs";
+
+// Staged files are hard links to a blob, so readers never need to know
+// about any of this. A blob's link count says how many staged files still
+// refer to it, so nothing is kept in memory and a restarted server, or
+// another one sharing the staging directory, sees the same thing.
+fn blob_path(root: &Path, hash: &FileHash) -> PathBuf {
+    root.join(BLOB_DIR).join(hex(hash))
+}
+
+// Moves a freshly received file into the blob store, leaving a link in its
+// place. If the same content is already stored, the new copy is dropped.
+// `root` is the staging directory `staged` is under.
+pub async fn store(root: &Path, staged: &Path, hash: FileHash) -> Result<()> {
+    let blob = blob_path(root, &hash);
+    tokio::fs::create_dir_all(root.join(BLOB_DIR)).await?;
+
+    // Link next to the staged file first and swap it in, so the copy is
+    // only dropped once the link is in place. The blob may have been
+    // released in between, in which case this copy becomes it: linked
+    // first and then renamed into place, so the blob never has fewer than
+    // two names for a release to take it as an orphan.
+    let mut link = staged.as_os_str().to_owned();
+    link.push(".dedup");
+    let _ = tokio::fs::remove_file(&link).await;
+    match tokio::fs::hard_link(&blob, &link).await {
+        Ok(()) => tokio::fs::rename(&link, staged).await,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            tokio::fs::hard_link(staged, &link).await?;
+            tokio::fs::rename(&link, &blob).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Deletes a staged file, and its blob if nothing else refers to it. Works
+// for files that were never deduplicated too.
+pub async fn release(root: &Path, staged: &Path) -> Result<()> {
+    let links = link_count(&tokio::fs::symlink_metadata(staged).await?);
+    // Only the blob is left with it, which is named by what's in it
+    let blob = match links {
+        Some(2) => Some(blob_path(root, &hash_file(staged).await?)),
+        _ => None,
+    };
+    tokio::fs::remove_file(staged).await?;
+
+    if let Some(blob) = blob {
+        remove_orphan(&blob).await?;
+    }
+
+    Ok(())
+}
+
+// Releases every file under a staged directory, then the directory itself
+pub async fn release_dir(root: &Path, staged: &Path) -> Result<()> {
+    let mut dirs = vec![staged.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                release(root, &entry.path()).await?;
+            }
+        }
+    }
+
+    tokio::fs::remove_dir_all(staged).await
+}
+
+// Deletes `blob` if no staged file links to it any more
+async fn remove_orphan(blob: &Path) -> Result<()> {
+    let metadata = match tokio::fs::symlink_metadata(blob).await {
+        Ok(metadata) => metadata,
+        // Another release got to it first, or it was never one
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if link_count(&metadata) == Some(1) {
+        match tokio::fs::remove_file(blob).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+async fn hash_file(path: &Path) -> Result<FileHash> {
+    let path = path.to_string_lossy();
+    Ok(crate::transfers::hash_file(&path).await?)
+}
+
+// How many names a file has, if the platform can tell
+#[cfg(unix)]
+fn link_count(metadata: &std::fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.nlink())
+}
+
+// Without link counts, blobs are kept once stored
+#[cfg(not(unix))]
+fn link_count(_metadata: &std::fs::Metadata) -> Option<u64> {
+    None
+}
@@ -0,0 +1,175 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand_core::OsRng;
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind, Result};
+use x25519_dalek::{EphemeralSecret, StaticSecret};
+
+use crate::data::{PublicKey, CHUNK_SIZE};
+
+// End-to-end encryption of file contents, so the server only ever relays
+// ciphertext. A sealed file is the sender's one-off public key followed by
+// records of at most `CHUNK_SIZE` bytes, each a ChaCha20-Poly1305 box of up
+// to `RECORD_SIZE` bytes of the file. The key comes from an X25519 exchange
+// between that one-off key and the recipient's published key.
+
+const TAG_SIZE: usize = 16;
+
+// How much of the file goes in each sealed record
+pub const RECORD_SIZE: usize = CHUNK_SIZE - TAG_SIZE;
+
+// A user's long lived key pair. The public half is published to the server
+// with `Transmission::PublicKey` after logging in.
+pub struct KeyPair {
+    secret: StaticSecret,
+    pub public: PublicKey,
+}
+
+impl KeyPair {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng).to_bytes())
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        Self { secret, public }
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+}
+
+fn derive_key(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> Key {
+    let mut hasher = Sha256::new();
+    hasher.update(b"glide-e2e");
+    hasher.update(shared);
+    hasher.update(ephemeral);
+    hasher.update(recipient);
+    let key: [u8; 32] = hasher.finalize().into();
+    key.into()
+}
+
+// Records are numbered from 0, and the last one is marked so a relay can't
+// cut the file short without the recipient noticing
+fn nonce(counter: u64, last: bool) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[0] = last as u8;
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+// How many bytes sealing `len` bytes of file produces. Even an empty file
+// gets one (empty) last record.
+pub fn sealed_len(len: u64) -> u64 {
+    let records = len.div_ceil(RECORD_SIZE as u64).max(1);
+    32 + len + records * TAG_SIZE as u64
+}
+
+// Encrypts a file record by record for one recipient
+pub struct Sealer {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+    pub ephemeral: PublicKey,
+}
+
+impl Sealer {
+    pub fn to(recipient: &PublicKey) -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = x25519_dalek::PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(*recipient));
+        let key = derive_key(shared.as_bytes(), &ephemeral, recipient);
+
+        Self {
+            cipher: ChaCha20Poly1305::new(&key),
+            counter: 0,
+            ephemeral,
+        }
+    }
+
+    // Seals the next record, which must be at most `RECORD_SIZE` bytes
+    pub fn seal(&mut self, record: &[u8], last: bool) -> Vec<u8> {
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(self.counter, last), record)
+            .expect("records are far below the AEAD's size limit");
+        self.counter += 1;
+        sealed
+    }
+}
+
+// Decrypts a sealed file as it arrives, however the relay chunked it
+pub struct Opener<'a> {
+    keys: &'a KeyPair,
+    cipher: Option<ChaCha20Poly1305>,
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl<'a> Opener<'a> {
+    pub fn new(keys: &'a KeyPair) -> Self {
+        Self {
+            keys,
+            cipher: None,
+            counter: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    // Takes the next piece of the sealed file, returning whatever plaintext
+    // it completes. A full record is held back until more arrives, since it
+    // might be the last one.
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buffer.extend_from_slice(data);
+
+        if self.cipher.is_none() {
+            if self.buffer.len() < 32 {
+                return Ok(Vec::new());
+            }
+            let ephemeral: PublicKey = self.buffer[..32].try_into().unwrap();
+            let shared = self
+                .keys
+                .secret
+                .diffie_hellman(&x25519_dalek::PublicKey::from(ephemeral));
+            let key = derive_key(shared.as_bytes(), &ephemeral, &self.keys.public);
+            self.cipher = Some(ChaCha20Poly1305::new(&key));
+            self.buffer.drain(..32);
+        }
+
+        let mut plaintext = Vec::new();
+        while self.buffer.len() > CHUNK_SIZE {
+            let record: Vec<u8> = self.buffer.drain(..CHUNK_SIZE).collect();
+            plaintext.extend(self.open(&record, false)?);
+        }
+        Ok(plaintext)
+    }
+
+    // Opens the record still held back once the whole file has arrived
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if self.cipher.is_none() || self.buffer.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Sealed file was cut short",
+            ));
+        }
+        let record = std::mem::take(&mut self.buffer);
+        self.open(&record, true)
+    }
+
+    fn open(&mut self, record: &[u8], last: bool) -> Result<Vec<u8>> {
+        let cipher = self.cipher.as_ref().unwrap();
+        let plaintext = cipher
+            .decrypt(&nonce(self.counter, last), record)
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Record {} failed to decrypt", self.counter),
+                )
+            })?;
+        self.counter += 1;
+        Ok(plaintext)
+    }
+}
@@ -0,0 +1,148 @@
+use std::{error::Error, fmt, io};
+
+use crate::{
+    protocol::{InvalidUtf8, ProtocolError, Truncated},
+    transfers::{Aborted, Cancelled, DigestMismatch},
+};
+
+// What the crate's public functions fail with. `kind` says which
+// `io::ErrorKind` it amounts to, and it converts back into an `io::Error`
+// so `?` still works in functions returning `io::Result`.
+#[derive(Debug)]
+pub enum GlideError {
+    Io(io::Error),
+    // Something on the wire that doesn't follow the protocol
+    Protocol(ProtocolError),
+    // A string field wasn't valid UTF-8
+    InvalidUtf8(InvalidUtf8),
+    // A V2 chunk's data didn't match the CRC32 sent with it
+    ChecksumMismatch { expected: u32, actual: u32 },
+    // A received file didn't match the digest its sender sent
+    DigestMismatch(DigestMismatch),
+    // The other side aborted the transfer
+    Aborted(Aborted),
+    // The transfer was called off by either side
+    Cancelled(Cancelled),
+}
+
+impl GlideError {
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            GlideError::Io(e) => e.kind(),
+            GlideError::Protocol(ProtocolError::UnexpectedEof | ProtocolError::Truncated(_)) => {
+                io::ErrorKind::UnexpectedEof
+            }
+            GlideError::Protocol(ProtocolError::Io(e)) => e.kind(),
+            GlideError::Protocol(_)
+            | GlideError::InvalidUtf8(_)
+            | GlideError::ChecksumMismatch { .. }
+            | GlideError::DigestMismatch(_) => io::ErrorKind::InvalidData,
+            GlideError::Aborted(_) => io::ErrorKind::ConnectionAborted,
+            GlideError::Cancelled(_) => io::ErrorKind::Interrupted,
+        }
+    }
+}
+
+impl fmt::Display for GlideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GlideError::Io(e) => e.fmt(f),
+            GlideError::Protocol(e) => e.fmt(f),
+            GlideError::InvalidUtf8(e) => e.fmt(f),
+            GlideError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Chunk checksum {:#010x} doesn't match its data ({:#010x})",
+                expected, actual
+            ),
+            GlideError::DigestMismatch(e) => e.fmt(f),
+            GlideError::Aborted(e) => e.fmt(f),
+            GlideError::Cancelled(e) => e.fmt(f),
+        }
+    }
+}
+
+impl Error for GlideError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GlideError::Io(e) => Some(e),
+            GlideError::Protocol(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+// Typed errors that were carried inside an `io::Error` come back out as
+// their own variants
+impl From<io::Error> for GlideError {
+    fn from(e: io::Error) -> Self {
+        let carried = e.get_ref().is_some_and(|inner| {
+            inner.is::<Aborted>()
+                || inner.is::<Cancelled>()
+                || inner.is::<DigestMismatch>()
+                || inner.is::<ProtocolError>()
+                || inner.is::<InvalidUtf8>()
+                || inner.is::<Truncated>()
+        });
+        if !carried {
+            return GlideError::Io(e);
+        }
+
+        let inner = e.into_inner().expect("checked it carries an error");
+        let inner = match inner.downcast::<Aborted>() {
+            Ok(aborted) => return GlideError::Aborted(*aborted),
+            Err(inner) => inner,
+        };
+        let inner = match inner.downcast::<Cancelled>() {
+            Ok(cancelled) => return GlideError::Cancelled(*cancelled),
+            Err(inner) => inner,
+        };
+        let inner = match inner.downcast::<DigestMismatch>() {
+            Ok(mismatch) => return GlideError::DigestMismatch(*mismatch),
+            Err(inner) => inner,
+        };
+        let inner = match inner.downcast::<ProtocolError>() {
+            Ok(protocol) => return GlideError::from(*protocol),
+            Err(inner) => inner,
+        };
+        let inner = match inner.downcast::<InvalidUtf8>() {
+            Ok(invalid) => return GlideError::InvalidUtf8(*invalid),
+            Err(inner) => inner,
+        };
+        match inner.downcast::<Truncated>() {
+            Ok(truncated) => GlideError::Protocol(ProtocolError::Truncated(*truncated)),
+            Err(_) => unreachable!("checked it's one of the above"),
+        }
+    }
+}
+
+impl From<ProtocolError> for GlideError {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::InvalidUtf8(invalid) => GlideError::InvalidUtf8(invalid),
+            ProtocolError::ChecksumMismatch { expected, actual } => {
+                GlideError::ChecksumMismatch { expected, actual }
+            }
+            ProtocolError::Io(e) => GlideError::from(e),
+            e => GlideError::Protocol(e),
+        }
+    }
+}
+
+// Wraps what the variant holds, so it comes back out the same through
+// `From<io::Error>`
+impl From<GlideError> for io::Error {
+    fn from(e: GlideError) -> Self {
+        let kind = e.kind();
+        match e {
+            GlideError::Io(e) => e,
+            GlideError::Protocol(e) => e.into(),
+            GlideError::InvalidUtf8(e) => io::Error::new(kind, e),
+            GlideError::ChecksumMismatch { expected, actual } => {
+                ProtocolError::ChecksumMismatch { expected, actual }.into()
+            }
+            GlideError::DigestMismatch(e) => io::Error::new(kind, e),
+            GlideError::Aborted(e) => io::Error::new(kind, e),
+            GlideError::Cancelled(e) => io::Error::new(kind, e),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use log::info;
+use std::time::{Duration, SystemTime};
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::{
+    commands,
+    data::{RequestExpiry, ServerConfig},
+    state::{self, SharedState},
+};
+
+// Drops glides nobody has answered within `ttl`, deleting what was staged
+// for them, until the returned task is aborted
+pub fn spawn_request_reaper(state: SharedState, ttl: Duration) -> JoinHandle<()> {
+    spawn_request_reaper_with(state, RequestExpiry::new(ttl), ServerConfig::default())
+}
+
+// Same as `spawn_request_reaper`, looking as often as `expiry` says and
+// auditing failed cleanups where `config` does
+pub fn spawn_request_reaper_with(
+    state: SharedState,
+    expiry: RequestExpiry,
+    config: ServerConfig,
+) -> JoinHandle<()> {
+    // The reaper keeps time on tokio's clock from here on, so it can be
+    // paused in tests. Glides are still stamped with the wall clock when
+    // they're offered, so if that jumps they expire early or late.
+    let (started, wall) = (Instant::now(), SystemTime::now());
+    tokio::spawn(async move {
+        let mut interval = time::interval(expiry.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            reap_at(&state, expiry.ttl, &config, wall + started.elapsed()).await;
+        }
+    })
+}
+
+// Drops every request offered more than `ttl` ago and deletes its staged
+// file, returning how many there were
+pub async fn reap(state: &SharedState, ttl: Duration, config: &ServerConfig) -> usize {
+    reap_at(state, ttl, config, SystemTime::now()).await
+}
+
+// Same as `reap`, as if it were `now`
+pub async fn reap_at(
+    state: &SharedState,
+    ttl: Duration,
+    config: &ServerConfig,
+    now: SystemTime,
+) -> usize {
+    let mut expired = Vec::new();
+    state::for_each_user(state, |recipient, client| {
+        client.incoming_requests.retain(|req| {
+            let stale = now
+                .duration_since(req.offered_at)
+                .is_ok_and(|age| age > ttl);
+            if stale {
+                expired.push((
+                    req.sender.clone(),
+                    recipient.to_string(),
+                    req.filename.clone(),
+                ));
+            }
+            !stale
+        });
+    })
+    .await;
+
+    for (sender, recipient, filename) in &expired {
+        info!(
+            "Glide of '{}' from @{} to @{} expired unanswered",
+            filename, sender, recipient
+        );
+        let _ = commands::cleanup_file(sender, recipient, filename, config).await;
+    }
+
+    expired.len()
+}
@@ -1,4 +1,18 @@
+pub mod audit;
+pub mod cache;
 pub mod commands;
+pub mod compression;
+pub mod connection;
 pub mod data;
+pub mod dedup;
+#[cfg(feature = "e2e")]
+pub mod e2e;
+pub mod error;
+pub mod expiry;
+pub mod outcomes;
 pub mod protocol;
+pub mod relay;
+pub mod state;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod transfers;
@@ -0,0 +1,125 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::protocol::{ProtocolVersion, Transmission};
+
+// How long an outcome waits for its sender to log back in
+pub const OUTCOME_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// Most outcomes kept for one sender, and senders kept at all
+pub const MAX_OUTCOMES_PER_SENDER: usize = 100;
+pub const MAX_OUTCOME_SENDERS: usize = 10_000;
+
+// Each sender's outcomes, with when each was queued
+type Pending = HashMap<String, Vec<(Instant, Transmission)>>;
+
+// Outcomes of glide requests whose sender was offline when the recipient
+// answered, keyed by sender username. They survive the sender's entry being
+// dropped from the shared state and are delivered when they log back in,
+// unless they expire first. Clones share them.
+#[derive(Clone)]
+pub struct Outcomes {
+    pending: Arc<Mutex<Pending>>,
+    ttl: Duration,
+    max_per_sender: usize,
+    max_senders: usize,
+}
+
+impl Outcomes {
+    pub fn new(ttl: Duration, max_per_sender: usize, max_senders: usize) -> Self {
+        Self {
+            pending: Arc::default(),
+            ttl,
+            max_per_sender,
+            max_senders,
+        }
+    }
+
+    // Keeps an outcome for `sender`. Past the cap their oldest one is
+    // dropped, and a new sender isn't kept once there are too many.
+    pub fn queue(&self, sender: &str, recipient: &str, filename: &str, accepted: bool) {
+        let mut pending = self.pending.lock().unwrap();
+        self.expire(&mut pending);
+        if !pending.contains_key(sender) && pending.len() >= self.max_senders {
+            return;
+        }
+
+        let outcomes = pending.entry(sender.to_string()).or_default();
+        outcomes.push((
+            Instant::now(),
+            Transmission::RequestOutcome(recipient.to_string(), filename.to_string(), accepted),
+        ));
+        let over = outcomes.len().saturating_sub(self.max_per_sender);
+        outcomes.drain(..over);
+    }
+
+    // Sends every queued outcome for `username`, in the wire format the
+    // connection speaks. Should be called by the server right after a user's
+    // name has been accepted.
+    pub async fn flush<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        username: &str,
+        version: ProtocolVersion,
+    ) -> std::io::Result<()> {
+        let outcomes = {
+            let mut pending = self.pending.lock().unwrap();
+            self.expire(&mut pending);
+            pending.remove(username).unwrap_or_default()
+        };
+
+        for (i, (_, outcome)) in outcomes.iter().enumerate() {
+            if let Err(e) = stream
+                .write_all(outcome.to_bytes_for(version).as_slice())
+                .await
+            {
+                // Put back whatever didn't make it so the next login gets it
+                self.pending
+                    .lock()
+                    .unwrap()
+                    .entry(username.to_string())
+                    .or_default()
+                    .splice(0..0, outcomes[i..].iter().cloned());
+                return Err(e);
+            }
+        }
+
+        stream.flush().await
+    }
+
+    // Moves whatever is queued for `from` over to `to`, after a rename
+    pub fn rename(&self, from: &str, to: &str) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(outcomes) = pending.remove(from) {
+            pending.entry(to.to_string()).or_default().extend(outcomes);
+        }
+    }
+
+    fn expire(&self, pending: &mut Pending) {
+        let now = Instant::now();
+        pending.retain(|_, outcomes| {
+            outcomes.retain(|(queued, _)| now.duration_since(*queued) < self.ttl);
+            !outcomes.is_empty()
+        });
+    }
+}
+
+impl Default for Outcomes {
+    fn default() -> Self {
+        Self::new(OUTCOME_TTL, MAX_OUTCOMES_PER_SENDER, MAX_OUTCOME_SENDERS)
+    }
+}
+
+impl fmt::Debug for Outcomes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Outcomes({})", self.pending.lock().unwrap().len())
+    }
+}
@@ -1,12 +1,256 @@
 use log::trace;
-use tokio::{
-    io::{AsyncReadExt, Result},
-    net::TcpStream,
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime},
 };
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Error, ErrorKind};
 
-use crate::{commands::Command, data::Request};
+use crate::{
+    commands::Command,
+    data::{FileHash, PublicKey, Request},
+    error::GlideError,
+};
+
+type Result<T> = std::result::Result<T, GlideError>;
+
+// A frame ended part way through a fixed-width field. Surfaces as an
+// `io::Error` of kind `UnexpectedEof` wrapping this.
+#[derive(Debug)]
+pub struct Truncated {
+    pub field: &'static str,
+}
+
+impl fmt::Display for Truncated {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Frame truncated while reading the {}", self.field)
+    }
+}
+
+impl std::error::Error for Truncated {}
+
+// A string field wasn't valid UTF-8. Surfaces as
+// `ProtocolError::InvalidUtf8`, or an `io::Error` of kind `InvalidData`
+// wrapping this.
+#[derive(Debug)]
+pub struct InvalidUtf8 {
+    pub field: &'static str,
+}
+
+impl fmt::Display for InvalidUtf8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The {} isn't valid UTF-8", self.field)
+    }
+}
+
+impl std::error::Error for InvalidUtf8 {}
+
+// The longest string field `from_stream` reads, so a peer that never sends
+// the terminator can't make us allocate forever
+pub const MAX_FIELD_LEN: usize = 4096;
+
+// Reads a null terminated string field of at most `max_len` bytes
+async fn read_string<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    field: &'static str,
+    max_len: usize,
+) -> std::result::Result<String, ProtocolError> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = stream.read_u8().await?;
+        if byte == 0 {
+            break;
+        }
+        if bytes.len() == max_len {
+            return Err(ProtocolError::FieldTooLong { field, max_len });
+        }
+        bytes.push(byte);
+    }
+    // Strictly, since a lossy decode would quietly turn a name into one
+    // that doesn't match anything
+    String::from_utf8(bytes).map_err(|_| ProtocolError::InvalidUtf8(InvalidUtf8 { field }))
+}
+
+// For `map_err` on a fixed-width read, naming the field on a short read
+fn truncated(field: &'static str) -> impl FnOnce(Error) -> Error {
+    move |e| match e.kind() {
+        ErrorKind::UnexpectedEof => Error::new(ErrorKind::UnexpectedEof, Truncated { field }),
+        _ => e,
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = flate2::Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+// Why `Transmission::from_stream` couldn't read a transmission. Converts to
+// an `io::Error` of a matching kind, so `?` works in functions returning
+// `io::Result`.
+#[derive(Debug)]
+pub enum ProtocolError {
+    UnknownControlByte(u8),
+    UnknownCommandType(u8),
+    // The stream ended, between frames or part way through a string
+    UnexpectedEof,
+    // The stream ended part way through a fixed-width field
+    Truncated(Truncated),
+    InvalidUtf8(InvalidUtf8),
+    // A string field went on past the longest we'll read
+    FieldTooLong { field: &'static str, max_len: usize },
+    // A V2 chunk's data didn't match the CRC32 sent with it
+    ChecksumMismatch { expected: u32, actual: u32 },
+    Io(Error),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownControlByte(byte) => {
+                write!(f, "Unknown control byte {:#04x}", byte)
+            }
+            ProtocolError::UnknownCommandType(byte) => {
+                write!(f, "Unknown command type {:#04x}", byte)
+            }
+            ProtocolError::UnexpectedEof => write!(f, "The stream ended unexpectedly"),
+            ProtocolError::Truncated(truncated) => truncated.fmt(f),
+            ProtocolError::InvalidUtf8(invalid) => invalid.fmt(f),
+            ProtocolError::FieldTooLong { field, max_len } => {
+                write!(f, "The {} is longer than {} bytes", field, max_len)
+            }
+            ProtocolError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Chunk checksum {:#010x} doesn't match its data ({:#010x})",
+                expected, actual
+            ),
+            ProtocolError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for ProtocolError {
+    fn from(e: Error) -> Self {
+        if e.kind() != ErrorKind::UnexpectedEof {
+            return ProtocolError::Io(e);
+        }
+        match e.get_ref() {
+            None => ProtocolError::UnexpectedEof,
+            Some(inner) => match inner.downcast_ref::<Truncated>() {
+                Some(truncated) => ProtocolError::Truncated(Truncated {
+                    field: truncated.field,
+                }),
+                None => ProtocolError::Io(e),
+            },
+        }
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        match e {
+            ProtocolError::UnknownControlByte(_)
+            | ProtocolError::UnknownCommandType(_)
+            | ProtocolError::FieldTooLong { .. }
+            | ProtocolError::ChecksumMismatch { .. } => Error::new(ErrorKind::InvalidData, e),
+            ProtocolError::UnexpectedEof => ErrorKind::UnexpectedEof.into(),
+            ProtocolError::Truncated(truncated) => Error::new(ErrorKind::UnexpectedEof, truncated),
+            ProtocolError::InvalidUtf8(invalid) => Error::new(ErrorKind::InvalidData, invalid),
+            ProtocolError::Io(e) => e,
+        }
+    }
+}
+
+// Which wire format a connection speaks. V1 is the original. V2 puts each
+// V1 transmission in a frame led by its length, so a peer that doesn't
+// understand one can still find where the next starts. V3 is V2 with each
+// incoming request also carrying its size and when it was sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProtocolVersion {
+    #[default]
+    V1,
+    V2,
+    V3,
+}
+
+impl ProtocolVersion {
+    // The number `Version` carries for this format
+    pub fn number(self) -> u16 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+            Self::V3 => 3,
+        }
+    }
 
+    pub fn from_number(number: u16) -> Option<Self> {
+        match number {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            3 => Some(Self::V3),
+            _ => None,
+        }
+    }
+}
+
+// Agrees on a wire format with the peer, as the first thing either side
+// sends. Each side offers the highest version it supports and both settle
+// on the lower of the two offers, which is an error if it isn't one we
+// support. The exchange itself is always V1.
+pub async fn negotiate_version<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    supported: &[u16],
+) -> Result<u16> {
+    let Some(&ours) = supported.iter().max() else {
+        return Err(Error::new(ErrorKind::InvalidInput, "No protocol versions to offer").into());
+    };
+    stream
+        .write_all(&Transmission::Version(ours).to_bytes())
+        .await?;
+
+    let theirs = match Transmission::from_stream(stream).await? {
+        Transmission::Version(theirs) => theirs,
+        other => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Expected a protocol version, got {:?}", other.redacted()),
+            )
+            .into())
+        }
+    };
+
+    let agreed = ours.min(theirs);
+    if !supported.contains(&agreed) {
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Peer speaks protocol version {}, which isn't supported",
+                theirs
+            ),
+        )
+        .into());
+    }
+
+    Ok(agreed)
+}
+
+// The largest V2 frame we'll read, so a bad length can't make us allocate
+// gigabytes
+pub const MAX_FRAME_SIZE: u32 = 1 << 20;
+
+// With the serde feature, chunk data is written as bytes rather than a
+// list of numbers, for formats that can tell the two apart
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Transmission {
     Username(String),
     UsernameOk,
@@ -14,29 +258,196 @@ pub enum Transmission {
     UsernameInvalid,
     Command(Command),
     GlideRequestSent,
-    Metadata(String, u32),
-    Chunk(String, Vec<u8>),
+    // Filename, size and free-form attributes like `content-type`
+    Metadata(String, u32, HashMap<String, String>),
+    Chunk(
+        String,
+        #[cfg_attr(feature = "serde", serde(with = "serde_bytes"))] Vec<u8>,
+    ),
     ConnectedUsers(Vec<String>),
     IncomingRequests(Vec<Request>),
     OkSuccess,
     OkFailed,
     NoSuccess,
     ClientDisconnected,
+    // Recipient, filename and whether the glide was accepted
+    RequestOutcome(String, String, bool),
+    Capabilities(Vec<String>),
+    // Either side giving up on the named file mid-transfer
+    Abort(String),
+    // A glide the server won't accept, with the reason
+    GlideRefused(String),
+    // Something went wrong on the other side, with a message for the user
+    Error(String),
+    // A user's key for end-to-end encryption, either being published by
+    // them or handed out in answer to `key`
+    PublicKey(String, PublicKey),
+    // Keepalive, answered with `Pong`
+    Ping,
+    Pong,
+    // A chunk of a sparse file, with the offset it goes at
+    ChunkAt(
+        String,
+        u64,
+        #[cfg_attr(feature = "serde", serde(with = "serde_bytes"))] Vec<u8>,
+    ),
+    // How many pending glides and staged files `purge` cleared out
+    Purged(u32),
+    // Asks the other side to send `len` bytes of a file from `start`, which
+    // it does as a transfer with a range attribute
+    RangeRequest {
+        filename: String,
+        start: u64,
+        len: u64,
+    },
+    // Another transmission with an id the answer will carry too, so a
+    // client can send several commands without waiting for each answer
+    Tagged(u32, Box<Transmission>),
+    // The server is at a limit and won't take this on now, but should be
+    // able to in about this long
+    Busy {
+        retry_after_secs: u32,
+    },
+    // The highest wire format version the sender speaks, exchanged before
+    // anything else
+    Version(u16),
+    // The SHA-256 of the whole file, sent after its last chunk when the
+    // metadata has a digest attribute
+    Digest(String, FileHash),
+    // Asks the other side to send a file again, skipping the bytes the
+    // receiver already has from a transfer that was cut off
+    ResumeFrom(String, u32),
+    // Either side calling off the named file on purpose, mid-transfer or
+    // before it starts. The server also answers `cancel` with it.
+    TransferCancelled(String),
+    // The answer to a glide to several users, naming any that don't exist.
+    // The sender sends the file once either way, for the rest.
+    GlideRequestsSent {
+        unknown: Vec<String>,
+    },
+    // The answer to `whoami`: the name the server has for the connection
+    // and how many glides are waiting for it
+    UserStatus {
+        username: String,
+        pending: u32,
+    },
+    // A directory of this many files follows, each sent as its own
+    // transfer named by its path from the directory's parent
+    Directory {
+        name: String,
+        files: u32,
+    },
+    // The answer to a paged `list`: one page of users, and how many there
+    // are on every page together
+    UsersPage {
+        total: u32,
+        users: Vec<String>,
+    },
 }
 
 impl Transmission {
+    // Wraps the transmission so it can be logged without leaking usernames,
+    // filenames or file contents. The derived `Debug` still shows everything.
+    pub fn redacted(&self) -> Redacted<'_> {
+        Redacted(self)
+    }
+
+    // The leading byte `to_bytes` would emit
+    pub fn control_byte(&self) -> u8 {
+        match self {
+            Self::Username(_) => 0x1,
+            Self::UsernameOk => 0x2,
+            Self::UsernameTaken => 0x3,
+            Self::UsernameInvalid => 0x4,
+            Self::Metadata(_, _, attributes) if attributes.is_empty() => 0x5,
+            Self::Metadata(..) => 0x11,
+            Self::Chunk(..) => 0x6,
+            Self::ConnectedUsers(_) => 0x7,
+            Self::IncomingRequests(_) => 0x8,
+            Self::Command(_) => 0x9,
+            Self::OkFailed => 0xa,
+            Self::NoSuccess => 0xb,
+            Self::ClientDisconnected => 0xc,
+            Self::GlideRequestSent => 0xd,
+            Self::OkSuccess => 0xe,
+            Self::RequestOutcome(..) => 0xf,
+            Self::Capabilities(_) => 0x10,
+            Self::Abort(_) => 0x12,
+            Self::GlideRefused(_) => 0x13,
+            Self::Error(_) => 0x14,
+            Self::PublicKey(..) => 0x15,
+            Self::Ping => 0x16,
+            Self::Pong => 0x17,
+            Self::ChunkAt(..) => 0x18,
+            Self::Purged(_) => 0x19,
+            Self::RangeRequest { .. } => 0x1a,
+            Self::Tagged(..) => 0x1b,
+            Self::Busy { .. } => 0x1c,
+            Self::Version(_) => 0x1d,
+            Self::Digest(..) => 0x1e,
+            Self::ResumeFrom(..) => 0x1f,
+            Self::TransferCancelled(_) => 0x20,
+            Self::GlideRequestsSent { .. } => 0x21,
+            Self::UserStatus { .. } => 0x22,
+            Self::Directory { .. } => 0x23,
+            Self::UsersPage { .. } => 0x24,
+        }
+    }
+
+    // What's inside a tagged transmission, or the transmission itself
+    pub fn untagged(&self) -> &Transmission {
+        match self {
+            Self::Tagged(_, inner) => inner,
+            other => other,
+        }
+    }
+
+    // Whether this is a bare signal, encoded as just its control byte
+    pub fn is_signal(&self) -> bool {
+        matches!(
+            self,
+            Self::UsernameOk
+                | Self::UsernameTaken
+                | Self::UsernameInvalid
+                | Self::OkFailed
+                | Self::NoSuccess
+                | Self::ClientDisconnected
+                | Self::GlideRequestSent
+                | Self::OkSuccess
+                | Self::Ping
+                | Self::Pong
+        )
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
+        self.encode(ProtocolVersion::V1)
+    }
+
+    // The transmission itself, without any framing `version` puts around it
+    fn encode(&self, version: ProtocolVersion) -> Vec<u8> {
         let ret = match *self {
             Self::Username(ref user) => Vec::from(format!("\u{1}{}\0", user)),
             Self::UsernameOk => vec![2],
             Self::UsernameTaken => vec![3],
             Self::UsernameInvalid => vec![4],
-            Self::Metadata(ref filename, size) => {
+            Self::Metadata(ref filename, size, ref attributes) if attributes.is_empty() => {
                 let mut ret = Vec::from(format!("\u{5}{}\0", filename));
                 size.to_be_bytes().iter().for_each(|&b| ret.push(b));
 
                 ret
             }
+            // Attributes use their own code so peers that predate them still
+            // understand plain metadata
+            Self::Metadata(ref filename, size, ref attributes) => {
+                let mut ret = Vec::from(format!("\u{11}{}\0", filename));
+                ret.extend(size.to_be_bytes());
+                ret.extend((attributes.len() as u16).to_be_bytes());
+                for (key, value) in attributes {
+                    ret.extend(format!("{}\0{}\0", key, value).as_bytes());
+                }
+
+                ret
+            }
             Self::Chunk(ref filename, ref data) => {
                 let chunk_size = data.len() as u16;
                 let chunk_size_bytes = chunk_size.to_be_bytes();
@@ -47,56 +458,437 @@ impl Transmission {
 
                 ret
             }
+            Self::ChunkAt(ref filename, offset, ref data) => {
+                let mut ret = Vec::from(format!("\u{18}{}\0", filename));
+                ret.extend(offset.to_be_bytes());
+                ret.extend((data.len() as u16).to_be_bytes());
+                ret.extend(data);
+
+                ret
+            }
             Self::ConnectedUsers(ref users) => {
-                let num_users = users.len() as u16;
-                let num_users_bytes = num_users.to_be_bytes();
-                let users_str = users.join("\0");
-                format!(
-                    "\u{7}{}{}\0{}",
-                    String::from_utf8_lossy(&num_users_bytes),
-                    users_str,
-                    "\0"
-                )
-                .into()
+                let mut ret = vec![0x7];
+                ret.extend((users.len() as u16).to_be_bytes());
+                for username in users {
+                    ret.extend(username.as_bytes());
+                    ret.push(0);
+                }
+
+                ret
             }
             Self::IncomingRequests(ref requests) => {
-                let num_requests = requests.len() as u16;
-                let num_requests_bytes = num_requests.to_be_bytes();
-                let requests_str: String = requests
-                    .iter()
-                    .map(|req| format!("{}\0{}", req.sender, req.filename))
-                    .collect::<Vec<_>>()
-                    .join("\0");
-                format!(
-                    "\u{8}{}{}\0",
-                    String::from_utf8_lossy(&num_requests_bytes),
-                    requests_str
-                )
-                .into()
+                // Any past what the count can say are left off, so what's
+                // sent still decodes. `reqs` won't list that many.
+                let count = u16::try_from(requests.len()).unwrap_or(u16::MAX);
+                let mut ret = vec![0x8];
+                ret.extend(count.to_be_bytes());
+                for req in &requests[..count as usize] {
+                    ret.extend(format!("{}\0{}\0", req.sender, req.filename).as_bytes());
+                    if version >= ProtocolVersion::V3 {
+                        let offered_at = req
+                            .offered_at
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .unwrap_or_default();
+                        ret.extend(req.size.to_be_bytes());
+                        ret.extend((offered_at.as_millis() as u64).to_be_bytes());
+                    }
+                }
+
+                ret
             }
             Self::Command(ref cmd) => match cmd {
-                Command::List => vec![9, 1],
+                Command::List {
+                    filter: None,
+                    page: None,
+                    page_size: None,
+                } => vec![9, 1],
+                Command::List {
+                    filter: Some(ref filter),
+                    page,
+                    page_size,
+                } => {
+                    let mut ret = Vec::from(format!("\u{9}\u{11}{}\0", filter));
+                    ret.extend(page.unwrap_or(0).to_be_bytes());
+                    ret.extend(page_size.unwrap_or(0).to_be_bytes());
+
+                    ret
+                }
+                Command::List {
+                    filter: None,
+                    page,
+                    page_size,
+                } => {
+                    let mut ret = vec![9, 16];
+                    ret.extend(page.unwrap_or(0).to_be_bytes());
+                    ret.extend(page_size.unwrap_or(0).to_be_bytes());
+
+                    ret
+                }
                 Command::Requests => vec![9, 2],
                 Command::Glide {
                     path,
                     to: ref username,
+                    as_name: None,
                 } => format!("\u{9}\u{3}{}\0{}\0", path, username).into(),
-                Command::Ok(ref username) => format!("\u{9}\u{4}{}\0", username).into(),
-                Command::No(ref username) => format!("\u{9}\u{4}{}\0", username).into(),
+                // Its own code, so glides without a name are as they always were
+                Command::Glide {
+                    path,
+                    to: ref username,
+                    as_name: Some(ref name),
+                } => format!("\u{9}\u{b}{}\0{}\0{}\0", path, username, name).into(),
+                // Where it's saved is up to the client
+                Command::Ok(ref username, None)
+                | Command::OkInto {
+                    from: ref username,
+                    filename: None,
+                    ..
+                } => format!("\u{9}\u{4}{}\0", username).into(),
+                // Its own code, so an ok without a name is as it always was
+                Command::Ok(ref username, Some(ref filename))
+                | Command::OkInto {
+                    from: ref username,
+                    filename: Some(ref filename),
+                    ..
+                } => format!("\u{9}\u{12}{}\0{}\0", username, filename).into(),
+                Command::No(ref username, ref filename) => format!(
+                    "\u{9}\u{5}{}\0{}\0",
+                    username,
+                    filename.as_deref().unwrap_or_default()
+                )
+                .into(),
+                Command::Capabilities => vec![9, 6],
+                Command::Purge => vec![9, 10],
+                Command::Whoami => vec![9, 14],
+                Command::OkLatest => vec![9, 19],
+                Command::NoLatest => vec![9, 20],
+                Command::GlideDir {
+                    ref path,
+                    to: ref username,
+                } => format!("\u{9}\u{f}{}\0{}\0", path, username).into(),
+                Command::Cancel(ref filename) => format!("\u{9}\u{c}{}\0", filename).into(),
+                Command::GlideMany { ref path, ref to } => {
+                    let mut ret = Vec::from(format!("\u{9}\u{d}{}\0", path));
+                    ret.extend((to.len() as u16).to_be_bytes());
+                    for username in to {
+                        ret.extend(username.as_bytes());
+                        ret.push(0);
+                    }
+
+                    ret
+                }
+                Command::SetName(ref username) => format!("\u{9}\u{7}{}\0", username).into(),
+                Command::Key(ref username) => format!("\u{9}\u{8}{}\0", username).into(),
+                Command::Peek {
+                    ref from,
+                    ref filename,
+                    bytes,
+                } => {
+                    let mut ret = Vec::from(format!("\u{9}\u{9}{}\0{}\0", from, filename));
+                    ret.extend(bytes.to_be_bytes());
+
+                    ret
+                }
             },
             Self::OkFailed => vec![10],
             Self::NoSuccess => vec![11],
             Self::ClientDisconnected => vec![12],
             Self::GlideRequestSent => vec![13],
             Self::OkSuccess => vec![14],
+            Self::Ping => vec![0x16],
+            Self::Pong => vec![0x17],
+            Self::RequestOutcome(ref recipient, ref filename, accepted) => {
+                let mut ret = Vec::from(format!("\u{f}{}\0{}\0", recipient, filename));
+                ret.push(accepted as u8);
+
+                ret
+            }
+            Self::Abort(ref filename) => format!("\u{12}{}\0", filename).into(),
+            Self::TransferCancelled(ref filename) => format!("\u{20}{}\0", filename).into(),
+            Self::GlideRefused(ref reason) => format!("\u{13}{}\0", reason).into(),
+            Self::Error(ref message) => format!("\u{14}{}\0", message).into(),
+            Self::Purged(count) => {
+                let mut ret = vec![0x19];
+                ret.extend(count.to_be_bytes());
+
+                ret
+            }
+            Self::RangeRequest {
+                ref filename,
+                start,
+                len,
+            } => {
+                let mut ret = Vec::from(format!("\u{1a}{}\0", filename));
+                ret.extend(start.to_be_bytes());
+                ret.extend(len.to_be_bytes());
+
+                ret
+            }
+            Self::Busy { retry_after_secs } => {
+                let mut ret = vec![0x1c];
+                ret.extend(retry_after_secs.to_be_bytes());
+
+                ret
+            }
+            Self::Version(version) => {
+                let mut ret = vec![0x1d];
+                ret.extend(version.to_be_bytes());
+
+                ret
+            }
+            Self::Tagged(id, ref inner) => {
+                let mut ret = vec![0x1b];
+                ret.extend(id.to_be_bytes());
+                ret.extend(inner.encode(version));
+
+                ret
+            }
+            Self::ResumeFrom(ref filename, offset) => {
+                let mut ret = Vec::from(format!("\u{1f}{}\0", filename));
+                ret.extend(offset.to_be_bytes());
+
+                ret
+            }
+            Self::Digest(ref filename, ref digest) => {
+                let mut ret = Vec::from(format!("\u{1e}{}\0", filename));
+                ret.extend(digest);
+
+                ret
+            }
+            Self::PublicKey(ref username, ref key) => {
+                let mut ret = Vec::from(format!("\u{15}{}\0", username));
+                ret.extend(key);
+
+                ret
+            }
+            Self::GlideRequestsSent { ref unknown } => {
+                let mut ret = vec![0x21];
+                ret.extend((unknown.len() as u16).to_be_bytes());
+                for username in unknown {
+                    ret.extend(username.as_bytes());
+                    ret.push(0);
+                }
+
+                ret
+            }
+            Self::UserStatus {
+                ref username,
+                pending,
+            } => {
+                let mut ret = Vec::from(format!("\u{22}{}\0", username));
+                ret.extend(pending.to_be_bytes());
+
+                ret
+            }
+            Self::Directory { ref name, files } => {
+                let mut ret = Vec::from(format!("\u{23}{}\0", name));
+                ret.extend(files.to_be_bytes());
+
+                ret
+            }
+            Self::UsersPage { total, ref users } => {
+                let mut ret = vec![0x24];
+                ret.extend(total.to_be_bytes());
+                ret.extend((users.len() as u16).to_be_bytes());
+                for username in users {
+                    ret.extend(username.as_bytes());
+                    ret.push(0);
+                }
+
+                ret
+            }
+            Self::Capabilities(ref commands) => {
+                let mut ret = vec![0x10];
+                ret.extend((commands.len() as u16).to_be_bytes());
+                for command in commands {
+                    ret.extend(command.as_bytes());
+                    ret.push(0);
+                }
+
+                ret
+            }
         };
 
-        trace!("Response: {:#?} - {:?}", self, ret.take(10));
+        trace!("Response: {:?} - {} bytes", self.redacted(), ret.len());
 
         ret
     }
 
-    pub async fn from_stream(stream: &mut TcpStream) -> Result<Transmission> {
+    // Writes the transmission to `w` as `to_bytes` encodes it. A chunk's
+    // data goes to the stream as it is, rather than being copied into a
+    // frame behind its header first.
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, w: &mut W) -> Result<()> {
+        let Some((header, data)) = self.chunk_parts() else {
+            return Ok(w.write_all(&self.to_bytes()).await?);
+        };
+
+        trace!(
+            "Response: {:?} - {} bytes",
+            self.redacted(),
+            header.len() + data.len()
+        );
+        w.write_all(&header).await?;
+        Ok(w.write_all(data).await?)
+    }
+
+    // Splits a chunk into its encoded header and its data, so the data can be
+    // written without copying it
+    fn chunk_parts(&self) -> Option<(Vec<u8>, &[u8])> {
+        match *self {
+            Self::Chunk(ref filename, ref data) => {
+                let mut header = Vec::with_capacity(filename.len() + 4);
+                header.push(0x6);
+                header.extend(filename.as_bytes());
+                header.push(0);
+                header.extend((data.len() as u16).to_be_bytes());
+                Some((header, data))
+            }
+            Self::ChunkAt(ref filename, offset, ref data) => {
+                let mut header = Vec::with_capacity(filename.len() + 12);
+                header.push(0x18);
+                header.extend(filename.as_bytes());
+                header.push(0);
+                header.extend(offset.to_be_bytes());
+                header.extend((data.len() as u16).to_be_bytes());
+                Some((header, data))
+            }
+            _ => None,
+        }
+    }
+
+    // Writes the transmission to `w` as `to_bytes_for` encodes it, without
+    // copying chunk data
+    pub async fn write_to_for<W: AsyncWrite + Unpin>(
+        &self,
+        w: &mut W,
+        version: ProtocolVersion,
+    ) -> Result<()> {
+        if version == ProtocolVersion::V1 {
+            return self.write_to(w).await;
+        }
+        let Some((header, data)) = self.chunk_parts() else {
+            return Ok(w.write_all(&self.to_bytes_for(version)).await?);
+        };
+
+        let len = (header.len() + data.len() + 4) as u32;
+        trace!("Response: {:?} - {} bytes", self.redacted(), len + 4);
+        w.write_all(&len.to_be_bytes()).await?;
+        w.write_all(&header).await?;
+        w.write_all(data).await?;
+        Ok(w.write_all(&crc32(data).to_be_bytes()).await?)
+    }
+
+    // Encodes the transmission as `version` of the wire format has it
+    pub fn to_bytes_for(&self, version: ProtocolVersion) -> Vec<u8> {
+        match version {
+            ProtocolVersion::V1 => self.to_bytes(),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => {
+                let mut frame = self.encode(version);
+                // V2 chunks carry a CRC32 of their data after it
+                if let Self::Chunk(_, ref data) | Self::ChunkAt(_, _, ref data) = *self {
+                    frame.extend(crc32(data).to_be_bytes());
+                }
+                let mut ret = Vec::with_capacity(4 + frame.len());
+                ret.extend((frame.len() as u32).to_be_bytes());
+                ret.extend(frame);
+
+                ret
+            }
+        }
+    }
+
+    // Reads the next transmission, as `version` of the wire format has it
+    pub async fn from_stream_for<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        version: ProtocolVersion,
+    ) -> Result<Transmission> {
+        Self::from_stream_with_limit_for(stream, MAX_FIELD_LEN, version).await
+    }
+
+    // Same as `from_stream_for`, with a different cap on string fields
+    pub async fn from_stream_with_limit_for<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        max_field_len: usize,
+        version: ProtocolVersion,
+    ) -> Result<Transmission> {
+        match version {
+            ProtocolVersion::V1 => Self::from_stream_with_limit(stream, max_field_len).await,
+            ProtocolVersion::V2 | ProtocolVersion::V3 => {
+                Ok(Self::decode_frame(stream, max_field_len, version).await?)
+            }
+        }
+    }
+
+    async fn decode_frame<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        max_field_len: usize,
+        version: ProtocolVersion,
+    ) -> std::result::Result<Transmission, ProtocolError> {
+        loop {
+            // Ending before the length is a hang up between frames, and part
+            // way through it a truncated frame
+            let mut len_bytes = [0u8; 4];
+            len_bytes[0] = stream.read_u8().await?;
+            stream
+                .read_exact(&mut len_bytes[1..])
+                .await
+                .map_err(truncated("frame length"))?;
+            let len = u32::from_be_bytes(len_bytes);
+            // An empty frame is padding, like a 0 byte in V1
+            if len == 0 {
+                continue;
+            }
+            if len > MAX_FRAME_SIZE {
+                return Err(ProtocolError::Io(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("A {} byte frame is too large", len),
+                )));
+            }
+
+            let mut frame = vec![0u8; len as usize];
+            stream
+                .read_exact(&mut frame)
+                .await
+                .map_err(truncated("frame"))?;
+
+            let mut rest = frame.as_slice();
+            let transmission = Self::decode(&mut rest, max_field_len, version, false).await?;
+            if let Self::Chunk(_, ref data) | Self::ChunkAt(_, _, ref data) = transmission {
+                let expected = rest.read_u32().await.map_err(truncated("chunk checksum"))?;
+                let actual = crc32(data);
+                if expected != actual {
+                    return Err(ProtocolError::ChecksumMismatch { expected, actual });
+                }
+            }
+            // V1 pads some transmissions with trailing 0 bytes
+            if rest.iter().any(|&b| b != 0) {
+                return Err(ProtocolError::Io(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} bytes left over in a frame", rest.len()),
+                )));
+            }
+            return Ok(transmission);
+        }
+    }
+
+    pub async fn from_stream<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Transmission> {
+        Ok(Self::decode(stream, MAX_FIELD_LEN, ProtocolVersion::V1, false).await?)
+    }
+
+    // Same as `from_stream`, with a different cap on string fields
+    pub async fn from_stream_with_limit<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        max_field_len: usize,
+    ) -> Result<Transmission> {
+        Ok(Self::decode(stream, max_field_len, ProtocolVersion::V1, false).await?)
+    }
+
+    // `in_tag` is set while reading what's inside a tagged transmission
+    async fn decode<R: AsyncRead + Unpin>(
+        stream: &mut R,
+        max_field_len: usize,
+        version: ProtocolVersion,
+        in_tag: bool,
+    ) -> std::result::Result<Transmission, ProtocolError> {
         loop {
             let first_byte = stream.read_u8().await?; // get the first byte (control byte)
 
@@ -104,14 +896,7 @@ impl Transmission {
                 0x0 => continue,
                 0x1 => {
                     // username
-                    let mut username = String::new();
-                    loop {
-                        let ch = stream.read_u8().await? as char;
-                        if ch == '\0' {
-                            break;
-                        }
-                        username.push(ch);
-                    }
+                    let username = read_string(stream, "username", max_field_len).await?;
                     Ok(Self::Username(username))
                 }
                 0x2 => Ok(Self::UsernameOk),
@@ -119,55 +904,46 @@ impl Transmission {
                 0x4 => Ok(Self::UsernameInvalid),
                 0x5 => {
                     // metadata
-                    let mut filename = String::new();
-                    loop {
-                        let ch = stream.read_u8().await? as char;
-                        if ch == '\0' {
-                            break;
-                        }
-                        filename.push(ch);
-                    }
+                    let filename = read_string(stream, "filename", max_field_len).await?;
                     let mut size_bytes = [0u8; 4];
-                    stream.read_exact(&mut size_bytes).await?;
+                    stream
+                        .read_exact(&mut size_bytes)
+                        .await
+                        .map_err(truncated("metadata size"))?;
                     let size = u32::from_be_bytes(size_bytes);
 
-                    Ok(Self::Metadata(filename, size))
+                    Ok(Self::Metadata(filename, size, HashMap::new()))
                 }
                 0x6 => {
                     // chunk
-                    let mut filename = String::new();
-                    loop {
-                        let ch = stream.read_u8().await? as char;
-                        if ch == '\0' {
-                            break;
-                        }
-                        filename.push(ch);
-                    }
+                    let filename = read_string(stream, "filename", max_field_len).await?;
                     let mut chunk_size_bytes = [0u8; 2];
-                    stream.read_exact(&mut chunk_size_bytes).await?;
+                    stream
+                        .read_exact(&mut chunk_size_bytes)
+                        .await
+                        .map_err(truncated("chunk size"))?;
                     let chunk_size = u16::from_be_bytes(chunk_size_bytes);
 
                     let mut data = vec![0u8; chunk_size as usize];
-                    stream.read_exact(&mut data).await?;
+                    stream
+                        .read_exact(&mut data)
+                        .await
+                        .map_err(truncated("chunk data"))?;
 
                     Ok(Self::Chunk(filename, data))
                 }
                 0x7 => {
                     // connected users
                     let mut num_users_bytes = [0u8; 2];
-                    stream.read_exact(&mut num_users_bytes).await?;
+                    stream
+                        .read_exact(&mut num_users_bytes)
+                        .await
+                        .map_err(truncated("user count"))?;
                     let num_users = u16::from_be_bytes(num_users_bytes);
 
                     let mut users = Vec::new();
                     for _ in 0..num_users {
-                        let mut user = String::new();
-                        loop {
-                            let ch = stream.read_u8().await? as char;
-                            if ch == '\0' {
-                                break;
-                            }
-                            user.push(ch);
-                        }
+                        let user = read_string(stream, "username", max_field_len).await?;
                         users.push(user);
                     }
 
@@ -176,82 +952,154 @@ impl Transmission {
                 0x8 => {
                     // incoming requests
                     let mut num_requests_bytes = [0u8; 2];
-                    stream.read_exact(&mut num_requests_bytes).await?;
+                    stream
+                        .read_exact(&mut num_requests_bytes)
+                        .await
+                        .map_err(truncated("request count"))?;
                     let num_requests = u16::from_be_bytes(num_requests_bytes);
 
                     let mut requests = Vec::new();
                     for _ in 0..num_requests {
-                        let mut sender = String::new();
-                        loop {
-                            let ch = stream.read_u8().await? as char;
-                            if ch == '\0' {
-                                break;
-                            }
-                            sender.push(ch);
-                        }
+                        let sender = read_string(stream, "sender", max_field_len).await?;
 
-                        let mut filename = String::new();
-                        loop {
-                            let ch = stream.read_u8().await? as char;
-                            if ch == '\0' {
-                                break;
-                            }
-                            filename.push(ch);
+                        let filename = read_string(stream, "filename", max_field_len).await?;
+
+                        let (mut size, mut offered_at) = (0, SystemTime::UNIX_EPOCH);
+                        if version >= ProtocolVersion::V3 {
+                            size = stream.read_u64().await.map_err(truncated("request size"))?;
+                            let millis =
+                                stream.read_u64().await.map_err(truncated("request time"))?;
+                            offered_at += Duration::from_millis(millis);
                         }
 
-                        requests.push(Request { sender, filename });
+                        requests.push(Request {
+                            sender,
+                            filename,
+                            size,
+                            offered_at,
+                        });
                     }
 
                     Ok(Self::IncomingRequests(requests))
                 }
                 0x9 => {
                     // command
-                    let command_type = stream.read_u8().await?;
+                    let command_type = stream.read_u8().await.map_err(truncated("command code"))?;
                     match command_type {
-                        1 => Ok(Self::Command(Command::List)),
+                        1 => Ok(Self::Command(Command::List {
+                            filter: None,
+                            page: None,
+                            page_size: None,
+                        })),
                         2 => Ok(Self::Command(Command::Requests)),
                         3 => {
-                            let mut path = String::new();
-                            loop {
-                                let ch = stream.read_u8().await? as char;
-                                if ch == '\0' {
-                                    break;
-                                }
-                                path.push(ch);
-                            }
-                            let mut username = String::new();
-                            loop {
-                                let ch = stream.read_u8().await? as char;
-                                if ch == '\0' {
-                                    break;
-                                }
-                                username.push(ch);
-                            }
-                            Ok(Self::Command(Command::Glide { path, to: username }))
+                            let path = read_string(stream, "path", max_field_len).await?;
+                            let username = read_string(stream, "username", max_field_len).await?;
+                            Ok(Self::Command(Command::Glide {
+                                path,
+                                to: username,
+                                as_name: None,
+                            }))
                         }
                         4 => {
-                            let mut username = String::new();
-                            loop {
-                                let ch = stream.read_u8().await? as char;
-                                if ch == '\0' {
-                                    break;
-                                }
-                                username.push(ch);
-                            }
-                            Ok(Self::Command(Command::Ok(username)))
+                            let username = read_string(stream, "username", max_field_len).await?;
+                            Ok(Self::Command(Command::Ok(username, None)))
                         }
                         5 => {
-                            let mut username = String::new();
-                            loop {
-                                let ch = stream.read_u8().await? as char;
-                                if ch == '\0' {
-                                    break;
-                                }
-                                username.push(ch);
+                            let username = read_string(stream, "username", max_field_len).await?;
+
+                            // Empty when the file isn't named
+                            let filename = read_string(stream, "filename", max_field_len).await?;
+                            let filename = (!filename.is_empty()).then_some(filename);
+
+                            Ok(Self::Command(Command::No(username, filename)))
+                        }
+                        6 => Ok(Self::Command(Command::Capabilities)),
+                        7 => {
+                            let username = read_string(stream, "username", max_field_len).await?;
+                            Ok(Self::Command(Command::SetName(username)))
+                        }
+                        8 => {
+                            let username = read_string(stream, "username", max_field_len).await?;
+                            Ok(Self::Command(Command::Key(username)))
+                        }
+                        9 => {
+                            let from = read_string(stream, "username", max_field_len).await?;
+
+                            let filename = read_string(stream, "filename", max_field_len).await?;
+
+                            let bytes =
+                                stream.read_u32().await.map_err(truncated("peek length"))?;
+
+                            Ok(Self::Command(Command::Peek {
+                                from,
+                                filename,
+                                bytes,
+                            }))
+                        }
+                        10 => Ok(Self::Command(Command::Purge)),
+                        14 => Ok(Self::Command(Command::Whoami)),
+                        19 => Ok(Self::Command(Command::OkLatest)),
+                        20 => Ok(Self::Command(Command::NoLatest)),
+                        15 => {
+                            let path = read_string(stream, "path", max_field_len).await?;
+                            let username = read_string(stream, "username", max_field_len).await?;
+                            Ok(Self::Command(Command::GlideDir { path, to: username }))
+                        }
+                        16 => {
+                            let page = stream.read_u32().await.map_err(truncated("page"))?;
+                            let size = stream.read_u16().await.map_err(truncated("page size"))?;
+                            // 0 leaves the size to the server
+                            Ok(Self::Command(Command::List {
+                                filter: None,
+                                page: Some(page),
+                                page_size: (size != 0).then_some(size),
+                            }))
+                        }
+                        17 => {
+                            let filter = read_string(stream, "filter", max_field_len).await?;
+                            let page = stream.read_u32().await.map_err(truncated("page"))?;
+                            let size = stream.read_u16().await.map_err(truncated("page size"))?;
+                            Ok(Self::Command(Command::List {
+                                filter: Some(filter),
+                                page: Some(page),
+                                page_size: (size != 0).then_some(size),
+                            }))
+                        }
+                        18 => {
+                            let username = read_string(stream, "username", max_field_len).await?;
+                            let filename = read_string(stream, "filename", max_field_len).await?;
+                            Ok(Self::Command(Command::Ok(username, Some(filename))))
+                        }
+                        11 => {
+                            let path = read_string(stream, "path", max_field_len).await?;
+                            let username = read_string(stream, "username", max_field_len).await?;
+                            let name = read_string(stream, "filename", max_field_len).await?;
+                            Ok(Self::Command(Command::Glide {
+                                path,
+                                to: username,
+                                as_name: Some(name),
+                            }))
+                        }
+                        12 => {
+                            let filename = read_string(stream, "filename", max_field_len).await?;
+                            Ok(Self::Command(Command::Cancel(filename)))
+                        }
+                        13 => {
+                            let path = read_string(stream, "path", max_field_len).await?;
+                            let count = stream
+                                .read_u16()
+                                .await
+                                .map_err(truncated("recipient count"))?;
+
+                            let mut to = Vec::new();
+                            for _ in 0..count {
+                                to.push(read_string(stream, "username", max_field_len).await?);
                             }
-                            Ok(Self::Command(Command::No(username)))
+
+                            Ok(Self::Command(Command::GlideMany { path, to }))
                         }
-                        something => panic!("what is this command {}", something),
+                        something => Err(ProtocolError::UnknownCommandType(something)),
                     }
                 }
                 0xa => Ok(Self::OkFailed),
@@ -259,16 +1107,269 @@ impl Transmission {
                 0xc => Ok(Self::ClientDisconnected),
                 0xd => Ok(Self::GlideRequestSent),
                 0xe => Ok(Self::OkSuccess),
-                something => {
-                    let mut wrong = [0u8; 1024];
-                    wrong[0] = something;
+                0xf => {
+                    // request outcome
+                    let recipient = read_string(stream, "recipient", max_field_len).await?;
+
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+
+                    let accepted = stream.read_u8().await.map_err(truncated("accepted flag"))? != 0;
+
+                    Ok(Self::RequestOutcome(recipient, filename, accepted))
+                }
+                0x10 => {
+                    // capabilities
+                    let mut num_commands_bytes = [0u8; 2];
+                    stream
+                        .read_exact(&mut num_commands_bytes)
+                        .await
+                        .map_err(truncated("command count"))?;
+                    let num_commands = u16::from_be_bytes(num_commands_bytes);
+
+                    let mut commands = Vec::new();
+                    for _ in 0..num_commands {
+                        let command = read_string(stream, "command name", max_field_len).await?;
+                        commands.push(command);
+                    }
+
+                    Ok(Self::Capabilities(commands))
+                }
+                0x11 => {
+                    // metadata with attributes
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+                    let size = stream
+                        .read_u32()
+                        .await
+                        .map_err(truncated("metadata size"))?;
+                    let num_attributes = stream
+                        .read_u16()
+                        .await
+                        .map_err(truncated("attribute count"))?;
+
+                    let mut attributes = HashMap::new();
+                    for _ in 0..num_attributes {
+                        let key = read_string(stream, "attribute key", max_field_len).await?;
+
+                        let value = read_string(stream, "attribute value", max_field_len).await?;
+
+                        attributes.insert(key, value);
+                    }
+
+                    Ok(Self::Metadata(filename, size, attributes))
+                }
+                0x12 => {
+                    // abort
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+                    Ok(Self::Abort(filename))
+                }
+                0x13 => {
+                    // glide refused
+                    let reason = read_string(stream, "reason", max_field_len).await?;
+                    Ok(Self::GlideRefused(reason))
+                }
+                0x14 => {
+                    // error
+                    let message = read_string(stream, "message", max_field_len).await?;
+                    Ok(Self::Error(message))
+                }
+                0x15 => {
+                    // public key
+                    let username = read_string(stream, "username", max_field_len).await?;
 
-                    stream.read(&mut wrong[1..]).await?;
-                    panic!("somethings really wrong :( {:#?}", wrong);
+                    let mut key = [0u8; 32];
+                    stream
+                        .read_exact(&mut key)
+                        .await
+                        .map_err(truncated("public key"))?;
+
+                    Ok(Self::PublicKey(username, key))
+                }
+                0x16 => Ok(Self::Ping),
+                0x17 => Ok(Self::Pong),
+                0x18 => {
+                    // chunk at an offset
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+                    let offset = stream.read_u64().await.map_err(truncated("chunk offset"))?;
+                    let chunk_size = stream.read_u16().await.map_err(truncated("chunk size"))?;
+
+                    let mut data = vec![0u8; chunk_size as usize];
+                    stream
+                        .read_exact(&mut data)
+                        .await
+                        .map_err(truncated("chunk data"))?;
+
+                    Ok(Self::ChunkAt(filename, offset, data))
+                }
+                0x19 => {
+                    // purged
+                    let count = stream.read_u32().await.map_err(truncated("purge count"))?;
+                    Ok(Self::Purged(count))
+                }
+                0x1a => {
+                    // range request
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+                    let start = stream.read_u64().await.map_err(truncated("range start"))?;
+                    let len = stream.read_u64().await.map_err(truncated("range length"))?;
+
+                    Ok(Self::RangeRequest {
+                        filename,
+                        start,
+                        len,
+                    })
+                }
+                // Refused as soon as it starts, so a long chain of tags
+                // can't recurse deep enough to overflow the stack
+                0x1b if in_tag => Err(ProtocolError::Io(Error::new(
+                    ErrorKind::InvalidData,
+                    "A tagged transmission can't be tagged again",
+                ))),
+                0x1b => {
+                    // tagged
+                    let id = stream.read_u32().await.map_err(truncated("tag"))?;
+                    let inner =
+                        Box::pin(Self::decode(stream, max_field_len, version, true)).await?;
+                    Ok(Self::Tagged(id, Box::new(inner)))
                 }
+                0x1c => {
+                    // busy
+                    let retry_after_secs =
+                        stream.read_u32().await.map_err(truncated("retry delay"))?;
+                    Ok(Self::Busy { retry_after_secs })
+                }
+                0x1d => {
+                    // version
+                    let version = stream.read_u16().await.map_err(truncated("version"))?;
+                    Ok(Self::Version(version))
+                }
+                0x1e => {
+                    // digest
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+
+                    let mut digest = [0u8; 32];
+                    stream
+                        .read_exact(&mut digest)
+                        .await
+                        .map_err(truncated("digest"))?;
+
+                    Ok(Self::Digest(filename, digest))
+                }
+                0x1f => {
+                    // resume from
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+                    let offset = stream
+                        .read_u32()
+                        .await
+                        .map_err(truncated("resume offset"))?;
+                    Ok(Self::ResumeFrom(filename, offset))
+                }
+                0x20 => {
+                    // transfer cancelled
+                    let filename = read_string(stream, "filename", max_field_len).await?;
+                    Ok(Self::TransferCancelled(filename))
+                }
+                0x21 => {
+                    // glide requests sent
+                    let count = stream.read_u16().await.map_err(truncated("user count"))?;
+
+                    let mut unknown = Vec::new();
+                    for _ in 0..count {
+                        unknown.push(read_string(stream, "username", max_field_len).await?);
+                    }
+
+                    Ok(Self::GlideRequestsSent { unknown })
+                }
+                0x22 => {
+                    // user status
+                    let username = read_string(stream, "username", max_field_len).await?;
+                    let pending = stream
+                        .read_u32()
+                        .await
+                        .map_err(truncated("pending count"))?;
+                    Ok(Self::UserStatus { username, pending })
+                }
+                0x23 => {
+                    // directory
+                    let name = read_string(stream, "directory name", max_field_len).await?;
+                    let files = stream.read_u32().await.map_err(truncated("file count"))?;
+                    Ok(Self::Directory { name, files })
+                }
+                0x24 => {
+                    // users page
+                    let total = stream.read_u32().await.map_err(truncated("user total"))?;
+                    let count = stream.read_u16().await.map_err(truncated("user count"))?;
+                    let mut users = Vec::new();
+                    for _ in 0..count {
+                        users.push(read_string(stream, "username", max_field_len).await?);
+                    }
+                    Ok(Self::UsersPage { total, users })
+                }
+                something => Err(ProtocolError::UnknownControlByte(something)),
             };
 
             return ret;
         }
     }
 }
+
+pub struct Redacted<'a>(&'a Transmission);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Transmission::Username(user) => write!(f, "Username(<{} bytes>)", user.len()),
+            Transmission::Command(cmd) => write!(f, "Command({})", cmd.name()),
+            Transmission::Metadata(_, size, attributes) => write!(
+                f,
+                "Metadata(<redacted>, {}, <{} attributes>)",
+                size,
+                attributes.len()
+            ),
+            Transmission::Chunk(_, data) => write!(f, "Chunk(<redacted>, <{} bytes>)", data.len()),
+            Transmission::ChunkAt(_, offset, data) => {
+                write!(f, "ChunkAt(<redacted>, {}, <{} bytes>)", offset, data.len())
+            }
+            Transmission::ConnectedUsers(users) => {
+                write!(f, "ConnectedUsers(<{} users>)", users.len())
+            }
+            Transmission::IncomingRequests(requests) => {
+                write!(f, "IncomingRequests(<{} requests>)", requests.len())
+            }
+            Transmission::Abort(_) => write!(f, "Abort(<redacted>)"),
+            Transmission::TransferCancelled(_) => write!(f, "TransferCancelled(<redacted>)"),
+            Transmission::GlideRequestsSent { unknown } => write!(
+                f,
+                "GlideRequestsSent {{ unknown: <{} users> }}",
+                unknown.len()
+            ),
+            Transmission::UserStatus { pending, .. } => write!(
+                f,
+                "UserStatus {{ username: <redacted>, pending: {} }}",
+                pending
+            ),
+            Transmission::UsersPage { total, users } => write!(
+                f,
+                "UsersPage {{ total: {}, users: <{} users> }}",
+                total,
+                users.len()
+            ),
+            Transmission::Directory { files, .. } => {
+                write!(f, "Directory {{ name: <redacted>, files: {} }}", files)
+            }
+            Transmission::Digest(..) => write!(f, "Digest(<redacted>, <redacted>)"),
+            Transmission::ResumeFrom(_, offset) => write!(f, "ResumeFrom(<redacted>, {})", offset),
+            Transmission::GlideRefused(_) => write!(f, "GlideRefused(<redacted>)"),
+            Transmission::Error(_) => write!(f, "Error(<redacted>)"),
+            Transmission::PublicKey(..) => write!(f, "PublicKey(<redacted>, <redacted>)"),
+            Transmission::Tagged(id, inner) => write!(f, "Tagged({}, {:?})", id, inner.redacted()),
+            Transmission::RangeRequest { start, len, .. } => write!(
+                f,
+                "RangeRequest {{ filename: <redacted>, start: {}, len: {} }}",
+                start, len
+            ),
+            Transmission::RequestOutcome(_, _, accepted) => {
+                write!(f, "RequestOutcome(<redacted>, <redacted>, {})", accepted)
+            }
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
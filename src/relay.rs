@@ -0,0 +1,149 @@
+use log::info;
+use std::io::{Error, ErrorKind, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::protocol::{ProtocolVersion, Transmission};
+
+// Optional behaviour for `relay`
+#[derive(Clone, Debug, Default)]
+pub struct RelayOptions {
+    // Commands clients may send through, by name. Anything else is answered
+    // with `Transmission::Error` and never reaches the backend.
+    pub allowed_commands: Option<Vec<String>>,
+    // Log the kind of every transmission that passes through
+    pub log_kinds: bool,
+}
+
+// Relays one client connection to a backend server, a whole transmission
+// at a time, until either side hangs up. The client has to log in first,
+// after agreeing on a wire format with the backend if it wants one.
+//
+// Each transmission is written on before the next is read, so a slow side
+// holds the other back rather than the relay buffering for it.
+pub async fn relay(
+    mut client: TcpStream,
+    backend: impl ToSocketAddrs,
+    options: &RelayOptions,
+) -> Result<()> {
+    let mut first = Transmission::from_stream(&mut client).await?;
+    let mut backend = TcpStream::connect(backend).await?;
+
+    // Passed through as it is, so both ends settle on the same version
+    let mut version = ProtocolVersion::V1;
+    if let Transmission::Version(theirs) = first {
+        backend.write_all(&first.to_bytes()).await?;
+        let answer = Transmission::from_stream(&mut backend).await?;
+        let Transmission::Version(ours) = answer else {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Expected a protocol version, got {:?}", answer.redacted()),
+            ));
+        };
+        client.write_all(&answer.to_bytes()).await?;
+        version = ProtocolVersion::from_number(theirs.min(ours)).ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                format!("No protocol version between {} and {}", theirs, ours),
+            )
+        })?;
+        first = Transmission::from_stream_for(&mut client, version).await?;
+    }
+
+    let username = match first {
+        Transmission::Username(username) => username,
+        other => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Expected a username first, got {:?}", other.redacted()),
+            ))
+        }
+    };
+    backend
+        .write_all(&Transmission::Username(username.clone()).to_bytes_for(version))
+        .await?;
+
+    let result = splice(&mut client, &mut backend, &username, version, options).await;
+
+    let _ = client.shutdown().await;
+    let _ = backend.shutdown().await;
+    result
+}
+
+async fn splice(
+    client: &mut TcpStream,
+    backend: &mut TcpStream,
+    username: &str,
+    version: ProtocolVersion,
+    options: &RelayOptions,
+) -> Result<()> {
+    let mut client_byte = [0; 1];
+    let mut backend_byte = [0; 1];
+    loop {
+        // Wait for the start of a transmission on either side, then read
+        // all of it, so nothing is ever forwarded half way
+        let from_client = tokio::select! {
+            peeked = client.peek(&mut client_byte) => {
+                if peeked? == 0 {
+                    return Ok(());
+                }
+                true
+            }
+            peeked = backend.peek(&mut backend_byte) => {
+                if peeked? == 0 {
+                    return Ok(());
+                }
+                false
+            }
+        };
+
+        // Padding between version 1 transmissions, which `from_stream` would
+        // skip and then sit waiting for the next one. Later versions start
+        // with a frame's length, which can begin with a 0.
+        let (side, first_byte) = match from_client {
+            true => (&mut *client, client_byte[0]),
+            false => (&mut *backend, backend_byte[0]),
+        };
+        if first_byte == 0 && version == ProtocolVersion::V1 {
+            side.read_u8().await?;
+            continue;
+        }
+
+        if from_client {
+            let transmission = Transmission::from_stream_for(client, version).await?;
+            if options.log_kinds {
+                info!("{} -> backend: {:?}", username, transmission.redacted());
+            }
+
+            if let Transmission::Command(command) = transmission.untagged() {
+                let allowed = options
+                    .allowed_commands
+                    .as_ref()
+                    .is_none_or(|allowed| allowed.iter().any(|name| name == command.name()));
+                if !allowed {
+                    let mut refusal =
+                        Transmission::Error(format!("'{}' isn't allowed here", command.name()));
+                    if let Transmission::Tagged(id, _) = transmission {
+                        refusal = Transmission::Tagged(id, Box::new(refusal));
+                    }
+                    client.write_all(&refusal.to_bytes_for(version)).await?;
+                    continue;
+                }
+            }
+
+            backend
+                .write_all(&transmission.to_bytes_for(version))
+                .await?;
+        } else {
+            let transmission = Transmission::from_stream_for(backend, version).await?;
+            if options.log_kinds {
+                info!("backend -> {}: {:?}", username, transmission.redacted());
+            }
+            client
+                .write_all(&transmission.to_bytes_for(version))
+                .await?;
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::data::UserData;
+
+// Connected users, keyed by username. With the `sharded-state` feature the
+// map is split into buckets with their own lock, so commands touching
+// different users don't contend on a single mutex.
+#[cfg(not(feature = "sharded-state"))]
+pub type SharedState = Arc<Mutex<HashMap<String, UserData>>>;
+
+#[cfg(feature = "sharded-state")]
+pub type SharedState = Arc<ShardedState>;
+
+#[cfg(feature = "sharded-state")]
+pub const SHARD_COUNT: usize = 16;
+
+#[cfg(feature = "sharded-state")]
+pub struct ShardedState {
+    shards: Vec<Mutex<HashMap<String, UserData>>>,
+}
+
+#[cfg(feature = "sharded-state")]
+impl ShardedState {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARD_COUNT)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+
+    fn shard(&self, username: &str) -> &Mutex<HashMap<String, UserData>> {
+        &self.shards[self.shard_index(username)]
+    }
+
+    fn shard_index(&self, username: &str) -> usize {
+        use std::hash::{DefaultHasher, Hash, Hasher};
+
+        // By the name in lower case, so names that only differ in case
+        // are in the same shard and can be checked against each other
+        let mut hasher = DefaultHasher::new();
+        username.to_lowercase().hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+}
+
+#[cfg(feature = "sharded-state")]
+impl Default for ShardedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn new_state() -> SharedState {
+    Arc::default()
+}
+
+// Locks whatever part of the state holds `username`
+async fn lock_for<'a>(
+    state: &'a SharedState,
+    #[allow(unused_variables)] username: &str,
+) -> MutexGuard<'a, HashMap<String, UserData>> {
+    #[cfg(not(feature = "sharded-state"))]
+    return state.lock().await;
+
+    #[cfg(feature = "sharded-state")]
+    return state.shard(username).lock().await;
+}
+
+// Whether someone has the name in any case, so `Bob` and `bob` can't both
+// be connected
+fn taken(clients: &HashMap<String, UserData>, username: &str) -> bool {
+    let folded = username.to_lowercase();
+    clients.keys().any(|name| name.to_lowercase() == folded)
+}
+
+// Claims a name for a user, returning false if it's already taken in any
+// case. The check and the insert happen under a single lock, so when two
+// connections race for the same name exactly one of them gets it.
+pub async fn insert_user(state: &SharedState, username: &str, data: UserData) -> bool {
+    let mut clients = lock_for(state, username).await;
+    if taken(&clients, username) {
+        return false;
+    }
+
+    clients.insert(username.to_string(), data);
+    true
+}
+
+// What came of `insert_user_capped`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Inserted {
+    Yes,
+    Taken,
+    // Already `max_users` connected
+    Full,
+}
+
+// Same as `insert_user`, turning the user away if `max_users` are already
+// connected. The count is taken under the same lock as the insert, so racing
+// connections can't push it past the cap.
+pub async fn insert_user_capped(
+    state: &SharedState,
+    username: &str,
+    data: UserData,
+    max_users: usize,
+) -> Inserted {
+    #[cfg(not(feature = "sharded-state"))]
+    {
+        let mut clients = state.lock().await;
+        let connected = clients.len();
+        insert_capped_in(&mut clients, connected, username, data, max_users)
+    }
+
+    // Every shard is held to count, taken in index order like `rename_user`
+    #[cfg(feature = "sharded-state")]
+    {
+        let mut shards = Vec::with_capacity(state.shards.len());
+        for shard in state.shards.iter() {
+            shards.push(shard.lock().await);
+        }
+        let connected = shards.iter().map(|clients| clients.len()).sum();
+        let clients = &mut shards[state.shard_index(username)];
+        insert_capped_in(clients, connected, username, data, max_users)
+    }
+}
+
+// `insert_user_capped` within one map, already locked, with `connected`
+// users across the whole state
+fn insert_capped_in(
+    clients: &mut HashMap<String, UserData>,
+    connected: usize,
+    username: &str,
+    data: UserData,
+    max_users: usize,
+) -> Inserted {
+    if connected >= max_users {
+        return Inserted::Full;
+    }
+    if taken(clients, username) {
+        return Inserted::Taken;
+    }
+
+    clients.insert(username.to_string(), data);
+    Inserted::Yes
+}
+
+pub async fn remove_user(state: &SharedState, username: &str) -> Option<UserData> {
+    lock_for(state, username).await.remove(username)
+}
+
+pub async fn contains_user(state: &SharedState, username: &str) -> bool {
+    lock_for(state, username).await.contains_key(username)
+}
+
+pub async fn usernames(state: &SharedState) -> Vec<String> {
+    #[cfg(not(feature = "sharded-state"))]
+    return state.lock().await.keys().cloned().collect();
+
+    #[cfg(feature = "sharded-state")]
+    {
+        let mut names = Vec::new();
+        for shard in state.shards.iter() {
+            names.extend(shard.lock().await.keys().cloned());
+        }
+        names
+    }
+}
+
+// Moves a user's entry to a new name, returning false if the new name is
+// taken in any case or the old one isn't connected
+pub async fn rename_user(state: &SharedState, old: &str, new: &str) -> bool {
+    #[cfg(not(feature = "sharded-state"))]
+    return rename_in(&mut *state.lock().await, old, new);
+
+    // Both shards are held for the check and the move, taken in index order
+    // so two renames the other way round can't deadlock
+    #[cfg(feature = "sharded-state")]
+    {
+        let (from, to) = (state.shard_index(old), state.shard_index(new));
+        if from == to {
+            return rename_in(&mut *state.shards[from].lock().await, old, new);
+        }
+
+        let mut first = state.shards[from.min(to)].lock().await;
+        let mut second = state.shards[from.max(to)].lock().await;
+        let (old_clients, new_clients) = match from < to {
+            true => (&mut *first, &mut *second),
+            false => (&mut *second, &mut *first),
+        };
+        // A name that only differs in case would be in the new name's shard
+        if !old_clients.contains_key(old) || taken(new_clients, new) {
+            return false;
+        }
+        let Some(data) = old_clients.remove(old) else {
+            return false;
+        };
+        new_clients.insert(new.to_string(), data);
+        true
+    }
+}
+
+// `rename_user` within one map, already locked
+fn rename_in(clients: &mut HashMap<String, UserData>, old: &str, new: &str) -> bool {
+    let Some(data) = clients.remove(old) else {
+        return false;
+    };
+    // Checked without the old name, so a user can change its case. Nothing
+    // else can have taken the old name meanwhile, since the lock is held.
+    if taken(clients, new) {
+        clients.insert(old.to_string(), data);
+        return false;
+    }
+    clients.insert(new.to_string(), data);
+    true
+}
+
+// Runs `f` on every connected user
+pub async fn for_each_user(state: &SharedState, mut f: impl FnMut(&str, &mut UserData)) {
+    #[cfg(not(feature = "sharded-state"))]
+    for (name, data) in state.lock().await.iter_mut() {
+        f(name, data);
+    }
+
+    #[cfg(feature = "sharded-state")]
+    for shard in state.shards.iter() {
+        for (name, data) in shard.lock().await.iter_mut() {
+            f(name, data);
+        }
+    }
+}
+
+// Runs `f` on a user's data while holding its lock, or returns `None` if the
+// user isn't connected
+pub async fn with_user<R>(
+    state: &SharedState,
+    username: &str,
+    f: impl FnOnce(&mut UserData) -> R,
+) -> Option<R> {
+    lock_for(state, username).await.get_mut(username).map(f)
+}
@@ -0,0 +1,64 @@
+use futures::{future::BoxFuture, FutureExt};
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    sync::Arc,
+};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::{client, rustls::pki_types::ServerName, server, TlsAcceptor, TlsConnector};
+
+use crate::connection::Socket;
+
+pub use tokio_rustls::rustls;
+
+// TLS around the connection, so names and file contents aren't readable on
+// the wire. The handshake happens before the protocol starts, and the
+// streams the helpers return are used exactly like a TcpStream: with
+// `Transmission::from_stream`, `send_file` and `receive_file` on either side,
+// and `connection::run` on the server's. See examples/tls.rs for setting up
+// certificates.
+
+// Connects to a server and does the handshake, checking its certificate is
+// for `server_name`
+pub async fn connect_tls(
+    addr: impl ToSocketAddrs,
+    server_name: &str,
+    config: Arc<rustls::ClientConfig>,
+) -> Result<client::TlsStream<TcpStream>> {
+    let server_name = ServerName::try_from(server_name.to_string())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let stream = TcpStream::connect(addr).await?;
+    TlsConnector::from(config)
+        .connect(server_name, stream)
+        .await
+}
+
+// Does the server's half of the handshake on a newly accepted connection
+pub async fn accept_tls(
+    stream: TcpStream,
+    config: Arc<rustls::ServerConfig>,
+) -> Result<server::TlsStream<TcpStream>> {
+    TlsAcceptor::from(config).accept(stream).await
+}
+
+impl Socket for server::TlsStream<TcpStream> {
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+
+    fn readable(&mut self) -> BoxFuture<'_, Result<()>> {
+        async move {
+            let (tcp, session) = self.get_mut();
+            // Anything already decrypted, or the end of the session, can be
+            // read straight away. Otherwise wait for the next record.
+            match session.reader().into_first_chunk() {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    let mut first_byte = [0; 1];
+                    tcp.peek(&mut first_byte).await.map(drop)
+                }
+                _ => Ok(()),
+            }
+        }
+        .boxed()
+    }
+}
@@ -1,98 +1,2629 @@
-use log::{info, trace};
-use std::io::{Result, Write};
-use std::path::Path;
+use futures::{future::BoxFuture, FutureExt, Stream};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Error, ErrorKind, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 use tokio::fs::create_dir_all;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-
-use crate::data::CHUNK_SIZE;
-use crate::protocol::Transmission;
-
-pub async fn receive_file(stream: &mut TcpStream, save_path: &str) -> Result<()> {
-    // Read the first transmission from the stream
-    match Transmission::from_stream(stream).await? {
-        Transmission::Metadata(filename, file_size) => {
-            // Construct the full file path to save the file
-            let file_path = format!("{}/{}", save_path, filename);
-
-            // Ensure the parent directories exist
-            if let Some(parent_dir) = Path::new(&file_path).parent() {
-                create_dir_all(parent_dir).await?;
-            }
-
-            // Create the file to save the incoming data
-            let mut file = tokio::fs::File::create(file_path).await?;
-
-            let mut total_bytes_received = 0;
-            while total_bytes_received < file_size {
-                // Read the next chunk of file data from the stream
-                match Transmission::from_stream(stream).await? {
-                    Transmission::Chunk(chunk_filename, data) if chunk_filename == filename => {
-                        // Write the chunk data to the file
-                        file.write_all(&data).await?;
-                        total_bytes_received += data.len() as u32;
-
-                        // Print progress (optional)
-                        info!(
-                            "Progress: {}/{} bytes ({:.2}%)\r",
-                            total_bytes_received,
-                            file_size,
-                            total_bytes_received as f64 / file_size as f64 * 100.0
-                        );
-                        std::io::stdout().flush().unwrap();
-                    }
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+use crate::cache::SentFiles;
+use crate::compression::{ChunkCodec, Deflater, Inflater, MAX_CODEC_INPUT};
+#[cfg(feature = "e2e")]
+use crate::data::PublicKey;
+use crate::data::{
+    is_text, Compression, ExtensionPolicy, FileHash, LineEnding, Progress, ReceivedFile,
+    RetryPolicy, SentFile, SymlinkPolicy, TransferStats, CHUNK_SIZE, COMPRESSION_ATTRIBUTE,
+    DIGEST_ATTRIBUTE, PARTIAL_SUFFIX, RANGE_ATTRIBUTE, RESUME_ATTRIBUTE, SPARSE_ATTRIBUTE,
+    TEXT_ATTRIBUTE,
+};
+#[cfg(feature = "e2e")]
+use crate::e2e;
+use crate::error::GlideError;
+use crate::protocol::{ProtocolError, ProtocolVersion, Transmission};
+
+// The other side sent `Transmission::Abort` for this file. Surfaces as an
+// `io::Error` of kind `ConnectionAborted` wrapping this.
+#[derive(Debug)]
+pub struct Aborted {
+    pub filename: String,
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfer of '{}' was aborted by the peer", self.filename)
+    }
+}
+
+impl std::error::Error for Aborted {}
+
+fn aborted(filename: String) -> Error {
+    Error::new(ErrorKind::ConnectionAborted, Aborted { filename })
+}
+
+// The transfer was called off on purpose, by the other side sending
+// `Transmission::TransferCancelled` or by our own cancel flag. Surfaces as
+// an `io::Error` of kind `Interrupted` wrapping this.
+#[derive(Debug)]
+pub struct Cancelled {
+    pub filename: String,
+}
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Transfer of '{}' was cancelled", self.filename)
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+fn cancelled(filename: String) -> Error {
+    Error::new(ErrorKind::Interrupted, Cancelled { filename })
+}
+
+// A received file didn't match the digest its sender sent, and was deleted.
+// Surfaces as an `io::Error` of kind `InvalidData` wrapping this.
+#[derive(Debug)]
+pub struct DigestMismatch {
+    pub filename: String,
+}
+
+impl fmt::Display for DigestMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' doesn't match the sender's digest", self.filename)
+    }
+}
+
+impl std::error::Error for DigestMismatch {}
+
+type Result<T> = std::result::Result<T, GlideError>;
+
+// Tells the other side we've called off `filename`
+pub async fn cancel_transfer<S: AsyncWrite + Unpin>(stream: &mut S, filename: &str) -> Result<()> {
+    cancel_transfer_for(stream, filename, ProtocolVersion::V1).await
+}
+
+// Same as `cancel_transfer`, on a stream speaking `version`
+pub async fn cancel_transfer_for<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    filename: &str,
+    version: ProtocolVersion,
+) -> Result<()> {
+    stream
+        .write_all(
+            Transmission::TransferCancelled(filename.to_string())
+                .to_bytes_for(version)
+                .as_slice(),
+        )
+        .await?;
+    Ok(())
+}
+
+fn is_set(flag: &Option<Arc<AtomicBool>>) -> bool {
+    flag.as_ref()
+        .is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+// Tells the other side to stop sending `filename`
+pub async fn abort_transfer<S: AsyncWrite + Unpin>(stream: &mut S, filename: &str) -> Result<()> {
+    abort_transfer_for(stream, filename, ProtocolVersion::V1).await
+}
+
+// Same as `abort_transfer`, on a stream speaking `version`
+pub async fn abort_transfer_for<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    filename: &str,
+    version: ProtocolVersion,
+) -> Result<()> {
+    stream
+        .write_all(
+            Transmission::Abort(filename.to_string())
+                .to_bytes_for(version)
+                .as_slice(),
+        )
+        .await?;
+    Ok(())
+}
+
+// Decides whether a received file may be kept, e.g. by handing it to a
+// virus scanner
+pub type Scanner = dyn Fn(&Path) -> BoxFuture<'static, std::io::Result<ScanVerdict>> + Send + Sync;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScanVerdict {
+    Clean,
+    // With the scanner's reason
+    Rejected(String),
+}
+
+// Optional behaviour for `receive_file_with`
+#[derive(Default)]
+pub struct ReceiveOptions<'a> {
+    pub retry: RetryPolicy,
+    // Give up with `ErrorKind::TimedOut` if the metadata takes longer
+    pub metadata_timeout: Option<Duration>,
+    // Runs on the finished file, while it still has `PARTIAL_SUFFIX` on its
+    // name; a rejected file is deleted and the receive fails with
+    // `ErrorKind::PermissionDenied`
+    pub scan: Option<&'a Scanner>,
+    // Refuse files this doesn't permit, before anything is written
+    pub extensions: Option<&'a ExtensionPolicy>,
+    // Refuse files bigger than this with `ErrorKind::FileTooLarge`, before
+    // anything is written. Empty files are always taken.
+    pub max_file_size: Option<u32>,
+    // Refuse directories with more files than this, or more bytes all told,
+    // with `ErrorKind::FileTooLarge`. The file count is checked before
+    // anything is written, and the bytes before each file.
+    pub max_dir_files: Option<u32>,
+    pub max_dir_bytes: Option<u64>,
+    // Rewrite line endings in files the sender flagged as text. Anything
+    // else is written byte for byte.
+    pub line_endings: Option<LineEnding>,
+    // Told after each chunk how much of the file has arrived
+    pub progress: Option<Progress>,
+    // Checked between chunks. Once set, the sender is told and the partial
+    // file is deleted.
+    pub cancel: Option<Arc<AtomicBool>>,
+    // Expect a file sealed to these keys, and write it out decrypted
+    #[cfg(feature = "e2e")]
+    pub decrypt_with: Option<&'a e2e::KeyPair>,
+    // The wire format the stream speaks, as agreed with `negotiate_version`
+    pub version: ProtocolVersion,
+}
+
+// Receives a file into `save_path`, returning its metadata and the hash of
+// what was written. It's written to a `PARTIAL_SUFFIX` file alongside and
+// renamed into place once it's all there and checked.
+pub async fn receive_file<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    save_path: &str,
+) -> Result<ReceivedFile> {
+    receive_file_with(stream, save_path, &ReceiveOptions::default()).await
+}
+
+// Same as `receive_file`, but retries reading chunks on transient errors as
+// described by `policy`
+pub async fn receive_file_with_retry<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    save_path: &str,
+    policy: &RetryPolicy,
+) -> Result<ReceivedFile> {
+    let options = ReceiveOptions {
+        retry: policy.clone(),
+        ..ReceiveOptions::default()
+    };
+    receive_file_with(stream, save_path, &options).await
+}
+
+// Same as `receive_file`, but gives up with `ErrorKind::TimedOut` if the
+// metadata doesn't arrive within `timeout`
+pub async fn receive_file_with_timeout<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    save_path: &str,
+    timeout: Duration,
+) -> Result<ReceivedFile> {
+    let options = ReceiveOptions {
+        metadata_timeout: Some(timeout),
+        ..ReceiveOptions::default()
+    };
+    receive_file_with(stream, save_path, &options).await
+}
+
+// Same as `receive_file`, with every optional behaviour in `options`
+pub async fn receive_file_with<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    save_path: &str,
+    options: &ReceiveOptions<'_>,
+) -> Result<ReceivedFile> {
+    let first = read_first(stream, options).await?;
+    receive_file_from(stream, save_path, first, options).await
+}
+
+// Receives a directory sent with `send_dir` into `save_path`, recreating its
+// tree there, and returns each file named by its path under `save_path`. A
+// lone file is received as `receive_file` would, so this takes whatever a
+// glide turns out to be.
+pub async fn receive_dir<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    save_path: &str,
+) -> Result<Vec<ReceivedFile>> {
+    receive_dir_with(stream, save_path, &ReceiveOptions::default()).await
+}
+
+// Same as `receive_dir`, with `options` applying to each file in turn
+pub async fn receive_dir_with<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    save_path: &str,
+    options: &ReceiveOptions<'_>,
+) -> Result<Vec<ReceivedFile>> {
+    let (name, count) = match read_first(stream, options).await? {
+        Transmission::Directory { name, files } => (name, files),
+        first => {
+            return Ok(vec![
+                receive_file_from(stream, save_path, first, options).await?,
+            ])
+        }
+    };
+    if local_filename(&name).as_deref() != Some(name.as_str()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' isn't a safe directory name", name),
+        )
+        .into());
+    }
+    if let Some(max) = options.max_dir_files.filter(|&max| count > max) {
+        let _ = abort_transfer_for(stream, &name, options.version).await;
+        return Err(Error::new(
+            ErrorKind::FileTooLarge,
+            format!("'{}' has {} files, over the limit of {}", name, count, max),
+        )
+        .into());
+    }
+
+    let mut received = Vec::new();
+    let mut total: u64 = 0;
+    for _ in 0..count {
+        let first = read_with_retry(stream, options).await?;
+        // Every file has to land somewhere inside the directory
+        let dir = match &first {
+            Transmission::Metadata(filename, size, _) => {
+                total += *size as u64;
+                if let Some(max) = options.max_dir_bytes.filter(|&max| total > max) {
+                    let _ = abort_transfer_for(stream, filename, options.version).await;
+                    return Err(Error::new(
+                        ErrorKind::FileTooLarge,
+                        format!("'{}' is over the limit of {} bytes", name, max),
+                    )
+                    .into());
+                }
+                let path = local_path(filename);
+                match path.as_deref().and_then(|path| path.rsplit_once('/')) {
+                    Some((dir, _)) if Path::new(dir).starts_with(&name) => dir.to_string(),
                     _ => {
-                        return Err(std::io::Error::new(
-                            std::io::ErrorKind::InvalidData,
-                            "Unexpected transmission type or mismatched file name",
+                        let _ = abort_transfer_for(stream, filename, options.version).await;
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("'{}' isn't a safe path in '{}'", filename, name),
                         )
                         .into());
                     }
                 }
             }
+            // Not a file at all, which `receive_file_from` reports
+            _ => name.clone(),
+        };
+
+        let save_dir = format!("{}/{}", save_path, dir);
+        let mut file = receive_file_from(stream, &save_dir, first, options).await?;
+        file.filename = format!("{}/{}", dir, file.filename);
+        received.push(file);
+    }
+
+    Ok(received)
+}
+
+// Reads the transmission a transfer starts with, giving up as
+// `metadata_timeout` says
+async fn read_first<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    options: &ReceiveOptions<'_>,
+) -> Result<Transmission> {
+    let read = Transmission::from_stream_for(stream, options.version);
+    match options.metadata_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read)
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Timed out waiting for file metadata"))?,
+        None => read.await,
+    }
+}
+
+fn is_transient(e: &GlideError) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::WouldBlock
+    )
+}
+
+// Reads the next transmission, retrying transient failures with backoff.
+// Only the wait for a transmission to start is retried: once any of it has
+// been read, starting again would parse from the middle of it, so running
+// out of time part way through fails the transfer instead.
+async fn read_with_retry<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    options: &ReceiveOptions<'_>,
+) -> Result<Transmission> {
+    let policy = &options.retry;
+    let mut attempt = 0;
+    let first_byte = loop {
+        // A single byte is either read or not, so it's safe to time out
+        let read = stream.read_u8();
+        let result = match policy.read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read)
+                .await
+                .unwrap_or_else(|_| {
+                    Err(Error::new(ErrorKind::TimedOut, "Timed out reading chunk"))
+                }),
+            None => read.await,
+        };
+
+        match result.map_err(|e| GlideError::from(ProtocolError::from(e))) {
+            Ok(first_byte) => break first_byte,
+            Err(e) if is_transient(&e) && attempt < policy.max_retries => {
+                info!(
+                    "Transient error reading chunk ({}), retry {}/{}",
+                    e,
+                    attempt + 1,
+                    policy.max_retries
+                );
+                tokio::time::sleep(policy.backoff * 2u32.saturating_pow(attempt)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    let first_byte = [first_byte];
+    let mut rest = (&first_byte[..]).chain(&mut *stream);
+    let read = Transmission::from_stream_for(&mut rest, options.version);
+    match policy.read_timeout {
+        Some(timeout) => tokio::time::timeout(timeout, read)
+            .await
+            .map_err(|_| Error::new(ErrorKind::TimedOut, "Timed out part way through a chunk"))?,
+        None => read.await,
+    }
+}
+
+// The name a file sent as `filename` is saved under: its last component, so
+// any directories the sender put in front are dropped. None if it's absolute,
+// climbs with "..", or has a null byte or nothing left to name the file by.
+pub fn local_filename(filename: &str) -> Option<String> {
+    let drive = filename.as_bytes().get(1) == Some(&b':');
+    if filename.contains('\0') || filename.starts_with(['/', '\\']) || drive {
+        return None;
+    }
+
+    let mut components = filename.split(['/', '\\']);
+    if components.clone().any(|component| component == "..") {
+        return None;
+    }
+
+    match components.next_back() {
+        Some("" | ".") | None => None,
+        Some(name) => Some(name.to_string()),
+    }
+}
+
+// Where a file sent as `filename` as part of a directory is saved, keeping
+// the directories in front of its name. None for the same names
+// `local_filename` refuses.
+pub fn local_path(filename: &str) -> Option<String> {
+    local_filename(filename)?;
+
+    let components: Vec<&str> = filename
+        .split(['/', '\\'])
+        .filter(|component| !component.is_empty() && *component != ".")
+        .collect();
+    Some(components.join("/"))
+}
+
+// The directory typed after `ok @user into`, ready to pass to
+// `receive_file` as `save_path`. A leading ~ is the home directory and
+// anything else relative is from the working directory. It has to end up in
+// one of `roots` once links are followed, and is created if it isn't there.
+pub async fn save_dir(into: &str, roots: &[PathBuf]) -> Result<String> {
+    let expanded = match into.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            let home = std::env::var_os("HOME")
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "No home directory to expand ~"))?;
+            PathBuf::from(home).join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(into),
+    };
+    let dir = std::path::absolute(&expanded)?;
+    if dir
+        .components()
+        .any(|c| c == std::path::Component::ParentDir)
+    {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("'{}' can't climb with ..", into),
+        )
+        .into());
+    }
+
+    // Whatever doesn't exist yet is created under the deepest part that
+    // does, so only that part can lead anywhere else
+    let mut existing = dir.as_path();
+    while !tokio::fs::try_exists(existing).await? {
+        existing = existing.parent().unwrap_or(Path::new("/"));
+    }
+    if !tokio::fs::metadata(existing).await?.is_dir() {
+        return Err(Error::new(
+            ErrorKind::NotADirectory,
+            format!("'{}' isn't a directory", into),
+        )
+        .into());
+    }
+    let mut resolved = tokio::fs::canonicalize(existing).await?;
+    if let Ok(missing) = dir.strip_prefix(existing) {
+        if !missing.as_os_str().is_empty() {
+            resolved.push(missing);
+        }
+    }
+    let mut permitted = false;
+    for root in roots {
+        if let Ok(root) = tokio::fs::canonicalize(root).await {
+            permitted |= resolved.starts_with(root);
+        }
+    }
+    if !permitted {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("'{}' is outside where files can be saved", into),
+        )
+        .into());
+    }
+
+    create_dir_all(&resolved).await?;
+    resolved.into_os_string().into_string().map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' isn't a UTF-8 path", into),
+        )
+        .into()
+    })
+}
+
+async fn receive_file_from<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    save_path: &str,
+    first: Transmission,
+    options: &ReceiveOptions<'_>,
+) -> Result<ReceivedFile> {
+    let started = Instant::now();
+    let mut incoming = check_metadata(stream, first, options).await?;
+    let filename = incoming.filename.clone();
+    let range_start = incoming.range_start;
+
+    // Construct the full file path to save the file
+    let file_path = format!("{}/{}", save_path, incoming.local_name);
+    // Where it's written until it's finished, so nothing sees half a file.
+    // A range is a window into the file that's there, so it goes straight in.
+    let part_path = match range_start {
+        None => partial_path(&file_path),
+        Some(_) => file_path.clone(),
+    };
+
+    // Ensure the parent directories exist and create the file to save
+    // the incoming data, stopping the sender if we can't
+    let created = async {
+        if let Some(parent_dir) = Path::new(&file_path).parent() {
+            create_dir_all(parent_dir).await?;
+            // Only what isn't here yet takes up more room
+            let needed = match (incoming.sparse_len, incoming.resume_from) {
+                (Some(len), _) => len,
+                (None, Some(offset)) => (incoming.file_size - offset) as u64,
+                (None, None) => incoming.file_size as u64,
+            };
+            if let Some(available) = available_space(parent_dir)? {
+                if needed > available {
+                    return Err(Error::new(
+                        ErrorKind::StorageFull,
+                        format!(
+                            "'{}' needs {} bytes, but only {} are free",
+                            filename, needed, available
+                        ),
+                    ));
+                }
+            }
+        }
+        match (range_start, incoming.resume_from) {
+            (None, None) => tokio::fs::File::create(&part_path).await,
+            _ => {
+                tokio::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&part_path)
+                    .await
+            }
+        }
+    };
+    let mut file = match created.await {
+        Ok(file) => file,
+        Err(e) => {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(e.into());
+        }
+    };
+
+    // Pick up after what's already here, which the digest covers too.
+    // Anything past the offset is from a different try, so it goes.
+    let mut hasher = Sha256::new();
+    if let Some(offset) = incoming.resume_from {
+        #[cfg(feature = "e2e")]
+        let decrypting = options.decrypt_with.is_some();
+        #[cfg(not(feature = "e2e"))]
+        let decrypting = false;
+        let resumed = async {
+            if decrypting {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("A resumed '{}' can't be encrypted", filename),
+                ));
+            }
+            hash_prefix(&mut file, offset as u64, &mut hasher).await?;
+            file.set_len(offset as u64).await
+        };
+        if let Err(e) = resumed.await {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(e.into());
+        }
+    }
+
+    let body = match receive_body(stream, &mut file, &mut incoming, hasher, options).await {
+        Ok(body) => body,
+        Err(Failed { error, discard }) => {
+            if discard {
+                drop(file);
+                remove_partial(&part_path, range_start).await;
+            }
+            return Err(error);
+        }
+    };
+
+    // Recreate the hole at the end, if any, which no chunk covers
+    if incoming.sparse_len.is_some() {
+        file.set_len(incoming.file_size as u64).await?;
+    }
+
+    if let Some(scan) = options.scan {
+        if let ScanVerdict::Rejected(reason) = scan(Path::new(&part_path)).await? {
+            drop(file);
+            tokio::fs::remove_file(&part_path).await?;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("'{}' was rejected by the scanner: {}", filename, reason),
+            )
+            .into());
+        }
+    }
+
+    drop(file);
+    if part_path != file_path {
+        if let Err(e) = tokio::fs::rename(&part_path, &file_path).await {
+            let _ = tokio::fs::remove_file(&part_path).await;
+            return Err(e.into());
+        }
+    }
+
+    info!("File transfer completed: {}", filename);
+    Ok(ReceivedFile {
+        filename: incoming.local_name,
+        size: body.size,
+        hash: body.hash,
+        attributes: incoming.attributes,
+        stats: TransferStats {
+            duration: started.elapsed(),
+            ..body.stats
+        },
+    })
+}
+
+// Receives a file and writes it into `sink` rather than to disk, for data
+// that's passed on or kept in memory. Returns its metadata and the hash of
+// what was written.
+pub async fn receive_stream<S, W>(stream: &mut S, sink: &mut W) -> Result<ReceivedFile>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    receive_stream_with(stream, sink, &ReceiveOptions::default()).await
+}
+
+// Same as `receive_stream`, with the options that make sense for a writer.
+// Ranges, resumed and sparse files are written at their offsets, so they're
+// refused, and there's no file for `scan` to look at.
+pub async fn receive_stream_with<S, W>(
+    stream: &mut S,
+    sink: &mut W,
+    options: &ReceiveOptions<'_>,
+) -> Result<ReceivedFile>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let started = Instant::now();
+    let first = read_first(stream, options).await?;
+    let mut incoming = check_metadata(stream, first, options).await?;
+    if incoming.range_start.is_some()
+        || incoming.resume_from.is_some()
+        || incoming.sparse_len.is_some()
+    {
+        let _ = abort_transfer_for(stream, &incoming.filename, options.version).await;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "'{}' is a range, resumed or sparse, which needs a file to go in",
+                incoming.filename
+            ),
+        )
+        .into());
+    }
+
+    let mut sink = Sequential(sink);
+    let body = receive_body(stream, &mut sink, &mut incoming, Sha256::new(), options)
+        .await
+        .map_err(|failed| failed.error)?;
+
+    info!("File transfer completed: {}", incoming.filename);
+    Ok(ReceivedFile {
+        filename: incoming.local_name,
+        size: body.size,
+        hash: body.hash,
+        attributes: incoming.attributes,
+        stats: TransferStats {
+            duration: started.elapsed(),
+            ..body.stats
+        },
+    })
+}
+
+// A transfer's metadata, once it's been checked over
+struct Incoming {
+    filename: String,
+    // What it's saved as
+    local_name: String,
+    file_size: u32,
+    attributes: HashMap<String, String>,
+    range_start: Option<u64>,
+    resume_from: Option<u32>,
+    inflater: Option<Inflater>,
+    codec: Option<ChunkCodec>,
+    sparse_len: Option<u64>,
+}
 
-            info!("\nFile transfer completed: {}\r", filename);
-            Ok(())
+// Checks the metadata a transfer starts with, stopping the sender if it's
+// something we won't take
+async fn check_metadata<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    first: Transmission,
+    options: &ReceiveOptions<'_>,
+) -> Result<Incoming> {
+    let Transmission::Metadata(filename, file_size, attributes) = first else {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Unexpected transmission type, expected Metadata, recieved {:?}",
+                first.redacted()
+            ),
+        )
+        .into());
+    };
+
+    // The name comes off the wire, so it mustn't lead out of
+    // `save_path`
+    let Some(local_name) = local_filename(&filename) else {
+        let _ = abort_transfer_for(stream, &filename, options.version).await;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("'{}' isn't a safe filename", filename),
+        )
+        .into());
+    };
+
+    if let Some(extensions) = options.extensions {
+        if !extensions.permits(&local_name) {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("'{}' isn't an accepted kind of file", filename),
+            )
+            .into());
+        }
+    }
+
+    if let Some(max) = options.max_file_size {
+        if file_size > max {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(Error::new(
+                ErrorKind::FileTooLarge,
+                format!(
+                    "'{}' is {} bytes, over the limit of {}",
+                    filename, file_size, max
+                ),
+            )
+            .into());
+        }
+    }
+
+    // A window of the file is written into whatever is already there
+    let range_start = match attributes.get(RANGE_ATTRIBUTE).map(|start| start.parse()) {
+        None => None,
+        Some(Ok(start)) => Some(start),
+        Some(Err(_)) => {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad range start for '{}'", filename),
+            )
+            .into());
+        }
+    };
+    // The start of the file is already here from an earlier try
+    let resume_from = match attributes
+        .get(RESUME_ATTRIBUTE)
+        .map(|offset| offset.parse::<u32>())
+    {
+        None => None,
+        Some(Ok(offset)) if offset <= file_size => Some(offset),
+        Some(_) => {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad resume offset for '{}'", filename),
+            )
+            .into());
+        }
+    };
+
+    let (inflater, codec) = match attributes.get(COMPRESSION_ATTRIBUTE).map(String::as_str) {
+        None => (None, None),
+        Some("deflate") => (Some(Inflater::new()), None),
+        Some(name) if ChunkCodec::from_name(name).is_some() => (None, ChunkCodec::from_name(name)),
+        Some(other) => {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown compression '{}'", other),
+            )
+            .into());
+        }
+    };
+    let sparse_len = match attributes
+        .get(SPARSE_ATTRIBUTE)
+        .map(|len| len.parse::<u64>())
+    {
+        None => None,
+        // There's never more data than file
+        Some(Ok(len)) if len <= file_size as u64 => Some(len),
+        Some(_) => {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Bad sparse data length for '{}'", filename),
+            )
+            .into());
         }
-        data => Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
+    };
+    match attributes.get(DIGEST_ATTRIBUTE).map(String::as_str) {
+        None | Some("sha256") => {}
+        Some(other) => {
+            let _ = abort_transfer_for(stream, &filename, options.version).await;
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown digest '{}'", other),
+            )
+            .into());
+        }
+    }
+    let compressed = inflater.is_some() || codec.is_some();
+    if range_start.is_some() && (compressed || sparse_len.is_some()) {
+        let _ = abort_transfer_for(stream, &filename, options.version).await;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("A range of '{}' can't be compressed or sparse", filename),
+        )
+        .into());
+    }
+    if resume_from.is_some() && (compressed || sparse_len.is_some() || range_start.is_some()) {
+        let _ = abort_transfer_for(stream, &filename, options.version).await;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
             format!(
-                "Unexpected transmission type, expected Metadata, recieved {:#?}",
-                data
+                "A resumed '{}' can't be compressed, sparse or a range",
+                filename
             ),
         )
-        .into()),
+        .into());
     }
+    Ok(Incoming {
+        filename,
+        local_name,
+        file_size,
+        attributes,
+        range_start,
+        resume_from,
+        inflater,
+        codec,
+        sparse_len,
+    })
 }
 
-pub async fn send_file(stream: &mut TcpStream, path: &str) -> Result<()> {
-    // Get file metadata
-    let metadata = tokio::fs::metadata(path).await?;
-    let file_size = metadata.len() as u32;
-    let file_name = Path::new(path)
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .to_string();
+// What `receive_body` got
+struct Body {
+    // After decrypting
+    size: u32,
+    hash: FileHash,
+    stats: TransferStats,
+}
 
-    // Send metadata as a `Transmission::Metadata` variant
-    let metadata_msg = Transmission::Metadata(file_name.clone(), file_size).to_bytes();
-    stream.write_all(metadata_msg.as_slice()).await?;
+// How `receive_body` went wrong, and whether what was written should go
+struct Failed {
+    error: GlideError,
+    discard: bool,
+}
 
-    // Open the file and send its content in chunks
-    let mut file = tokio::fs::File::open(path).await?;
-    let mut buffer = vec![0; CHUNK_SIZE]; // Chunk size
-    while let Ok(bytes_read) = file.read(&mut buffer).await {
-        if bytes_read == 0 {
-            break; // End of file
+impl From<GlideError> for Failed {
+    fn from(error: GlideError) -> Self {
+        Failed {
+            error,
+            discard: false,
         }
+    }
+}
 
-        // Send each chunk as a `Transmission::Chunk` variant
-        let chunk_data = buffer[..bytes_read].to_vec();
-        let chunk_msg = Transmission::Chunk(file_name.clone(), chunk_data).to_bytes();
-        stream.write_all(chunk_msg.as_slice()).await?;
+impl From<Error> for Failed {
+    fn from(error: Error) -> Self {
+        GlideError::from(error).into()
     }
+}
 
-    println!("File sent successfully: {}\r", file_name);
-    Ok(())
+// Receives the chunks after a transfer's metadata into `sink`, and checks
+// them against the digest. `hasher` has already seen anything a resumed
+// transfer skips.
+async fn receive_body<S, W>(
+    stream: &mut S,
+    sink: &mut W,
+    incoming: &mut Incoming,
+    mut hasher: Sha256,
+    options: &ReceiveOptions<'_>,
+) -> std::result::Result<Body, Failed>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    W: AsyncWrite + AsyncSeek + Unpin,
+{
+    let filename = &incoming.filename;
+    let file_size = incoming.file_size;
+    let (range_start, resume_from, sparse_len) = (
+        incoming.range_start,
+        incoming.resume_from,
+        incoming.sparse_len,
+    );
+    let codec = incoming.codec;
+    let inflater = &mut incoming.inflater;
+
+    // Where the next byte of a sparse file or range goes
+    let mut position = range_start.unwrap_or(0);
+    // Rewriting a range would shift the rest of the file, and a resumed
+    // file's start was written before
+    let mut normalizer = options
+        .line_endings
+        .filter(|_| is_text(&incoming.attributes) && range_start.is_none() && resume_from.is_none())
+        .map(Normalizer::new);
+    #[cfg(feature = "e2e")]
+    let mut opener = options.decrypt_with.map(e2e::Opener::new);
+    #[cfg(feature = "e2e")]
+    let decrypting = opener.is_some();
+    #[cfg(not(feature = "e2e"))]
+    let decrypting = false;
+    // The sender's digest is of the data as it came over the wire, before
+    // it's decrypted or has its line endings rewritten
+    let mut as_sent = (normalizer.is_some() || decrypting).then(Sha256::new);
+    #[cfg(feature = "e2e")]
+    let mut plaintext_size = 0;
+
+    // A compressed stream says itself where it ends, and may carry on a
+    // little after the last byte of the file comes out
+    let mut total_bytes_received = resume_from.unwrap_or(0) as u64;
+    let mut chunks = 0;
+    while match &inflater {
+        Some(inflater) => !inflater.is_done(),
+        None => total_bytes_received < sparse_len.unwrap_or(file_size as u64),
+    } {
+        if is_set(&options.cancel) {
+            let _ = cancel_transfer_for(stream, filename, options.version).await;
+            return Err(Failed {
+                error: cancelled(filename.clone()).into(),
+                discard: true,
+            });
+        }
+
+        // Read the next chunk of file data from the stream
+        match read_with_retry(stream, options).await? {
+            Transmission::Chunk(chunk_filename, data)
+                if chunk_filename == *filename && sparse_len.is_none() && range_start.is_none() =>
+            {
+                chunks += 1;
+                let rest = (file_size as u64).saturating_sub(total_bytes_received) as usize;
+                let data = match inflater.as_mut().map(|inflater| inflater.push(&data, rest)) {
+                    Some(Ok(inflated)) => inflated,
+                    Some(Err(e)) => {
+                        let _ = abort_transfer_for(stream, filename, options.version).await;
+                        return Err(e.into());
+                    }
+                    None => data,
+                };
+                let data = match codec.map(|codec| codec.decompress(&data, rest)) {
+                    Some(Ok(decompressed)) => decompressed,
+                    Some(Err(e)) => {
+                        let _ = abort_transfer_for(stream, filename, options.version).await;
+                        return Err(e.into());
+                    }
+                    None => data,
+                };
+                total_bytes_received += data.len() as u64;
+                if let Some(as_sent) = as_sent.as_mut() {
+                    as_sent.update(&data);
+                }
+
+                #[cfg(feature = "e2e")]
+                let data = match opener.as_mut().map(|opener| opener.push(&data)) {
+                    Some(Ok(plaintext)) => plaintext,
+                    Some(Err(e)) => {
+                        let _ = abort_transfer_for(stream, filename, options.version).await;
+                        return Err(e.into());
+                    }
+                    None => data,
+                };
+                #[cfg(feature = "e2e")]
+                {
+                    plaintext_size += data.len() as u32;
+                }
+
+                let data = match normalizer.as_mut() {
+                    Some(normalizer) => normalizer.push(&data),
+                    None => data,
+                };
+
+                // Write the chunk data out, and stop the sender if we can't
+                // (e.g. the disk is full)
+                if let Err(e) = sink.write_all(&data).await {
+                    let _ = abort_transfer_for(stream, filename, options.version).await;
+                    return Err(e.into());
+                }
+                hasher.update(&data);
+
+                if let Some(progress) = &options.progress {
+                    progress.report(total_bytes_received, file_size as u64);
+                }
+            }
+            Transmission::ChunkAt(chunk_filename, offset, data)
+                if chunk_filename == *filename
+                    && (sparse_len.is_some() || range_start.is_some()) =>
+            {
+                chunks += 1;
+                // Segments come in order, and whatever they skip over is a
+                // hole. A range has no holes.
+                let end = offset + data.len() as u64;
+                if offset < position
+                    || end > range_start.unwrap_or(0) + file_size as u64
+                    || (range_start.is_some() && offset != position)
+                {
+                    let _ = abort_transfer_for(stream, filename, options.version).await;
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Chunk at {} of '{}' is out of place", offset, filename),
+                    )
+                    .into());
+                }
+
+                let written = async {
+                    sink.seek(SeekFrom::Start(offset)).await?;
+                    sink.write_all(&data).await
+                };
+                if let Err(e) = written.await {
+                    let _ = abort_transfer_for(stream, filename, options.version).await;
+                    return Err(e.into());
+                }
+                hash_zeros(&mut hasher, offset - position);
+                hasher.update(&data);
+                if let Some(as_sent) = as_sent.as_mut() {
+                    hash_zeros(as_sent, offset - position);
+                    as_sent.update(&data);
+                }
+                position = end;
+                total_bytes_received += data.len() as u64;
+
+                // Holes don't count, since they're never sent
+                if let Some(progress) = &options.progress {
+                    progress.report(total_bytes_received, sparse_len.unwrap_or(file_size as u64));
+                }
+            }
+            Transmission::Abort(aborted_filename) if aborted_filename == *filename => {
+                return Err(aborted(filename.clone()).into());
+            }
+            Transmission::TransferCancelled(cancelled_filename)
+                if cancelled_filename == *filename =>
+            {
+                return Err(Failed {
+                    error: cancelled(filename.clone()).into(),
+                    discard: true,
+                });
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unexpected transmission type or mismatched file name",
+                )
+                .into());
+            }
+        }
+    }
+
+    // The hole at the end, if any, which no chunk covers
+    if sparse_len.is_some() {
+        hash_zeros(&mut hasher, file_size as u64 - position);
+        if let Some(as_sent) = as_sent.as_mut() {
+            hash_zeros(as_sent, file_size as u64 - position);
+        }
+    }
+
+    if inflater.is_some() && total_bytes_received != file_size as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "'{}' decompressed to {} bytes, expected {}",
+                filename, total_bytes_received, file_size
+            ),
+        )
+        .into());
+    }
+
+    #[cfg(feature = "e2e")]
+    let file_size = match opener {
+        Some(opener) => {
+            let last = opener.finish()?;
+            let size = plaintext_size + last.len() as u32;
+            let last = match normalizer.as_mut() {
+                Some(normalizer) => normalizer.push(&last),
+                None => last,
+            };
+            sink.write_all(&last).await?;
+            hasher.update(&last);
+            size
+        }
+        None => file_size,
+    };
+
+    if let Some(normalizer) = normalizer {
+        let rest = normalizer.finish();
+        sink.write_all(&rest).await?;
+        hasher.update(&rest);
+    }
+
+    // Make sure everything is written out before anyone looks at it
+    sink.flush().await?;
+    let hash: FileHash = hasher.finalize().into();
+
+    if incoming.attributes.contains_key(DIGEST_ATTRIBUTE) {
+        let expected = match read_with_retry(stream, options).await? {
+            Transmission::Digest(digest_filename, digest) if digest_filename == *filename => {
+                Some(digest)
+            }
+            Transmission::Abort(aborted_filename) if aborted_filename == *filename => None,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unexpected transmission type or mismatched file name",
+                )
+                .into());
+            }
+        };
+        let actual = match as_sent {
+            Some(as_sent) => as_sent.finalize().into(),
+            None => hash,
+        };
+
+        if expected != Some(actual) {
+            let filename = filename.clone();
+            return Err(Failed {
+                error: match expected {
+                    Some(_) => Error::new(ErrorKind::InvalidData, DigestMismatch { filename }),
+                    None => aborted(filename),
+                }
+                .into(),
+                discard: true,
+            });
+        }
+    }
+
+    Ok(Body {
+        size: file_size,
+        hash,
+        stats: TransferStats {
+            bytes: total_bytes_received - resume_from.unwrap_or(0) as u64,
+            chunks,
+            ..TransferStats::default()
+        },
+    })
+}
+
+// A writer that can only go front to back, for `receive_body`, which only
+// seeks for ranges and sparse files
+struct Sequential<'a, W>(&'a mut W);
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Sequential<'_, W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut *self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut *self.0).poll_shutdown(cx)
+    }
+}
+
+impl<W> AsyncSeek for Sequential<'_, W> {
+    fn start_seek(self: Pin<&mut Self>, _: SeekFrom) -> std::io::Result<()> {
+        Err(Error::new(ErrorKind::Unsupported, "Can't seek in a stream"))
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Err(Error::new(
+            ErrorKind::Unsupported,
+            "Can't seek in a stream",
+        )))
+    }
+}
+
+// Where a file going to `file_path` is written until it's all there
+pub fn partial_path(file_path: &str) -> String {
+    format!("{}{}", file_path, PARTIAL_SUFFIX)
+}
+
+// Deletes what was received of a file that won't be finished. A range went
+// into a file that was there before, so that's left alone.
+async fn remove_partial(file_path: &str, range_start: Option<u64>) {
+    if range_start.is_none() {
+        let _ = tokio::fs::remove_file(file_path).await;
+    }
+}
+
+// Sends the file at `path`, returning the hash of what was sent
+pub async fn send_file<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &str,
+) -> Result<FileHash> {
+    send_file_with_attributes(stream, path, &HashMap::new()).await
+}
+
+// Optional behaviour for `send_file_with`
+#[derive(Clone, Debug, Default)]
+pub struct SendOptions {
+    // Extra key/value pairs carried in the metadata
+    pub attributes: HashMap<String, String>,
+    // Send the file under this name rather than its own, e.g. the name
+    // given with `glide ... as`
+    pub name: Option<String>,
+    pub symlinks: SymlinkPolicy,
+    // Send at most this many bytes from the start of the file
+    pub limit: Option<u32>,
+    // Send only this window of the file, as chunks at their offsets. Cut
+    // down to fit the file, and further by `limit` if that's set too.
+    pub range: Option<Range<u64>>,
+    // Whether to flag the file as text in the metadata. Left unset, it's
+    // guessed from the start of the file.
+    pub text: Option<bool>,
+    pub compression: Compression,
+    // Send only the parts of the file holding data, so the receiver can
+    // leave the holes as holes
+    pub sparse: bool,
+    // Most bytes of the file per chunk, `CHUNK_SIZE` if unset. Bigger
+    // chunks suit slow links, smaller ones small devices. Encrypted files
+    // always go in records of `e2e::RECORD_SIZE`.
+    pub chunk_size: Option<usize>,
+    // Told after each chunk how much of the file has been sent
+    pub progress: Option<Progress>,
+    // Skip this many bytes from the start of the file, which the receiver
+    // already has from a transfer that was cut off
+    pub resume_from: Option<u32>,
+    // Checked between chunks. Once set, the receiver is told and the send
+    // stops.
+    pub cancel: Option<Arc<AtomicBool>>,
+    // Keep the file's data to this many bytes a second on average, by
+    // waiting between chunks
+    pub max_bytes_per_sec: Option<u64>,
+    // Seal the contents to this key so only its owner can read them
+    #[cfg(feature = "e2e")]
+    pub encrypt_to: Option<PublicKey>,
+    // The wire format the stream speaks, as agreed with `negotiate_version`
+    pub version: ProtocolVersion,
+    // Size and hash of files sent before, so one that hasn't changed since
+    // isn't hashed again. Pass the same one, or a clone, to every send.
+    pub sent_files: Option<SentFiles>,
+}
+
+// Same as `send_file`, with extra key/value pairs carried in the metadata
+pub async fn send_file_with_attributes<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &str,
+    attributes: &HashMap<String, String>,
+) -> Result<FileHash> {
+    let options = SendOptions {
+        attributes: attributes.clone(),
+        ..SendOptions::default()
+    };
+    send_file_with(stream, path, &options).await
+}
+
+// Checks `path` can be sent as it would be with `options`, so a client can
+// say what's wrong before asking the server to glide it rather than once the
+// request is already waiting
+pub async fn check_sendable(path: &str, options: &SendOptions) -> Result<()> {
+    open_for_send(path, &options.symlinks).await.map(drop)
+}
+
+// Opens `path` for sending, refusing symlinks the policy doesn't allow and
+// anything that isn't a file
+async fn open_for_send(path: &str, policy: &SymlinkPolicy) -> Result<tokio::fs::File> {
+    let is_link = tokio::fs::symlink_metadata(path)
+        .await
+        .map_err(|e| unreadable(path, e))?
+        .file_type()
+        .is_symlink();
+
+    if is_link {
+        match policy {
+            SymlinkPolicy::Follow => {}
+            SymlinkPolicy::Reject => {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("'{}' is a symlink", path),
+                )
+                .into());
+            }
+            SymlinkPolicy::Within(root) => {
+                let target = tokio::fs::canonicalize(path).await?;
+                let root = tokio::fs::canonicalize(root).await?;
+                if !target.starts_with(&root) {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!("'{}' links outside of '{}'", path, root.display()),
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+
+    let file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| unreadable(path, e))?;
+    if file.metadata().await?.is_dir() {
+        return Err(Error::new(
+            ErrorKind::IsADirectory,
+            format!("'{}' is a directory", path),
+        )
+        .into());
+    }
+
+    Ok(file)
+}
+
+// Puts the path in the errors people most often hit, which say nothing
+// about what they were trying to open by themselves
+fn unreadable(path: &str, e: Error) -> Error {
+    match e.kind() {
+        ErrorKind::NotFound => Error::new(ErrorKind::NotFound, format!("No such file '{}'", path)),
+        ErrorKind::PermissionDenied => Error::new(
+            ErrorKind::PermissionDenied,
+            format!("Permission denied reading '{}'", path),
+        ),
+        _ => e,
+    }
+}
+
+// Same as `send_file`, with every optional behaviour in `options`
+pub async fn send_file_with<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &str,
+    options: &SendOptions,
+) -> Result<FileHash> {
+    send_file_stats(stream, path, options)
+        .await
+        .map(|sent| sent.hash)
+}
+
+// Same as `send_file_with`, also saying how the transfer went
+pub async fn send_file_stats<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &str,
+    options: &SendOptions,
+) -> Result<SentFile> {
+    checked_chunk_size(options)?;
+
+    // Open the file first and take its metadata from the handle, so both
+    // are about the same file even if the path is swapped underneath us
+    let mut file = open_for_send(path, &options.symlinks).await?;
+    let metadata = file.metadata().await?;
+    // With a limit only the start of the file is sent, as its own
+    // complete (shorter) transfer
+    let start = options
+        .range
+        .as_ref()
+        .map_or(0, |range| range.start.min(metadata.len()));
+    let end = options.range.as_ref().map_or(metadata.len(), |range| {
+        range.end.clamp(start, metadata.len())
+    });
+    let file_name = match &options.name {
+        Some(name) => name.clone(),
+        None => file_name_of(path)?,
+    };
+    let file_size = match options.limit {
+        Some(limit) => (end - start).min(limit as u64) as u32,
+        None => wire_size(&file_name, end - start)?,
+    };
+    let whole = file_size as u64 == metadata.len();
+
+    let is_text = match options.text {
+        Some(is_text) => is_text,
+        None => looks_like_text(&mut file).await?,
+    };
+    let mut attributes = options.attributes.clone();
+    attributes.insert(TEXT_ATTRIBUTE.to_string(), is_text.to_string());
+
+    let compressed = options.compression != Compression::None;
+    #[cfg(feature = "e2e")]
+    let sealed = options.encrypt_to.is_some();
+    #[cfg(not(feature = "e2e"))]
+    let sealed = false;
+
+    let segments = if options.sparse {
+        Some(data_segments(&file, file_size as u64)?)
+    } else {
+        None
+    };
+    if let Some(segments) = &segments {
+        if compressed || sealed {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A sparse transfer can't be compressed or encrypted",
+            )
+            .into());
+        }
+
+        let data_len: u64 = segments.iter().map(|(_, len)| len).sum();
+        attributes.insert(SPARSE_ATTRIBUTE.to_string(), data_len.to_string());
+    }
+
+    if options.range.is_some() {
+        if compressed || sealed || options.sparse {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A range can't be compressed, encrypted or sparse",
+            )
+            .into());
+        }
+
+        attributes.insert(RANGE_ATTRIBUTE.to_string(), start.to_string());
+    }
+
+    if let Some(offset) = options.resume_from {
+        if compressed || sealed || options.sparse || options.range.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "A resumed transfer can't be compressed, encrypted, sparse or a range",
+            )
+            .into());
+        }
+        if offset > file_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Can't resume '{}' from {}, it's only {} bytes",
+                    file_name, offset, file_size
+                ),
+            )
+            .into());
+        }
+
+        attributes.insert(RESUME_ATTRIBUTE.to_string(), offset.to_string());
+    }
+
+    // Sparse files and ranges are written at their offsets, which only a
+    // file can do, so they're sent from here rather than `send_from`
+    if segments.is_some() || options.range.is_some() {
+        let started = Instant::now();
+        attributes.insert(DIGEST_ATTRIBUTE.to_string(), "sha256".to_string());
+        let metadata_msg = Transmission::Metadata(file_name.clone(), file_size, attributes)
+            .to_bytes_for(options.version);
+        stream.write_all(metadata_msg.as_slice()).await?;
+
+        if let Some(segments) = segments {
+            let sent =
+                send_sparse(stream, &mut file, &file_name, file_size, &segments, options).await?;
+            return Ok(SentFile {
+                hash: sent.hash,
+                stats: TransferStats {
+                    duration: started.elapsed(),
+                    ..sent.stats
+                },
+            });
+        }
+
+        let mut throttle = options.max_bytes_per_sec.map(Throttle::new);
+        let mut hasher = Sha256::new();
+        let mut stats = TransferStats::default();
+        send_segment(
+            stream,
+            &mut file,
+            &file_name,
+            start..start + file_size as u64,
+            options,
+            &mut throttle,
+            &mut |data| {
+                hasher.update(data);
+                stats.bytes += data.len() as u64;
+                stats.chunks += 1;
+                if let Some(progress) = &options.progress {
+                    progress.report(stats.bytes, file_size as u64);
+                }
+            },
+        )
+        .await?;
+        let hash = hasher.finalize().into();
+        send_digest(stream, &file_name, hash, options.version).await?;
+
+        info!("File sent successfully: {}", file_name);
+        stats.duration = started.elapsed();
+        return Ok(SentFile { hash, stats });
+    }
+
+    // Skip hashing if we've sent this exact version of the file before
+    // A prefix's hash isn't the file's, so leave the cache out of it
+    let modified = metadata.modified().ok().filter(|_| whole);
+    let sent_files = options.sent_files.as_ref();
+    let cached_hash = sent_files
+        .zip(modified)
+        .and_then(|(sent_files, modified)| sent_files.get(Path::new(path), modified))
+        .filter(|(size, _)| *size == metadata.len())
+        .map(|(_, hash)| hash);
+
+    let mut hasher = cached_hash.is_none().then(Sha256::new);
+    // The digest is of the whole file, so the part being skipped still
+    // needs hashing
+    if let Some(offset) = options.resume_from {
+        match hasher.as_mut() {
+            Some(hasher) => hash_prefix(&mut file, offset as u64, hasher).await?,
+            None => {
+                file.seek(SeekFrom::Start(offset as u64)).await?;
+            }
+        }
+    }
+
+    let outgoing = Outgoing {
+        name: file_name,
+        size: file_size,
+        attributes,
+        offset: options.resume_from.unwrap_or(0),
+        hasher,
+        known_hash: cached_hash,
+    };
+    let sent = send_from(stream, &mut file, outgoing, options).await?;
+
+    if let (Some(sent_files), Some(modified), None) = (sent_files, modified, cached_hash) {
+        sent_files.insert(Path::new(path), modified, metadata.len(), sent.hash);
+    }
+    Ok(sent)
+}
+
+// Sends `size` bytes read from `reader` as a file called `name`, for data
+// that isn't sitting in a file of its own: made on the fly, piped in or
+// held in memory
+pub async fn send_stream<S, R>(
+    stream: &mut S,
+    reader: &mut R,
+    name: &str,
+    size: u32,
+) -> Result<SentFile>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    send_stream_with(stream, reader, name, size, &SendOptions::default()).await
+}
+
+// Same as `send_stream`, with the options that make sense for a reader.
+// Sparse, range and resumed sends have to seek, so they're refused. With
+// nothing to look ahead at, the data is only flagged as text if `text`
+// says so, and `name` and `symlinks` are for files.
+pub async fn send_stream_with<S, R>(
+    stream: &mut S,
+    reader: &mut R,
+    name: &str,
+    size: u32,
+    options: &SendOptions,
+) -> Result<SentFile>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    if options.sparse || options.range.is_some() || options.resume_from.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "A stream can't be sent sparse, as a range or resumed",
+        )
+        .into());
+    }
+
+    let mut attributes = options.attributes.clone();
+    attributes.insert(
+        TEXT_ATTRIBUTE.to_string(),
+        options.text.unwrap_or(false).to_string(),
+    );
+    let outgoing = Outgoing {
+        name: name.to_string(),
+        size: options.limit.map_or(size, |limit| size.min(limit)),
+        attributes,
+        offset: 0,
+        hasher: Some(Sha256::new()),
+        known_hash: None,
+    };
+    send_from(stream, reader, outgoing, options).await
+}
+
+// What `send_from` sends
+struct Outgoing {
+    name: String,
+    size: u32,
+    attributes: HashMap<String, String>,
+    // How much the receiver already has, which the reader is already past
+    offset: u32,
+    // Fed everything up to `offset`, or None when the hash is `known_hash`
+    hasher: Option<Sha256>,
+    known_hash: Option<FileHash>,
+}
+
+// Sends the metadata and then the data from `reader` as chunks, compressed
+// or sealed as `options` says
+async fn send_from<S, R>(
+    stream: &mut S,
+    reader: &mut R,
+    outgoing: Outgoing,
+    options: &SendOptions,
+) -> Result<SentFile>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + Unpin,
+{
+    let chunk_size = checked_chunk_size(options)?;
+    let Outgoing {
+        name: file_name,
+        size: file_size,
+        mut attributes,
+        offset,
+        mut hasher,
+        known_hash,
+    } = outgoing;
+
+    let (mut deflater, codec) = match options.compression {
+        Compression::None => (None, None),
+        Compression::Deflate => (Some(Deflater::new()), None),
+        Compression::Gzip => (None, Some(ChunkCodec::Gzip)),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => (None, Some(ChunkCodec::Zstd)),
+    };
+    match (&deflater, codec) {
+        (Some(_), _) => {
+            attributes.insert(COMPRESSION_ATTRIBUTE.to_string(), "deflate".to_string());
+        }
+        (None, Some(codec)) => {
+            attributes.insert(COMPRESSION_ATTRIBUTE.to_string(), codec.name().to_string());
+        }
+        (None, None) => {}
+    }
+    // Each compressed chunk has to fit in one frame
+    let chunk_size = match codec {
+        Some(_) => chunk_size.min(MAX_CODEC_INPUT),
+        None => chunk_size,
+    };
+    // Sealed records don't compress, and their sizes assume they're sent
+    // as they are
+    #[cfg(feature = "e2e")]
+    if options.compression != Compression::None && options.encrypt_to.is_some() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Can't both compress and encrypt a transfer",
+        )
+        .into());
+    }
+
+    // Sealing changes how much goes over the wire, and leaves room in each
+    // chunk for the record's tag
+    #[cfg(feature = "e2e")]
+    let mut sealer = options.encrypt_to.as_ref().map(e2e::Sealer::to);
+    #[cfg(feature = "e2e")]
+    let (wire_size, chunk_size) = match sealer {
+        Some(_) => (
+            wire_size(&file_name, e2e::sealed_len(file_size as u64))?,
+            e2e::RECORD_SIZE,
+        ),
+        None => (file_size, chunk_size),
+    };
+    #[cfg(not(feature = "e2e"))]
+    let wire_size = file_size;
+    // Sealed records are already checked one by one, and the file's hash
+    // would give away something about what's in it
+    #[cfg(feature = "e2e")]
+    let digest = sealer.is_none();
+    #[cfg(not(feature = "e2e"))]
+    let digest = true;
+    if digest {
+        attributes.insert(DIGEST_ATTRIBUTE.to_string(), "sha256".to_string());
+    }
+
+    let started = Instant::now();
+    let mut stats = TransferStats::default();
+
+    // Send metadata as a `Transmission::Metadata` variant
+    let metadata_msg = Transmission::Metadata(file_name.clone(), wire_size, attributes)
+        .to_bytes_for(options.version);
+    stream.write_all(metadata_msg.as_slice()).await?;
+
+    #[cfg(feature = "e2e")]
+    if let Some(sealer) = &sealer {
+        let key_msg = Transmission::Chunk(file_name.clone(), sealer.ephemeral.to_vec())
+            .to_bytes_for(options.version);
+        stream.write_all(key_msg.as_slice()).await?;
+        stats.chunks += 1;
+    }
+
+    let mut throttle = options.max_bytes_per_sec.map(Throttle::new);
+
+    // Send the data in chunks. The buffer is handed over as the chunk's
+    // data, and taken back once it's written when it can be.
+    let mut buffer = Vec::new();
+    let mut bytes_sent = offset;
+    while bytes_sent < file_size {
+        // Never send more than we promised, in case the file grew
+        let remaining = ((file_size - bytes_sent) as usize).min(chunk_size);
+        buffer.resize(chunk_size, 0);
+        let bytes_read = match reader.read(&mut buffer[..remaining]).await {
+            Ok(bytes_read) => bytes_read,
+            // Tell the receiver, rather than leave it waiting on the rest
+            Err(e) => {
+                let _ = abort_transfer_for(stream, &file_name, options.version).await;
+                return Err(e.into());
+            }
+        };
+        if bytes_read == 0 {
+            break; // End of file
+        }
+
+        check_stop(stream, &file_name, options).await?;
+
+        // Send each chunk as a `Transmission::Chunk` variant
+        if let Some(hasher) = hasher.as_mut() {
+            hasher.update(&buffer[..bytes_read]);
+        }
+        buffer.truncate(bytes_read);
+        let chunk_data = std::mem::take(&mut buffer);
+        #[cfg(feature = "e2e")]
+        let chunk_data = match sealer.as_mut() {
+            Some(sealer) => sealer.seal(&chunk_data, bytes_sent + bytes_read as u32 == file_size),
+            None => chunk_data,
+        };
+        match (deflater.as_mut(), codec) {
+            (Some(deflater), _) => {
+                stats.chunks += send_chunks(
+                    stream,
+                    &file_name,
+                    &deflater.push(&chunk_data),
+                    chunk_size,
+                    options.version,
+                )
+                .await?;
+            }
+            (None, Some(codec)) => {
+                Transmission::Chunk(file_name.clone(), codec.compress(&chunk_data))
+                    .write_to_for(stream, options.version)
+                    .await?;
+                stats.chunks += 1;
+            }
+            (None, None) => {
+                let chunk = Transmission::Chunk(file_name.clone(), chunk_data);
+                chunk.write_to_for(stream, options.version).await?;
+                stats.chunks += 1;
+                let Transmission::Chunk(_, data) = chunk else {
+                    unreachable!()
+                };
+                buffer = data;
+            }
+        }
+        bytes_sent += bytes_read as u32;
+
+        if let Some(progress) = &options.progress {
+            progress.report(bytes_sent as u64, file_size as u64);
+        }
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.spend(bytes_read).await;
+        }
+    }
+
+    // The file shrank while we were sending it, or the reader ran dry, so
+    // the receiver would wait forever for the rest. Tell it to give up
+    // instead.
+    if bytes_sent < file_size {
+        abort_transfer_for(stream, &file_name, options.version).await?;
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!(
+                "'{}' shrank during transfer, sent {} of {} bytes",
+                file_name, bytes_sent, file_size
+            ),
+        )
+        .into());
+    }
+
+    if let Some(deflater) = deflater {
+        stats.chunks += send_chunks(
+            stream,
+            &file_name,
+            &deflater.finish(),
+            chunk_size,
+            options.version,
+        )
+        .await?;
+    }
+
+    // An empty file still gets its last record, so a relay can't pass off
+    // a cut short file as an empty one
+    #[cfg(feature = "e2e")]
+    if let (Some(sealer), 0) = (sealer.as_mut(), file_size) {
+        let record_msg = Transmission::Chunk(file_name.clone(), sealer.seal(&[], true))
+            .to_bytes_for(options.version);
+        stream.write_all(record_msg.as_slice()).await?;
+        stats.chunks += 1;
+    }
+
+    let hash = match (hasher, known_hash) {
+        (Some(hasher), _) => hasher.finalize().into(),
+        (None, Some(hash)) => hash,
+        (None, None) => unreachable!("we always hash when the hash isn't known"),
+    };
+    if digest {
+        send_digest(stream, &file_name, hash, options.version).await?;
+    }
+
+    info!("File sent successfully: {}", file_name);
+    stats.bytes = (bytes_sent - offset) as u64;
+    stats.duration = started.elapsed();
+    Ok(SentFile { hash, stats })
+}
+
+// The chunk size `options` asks for, if it fits on the wire, checking the
+// rate limit while we're at it
+fn checked_chunk_size(options: &SendOptions) -> Result<usize> {
+    // A chunk's length goes on the wire in two bytes
+    let chunk_size = options.chunk_size.unwrap_or(CHUNK_SIZE);
+    if chunk_size == 0 || chunk_size > u16::MAX as usize {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Chunks must be between 1 and {} bytes, not {}",
+                u16::MAX,
+                chunk_size
+            ),
+        )
+        .into());
+    }
+    if options.max_bytes_per_sec == Some(0) {
+        return Err(Error::new(ErrorKind::InvalidInput, "Can't send at 0 bytes a second").into());
+    }
+
+    Ok(chunk_size)
+}
+
+// The name `path` is sent under. A path ending in `..` has none.
+fn file_name_of(path: &str) -> Result<String> {
+    match Path::new(path).file_name() {
+        Some(name) => Ok(name.to_string_lossy().to_string()),
+        None => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("'{}' doesn't name a file", path),
+        )
+        .into()),
+    }
+}
+
+// `size` as it goes in a `Metadata`, which only has four bytes for it
+fn wire_size(file_name: &str, size: u64) -> Result<u32> {
+    u32::try_from(size).map_err(|_| {
+        Error::new(
+            ErrorKind::FileTooLarge,
+            format!(
+                "'{}' is {} bytes, more than the {} a transfer can carry",
+                file_name,
+                size,
+                u32::MAX
+            ),
+        )
+        .into()
+    })
+}
+
+// Sends every file under the directory at `path`, each named by its path
+// from the directory's parent so the receiver can rebuild the tree. Empty
+// directories aren't sent.
+pub async fn send_dir<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &str,
+) -> Result<Vec<SentFile>> {
+    send_dir_with(stream, path, &SendOptions::default()).await
+}
+
+// Same as `send_dir`, with `options` applying to each file in turn. `name`
+// renames the directory itself. Symlinks are followed only as `symlinks`
+// allows and skipped otherwise, and a directory is only ever sent once, so
+// a link back up the tree can't loop.
+pub async fn send_dir_with<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    path: &str,
+    options: &SendOptions,
+) -> Result<Vec<SentFile>> {
+    let root = tokio::fs::canonicalize(path).await?;
+    let name = match (&options.name, root.file_name()) {
+        (Some(name), _) => name.clone(),
+        (None, Some(name)) => name.to_string_lossy().to_string(),
+        (None, None) => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' doesn't name a directory to send", path),
+            )
+            .into())
+        }
+    };
+
+    let files = dir_files(&root, &name, &options.symlinks).await?;
+    let header = Transmission::Directory {
+        name,
+        files: files.len() as u32,
+    };
+    stream
+        .write_all(&header.to_bytes_for(options.version))
+        .await?;
+
+    let mut sent = Vec::with_capacity(files.len());
+    for (file, name) in files {
+        let options = SendOptions {
+            name: Some(name),
+            ..options.clone()
+        };
+        sent.push(send_file_stats(stream, &file, &options).await?);
+    }
+
+    Ok(sent)
+}
+
+// The files under `root`, each with the name it's sent as, in name order.
+// Anything whose path isn't UTF-8 can't be named on the wire, so it's left
+// out with a warning.
+async fn dir_files(
+    root: &Path,
+    name: &str,
+    symlinks: &SymlinkPolicy,
+) -> Result<Vec<(String, String)>> {
+    let within = match symlinks {
+        SymlinkPolicy::Within(dir) => Some(tokio::fs::canonicalize(dir).await?),
+        _ => None,
+    };
+
+    let mut visited = HashSet::from([root.to_path_buf()]);
+    let mut pending = vec![(root.to_path_buf(), name.to_string())];
+    let mut files = Vec::new();
+    while let Some((dir, prefix)) = pending.pop() {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let Some(utf8_path) = path.to_str().map(str::to_string) else {
+                warn!("Not sending '{}', its path isn't UTF-8", path.display());
+                continue;
+            };
+            // Lossless, now the whole path is known to be UTF-8
+            let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+
+            let mut file_type = entry.file_type().await?;
+            if file_type.is_symlink() {
+                let target = tokio::fs::canonicalize(&path).await.ok();
+                let follow = match (symlinks, &target, &within) {
+                    (SymlinkPolicy::Follow, Some(_), _) => true,
+                    (SymlinkPolicy::Within(_), Some(target), Some(within)) => {
+                        target.starts_with(within)
+                    }
+                    _ => false,
+                };
+                if !follow {
+                    continue;
+                }
+                file_type = tokio::fs::metadata(&path).await?.file_type();
+            }
+
+            if file_type.is_dir() {
+                if visited.insert(tokio::fs::canonicalize(&path).await?) {
+                    pending.push((path, name));
+                }
+            } else if file_type.is_file() {
+                files.push((utf8_path, name));
+            }
+        }
+    }
+
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(files)
+}
+
+// The parts of the first `len` bytes of a file that hold data, as offset
+// and length, leaving out holes. Where holes can't be found the whole file
+// is one part.
+#[cfg(target_os = "linux")]
+fn data_segments(file: &tokio::fs::File, len: u64) -> std::io::Result<Vec<(u64, u64)>> {
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        // SAFETY: `fd` is open for as long as `file` is borrowed
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = Error::last_os_error();
+            return match e.raw_os_error() {
+                // Nothing but a hole from here on
+                Some(libc::ENXIO) => Ok(segments),
+                // The filesystem can't tell us about holes
+                Some(libc::EINVAL) if segments.is_empty() => Ok(vec![(0, len)]),
+                _ => Err(e),
+            };
+        }
+        // SAFETY: as above
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        let (start, end) = (data as u64, (hole as u64).min(len));
+        if start >= end {
+            break;
+        }
+        segments.push((start, end - start));
+        offset = end;
+    }
+    Ok(segments)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn data_segments(_file: &tokio::fs::File, len: u64) -> std::io::Result<Vec<(u64, u64)>> {
+    Ok(vec![(0, len)])
+}
+
+// Bytes free to us on the filesystem holding `dir`, if we can tell
+#[cfg(target_os = "linux")]
+fn available_space(dir: &Path) -> std::io::Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `dir` is NUL terminated and `stats` is only read once filled in
+    if unsafe { libc::statvfs(dir.as_ptr(), stats.as_mut_ptr()) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    let stats = unsafe { stats.assume_init() };
+    Ok(Some(stats.f_bavail * stats.f_frsize))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn available_space(_dir: &Path) -> std::io::Result<Option<u64>> {
+    Ok(None)
+}
+
+// Sends the data segments of a sparse file, returning the hash of the whole
+// file with its holes read as zeros
+async fn send_sparse<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    file: &mut tokio::fs::File,
+    file_name: &str,
+    file_size: u32,
+    segments: &[(u64, u64)],
+    options: &SendOptions,
+) -> Result<SentFile> {
+    // Holes don't count towards progress, since they're never sent
+    let data_len = segments.iter().map(|(_, len)| len).sum();
+    let mut stats = TransferStats::default();
+    let mut throttle = options.max_bytes_per_sec.map(Throttle::new);
+    let mut hasher = Sha256::new();
+    let mut bytes_sent = 0;
+    let mut position = 0;
+    for &(start, len) in segments {
+        hash_zeros(&mut hasher, start - position);
+        send_segment(
+            stream,
+            file,
+            file_name,
+            start..start + len,
+            options,
+            &mut throttle,
+            &mut |data| {
+                hasher.update(data);
+                bytes_sent += data.len() as u64;
+                stats.chunks += 1;
+                if let Some(progress) = &options.progress {
+                    progress.report(bytes_sent, data_len);
+                }
+            },
+        )
+        .await?;
+        position = start + len;
+    }
+    hash_zeros(&mut hasher, file_size as u64 - position);
+    let hash = hasher.finalize().into();
+    send_digest(stream, file_name, hash, options.version).await?;
+    stats.bytes = bytes_sent;
+
+    info!("File sent successfully: {}", file_name);
+    Ok(SentFile { hash, stats })
+}
+
+// Sends `segment` of the file as chunks at their offsets, handing each
+// one's data to `on_chunk` once it's sent
+async fn send_segment<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    file: &mut tokio::fs::File,
+    file_name: &str,
+    segment: Range<u64>,
+    options: &SendOptions,
+    throttle: &mut Option<Throttle>,
+    on_chunk: &mut (dyn FnMut(&[u8]) + Send),
+) -> Result<()> {
+    let chunk_size = options.chunk_size.unwrap_or(CHUNK_SIZE);
+    let mut buffer = Vec::new();
+    file.seek(SeekFrom::Start(segment.start)).await?;
+
+    let end = segment.end;
+    let mut offset = segment.start;
+    while offset < end {
+        let remaining = ((end - offset) as usize).min(chunk_size);
+        buffer.resize(chunk_size, 0);
+        let bytes_read = match file.read(&mut buffer[..remaining]).await {
+            Ok(bytes_read) => bytes_read,
+            Err(e) => {
+                let _ = abort_transfer_for(stream, file_name, options.version).await;
+                return Err(e.into());
+            }
+        };
+        if bytes_read == 0 {
+            abort_transfer_for(stream, file_name, options.version).await?;
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("'{}' shrank during transfer", file_name),
+            )
+            .into());
+        }
+
+        check_stop(stream, file_name, options).await?;
+
+        buffer.truncate(bytes_read);
+        let chunk = Transmission::ChunkAt(file_name.to_string(), offset, buffer);
+        chunk.write_to_for(stream, options.version).await?;
+        let Transmission::ChunkAt(_, _, data) = chunk else {
+            unreachable!()
+        };
+        buffer = data;
+        on_chunk(&buffer);
+        offset += bytes_read as u64;
+
+        if let Some(throttle) = throttle {
+            throttle.spend(bytes_read).await;
+        }
+    }
+    Ok(())
+}
+
+// A token bucket keeping a sender under `rate` bytes a second on average.
+// It fills at that rate, up to a second's worth, and a sender that spends
+// more than it holds waits for it to catch up. It keeps time on tokio's
+// clock, the one it sleeps on.
+struct Throttle {
+    rate: u64,
+    tokens: f64,
+    last: tokio::time::Instant,
+}
+
+impl Throttle {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: 0.0,
+            last: tokio::time::Instant::now(),
+        }
+    }
+
+    async fn spend(&mut self, bytes: usize) {
+        let now = tokio::time::Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64) - bytes as f64;
+        self.last = now;
+
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate as f64)).await;
+        }
+    }
+}
+
+// Asks the other side for `range` of `filename`, and writes it in place
+// into the copy under `save_path`. The hash is of just the range.
+pub async fn request_range<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    filename: &str,
+    range: Range<u64>,
+    save_path: &str,
+) -> Result<ReceivedFile> {
+    let request = Transmission::RangeRequest {
+        filename: filename.to_string(),
+        start: range.start,
+        len: range.end.saturating_sub(range.start),
+    };
+    stream.write_all(request.to_bytes().as_slice()).await?;
+    receive_file(stream, save_path).await
+}
+
+// Asks the other side to send `filename` again, picking up after however
+// much of it an earlier try left under `save_path`. The digest still covers
+// the whole file, so if it changed since the first try the copy is deleted.
+pub async fn resume_file<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    filename: &str,
+    save_path: &str,
+) -> Result<ReceivedFile> {
+    let partial = partial_path(&format!("{}/{}", save_path, filename));
+    let have = match tokio::fs::metadata(partial).await {
+        Ok(metadata) => u32::try_from(metadata.len()).unwrap_or(u32::MAX),
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+
+    let request = Transmission::ResumeFrom(filename.to_string(), have);
+    stream.write_all(request.to_bytes().as_slice()).await?;
+    receive_file(stream, save_path).await
+}
+
+// Answers a `Transmission::ResumeFrom` for a file in `dir`, the same way
+// `serve_range` does. If the file is now shorter than the receiver's copy
+// it has changed, and is sent from the start.
+pub async fn serve_resume<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    dir: &str,
+    filename: &str,
+    offset: u32,
+) -> Result<FileHash> {
+    if Path::new(filename).file_name() != Some(filename.as_ref()) {
+        let _ = abort_transfer(stream, filename).await;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("'{}' isn't a file in '{}'", filename, dir),
+        )
+        .into());
+    }
+
+    let path = format!("{}/{}", dir, filename);
+    let len = match tokio::fs::metadata(&path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) => {
+            let _ = abort_transfer(stream, filename).await;
+            return Err(e.into());
+        }
+    };
+    let options = SendOptions {
+        resume_from: (offset as u64 <= len).then_some(offset),
+        ..SendOptions::default()
+    };
+    send_file_with(stream, &path, &options).await
+}
+
+// Answers a `Transmission::RangeRequest` for a file in `dir`. Only files
+// directly inside it can be asked for.
+pub async fn serve_range<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    dir: &str,
+    filename: &str,
+    start: u64,
+    len: u64,
+) -> Result<FileHash> {
+    if Path::new(filename).file_name() != Some(filename.as_ref()) {
+        let _ = abort_transfer(stream, filename).await;
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("'{}' isn't a file in '{}'", filename, dir),
+        )
+        .into());
+    }
+
+    let options = SendOptions {
+        range: Some(start..start.saturating_add(len)),
+        ..SendOptions::default()
+    };
+    send_file_with(stream, &format!("{}/{}", dir, filename), &options).await
+}
+
+// Hashes the first `len` bytes of `file`, leaving it positioned just after
+// them
+async fn hash_prefix(file: &mut tokio::fs::File, len: u64, hasher: &mut Sha256) -> Result<()> {
+    file.seek(SeekFrom::Start(0)).await?;
+    let mut prefix = file.take(len);
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut hashed = 0;
+    loop {
+        let bytes_read = prefix.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+        hashed += bytes_read as u64;
+    }
+
+    if hashed < len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!(
+                "Only {} of the {} bytes to resume from are there",
+                hashed, len
+            ),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Hashes `len` zero bytes, standing in for a hole
+fn hash_zeros(hasher: &mut Sha256, mut len: u64) {
+    let zeros = [0; CHUNK_SIZE];
+    while len > 0 {
+        let n = len.min(CHUNK_SIZE as u64) as usize;
+        hasher.update(&zeros[..n]);
+        len -= n as u64;
+    }
+}
+
+// Follows the last chunk with the hash of the whole file, for a receiver
+// told to expect it by the digest attribute
+async fn send_digest<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    file_name: &str,
+    hash: FileHash,
+    version: ProtocolVersion,
+) -> Result<()> {
+    let digest_msg = Transmission::Digest(file_name.to_string(), hash).to_bytes_for(version);
+    stream.write_all(digest_msg.as_slice()).await?;
+    Ok(())
+}
+
+// Sends `data` in as many chunks as it takes, returning how many
+async fn send_chunks<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    file_name: &str,
+    data: &[u8],
+    chunk_size: usize,
+    version: ProtocolVersion,
+) -> Result<u64> {
+    let mut chunks = 0;
+    for piece in data.chunks(chunk_size) {
+        Transmission::Chunk(file_name.to_string(), piece.to_vec())
+            .write_to_for(stream, version)
+            .await?;
+        chunks += 1;
+    }
+    Ok(chunks)
+}
+
+// Guesses whether a file is text from its first chunk: UTF-8 with no NUL
+// bytes. Leaves the file where it found it, at the start.
+async fn looks_like_text(file: &mut tokio::fs::File) -> Result<bool> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut filled = 0;
+    while filled < buffer.len() {
+        let bytes_read = file.read(&mut buffer[filled..]).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        filled += bytes_read;
+    }
+    file.seek(std::io::SeekFrom::Start(0)).await?;
+
+    let sample = &buffer[..filled];
+    if sample.contains(&0) {
+        return Ok(false);
+    }
+    // The sample may end part way through a character
+    Ok(match std::str::from_utf8(sample) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    })
+}
+
+// Rewrites the line endings of a text file as it streams past. A CR at the
+// end of one chunk is held back in case the next starts with LF.
+struct Normalizer {
+    target: LineEnding,
+    pending_cr: bool,
+}
+
+impl Normalizer {
+    fn new(target: LineEnding) -> Self {
+        Self {
+            target,
+            pending_cr: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &byte in data {
+            if std::mem::take(&mut self.pending_cr) {
+                if byte == b'\n' {
+                    out.extend(self.target.as_bytes());
+                    continue;
+                }
+                // A lone CR isn't a line ending we touch
+                out.push(b'\r');
+            }
+
+            match byte {
+                b'\r' => self.pending_cr = true,
+                b'\n' => out.extend(self.target.as_bytes()),
+                byte => out.push(byte),
+            }
+        }
+        out
+    }
+
+    fn finish(self) -> Vec<u8> {
+        if self.pending_cr {
+            vec![b'\r']
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+// How many chunks `send_file_multi` lets a slow recipient fall behind
+// before the read side waits for them
+pub const FANOUT_BUFFER: usize = 16;
+
+// Sends one file to several streams at once, reading it from disk a single
+// time. Each recipient gets its own bounded queue of encoded chunks, so a
+// slow one only holds the others up once its queue is full. The outer error
+// is about the file itself, the inner results are per stream.
+pub async fn send_file_multi<S: AsyncWrite + Unpin>(
+    streams: &mut [S],
+    path: &str,
+) -> Result<Vec<Result<FileHash>>> {
+    let mut file = open_for_send(path, &SymlinkPolicy::default()).await?;
+    let metadata = file.metadata().await?;
+    let attributes = HashMap::from([
+        (
+            TEXT_ATTRIBUTE.to_string(),
+            looks_like_text(&mut file).await?.to_string(),
+        ),
+        (DIGEST_ATTRIBUTE.to_string(), "sha256".to_string()),
+    ]);
+    let file_name = file_name_of(path)?;
+    let file_size = wire_size(&file_name, metadata.len())?;
+
+    let (senders, receivers): (Vec<_>, Vec<_>) = streams
+        .iter()
+        .map(|_| tokio::sync::mpsc::channel::<std::sync::Arc<Vec<u8>>>(FANOUT_BUFFER))
+        .unzip();
+
+    let writers = streams
+        .iter_mut()
+        .zip(receivers)
+        .map(|(stream, mut receiver)| async move {
+            while let Some(bytes) = receiver.recv().await {
+                stream.write_all(&bytes).await?;
+            }
+            Ok::<_, Error>(())
+        });
+
+    let reader = async move {
+        // Recipients whose writer has failed drop their receiver, and are
+        // skipped from then on
+        let broadcast = |bytes: Vec<u8>| {
+            let bytes = std::sync::Arc::new(bytes);
+            let senders = &senders;
+            async move {
+                for sender in senders {
+                    let _ = sender.send(bytes.clone()).await;
+                }
+            }
+        };
+
+        broadcast(Transmission::Metadata(file_name.clone(), file_size, attributes).to_bytes())
+            .await;
+
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut hasher = Sha256::new();
+        let mut bytes_sent = 0;
+        while bytes_sent < file_size {
+            let remaining = ((file_size - bytes_sent) as usize).min(CHUNK_SIZE);
+            let bytes_read = match file.read(&mut buffer[..remaining]).await {
+                Ok(bytes_read) => bytes_read,
+                Err(e) => {
+                    broadcast(Transmission::Abort(file_name.clone()).to_bytes()).await;
+                    return Err(e);
+                }
+            };
+            if bytes_read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..bytes_read]);
+            let chunk = Transmission::Chunk(file_name.clone(), buffer[..bytes_read].to_vec());
+            broadcast(chunk.to_bytes()).await;
+            bytes_sent += bytes_read as u32;
+        }
+
+        if bytes_sent < file_size {
+            broadcast(Transmission::Abort(file_name.clone()).to_bytes()).await;
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "'{}' shrank during transfer, sent {} of {} bytes",
+                    file_name, bytes_sent, file_size
+                ),
+            ));
+        }
+
+        let hash: FileHash = hasher.finalize().into();
+        broadcast(Transmission::Digest(file_name.clone(), hash).to_bytes()).await;
+
+        Ok::<FileHash, Error>(hash)
+    };
+
+    let (hash, written) = futures::join!(reader, futures::future::join_all(writers));
+    let hash = hash?;
+
+    Ok(written
+        .into_iter()
+        .map(|result| result.map(|()| hash).map_err(GlideError::from))
+        .collect())
+}
+
+// Stops a send part way if `cancel` is set, telling the receiver, or if the
+// receiver has aborted or cancelled it, without waiting on them
+async fn check_stop<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    file_name: &str,
+    options: &SendOptions,
+) -> Result<()> {
+    if is_set(&options.cancel) {
+        cancel_transfer_for(stream, file_name, options.version).await?;
+        return Err(cancelled(file_name.to_string()).into());
+    }
+
+    match poll_abort(stream, options.version).await? {
+        Some(Transmission::Abort(filename)) if filename == file_name => {
+            Err(aborted(filename).into())
+        }
+        Some(Transmission::TransferCancelled(filename)) if filename == file_name => {
+            Err(cancelled(filename).into())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Checks whether the receiver has sent anything back mid-transfer, without
+// waiting if it hasn't, and reads it if so. Works on any stream: only a byte
+// that's already there is taken, and the rest of the transmission is read
+// after it. A receiver only ever sends an abort or a cancel while a file is
+// on its way, so anything else is an error.
+async fn poll_abort<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    version: ProtocolVersion,
+) -> Result<Option<Transmission>> {
+    let mut first = [0u8; 1];
+    match stream.read(&mut first).now_or_never() {
+        Some(Ok(1)) => {}
+        // Nothing yet, or they hung up and the next write will say so
+        Some(Ok(_)) | None => return Ok(None),
+        Some(Err(e)) => return Err(e.into()),
+    }
+
+    let mut rest = std::io::Cursor::new(first).chain(stream);
+    match Transmission::from_stream_for(&mut rest, version).await? {
+        sent @ (Transmission::Abort(_) | Transmission::TransferCancelled(_)) => Ok(Some(sent)),
+        other => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Unexpected {:?} from the receiver mid-transfer",
+                other.redacted()
+            ),
+        )
+        .into()),
+    }
+}
+
+// What sending `path` in `chunk_size` pieces amounts to, without writing it
+// anywhere: the `Metadata`, its `Chunk`s and the `Digest` after them. It's
+// sent just as `send_file` would, into a pipe it's read back out of.
+pub fn file_transmissions(
+    path: &str,
+    chunk_size: usize,
+) -> impl Stream<Item = Result<Transmission>> {
+    enum Step {
+        Start(String, usize),
+        Reading(
+            tokio::io::DuplexStream,
+            tokio::task::JoinHandle<Result<FileHash>>,
+        ),
+        Done,
+    }
+
+    futures::stream::unfold(
+        Step::Start(path.to_string(), chunk_size),
+        |step| async move {
+            let (mut receiver, sending) = match step {
+                Step::Start(path, chunk_size) => {
+                    let options = SendOptions {
+                        chunk_size: Some(chunk_size),
+                        ..SendOptions::default()
+                    };
+                    if let Err(e) = checked_chunk_size(&options) {
+                        return Some((Err(e), Step::Done));
+                    }
+                    let (mut sender, receiver) = tokio::io::duplex(2 * CHUNK_SIZE);
+                    let sending =
+                        tokio::spawn(
+                            async move { send_file_with(&mut sender, &path, &options).await },
+                        );
+                    (receiver, sending)
+                }
+                Step::Reading(receiver, sending) => (receiver, sending),
+                Step::Done => return None,
+            };
+
+            match Transmission::from_stream_for(&mut receiver, ProtocolVersion::default()).await {
+                Ok(transmission) => Some((Ok(transmission), Step::Reading(receiver, sending))),
+                // The sender's done, and says how it went
+                Err(GlideError::Protocol(ProtocolError::UnexpectedEof)) => match sending.await {
+                    Ok(Ok(_)) => None,
+                    Ok(Err(e)) => Some((Err(e), Step::Done)),
+                    Err(e) => Some((Err(Error::other(e).into()), Step::Done)),
+                },
+                Err(e) => Some((Err(e), Step::Done)),
+            }
+        },
+    )
+}
+
+// Hashes a file already on disk, reading it in chunks
+pub async fn hash_file(path: &str) -> Result<FileHash> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+
+    loop {
+        let bytes_read = file.read(&mut buffer).await?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+
+    Ok(hasher.finalize().into())
 }
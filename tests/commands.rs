@@ -0,0 +1,1276 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use utils::audit::{self, AuditLogger};
+use utils::commands::{self, Command, COMMAND_NAMES, LIST_PAGE_SIZE};
+use utils::connection;
+use utils::data::{
+    CommandMetrics, ConnectionCap, ExtensionPolicy, Request, ServerConfig, UserData, PARTIAL_SUFFIX,
+};
+use utils::protocol::{ProtocolVersion, Transmission};
+use utils::state::{self, SharedState};
+use utils::transfers;
+
+fn user() -> UserData {
+    UserData {
+        socket: String::new(),
+        incoming_requests: Vec::new(),
+        public_key: None,
+    }
+}
+
+// Stages `contents` as a glide of `filename` from `from` to `to`
+async fn stage(
+    state: &SharedState,
+    config: &ServerConfig,
+    from: &str,
+    to: &str,
+    filename: &str,
+    contents: &str,
+) {
+    let dir = format!("{}/{}/{}", config.staging_dir, from, to);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(format!("{}/{}", dir, filename), contents).unwrap();
+    state::with_user(state, to, |client| {
+        client.incoming_requests.push(Request {
+            sender: from.to_string(),
+            filename: filename.to_string(),
+            size: contents.len() as u64,
+            offered_at: SystemTime::now(),
+        })
+    })
+    .await
+    .unwrap();
+}
+
+async fn connected() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    (server, client)
+}
+
+// Runs `command` as `username`, returning the response and, if it was an
+// accepted `ok`, the name and contents of the file that came with it
+async fn handle(
+    state: &SharedState,
+    config: &ServerConfig,
+    username: &str,
+    command: Command,
+) -> (Transmission, Option<(String, String)>) {
+    let (mut server, mut client) = connected().await;
+    let username = username.to_string();
+    let (state, config) = (state.clone(), config.clone());
+    let handled = tokio::spawn(async move {
+        Command::handle_with_config(command, &username, &mut server, &state, &config).await
+    });
+
+    let response = Transmission::from_stream(&mut client).await.unwrap();
+    let file = match response {
+        Transmission::OkSuccess => {
+            let save_path = std::env::temp_dir()
+                .join(format!("glide-utils-tests-{}-received", std::process::id()))
+                .to_string_lossy()
+                .to_string();
+            let received = transfers::receive_file(&mut client, &save_path)
+                .await
+                .unwrap();
+            let contents =
+                std::fs::read_to_string(format!("{}/{}", save_path, received.filename)).unwrap();
+            Some((received.filename, contents))
+        }
+        _ => None,
+    };
+
+    let handled = handled.await.unwrap().unwrap();
+    assert_eq!(format!("{:?}", handled), format!("{:?}", response));
+    (response, file)
+}
+
+// Where `path` is under the staging directory
+fn in_staging(config: &ServerConfig, path: &str) -> PathBuf {
+    Path::new(&config.staging_dir).join(path)
+}
+
+async fn pending(state: &SharedState, username: &str) -> Vec<String> {
+    state::with_user(state, username, |client| {
+        client
+            .incoming_requests
+            .iter()
+            .map(|req| format!("{}/{}", req.sender, req.filename))
+            .collect()
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn ok_twice_delivers_each_glide_once() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "twice_to", user()).await;
+    stage(
+        &state,
+        &config,
+        "twice_from",
+        "twice_to",
+        "first.txt",
+        "one",
+    )
+    .await;
+    stage(
+        &state,
+        &config,
+        "twice_from",
+        "twice_to",
+        "second.txt",
+        "two",
+    )
+    .await;
+
+    let (response, file) = handle(&state, &config, "twice_to", Command::OkLatest).await;
+    assert!(matches!(response, Transmission::OkSuccess));
+    assert_eq!(file, Some(("second.txt".to_string(), "two".to_string())));
+    assert_eq!(pending(&state, "twice_to").await, ["twice_from/first.txt"]);
+
+    let (response, file) = handle(&state, &config, "twice_to", Command::OkLatest).await;
+    assert!(matches!(response, Transmission::OkSuccess));
+    assert_eq!(file, Some(("first.txt".to_string(), "one".to_string())));
+    assert!(pending(&state, "twice_to").await.is_empty());
+
+    let (response, file) = handle(&state, &config, "twice_to", Command::OkLatest).await;
+    assert!(matches!(response, Transmission::Error(_)), "{:?}", response);
+    assert_eq!(file, None);
+}
+
+#[tokio::test]
+async fn a_bare_ok_or_no_with_nothing_pending_says_so() {
+    let state = state::new_state();
+    state::insert_user(&state, "idle_to", user()).await;
+    for command in [Command::OkLatest, Command::NoLatest] {
+        let response = command.execute(&state, "idle_to").await;
+        assert!(
+            matches!(response, Transmission::Error(ref message) if message == "No glides are pending"),
+            "{:?}",
+            response
+        );
+    }
+}
+
+#[tokio::test]
+async fn ok_from_a_sender_twice_moves_on_to_their_next_glide() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "again_to", user()).await;
+    stage(&state, &config, "again_from", "again_to", "a.txt", "A").await;
+    stage(&state, &config, "again_from", "again_to", "b.txt", "B").await;
+
+    let ok = Command::Ok("again_from".to_string(), None);
+    let (_, file) = handle(&state, &config, "again_to", ok.clone()).await;
+    assert_eq!(file, Some(("a.txt".to_string(), "A".to_string())));
+    let (_, file) = handle(&state, &config, "again_to", ok.clone()).await;
+    assert_eq!(file, Some(("b.txt".to_string(), "B".to_string())));
+    let (response, _) = handle(&state, &config, "again_to", ok).await;
+    assert!(matches!(response, Transmission::OkFailed));
+}
+
+#[tokio::test]
+async fn ok_naming_a_file_delivers_that_file() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "named_to", user()).await;
+    stage(&state, &config, "named_from", "named_to", "a.txt", "A").await;
+    stage(&state, &config, "named_from", "named_to", "report.pdf", "R").await;
+
+    let (ok, _) = Command::parse_with_trailer("ok @named_from report.pdf").unwrap();
+    let (_, file) = handle(&state, &config, "named_to", ok).await;
+    assert_eq!(file, Some(("report.pdf".to_string(), "R".to_string())));
+    assert_eq!(pending(&state, "named_to").await, ["named_from/a.txt"]);
+
+    let (ok, _) = Command::parse_with_trailer("ok @named_from report.pdf").unwrap();
+    let (response, _) = handle(&state, &config, "named_to", ok).await;
+    assert!(matches!(response, Transmission::Error(_)));
+}
+
+#[tokio::test]
+async fn no_naming_a_file_refuses_that_file() {
+    let state = state::new_state();
+    let config = common::scratch_config();
+    state::insert_user(&state, "refuse_to", user()).await;
+    stage(&state, &config, "refuse_from", "refuse_to", "keep.txt", "K").await;
+    stage(&state, &config, "refuse_from", "refuse_to", "file.txt", "F").await;
+
+    let (no, _) = Command::parse_with_trailer("no @refuse_from file.txt").unwrap();
+    let (response, _) = handle(&state, &config, "refuse_to", no).await;
+    assert!(matches!(response, Transmission::NoSuccess));
+    assert_eq!(pending(&state, "refuse_to").await, ["refuse_from/keep.txt"]);
+}
+
+#[test]
+fn a_delivery_is_forgotten_once_its_guard_is_dropped() {
+    let config = ServerConfig::default();
+    let delivery = config.deliveries.start("guard_from", "guard_to", "f.txt");
+    // Clones share the deliveries
+    assert_eq!(config.clone().deliveries.cancel("guard_from", "f.txt"), 1);
+    assert!(delivery.cancel.load(Ordering::Relaxed));
+
+    // However the delivery ended, even by its handler being dropped
+    drop(delivery);
+    assert_eq!(config.deliveries.cancel("guard_from", "f.txt"), 0);
+}
+
+#[test]
+fn each_server_has_its_own_deliveries() {
+    let one = ServerConfig::default();
+    let other = ServerConfig::default();
+    let _delivery = one.deliveries.start("own_from", "own_to", "f.txt");
+    assert_eq!(other.deliveries.cancel("own_from", "f.txt"), 0);
+    assert_eq!(one.deliveries.cancel("own_from", "f.txt"), 1);
+}
+
+#[tokio::test]
+async fn a_dropped_handler_leaves_no_delivery_behind() {
+    let state = state::new_state();
+    let config = common::scratch_config();
+    state::insert_user(&state, "dropped_to", user()).await;
+    // Big enough that the delivery blocks once the socket's buffers fill
+    let big = "x".repeat(32 * 1024 * 1024);
+    stage(
+        &state,
+        &config,
+        "dropped_from",
+        "dropped_to",
+        "big.bin",
+        &big,
+    )
+    .await;
+
+    let (mut server, mut client) = connected().await;
+    let handling = {
+        let (state, config) = (state.clone(), config.clone());
+        tokio::spawn(async move {
+            let ok = Command::Ok("dropped_from".to_string(), None);
+            Command::handle_with_config(ok, "dropped_to", &mut server, &state, &config).await
+        })
+    };
+    assert!(matches!(
+        Transmission::from_stream(&mut client).await.unwrap(),
+        Transmission::OkSuccess
+    ));
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(!handling.is_finished());
+
+    // As the shutdown `select!` in `connection` does
+    handling.abort();
+    let _ = handling.await;
+    assert_eq!(config.deliveries.cancel("dropped_from", "big.bin"), 0);
+}
+
+#[tokio::test]
+async fn a_disabled_command_is_left_out_of_caps_and_refused() {
+    let state = state::new_state();
+    state::insert_user(&state, "policy_user", user()).await;
+    let config = ServerConfig {
+        allowed_commands: Some(vec!["caps".to_string(), "list".to_string()]),
+        ..ServerConfig::default()
+    };
+
+    let caps = Command::Capabilities
+        .execute_with_config(&state, "policy_user", &config)
+        .await;
+    assert_eq!(format!("{:?}", caps), r#"Capabilities(["list", "caps"])"#);
+
+    let purge = Command::Purge
+        .execute_with_config(&state, "policy_user", &config)
+        .await;
+    assert!(matches!(purge, Transmission::Error(message) if message.contains("purge")));
+}
+
+#[tokio::test]
+async fn every_command_is_in_caps_by_default() {
+    let state = state::new_state();
+    let caps = Command::Capabilities
+        .execute_with_config(&state, "default_user", &ServerConfig::default())
+        .await;
+    let Transmission::Capabilities(names) = caps else {
+        panic!("{:?}", caps)
+    };
+    assert_eq!(names, COMMAND_NAMES);
+}
+
+async fn send(stream: &mut TcpStream, command: Command) -> Transmission {
+    let command = Transmission::Command(command);
+    stream.write_all(&command.to_bytes()).await.unwrap();
+    Transmission::from_stream(stream).await.unwrap()
+}
+
+#[tokio::test]
+async fn an_answer_waits_for_its_sender_to_reconnect() {
+    let state = state::new_state();
+    let config = common::scratch_config();
+    let addr = common::serve(&state, &config).await;
+    let mut recipient = common::log_in(addr, "away_to").await;
+
+    // Glide, then go away before it's answered
+    let outbox =
+        std::env::temp_dir().join(format!("glide-utils-tests-{}-outbox", std::process::id()));
+    std::fs::create_dir_all(&outbox).unwrap();
+    let path = outbox.join("away.txt");
+    std::fs::write(&path, "while you were out").unwrap();
+    let path = path.to_str().unwrap().to_string();
+    let mut sender = common::log_in(addr, "away_from").await;
+    let glide = Command::Glide {
+        path: path.clone(),
+        to: "away_to".to_string(),
+        as_name: None,
+    };
+    assert!(matches!(
+        send(&mut sender, glide).await,
+        Transmission::GlideRequestSent
+    ));
+    transfers::send_file(&mut sender, &path).await.unwrap();
+    // Gone once the server's staged the file and reads on
+    drop(sender);
+    while state::contains_user(&state, "away_from").await {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let ok = Command::Ok("away_from".to_string(), Some("away.txt".to_string()));
+    assert!(matches!(
+        send(&mut recipient, ok).await,
+        Transmission::OkSuccess
+    ));
+    let save_path = std::env::temp_dir()
+        .join(format!("glide-utils-tests-{}-away", std::process::id()))
+        .to_string_lossy()
+        .to_string();
+    transfers::receive_file(&mut recipient, &save_path)
+        .await
+        .unwrap();
+
+    // Told as soon as they're back
+    let mut sender = common::log_in(addr, "away_from").await;
+    assert!(matches!(
+        Transmission::from_stream(&mut sender).await.unwrap(),
+        Transmission::RequestOutcome(to, filename, true) if to == "away_to" && filename == "away.txt"
+    ));
+
+    // Another server never heard of it
+    let elsewhere = common::serve(&state::new_state(), &common::scratch_config()).await;
+    let mut namesake = common::log_in(elsewhere, "away_from").await;
+    assert!(matches!(
+        send(&mut namesake, Command::Requests).await,
+        Transmission::IncomingRequests(requests) if requests.is_empty()
+    ));
+}
+
+#[tokio::test]
+async fn a_login_past_the_cap_is_told_when_to_retry() {
+    let state = state::new_state();
+    let config = ServerConfig {
+        connection_cap: Some(ConnectionCap {
+            max_users: 1,
+            retry_after: Duration::from_secs(30),
+        }),
+        ..ServerConfig::default()
+    };
+    let addr = common::serve(&state, &config).await;
+    let _first = common::log_in(addr, "cap_first").await;
+
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    let login = Transmission::Username("cap_second".to_string());
+    second.write_all(&login.to_bytes()).await.unwrap();
+    assert!(matches!(
+        Transmission::from_stream(&mut second).await.unwrap(),
+        Transmission::Busy {
+            retry_after_secs: 30
+        }
+    ));
+    assert!(!state::contains_user(&state, "cap_second").await);
+}
+
+#[tokio::test]
+async fn nick_moves_staged_files_and_waiting_answers() {
+    let state = state::new_state();
+    let config = common::scratch_config();
+    state::insert_user(&state, "nick_old", user()).await;
+    state::insert_user(&state, "nick_to", user()).await;
+    stage(&state, &config, "nick_old", "nick_to", "sent.txt", "sent").await;
+    stage(
+        &state,
+        &config,
+        "nick_from",
+        "nick_old",
+        "waiting.txt",
+        "waiting",
+    )
+    .await;
+    config
+        .outcomes
+        .queue("nick_old", "nick_to", "answered.txt", true);
+
+    let nick = Command::SetName("nick_new".to_string());
+    let response = nick.execute_with_config(&state, "nick_old", &config).await;
+    assert!(
+        matches!(response, Transmission::UsernameOk),
+        "{:?}",
+        response
+    );
+
+    assert_eq!(pending(&state, "nick_to").await, ["nick_new/sent.txt"]);
+    assert_eq!(pending(&state, "nick_new").await, ["nick_from/waiting.txt"]);
+    assert!(in_staging(&config, "nick_new/nick_to/sent.txt").exists());
+    assert!(in_staging(&config, "nick_from/nick_new/waiting.txt").exists());
+    assert!(!in_staging(&config, "nick_old").exists());
+
+    let mut sent = Vec::new();
+    config
+        .outcomes
+        .flush(&mut sent, "nick_new", ProtocolVersion::V1)
+        .await
+        .unwrap();
+    assert!(matches!(
+        Transmission::from_stream(&mut sent.as_slice()).await.unwrap(),
+        Transmission::RequestOutcome(_, filename, true) if filename == "answered.txt"
+    ));
+}
+
+#[tokio::test]
+async fn nick_that_cant_move_staged_files_changes_nothing() {
+    let state = state::new_state();
+    let config = common::scratch_config();
+    state::insert_user(&state, "stuck_old", user()).await;
+    state::insert_user(&state, "stuck_to", user()).await;
+    stage(&state, &config, "stuck_old", "stuck_to", "sent.txt", "sent").await;
+    // Left behind by someone who had the name before
+    std::fs::create_dir_all(in_staging(&config, "stuck_new/someone")).unwrap();
+    std::fs::write(in_staging(&config, "stuck_new/someone/left.txt"), "left").unwrap();
+
+    let nick = Command::SetName("stuck_new".to_string());
+    let response = nick.execute_with_config(&state, "stuck_old", &config).await;
+    assert!(matches!(response, Transmission::Error(_)), "{:?}", response);
+
+    assert!(state::contains_user(&state, "stuck_old").await);
+    assert!(!state::contains_user(&state, "stuck_new").await);
+    assert_eq!(pending(&state, "stuck_to").await, ["stuck_old/sent.txt"]);
+    assert!(in_staging(&config, "stuck_old/stuck_to/sent.txt").exists());
+    assert!(in_staging(&config, "stuck_new/someone/left.txt").exists());
+}
+
+// The code the server answers a login as `username` with
+async fn login_code(addr: SocketAddr, username: &str) -> u8 {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let login = Transmission::Username(username.to_string());
+    stream.write_all(&login.to_bytes()).await.unwrap();
+    let mut code = [0u8; 1];
+    stream.read_exact(&mut code).await.unwrap();
+    code[0]
+}
+
+#[tokio::test]
+async fn a_name_taken_in_another_case_is_answered_with_taken() {
+    let state = state::new_state();
+    let addr = common::serve(&state, &ServerConfig::default()).await;
+    let _bob = common::log_in(addr, "CaseBob").await;
+
+    // As protocol.txt has it, TAKEN is 3
+    for name in ["casebob", "CASEBOB", "CaseBob"] {
+        assert_eq!(login_code(addr, name).await, 3, "{}", name);
+    }
+    assert_eq!(state::usernames(&state).await, ["CaseBob"]);
+}
+
+#[tokio::test]
+async fn a_reserved_name_is_answered_with_invalid() {
+    let state = state::new_state();
+    let addr = common::serve(&state, &ServerConfig::default()).await;
+
+    // And INVALID is 4, in any case
+    for name in ["server", "Admin", "EVERYONE", ".blobs", ".", ".."] {
+        assert_eq!(login_code(addr, name).await, 4, "{}", name);
+    }
+    assert!(state::usernames(&state).await.is_empty());
+}
+
+// The fields of an audit line, as the strings they're written as
+fn audit_fields(line: &str) -> HashMap<String, String> {
+    let line = line.strip_prefix('{').unwrap().strip_suffix('}').unwrap();
+    line.split(',')
+        .map(|field| {
+            let (key, value) = field.split_once(':').unwrap();
+            (
+                key.trim_matches('"').to_string(),
+                value.trim_matches('"').to_string(),
+            )
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn a_finished_transfer_is_in_the_audit_log() {
+    let log = std::env::temp_dir().join(format!(
+        "glide-utils-tests-{}-audit.log",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&log);
+    let state = state::new_state();
+    let config = ServerConfig {
+        audit: Some(AuditLogger::new(log.to_str().unwrap())),
+        ..common::scratch_config()
+    };
+    let addr = common::serve(&state, &config).await;
+
+    let outbox =
+        std::env::temp_dir().join(format!("glide-utils-tests-{}-audited", std::process::id()));
+    std::fs::create_dir_all(&outbox).unwrap();
+    let path = outbox.join("audited.txt");
+    std::fs::write(&path, "on the record").unwrap();
+    let path = path.to_str().unwrap().to_string();
+    let _recipient = common::log_in(addr, "audit_to").await;
+    let mut sender = common::log_in(addr, "audit_from").await;
+    let glide = Command::Glide {
+        path: path.clone(),
+        to: "audit_to".to_string(),
+        as_name: None,
+    };
+    assert!(matches!(
+        send(&mut sender, glide).await,
+        Transmission::GlideRequestSent
+    ));
+    let hash = transfers::send_file(&mut sender, &path).await.unwrap();
+
+    // Written and flushed without the server stopping
+    let line = loop {
+        let written = std::fs::read_to_string(&log).unwrap_or_default();
+        if let Some(line) = written.lines().next() {
+            break line.to_string();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let fields = audit_fields(&line);
+    assert!(fields["timestamp"].parse::<u64>().unwrap() > 0);
+    assert_eq!(fields["sender"], "audit_from");
+    assert_eq!(fields["recipient"], "audit_to");
+    assert_eq!(fields["filename"], "audited.txt");
+    assert_eq!(fields["bytes"], "13");
+    assert_eq!(fields["outcome"], "staged");
+    assert_eq!(fields["hash"], audit::hex(&hash));
+}
+
+#[tokio::test]
+async fn a_sender_that_never_starts_is_timed_out_and_withdrawn() {
+    let config = ServerConfig {
+        glide_receive_timeout: Duration::from_millis(50),
+        ..common::scratch_config()
+    };
+    let state = state::new_state();
+    state::insert_user(&state, "idle_from", user()).await;
+    state::insert_user(&state, "idle_to", user()).await;
+    let (mut server, mut client) = connected().await;
+
+    let glide = Command::Glide {
+        path: "idle.txt".to_string(),
+        to: "idle_to".to_string(),
+        as_name: None,
+    };
+    let handling = tokio::spawn({
+        let (state, config) = (state.clone(), config.clone());
+        async move {
+            Command::handle_with_config(glide, "idle_from", &mut server, &state, &config).await
+        }
+    });
+    assert!(matches!(
+        Transmission::from_stream(&mut client).await.unwrap(),
+        Transmission::GlideRequestSent
+    ));
+    assert_eq!(pending(&state, "idle_to").await, ["idle_from/idle.txt"]);
+
+    // And then nothing is sent
+    let err = tokio::time::timeout(Duration::from_secs(5), handling)
+        .await
+        .expect("the server should give up on the sender")
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(pending(&state, "idle_to").await.is_empty());
+    assert!(!in_staging(&config, "idle_from/idle_to/idle.txt").exists());
+}
+
+#[tokio::test]
+async fn a_sender_that_stalls_after_the_metadata_is_timed_out_and_withdrawn() {
+    let state = state::new_state();
+    let config = ServerConfig {
+        read_timeout: Some(Duration::from_millis(50)),
+        ..common::scratch_config()
+    };
+    state::insert_user(&state, "stall_from", user()).await;
+    state::insert_user(&state, "stall_to", user()).await;
+    let (mut server, mut client) = connected().await;
+
+    let glide = Command::Glide {
+        path: "stall.txt".to_string(),
+        to: "stall_to".to_string(),
+        as_name: None,
+    };
+    let handling = tokio::spawn({
+        let (state, config) = (state.clone(), config.clone());
+        async move {
+            Command::handle_with_config(glide, "stall_from", &mut server, &state, &config).await
+        }
+    });
+    assert!(matches!(
+        Transmission::from_stream(&mut client).await.unwrap(),
+        Transmission::GlideRequestSent
+    ));
+    let metadata = Transmission::Metadata("stall.txt".to_string(), 10, HashMap::new());
+    client.write_all(&metadata.to_bytes()).await.unwrap();
+
+    // And then no chunks
+    let err = tokio::time::timeout(Duration::from_secs(5), handling)
+        .await
+        .expect("the server should give up on the sender")
+        .unwrap()
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    assert!(pending(&state, "stall_to").await.is_empty());
+    assert!(!in_staging(&config, "stall_from/stall_to/stall.txt").exists());
+}
+
+#[tokio::test]
+async fn a_client_that_stalls_part_way_through_a_command_is_dropped() {
+    let state = state::new_state();
+    let config = ServerConfig {
+        read_timeout: Some(Duration::from_millis(50)),
+        ..ServerConfig::default()
+    };
+    let addr = common::serve(&state, &config).await;
+    let mut client = common::log_in(addr, "half_said").await;
+
+    let list = Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    };
+    let bytes = Transmission::Command(list).to_bytes();
+    client.write_all(&bytes[..bytes.len() - 1]).await.unwrap();
+
+    // Closed on them, rather than waiting for the rest forever
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("the server should give up on the client")
+        .unwrap();
+    assert!(rest.is_empty());
+    assert!(!state::contains_user(&state, "half_said").await);
+}
+
+#[tokio::test]
+async fn a_connection_cancelled_mid_glide_leaves_nothing_behind() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "cancel_to", user()).await;
+    let (server, mut client) = connected().await;
+    let (cancel, cancelled) = tokio::sync::oneshot::channel::<()>();
+    let running = {
+        let (state, config) = (state.clone(), config.clone());
+        tokio::spawn(async move {
+            let shutdown = async {
+                let _ = cancelled.await;
+            };
+            connection::run_with_config(server, &state, &config, shutdown).await
+        })
+    };
+
+    let login = Transmission::Username("cancel_from".to_string());
+    client.write_all(&login.to_bytes()).await.unwrap();
+    assert!(matches!(
+        Transmission::from_stream(&mut client).await.unwrap(),
+        Transmission::UsernameOk
+    ));
+    let glide = Command::Glide {
+        path: "half.bin".to_string(),
+        to: "cancel_to".to_string(),
+        as_name: None,
+    };
+    assert!(matches!(
+        send(&mut client, glide).await,
+        Transmission::GlideRequestSent
+    ));
+
+    // Start the file, but only send the first chunk of it
+    let metadata = Transmission::Metadata("half.bin".to_string(), 1 << 20, HashMap::new());
+    client.write_all(&metadata.to_bytes()).await.unwrap();
+    let chunk = Transmission::Chunk("half.bin".to_string(), vec![1; 1024]);
+    client.write_all(&chunk.to_bytes()).await.unwrap();
+    let staged = format!("{}/cancel_from/cancel_to/half.bin", config.staging_dir);
+    while !Path::new(&transfers::partial_path(&staged)).exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    cancel.send(()).unwrap();
+    running.await.unwrap().unwrap();
+
+    assert!(!state::contains_user(&state, "cancel_from").await);
+    assert!(pending(&state, "cancel_to").await.is_empty());
+    assert!(!Path::new(&staged).exists());
+    assert!(!Path::new(&transfers::partial_path(&staged)).exists());
+    // And the socket's closed
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+}
+
+#[tokio::test]
+async fn glides_past_the_per_sender_cap_are_refused() {
+    let state = state::new_state();
+    let config = ServerConfig {
+        max_staged_per_sender: Some(2),
+        ..ServerConfig::default()
+    };
+    state::insert_user(&state, "capped_to", user()).await;
+    state::insert_user(&state, "capped_also", user()).await;
+    let glide = |filename: &str| Command::Glide {
+        path: filename.to_string(),
+        to: "capped_to".to_string(),
+        as_name: None,
+    };
+
+    for filename in ["one.txt", "two.txt"] {
+        assert!(matches!(
+            glide(filename)
+                .execute_with_config(&state, "capped_from", &config)
+                .await,
+            Transmission::GlideRequestSent
+        ));
+    }
+    let refused = glide("three.txt")
+        .execute_with_config(&state, "capped_from", &config)
+        .await;
+    assert!(
+        matches!(refused, Transmission::GlideRefused(ref reason) if reason.contains("@capped_to")),
+        "{:?}",
+        refused
+    );
+
+    // Nobody gets it if anyone's full
+    let many = Command::GlideMany {
+        path: "four.txt".to_string(),
+        to: vec!["capped_also".to_string(), "capped_to".to_string()],
+    };
+    assert!(matches!(
+        many.execute_with_config(&state, "capped_from", &config)
+            .await,
+        Transmission::GlideRefused(_)
+    ));
+    assert!(pending(&state, "capped_also").await.is_empty());
+
+    // The cap is per sender
+    assert!(matches!(
+        glide("mine.txt")
+            .execute_with_config(&state, "capped_other", &config)
+            .await,
+        Transmission::GlideRequestSent
+    ));
+    assert_eq!(
+        pending(&state, "capped_to").await,
+        [
+            "capped_from/one.txt",
+            "capped_from/two.txt",
+            "capped_other/mine.txt"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_glide_that_cant_be_staged_is_answered_with_an_error() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "blocked_to", user()).await;
+    // A file where the sender's staging directory would go
+    std::fs::create_dir_all(&config.staging_dir).unwrap();
+    std::fs::write(in_staging(&config, "blocked_from"), "in the way").unwrap();
+
+    let glide = Command::Glide {
+        path: "report.pdf".to_string(),
+        to: "blocked_to".to_string(),
+        as_name: None,
+    };
+    let (response, _) = handle(&state, &config, "blocked_from", glide).await;
+    assert!(
+        matches!(response, Transmission::Error(ref message) if message.contains("a file is in the way")),
+        "{:?}",
+        response
+    );
+    assert!(pending(&state, "blocked_to").await.is_empty());
+    std::fs::remove_file(in_staging(&config, "blocked_from")).unwrap();
+}
+
+#[tokio::test]
+async fn a_sender_gone_mid_file_has_their_glide_withdrawn() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "gone_from", user()).await;
+    state::insert_user(&state, "gone_to", user()).await;
+    let (mut server, mut client) = connected().await;
+
+    let glide = Command::Glide {
+        path: "gone.bin".to_string(),
+        to: "gone_to".to_string(),
+        as_name: None,
+    };
+    let handling = tokio::spawn({
+        let (state, config) = (state.clone(), config.clone());
+        async move {
+            Command::handle_with_config(glide, "gone_from", &mut server, &state, &config).await
+        }
+    });
+    assert!(matches!(
+        Transmission::from_stream(&mut client).await.unwrap(),
+        Transmission::GlideRequestSent
+    ));
+
+    // Start the file, then hang up after its first chunk
+    let metadata = Transmission::Metadata("gone.bin".to_string(), 1 << 20, HashMap::new());
+    client.write_all(&metadata.to_bytes()).await.unwrap();
+    let chunk = Transmission::Chunk("gone.bin".to_string(), vec![1; 1024]);
+    client.write_all(&chunk.to_bytes()).await.unwrap();
+    let staged = format!("{}/gone_from/gone_to/gone.bin", config.staging_dir);
+    while !Path::new(&transfers::partial_path(&staged)).exists() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    drop(client);
+
+    let err = handling.await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof, "{}", err);
+    assert!(pending(&state, "gone_to").await.is_empty());
+    assert!(!Path::new(&staged).exists());
+    assert!(!Path::new(&transfers::partial_path(&staged)).exists());
+}
+
+#[tokio::test]
+async fn peek_delivers_exactly_the_bytes_asked_for() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "peek_to", user()).await;
+    stage(
+        &state,
+        &config,
+        "peek_from",
+        "peek_to",
+        "long.txt",
+        "0123456789abcdef",
+    )
+    .await;
+
+    let (peek, _) = Command::parse_with_trailer("peek @peek_from long.txt 10").unwrap();
+    let (response, file) = handle(&state, &config, "peek_to", peek).await;
+    assert!(matches!(response, Transmission::OkSuccess));
+    assert_eq!(
+        file,
+        Some(("long.txt".to_string(), "0123456789".to_string()))
+    );
+    // Only a preview, so it's still waiting
+    assert_eq!(pending(&state, "peek_to").await, ["peek_from/long.txt"]);
+}
+
+#[tokio::test]
+async fn a_plain_list_too_long_for_one_answer_says_so() {
+    let state = state::new_state();
+    let list = Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    };
+    for i in 0..LIST_PAGE_SIZE {
+        state::insert_user(&state, &format!("crowd{:04}", i), user()).await;
+    }
+    // Everyone else fits, so it's answered as it always was
+    let answer = list.execute(&state, "crowd0000").await;
+    assert!(
+        matches!(answer, Transmission::ConnectedUsers(ref users) if users.len() == LIST_PAGE_SIZE as usize - 1),
+        "{:?}",
+        answer
+    );
+
+    state::insert_user(&state, "crowd_extra", user()).await;
+    state::insert_user(&state, "crowd_more", user()).await;
+    let answer = list.execute(&state, "crowd0000").await;
+    let Transmission::UsersPage { total, users } = answer else {
+        panic!("Expected a page, got {:?}", answer);
+    };
+    assert_eq!(total, LIST_PAGE_SIZE as u32 + 1);
+    assert_eq!(users.len(), LIST_PAGE_SIZE as usize);
+}
+
+#[tokio::test]
+async fn purge_clears_the_inbox_and_leaves_everything_else() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "purge_to", user()).await;
+    state::insert_user(&state, "purge_other", user()).await;
+    stage(&state, &config, "purge_from", "purge_to", "a.txt", "A").await;
+    stage(&state, &config, "purge_also", "purge_to", "b.txt", "B").await;
+    stage(
+        &state,
+        &config,
+        "purge_from",
+        "purge_other",
+        "theirs.txt",
+        "T",
+    )
+    .await;
+    // Staged with no request left pointing at it
+    std::fs::write(in_staging(&config, "purge_from/purge_to/stray.txt"), "S").unwrap();
+    // Still on its way in
+    let arriving =
+        transfers::partial_path(&format!("{}/purge_also/purge_to/c.txt", config.staging_dir));
+    std::fs::write(&arriving, "C").unwrap();
+
+    let purged = Command::Purge
+        .execute_with_config(&state, "purge_to", &config)
+        .await;
+    assert!(matches!(purged, Transmission::Purged(3)), "{:?}", purged);
+    assert!(pending(&state, "purge_to").await.is_empty());
+    let left = |dir: PathBuf| -> Vec<String> {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect()
+    };
+    assert!(left(in_staging(&config, "purge_from/purge_to")).is_empty());
+    assert_eq!(
+        left(in_staging(&config, "purge_also/purge_to")),
+        [format!("c.txt{}", PARTIAL_SUFFIX)]
+    );
+
+    // Nobody else's glides are touched
+    assert_eq!(
+        pending(&state, "purge_other").await,
+        ["purge_from/theirs.txt"]
+    );
+    assert_eq!(
+        std::fs::read_to_string(in_staging(&config, "purge_from/purge_other/theirs.txt")).unwrap(),
+        "T"
+    );
+}
+
+#[tokio::test]
+async fn a_delivery_cancelled_by_its_sender_leaves_no_partial_file() {
+    let state = state::new_state();
+    let config = common::scratch_config();
+    state::insert_user(&state, "halt_to", user()).await;
+    // Big enough that the delivery blocks once the socket's buffers fill
+    let big = "x".repeat(32 * 1024 * 1024);
+    stage(&state, &config, "halt_from", "halt_to", "big.bin", &big).await;
+
+    let (mut server, mut client) = connected().await;
+    let handling = {
+        let (state, config) = (state.clone(), config.clone());
+        tokio::spawn(async move {
+            let ok = Command::Ok("halt_from".to_string(), None);
+            Command::handle_with_config(ok, "halt_to", &mut server, &state, &config).await
+        })
+    };
+    assert!(matches!(
+        Transmission::from_stream(&mut client).await.unwrap(),
+        Transmission::OkSuccess
+    ));
+    // The file's on its way once anything of it is
+    client.peek(&mut [0; 1]).await.unwrap();
+
+    let cancel = Command::Cancel("big.bin".to_string());
+    let response = cancel
+        .execute_with_config(&state, "halt_from", &config)
+        .await;
+    assert!(
+        matches!(response, Transmission::TransferCancelled(ref filename) if filename == "big.bin"),
+        "{:?}",
+        response
+    );
+
+    let save =
+        std::env::temp_dir().join(format!("glide-utils-tests-{}-halted", std::process::id()));
+    let _ = std::fs::remove_dir_all(&save);
+    std::fs::create_dir_all(&save).unwrap();
+    let err = transfers::receive_file(&mut client, save.to_str().unwrap())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::Interrupted, "{}", err);
+    handling.await.unwrap().unwrap();
+
+    assert_eq!(std::fs::read_dir(&save).unwrap().count(), 0);
+    assert!(pending(&state, "halt_to").await.is_empty());
+    assert!(!in_staging(&config, "halt_from/halt_to/big.bin").exists());
+}
+
+#[tokio::test]
+async fn glides_of_files_the_server_doesnt_accept_are_refused() {
+    let state = state::new_state();
+    let config = ServerConfig {
+        extensions: ExtensionPolicy::Deny(vec!["exe".to_string()]),
+        ..ServerConfig::default()
+    };
+    state::insert_user(&state, "picky_to", user()).await;
+    let glide = |path: &str, as_name: Option<&str>| Command::Glide {
+        path: path.to_string(),
+        to: "picky_to".to_string(),
+        as_name: as_name.map(str::to_string),
+    };
+
+    for (path, as_name) in [
+        ("setup.exe", None),
+        ("setup.EXE. ", None),
+        ("notes.txt", Some("notes.exe")),
+    ] {
+        let refused = glide(path, as_name)
+            .execute_with_config(&state, "picky_from", &config)
+            .await;
+        assert!(
+            matches!(refused, Transmission::GlideRefused(ref reason) if reason.contains("doesn't accept")),
+            "{} as {:?}: {:?}",
+            path,
+            as_name,
+            refused
+        );
+    }
+    assert!(pending(&state, "picky_to").await.is_empty());
+
+    assert!(matches!(
+        glide("setup.exe", Some("setup.txt"))
+            .execute_with_config(&state, "picky_from", &config)
+            .await,
+        Transmission::GlideRequestSent
+    ));
+    assert_eq!(
+        pending(&state, "picky_to").await,
+        vec!["picky_from/setup.txt"]
+    );
+}
+
+#[tokio::test]
+async fn each_command_is_recorded_under_its_name() {
+    let state = state::new_state();
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let config = ServerConfig {
+        metrics: Some(CommandMetrics::new({
+            let recorded = recorded.clone();
+            move |command, _| recorded.lock().unwrap().push(command.to_string())
+        })),
+        ..ServerConfig::default()
+    };
+    let addr = common::serve(&state, &config).await;
+    let mut client = common::log_in(addr, "metered").await;
+
+    let list = Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    };
+    for command in [list, Command::Requests, Command::NoLatest] {
+        send(&mut client, command).await;
+    }
+    // Tagged ones run elsewhere, and are recorded all the same
+    let tagged = Transmission::Tagged(1, Box::new(Transmission::Command(Command::Whoami)));
+    client.write_all(&tagged.to_bytes()).await.unwrap();
+    Transmission::from_stream(&mut client).await.unwrap();
+
+    assert_eq!(*recorded.lock().unwrap(), ["list", "reqs", "no", "whoami"]);
+}
+
+#[tokio::test]
+async fn a_refusal_whose_cleanup_fails_still_goes_through_and_is_audited() {
+    let log = std::env::temp_dir().join(format!(
+        "glide-utils-tests-{}-cleanup.log",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&log);
+    let state = state::new_state();
+    let config = ServerConfig {
+        audit: Some(AuditLogger::new(log.to_str().unwrap())),
+        ..common::scratch_config()
+    };
+    state::insert_user(&state, "stuck_to", user()).await;
+    stage(
+        &state,
+        &config,
+        "stuck_from",
+        "stuck_to",
+        "stuck.txt",
+        "unremovable",
+    )
+    .await;
+    // Somewhere the staged file can't be reached to be removed, even by root
+    std::fs::remove_dir_all(in_staging(&config, "stuck_from/stuck_to")).unwrap();
+    std::fs::write(
+        in_staging(&config, "stuck_from/stuck_to"),
+        "not a directory",
+    )
+    .unwrap();
+
+    let no = Command::No("stuck_from".to_string(), Some("stuck.txt".to_string()));
+    let response = no.execute_with_config(&state, "stuck_to", &config).await;
+    assert!(
+        matches!(response, Transmission::NoSuccess),
+        "{:?}",
+        response
+    );
+    assert!(pending(&state, "stuck_to").await.is_empty());
+
+    let line = loop {
+        let written = std::fs::read_to_string(&log).unwrap_or_default();
+        if let Some(line) = written.lines().next() {
+            break line.to_string();
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let fields = audit_fields(&line);
+    assert_eq!(fields["sender"], "stuck_from");
+    assert_eq!(fields["recipient"], "stuck_to");
+    assert_eq!(fields["filename"], "stuck.txt");
+    assert_eq!(fields["outcome"], "cleanup-failed");
+}
+
+#[tokio::test]
+async fn reqs_with_more_pending_than_can_be_counted_says_so() {
+    let state = state::new_state();
+    let request = Request {
+        sender: "swamp_from".to_string(),
+        filename: "a.txt".to_string(),
+        size: 1,
+        offered_at: SystemTime::now(),
+    };
+    let swamped = UserData {
+        incoming_requests: vec![request; u16::MAX as usize + 1],
+        ..user()
+    };
+    state::insert_user(&state, "swamped", swamped).await;
+
+    let response = Command::Requests.execute(&state, "swamped").await;
+    assert!(
+        matches!(response, Transmission::Error(ref message) if message.contains("Too many")),
+        "{:?}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn reqs_for_a_user_whos_gone_is_answered_not_panicked_on() {
+    let state = state::new_state();
+    let response = Command::Requests.execute(&state, "never_here").await;
+    assert!(
+        matches!(response, Transmission::UsernameInvalid),
+        "{:?}",
+        response
+    );
+
+    // Or dropped since the command was read
+    state::insert_user(&state, "just_left", user()).await;
+    state::remove_user(&state, "just_left").await;
+    let response = Command::Requests.execute(&state, "just_left").await;
+    assert!(
+        matches!(response, Transmission::UsernameInvalid),
+        "{:?}",
+        response
+    );
+}
+
+#[tokio::test]
+async fn list_with_hundreds_of_users_names_them_all() {
+    let state = state::new_state();
+    for i in 0..300 {
+        state::insert_user(&state, &format!("crowd{}", i), user()).await;
+    }
+    let list = Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    };
+    let response = list.execute(&state, "crowd0").await;
+    let Transmission::ConnectedUsers(users) = response else {
+        panic!("{:?}", response);
+    };
+    assert_eq!(users.len(), 299);
+}
+
+#[tokio::test]
+async fn a_cleaned_up_user_is_no_longer_listed() {
+    let config = common::scratch_config();
+    let state = state::new_state();
+    state::insert_user(&state, "leaving_watcher", user()).await;
+    state::insert_user(&state, "leaving", user()).await;
+    stage(
+        &state,
+        &config,
+        "leaving_from",
+        "leaving",
+        "left.txt",
+        "unread",
+    )
+    .await;
+    let list = Command::List {
+        filter: Some("leaving".to_string()),
+        page: None,
+        page_size: None,
+    };
+    let listed = |response: Transmission| match response {
+        Transmission::UsersPage { users, .. } => users,
+        other => panic!("{:?}", other),
+    };
+    assert_eq!(
+        listed(list.execute(&state, "leaving_watcher").await),
+        ["leaving"]
+    );
+
+    let data = commands::cleanup_user_with(&state, "leaving", &config).await;
+    assert_eq!(data.unwrap().incoming_requests.len(), 1);
+    assert!(listed(list.execute(&state, "leaving_watcher").await).is_empty());
+    assert!(!state::contains_user(&state, "leaving").await);
+    assert!(!in_staging(&config, "leaving_from/leaving/left.txt").exists());
+    // Gone once is gone
+    assert!(commands::cleanup_user_with(&state, "leaving", &config)
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn list_filters_by_name() {
+    let state = state::new_state();
+    for name in ["filter_me", "filter_alice", "filter_alicia", "filter_bob"] {
+        state::insert_user(&state, name, user()).await;
+    }
+    let listed = |filter: &str| {
+        let list = Command::List {
+            filter: Some(filter.to_string()),
+            page: None,
+            page_size: None,
+        };
+        let state = state.clone();
+        async move {
+            match list.execute(&state, "filter_me").await {
+                Transmission::UsersPage { users, total } => {
+                    assert_eq!(users.len() as u32, total);
+                    users
+                }
+                other => panic!("{:?}", other),
+            }
+        }
+    };
+
+    assert_eq!(listed("filter_bob").await, ["filter_bob"]);
+    // Anywhere in the name, whatever the case
+    assert_eq!(listed("ALI").await, ["filter_alice", "filter_alicia"]);
+    assert_eq!(listed("filter_ali?e").await, ["filter_alice"]);
+    assert!(listed("carol").await.is_empty());
+    // Never the one asking
+    assert!(listed("filter_me").await.is_empty());
+}
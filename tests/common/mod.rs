@@ -0,0 +1,197 @@
+// Helpers shared by the integration tests. Each test file only uses some.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use utils::commands::Command;
+use utils::connection;
+use utils::data::{Request, ServerConfig};
+use utils::protocol::Transmission;
+use utils::state::SharedState;
+
+// One of every kind of transmission, in the same order as the vectors in
+// tests/vectors.txt and each as it decodes
+pub fn every_transmission() -> Vec<Transmission> {
+    let commands = vec![
+        Command::List {
+            filter: None,
+            page: None,
+            page_size: None,
+        },
+        Command::List {
+            filter: None,
+            page: Some(2),
+            page_size: Some(50),
+        },
+        Command::List {
+            filter: Some("al*".to_string()),
+            page: Some(0),
+            page_size: None,
+        },
+        Command::Requests,
+        Command::Glide {
+            path: "notes.txt".to_string(),
+            to: "bob".to_string(),
+            as_name: None,
+        },
+        Command::Glide {
+            path: "notes.txt".to_string(),
+            to: "bob".to_string(),
+            as_name: Some("n.txt".to_string()),
+        },
+        Command::Ok("alice".to_string(), None),
+        Command::Ok("alice".to_string(), Some("a.txt".to_string())),
+        Command::No("alice".to_string(), None),
+        Command::No("alice".to_string(), Some("a.txt".to_string())),
+        Command::OkLatest,
+        Command::NoLatest,
+        Command::Capabilities,
+        Command::SetName("carol".to_string()),
+        Command::Key("alice".to_string()),
+        Command::Peek {
+            from: "alice".to_string(),
+            filename: "a.txt".to_string(),
+            bytes: 64,
+        },
+        Command::Purge,
+        Command::Cancel("a.txt".to_string()),
+        Command::GlideMany {
+            path: "a.txt".to_string(),
+            to: vec!["bob".to_string(), "carol".to_string()],
+        },
+        Command::Whoami,
+        Command::GlideDir {
+            path: "photos".to_string(),
+            to: "bob".to_string(),
+        },
+    ];
+
+    let mut transmissions = vec![
+        Transmission::Username("alice".to_string()),
+        Transmission::UsernameOk,
+        Transmission::UsernameTaken,
+        Transmission::UsernameInvalid,
+    ];
+    transmissions.extend(commands.into_iter().map(Transmission::Command));
+    transmissions.extend([
+        Transmission::GlideRequestSent,
+        Transmission::Metadata("a.txt".to_string(), 5, HashMap::new()),
+        Transmission::Metadata(
+            "a.txt".to_string(),
+            5,
+            HashMap::from([("mode".to_string(), "644".to_string())]),
+        ),
+        Transmission::Chunk("a.txt".to_string(), b"hello".to_vec()),
+        Transmission::ConnectedUsers(vec!["alice".to_string(), "bob".to_string()]),
+        Transmission::IncomingRequests(vec![Request {
+            sender: "alice".to_string(),
+            filename: "a.txt".to_string(),
+            // Version 1 carries neither of these
+            size: 0,
+            offered_at: SystemTime::UNIX_EPOCH,
+        }]),
+        Transmission::OkSuccess,
+        Transmission::OkFailed,
+        Transmission::NoSuccess,
+        Transmission::ClientDisconnected,
+        Transmission::RequestOutcome("bob".to_string(), "a.txt".to_string(), true),
+        Transmission::Capabilities(vec!["list".to_string(), "glide".to_string()]),
+        Transmission::Abort("too big".to_string()),
+        Transmission::GlideRefused("a.txt".to_string()),
+        Transmission::Error("no such user".to_string()),
+        Transmission::PublicKey("alice".to_string(), [7; 32]),
+        Transmission::Ping,
+        Transmission::Pong,
+        Transmission::ChunkAt("a.txt".to_string(), 1024, b"hi".to_vec()),
+        Transmission::Purged(3),
+        Transmission::RangeRequest {
+            filename: "a.txt".to_string(),
+            start: 1024,
+            len: 4096,
+        },
+        Transmission::Tagged(42, Box::new(Transmission::Ping)),
+        Transmission::Busy {
+            retry_after_secs: 30,
+        },
+        Transmission::Version(3),
+        Transmission::Digest("a.txt".to_string(), [0xab; 32]),
+        Transmission::ResumeFrom("a.txt".to_string(), 4),
+        Transmission::TransferCancelled("a.txt".to_string()),
+        Transmission::GlideRequestsSent {
+            unknown: vec!["dave".to_string()],
+        },
+        Transmission::UserStatus {
+            username: "alice".to_string(),
+            pending: 2,
+        },
+        Transmission::Directory {
+            name: "photos".to_string(),
+            files: 12,
+        },
+        Transmission::UsersPage {
+            total: 3,
+            users: vec!["alice".to_string(), "bob".to_string()],
+        },
+    ]);
+    transmissions
+}
+
+// Serves every connection to the address it returns as the server would,
+// sharing `state` and `config`, until the test ends
+pub async fn serve(state: &SharedState, config: &ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (state, config) = (state.clone(), config.clone());
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (state, config) = (state.clone(), config.clone());
+            tokio::spawn(async move {
+                let _ =
+                    connection::run_with_config(stream, &state, &config, std::future::pending())
+                        .await;
+            });
+        }
+    });
+    addr
+}
+
+// Connects and logs in as `username`, which has to be free
+pub async fn log_in(addr: SocketAddr, username: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let login = Transmission::Username(username.to_string());
+    stream.write_all(&login.to_bytes()).await.unwrap();
+    let answer = Transmission::from_stream(&mut stream).await.unwrap();
+    assert!(matches!(answer, Transmission::UsernameOk), "{:?}", answer);
+    stream
+}
+
+// An empty directory of the test's own, named after `name`, so tests
+// running side by side never share one or need to change the working
+// directory
+pub fn scratch_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "glide-utils-tests-{}-{}-{}",
+        std::process::id(),
+        name,
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// The default config, staging glides in a scratch directory
+pub fn scratch_config() -> ServerConfig {
+    ServerConfig {
+        staging_dir: scratch_dir("staging").to_str().unwrap().to_string(),
+        ..ServerConfig::default()
+    }
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use std::io::Cursor;
+
+use utils::protocol::Transmission;
+
+// One line per vector in tests/vectors.txt: the bytes in hex, a tab, and
+// the value they decode to
+const VECTORS: &str = include_str!("vectors.txt");
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+fn vectors() -> Vec<(&'static str, &'static str)> {
+    VECTORS
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.split_once('\t').unwrap())
+        .collect()
+}
+
+#[test]
+fn every_transmission_has_a_vector() {
+    let transmissions = common::every_transmission();
+    assert_eq!(transmissions.len(), vectors().len());
+
+    let mut control_bytes: Vec<u8> = transmissions.iter().map(|t| t.control_byte()).collect();
+    control_bytes.sort();
+    control_bytes.dedup();
+    assert_eq!(control_bytes, (0x1..=0x24).collect::<Vec<u8>>());
+}
+
+#[test]
+fn transmissions_encode_to_their_vectors() {
+    for (transmission, (bytes, decoded)) in common::every_transmission().iter().zip(vectors()) {
+        assert_eq!(format!("{:?}", transmission), decoded);
+        assert_eq!(hex(&transmission.to_bytes()), bytes, "{}", decoded);
+    }
+}
+
+#[tokio::test]
+async fn vectors_decode_to_their_transmissions() {
+    for (bytes, decoded) in vectors() {
+        let bytes = unhex(bytes);
+        let mut stream = Cursor::new(bytes.as_slice());
+        let transmission = Transmission::from_stream(&mut stream).await.unwrap();
+        assert_eq!(format!("{:?}", transmission), decoded);
+        // Nothing is left over but padding
+        let rest = &bytes[stream.position() as usize..];
+        assert!(rest.iter().all(|&b| b == 0), "{}", decoded);
+        assert_eq!(transmission.to_bytes(), bytes, "{}", decoded);
+    }
+}
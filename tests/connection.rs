@@ -0,0 +1,332 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{BoxFuture, FutureExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use utils::commands::Command;
+use utils::connection::{self, ConnState, InvalidTransition, Socket};
+use utils::data::{KeepAlive, ServerConfig};
+use utils::protocol::{ProtocolVersion, Transmission};
+use utils::state;
+
+#[test]
+fn a_connection_advances_through_its_states() {
+    let conn = ConnState::Unauthenticated;
+    assert_eq!(conn.username(), None);
+    let conn = conn.register("alice".to_string()).unwrap();
+    assert_eq!(conn, ConnState::Registered("alice".to_string()));
+    let conn = conn.begin_transfer().unwrap();
+    assert_eq!(conn, ConnState::Transferring("alice".to_string()));
+    assert_eq!(conn.username(), Some("alice"));
+    let conn = conn.finish_transfer().unwrap();
+    let conn = conn.rename("alicia".to_string()).unwrap();
+    assert_eq!(conn, ConnState::Registered("alicia".to_string()));
+}
+
+#[test]
+fn invalid_transitions_are_refused() {
+    let refused = |result: Result<ConnState, InvalidTransition>| result.unwrap_err().attempted;
+
+    assert_eq!(
+        refused(ConnState::Unauthenticated.begin_transfer()),
+        "begin a transfer"
+    );
+    assert_eq!(
+        refused(ConnState::Unauthenticated.rename("bob".to_string())),
+        "rename"
+    );
+    assert_eq!(
+        refused(ConnState::Registered("bob".to_string()).register("bob".to_string())),
+        "register"
+    );
+    assert_eq!(
+        refused(ConnState::Registered("bob".to_string()).finish_transfer()),
+        "finish a transfer"
+    );
+    // Not while a file's on its way
+    let transferring = ConnState::Transferring("bob".to_string());
+    assert_eq!(
+        refused(transferring.clone().begin_transfer()),
+        "begin a transfer"
+    );
+    assert_eq!(refused(transferring.rename("rob".to_string())), "rename");
+}
+
+#[tokio::test]
+async fn a_command_before_registering_is_refused() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    let state = state::new_state();
+
+    let command = Transmission::Command(Command::Requests);
+    client.write_all(&command.to_bytes()).await.unwrap();
+    let err = connection::run(server, &state, std::future::pending())
+        .await
+        .unwrap_err();
+    let refused = err.downcast_ref::<InvalidTransition>().unwrap();
+    assert_eq!(refused.state, ConnState::Unauthenticated);
+    assert_eq!(refused.attempted, "run a command");
+
+    // Hung up on without an answer
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+    assert!(state::usernames(&state).await.is_empty());
+}
+
+// Logs in as `username`, returning what the server said
+async fn register(addr: SocketAddr, username: &str) -> Transmission {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let login = Transmission::Username(username.to_string());
+    stream.write_all(&login.to_bytes()).await.unwrap();
+    let answer = Transmission::from_stream(&mut stream).await.unwrap();
+    // Stay connected so the name stays taken
+    tokio::spawn(async move {
+        let _ = stream.read_to_end(&mut Vec::new()).await;
+    });
+    answer
+}
+
+// Run with `--features sharded-state` too, where the names live in shards
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn only_one_of_two_identical_registrations_wins() {
+    let state = state::new_state();
+    let addr = common::serve(&state, &ServerConfig::default()).await;
+
+    for round in 0..50 {
+        let username = format!("racer{}", round);
+        let (one, two) = tokio::join!(register(addr, &username), register(addr, &username));
+        let won = [&one, &two]
+            .iter()
+            .filter(|answer| matches!(answer, Transmission::UsernameOk))
+            .count();
+        let lost = [&one, &two]
+            .iter()
+            .filter(|answer| matches!(answer, Transmission::UsernameTaken))
+            .count();
+        assert_eq!((won, lost), (1, 1), "{:?} {:?}", one, two);
+    }
+    assert_eq!(state::usernames(&state).await.len(), 50);
+}
+
+#[tokio::test]
+async fn tagged_commands_are_each_answered_under_their_tag() {
+    let state = state::new_state();
+    let addr = common::serve(&state, &ServerConfig::default()).await;
+    let _other = common::log_in(addr, "pipe_other").await;
+    let mut client = common::log_in(addr, "pipe_user").await;
+
+    // Both sent before either is answered
+    let list = Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    };
+    let mut sent = Transmission::Tagged(1, Box::new(Transmission::Command(list))).to_bytes();
+    sent.extend(
+        Transmission::Tagged(2, Box::new(Transmission::Command(Command::Requests))).to_bytes(),
+    );
+    client.write_all(&sent).await.unwrap();
+
+    let mut answers = HashMap::new();
+    for _ in 0..2 {
+        let answer = Transmission::from_stream(&mut client).await.unwrap();
+        let Transmission::Tagged(id, answer) = answer else {
+            panic!("Expected a tagged answer, got {:?}", answer);
+        };
+        assert!(
+            answers.insert(id, *answer).is_none(),
+            "{} answered twice",
+            id
+        );
+    }
+    assert!(
+        matches!(&answers[&1], Transmission::ConnectedUsers(users) if users == &["pipe_other"]),
+        "{:?}",
+        answers
+    );
+    assert!(
+        matches!(&answers[&2], Transmission::IncomingRequests(requests) if requests.is_empty()),
+        "{:?}",
+        answers
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_user_who_stops_answering_pings_is_dropped() {
+    let state = state::new_state();
+    let config = ServerConfig {
+        keepalive: Some(KeepAlive {
+            interval: Duration::from_secs(30),
+            max_missed: 2,
+        }),
+        ..ServerConfig::default()
+    };
+    let addr = common::serve(&state, &config).await;
+    let mut quiet = common::log_in(addr, "quiet_user").await;
+    let list = Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    };
+    let listed = list.execute(&state, "quiet_watcher").await;
+    assert!(
+        matches!(listed, Transmission::ConnectedUsers(ref users) if users == &["quiet_user"]),
+        "{:?}",
+        listed
+    );
+
+    // Pinged, never answering, until the server gives up
+    let mut pings = 0;
+    while let Ok(Transmission::Ping) = Transmission::from_stream(&mut quiet).await {
+        pings += 1;
+    }
+    assert_eq!(pings, 2);
+    let listed = list.execute(&state, "quiet_watcher").await;
+    assert!(
+        matches!(listed, Transmission::ConnectedUsers(ref users) if users.is_empty()),
+        "{:?}",
+        listed
+    );
+}
+
+// A socket that holds on to what's written until it's flushed, as a TLS
+// stream or a `BufWriter` does
+struct Buffered(BufWriter<TcpStream>);
+
+impl AsyncRead for Buffered {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Buffered {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl Socket for Buffered {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        self.0.get_ref().peer_addr()
+    }
+
+    fn readable(&mut self) -> BoxFuture<'_, std::io::Result<()>> {
+        self.0.get_mut().readable().boxed()
+    }
+}
+
+// Nothing else is written on the connection, so an answer left in the
+// buffer would never come
+async fn answer(client: &mut TcpStream, version: ProtocolVersion) -> Transmission {
+    tokio::time::timeout(
+        Duration::from_secs(5),
+        Transmission::from_stream_for(client, version),
+    )
+    .await
+    .expect("answered without anything more being written")
+    .unwrap()
+}
+
+// The next answer that isn't the server checking on us
+async fn reply(client: &mut TcpStream, version: ProtocolVersion) -> Transmission {
+    loop {
+        match answer(client, version).await {
+            Transmission::Ping => continue,
+            other => return other,
+        }
+    }
+}
+
+#[tokio::test]
+async fn answers_over_a_buffered_socket_arrive_without_more_being_written() {
+    let state = state::new_state();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (server, _) = listener.accept().await.unwrap();
+    tokio::spawn({
+        let state = state.clone();
+        async move {
+            let server = Buffered(BufWriter::new(server));
+            let config = ServerConfig {
+                keepalive: Some(KeepAlive {
+                    interval: Duration::from_millis(200),
+                    max_missed: 50,
+                }),
+                ..ServerConfig::default()
+            };
+            let _ =
+                connection::run_with_config(server, &state, &config, std::future::pending()).await;
+        }
+    });
+
+    client
+        .write_all(&Transmission::Version(3).to_bytes())
+        .await
+        .unwrap();
+    let offer = reply(&mut client, ProtocolVersion::V1).await;
+    assert!(matches!(offer, Transmission::Version(3)), "{:?}", offer);
+    let v3 = ProtocolVersion::V3;
+
+    let login = Transmission::Username("buffered_user".to_string());
+    client.write_all(&login.to_bytes_for(v3)).await.unwrap();
+    let registered = reply(&mut client, v3).await;
+    assert!(
+        matches!(registered, Transmission::UsernameOk),
+        "{:?}",
+        registered
+    );
+
+    let list = Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    };
+    client
+        .write_all(&Transmission::Command(list).to_bytes_for(v3))
+        .await
+        .unwrap();
+    let listed = reply(&mut client, v3).await;
+    assert!(
+        matches!(listed, Transmission::ConnectedUsers(_)),
+        "{:?}",
+        listed
+    );
+
+    client
+        .write_all(&Transmission::Ping.to_bytes_for(v3))
+        .await
+        .unwrap();
+    let pong = reply(&mut client, v3).await;
+    assert!(matches!(pong, Transmission::Pong), "{:?}", pong);
+
+    // And the server's own pings
+    assert!(matches!(answer(&mut client, v3).await, Transmission::Ping));
+}
@@ -0,0 +1,94 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use utils::audit::hex;
+use utils::dedup::{self, BLOB_DIR};
+
+// Writes `contents` to `path` under the staging directory `root`, returning
+// the blob it's stored as
+fn stage(root: &Path, path: &str, contents: &str) -> ([u8; 32], PathBuf) {
+    let path = root.join(path);
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, contents).unwrap();
+    let hash: [u8; 32] = Sha256::digest(contents).into();
+    (hash, root.join(BLOB_DIR).join(hex(&hash)))
+}
+
+#[tokio::test]
+async fn the_same_file_staged_twice_is_stored_once() {
+    let root = common::scratch_dir("dedup");
+    let mut blobs = Vec::new();
+    for to in ["first", "second"] {
+        let staged = format!("sender/{}/same.txt", to);
+        let (hash, blob) = stage(&root, &staged, "same contents");
+        dedup::store(&root, &root.join(&staged), hash)
+            .await
+            .unwrap();
+        blobs.push(blob);
+    }
+    assert_eq!(blobs[0], blobs[1]);
+    let blob = &blobs[0];
+    assert_eq!(std::fs::read_to_string(blob).unwrap(), "same contents");
+    let second = root.join("sender/second/same.txt");
+    assert_eq!(std::fs::read_to_string(&second).unwrap(), "same contents");
+
+    // Kept while anyone still has it
+    dedup::release(&root, &root.join("sender/first/same.txt"))
+        .await
+        .unwrap();
+    assert!(blob.exists());
+    assert_eq!(std::fs::read_to_string(&second).unwrap(), "same contents");
+
+    dedup::release(&root, &second).await.unwrap();
+    #[cfg(unix)]
+    assert!(!blob.exists());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_release_leaves_other_blobs_alone() {
+    let root = common::scratch_dir("dedup");
+    let mine = root.join("alone/to/mine.txt");
+    let (hash, blob) = stage(&root, "alone/to/mine.txt", "released");
+    dedup::store(&root, &mine, hash).await.unwrap();
+    // A blob with no staged file, as one being stored elsewhere briefly is
+    let (_, other) = stage(&root, "alone/to/other.txt", "not released");
+    std::fs::create_dir_all(root.join(BLOB_DIR)).unwrap();
+    std::fs::rename(root.join("alone/to/other.txt"), &other).unwrap();
+
+    dedup::release(&root, &mine).await.unwrap();
+    assert!(!blob.exists());
+    assert_eq!(std::fs::read_to_string(&other).unwrap(), "not released");
+}
+
+// Storing a file whose blob was just released never loses the file, however
+// the two interleave
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn storing_while_the_blob_is_released_keeps_the_file() {
+    let root = common::scratch_dir("dedup");
+    for round in 0..200 {
+        let contents = format!("racing {}", round);
+        let (old, new) = (
+            format!("race/old/{}.txt", round),
+            format!("race/new/{}.txt", round),
+        );
+        let (hash, _) = stage(&root, &old, &contents);
+        stage(&root, &new, &contents);
+        let (old, new) = (root.join(old), root.join(new));
+        dedup::store(&root, &old, hash).await.unwrap();
+
+        let releasing = {
+            let root = root.clone();
+            tokio::spawn(async move { dedup::release(&root, &old).await })
+        };
+        let storing = {
+            let (root, new) = (root.clone(), new.clone());
+            tokio::spawn(async move { dedup::store(&root, &new, hash).await })
+        };
+        releasing.await.unwrap().unwrap();
+        storing.await.unwrap().unwrap();
+        assert_eq!(std::fs::read_to_string(&new).unwrap(), contents);
+    }
+}
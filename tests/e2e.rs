@@ -0,0 +1,70 @@
+#![cfg(feature = "e2e")]
+
+use std::path::PathBuf;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use utils::e2e::KeyPair;
+use utils::transfers::{self, ReceiveOptions, SendOptions};
+
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("glide-utils-e2e-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Sends `path` sealed to `to`, returning everything that went over the wire
+// as a relay in the middle would see it
+async fn relayed(path: &str, to: &KeyPair) -> Vec<u8> {
+    let (mut sender, mut relay) = tokio::io::duplex(1 << 20);
+    let options = SendOptions {
+        encrypt_to: Some(to.public),
+        ..SendOptions::default()
+    };
+    transfers::send_file_with(&mut sender, path, &options)
+        .await
+        .unwrap();
+    drop(sender);
+    let mut seen = Vec::new();
+    relay.read_to_end(&mut seen).await.unwrap();
+    seen
+}
+
+// Receives what the relay saw as the holder of `keys`
+async fn receive(seen: &[u8], save: &str, keys: &KeyPair) -> std::io::Result<Vec<u8>> {
+    let (mut relay, mut receiver) = tokio::io::duplex(1 << 20);
+    relay.write_all(seen).await.unwrap();
+    let options = ReceiveOptions {
+        decrypt_with: Some(keys),
+        ..ReceiveOptions::default()
+    };
+    let received = transfers::receive_file_with(&mut receiver, save, &options).await?;
+    Ok(std::fs::read(format!("{}/{}", save, received.filename)).unwrap())
+}
+
+#[tokio::test]
+async fn only_the_recipient_can_read_a_sealed_file() {
+    let dir = scratch("sealed");
+    let plaintext = "attack at dawn, bring snacks. ".repeat(100);
+    let source = dir.join("plans.txt");
+    std::fs::write(&source, &plaintext).unwrap();
+    let bob = KeyPair::generate();
+
+    let seen = relayed(source.to_str().unwrap(), &bob).await;
+    // Nothing of the contents is visible on the way
+    assert!(!seen
+        .windows(b"attack at dawn".len())
+        .any(|window| window == b"attack at dawn"));
+
+    let save = dir.join("bob");
+    std::fs::create_dir_all(&save).unwrap();
+    let opened = receive(&seen, save.to_str().unwrap(), &bob).await.unwrap();
+    assert_eq!(opened, plaintext.as_bytes());
+
+    // Nor can anyone else open it
+    let save = dir.join("eve");
+    std::fs::create_dir_all(&save).unwrap();
+    let eve = KeyPair::generate();
+    assert!(receive(&seen, save.to_str().unwrap(), &eve).await.is_err());
+    assert!(!save.join("plans.txt").exists());
+}
@@ -0,0 +1,41 @@
+use std::time::{Duration, SystemTime};
+
+use utils::data::{Request, RequestExpiry, ServerConfig, UserData};
+use utils::expiry;
+use utils::state::{self, SharedState};
+
+async fn waiting(state: &SharedState, username: &str) -> usize {
+    state::with_user(state, username, |client| client.incoming_requests.len())
+        .await
+        .unwrap()
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_glide_expires_once_its_ttl_has_passed_on_tokios_clock() {
+    let state = state::new_state();
+    let request = Request {
+        sender: "expiry_from".to_string(),
+        filename: "never-staged.txt".to_string(),
+        size: 0,
+        offered_at: SystemTime::now(),
+    };
+    let user = UserData {
+        socket: String::new(),
+        incoming_requests: vec![request],
+        public_key: None,
+    };
+    state::insert_user(&state, "expiry_to", user).await;
+
+    let expiry = RequestExpiry {
+        ttl: Duration::from_secs(60),
+        interval: Duration::from_secs(10),
+    };
+    let reaper = expiry::spawn_request_reaper_with(state.clone(), expiry, ServerConfig::default());
+
+    tokio::time::sleep(Duration::from_secs(55)).await;
+    assert_eq!(waiting(&state, "expiry_to").await, 1);
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    assert_eq!(waiting(&state, "expiry_to").await, 0);
+    reaper.abort();
+}
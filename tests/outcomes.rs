@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use utils::outcomes::Outcomes;
+use utils::protocol::{ProtocolVersion, Transmission};
+
+// Everything `flush` sends for `username`
+async fn flushed(outcomes: &Outcomes, username: &str) -> Vec<String> {
+    let mut sent = Vec::new();
+    outcomes
+        .flush(&mut sent, username, ProtocolVersion::V1)
+        .await
+        .unwrap();
+
+    let mut sent = sent.as_slice();
+    let mut filenames = Vec::new();
+    while !sent.is_empty() {
+        let Transmission::RequestOutcome(_, filename, _) =
+            Transmission::from_stream(&mut sent).await.unwrap()
+        else {
+            panic!("Expected an outcome");
+        };
+        filenames.push(filename);
+    }
+    filenames
+}
+
+#[tokio::test(start_paused = true)]
+async fn an_outcome_expires() {
+    let outcomes = Outcomes::new(Duration::from_secs(60), 10, 10);
+    outcomes.queue("sender", "recipient", "old.txt", true);
+    tokio::time::advance(Duration::from_secs(45)).await;
+    outcomes.queue("sender", "recipient", "new.txt", false);
+    tokio::time::advance(Duration::from_secs(30)).await;
+
+    assert_eq!(flushed(&outcomes, "sender").await, ["new.txt"]);
+    assert!(flushed(&outcomes, "sender").await.is_empty());
+}
+
+#[tokio::test]
+async fn outcomes_past_the_cap_drop_the_oldest() {
+    let outcomes = Outcomes::new(Duration::from_secs(60), 2, 1);
+    for filename in ["a.txt", "b.txt", "c.txt"] {
+        outcomes.queue("sender", "recipient", filename, true);
+    }
+    // No room for anyone else's
+    outcomes.queue("someone_else", "recipient", "d.txt", true);
+
+    assert_eq!(flushed(&outcomes, "sender").await, ["b.txt", "c.txt"]);
+    assert!(flushed(&outcomes, "someone_else").await.is_empty());
+}
+
+#[tokio::test]
+async fn clones_share_outcomes_and_new_ones_dont() {
+    let outcomes = Outcomes::default();
+    outcomes.queue("sender", "recipient", "a.txt", true);
+
+    assert!(flushed(&Outcomes::default(), "sender").await.is_empty());
+    assert_eq!(flushed(&outcomes.clone(), "sender").await, ["a.txt"]);
+}
@@ -0,0 +1,403 @@
+use utils::commands::{Command, ParseError};
+
+fn with_trailer(input: &str) -> (String, Option<String>) {
+    let (command, trailer) = Command::parse_with_trailer(input).unwrap();
+    (format!("{:?}", command), trailer)
+}
+
+#[test]
+fn ok_with_trailer_names_the_file() {
+    assert_eq!(
+        with_trailer("ok @alice report.pdf"),
+        (r#"Ok("alice", Some("report.pdf"))"#.to_string(), None)
+    );
+    assert_eq!(
+        with_trailer("ok @alice report.pdf and thanks"),
+        (
+            r#"Ok("alice", Some("report.pdf"))"#.to_string(),
+            Some("and thanks".to_string())
+        )
+    );
+    assert_eq!(
+        with_trailer("ok @alice"),
+        (r#"Ok("alice", None)"#.to_string(), None)
+    );
+}
+
+#[test]
+fn ok_with_trailer_into_a_directory() {
+    assert_eq!(
+        with_trailer("ok @alice into ~/in"),
+        (
+            r#"OkInto { from: "alice", filename: None, into: "~/in" }"#.to_string(),
+            None
+        )
+    );
+    assert_eq!(
+        with_trailer("ok @alice report.pdf into ~/in then more"),
+        (
+            r#"OkInto { from: "alice", filename: Some("report.pdf"), into: "~/in" }"#.to_string(),
+            Some("then more".to_string())
+        )
+    );
+}
+
+#[test]
+fn ok_with_trailer_refuses_a_bad_filename() {
+    assert!(matches!(
+        Command::parse_with_trailer("ok @alice ../etc/passwd"),
+        Err(ParseError::InvalidFilename(name)) if name == "../etc/passwd"
+    ));
+}
+
+#[test]
+fn no_with_trailer_names_the_file() {
+    assert_eq!(
+        with_trailer("no @alice file.txt"),
+        (r#"No("alice", Some("file.txt"))"#.to_string(), None)
+    );
+    assert_eq!(
+        with_trailer("no @alice file.txt not today"),
+        (
+            r#"No("alice", Some("file.txt"))"#.to_string(),
+            Some("not today".to_string())
+        )
+    );
+    assert_eq!(
+        with_trailer("no @alice"),
+        (r#"No("alice", None)"#.to_string(), None)
+    );
+}
+
+#[test]
+fn parse_refuses_a_missing_username() {
+    assert!(matches!(
+        Command::parse("ok @@@"),
+        Err(ParseError::MissingUsername)
+    ));
+    assert!(matches!(
+        Command::parse("no @@"),
+        Err(ParseError::MissingUsername)
+    ));
+}
+
+#[test]
+fn parse_refuses_a_bad_username() {
+    for input in ["ok @bad/name", "no @bad/name", "glide f.txt @bob smith"] {
+        assert!(
+            matches!(Command::parse(input), Err(ParseError::InvalidUsername(_))),
+            "{}",
+            input
+        );
+    }
+    assert!(matches!(
+        Command::parse("glide f.txt @alice @bad/name"),
+        Err(ParseError::InvalidUsername(name)) if name == "@bad/name"
+    ));
+}
+
+#[test]
+fn parse_keeps_good_usernames() {
+    assert_eq!(
+        format!("{:?}", Command::parse("ok @alice").unwrap()),
+        r#"Ok("alice", None)"#
+    );
+    assert_eq!(
+        format!("{:?}", Command::parse("glide f.txt @bob").unwrap()),
+        r#"Glide { path: "f.txt", to: "bob", as_name: None }"#
+    );
+}
+
+#[test]
+fn glide_with_trailer_keeps_the_message() {
+    assert_eq!(
+        with_trailer("glide file @bob  see attached"),
+        (
+            r#"Glide { path: "file", to: "bob", as_name: None }"#.to_string(),
+            Some("see attached".to_string())
+        )
+    );
+    assert_eq!(
+        with_trailer("glide file @bob"),
+        (
+            r#"Glide { path: "file", to: "bob", as_name: None }"#.to_string(),
+            None
+        )
+    );
+    assert_eq!(
+        with_trailer("list"),
+        (
+            r#"List { filter: None, page: None, page_size: None }"#.to_string(),
+            None
+        )
+    );
+    assert_eq!(
+        with_trailer("reqs please"),
+        ("Requests".to_string(), Some("please".to_string()))
+    );
+}
+
+#[test]
+fn parse_stays_strict_about_trailing_text() {
+    assert!(Command::parse("reqs please").is_err());
+    assert!(Command::parse("caps now").is_err());
+}
+
+#[test]
+fn glide_glob_expands_to_one_glide_per_file() {
+    let dir = std::env::temp_dir().join(format!("glide-utils-parse-{}-glob", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(dir.join("nested.log")).unwrap();
+    std::fs::write(dir.join("a.log"), b"a").unwrap();
+    std::fs::write(dir.join("b.log"), b"b").unwrap();
+    std::fs::write(dir.join("c.txt"), b"c").unwrap();
+
+    let pattern = format!("{}/*.log", dir.display());
+    let glides = Command::parse(&format!("glide {} @bob", pattern))
+        .unwrap()
+        .expand_glob()
+        .unwrap();
+    let paths: Vec<String> = glides
+        .iter()
+        .map(|glide| match glide {
+            Command::Glide { path, to, .. } => {
+                assert_eq!(to, "bob");
+                path.clone()
+            }
+            other => panic!("expected a glide, got {:?}", other),
+        })
+        .collect();
+    // Directories that happen to match are skipped
+    assert_eq!(
+        paths,
+        vec![
+            dir.join("a.log").display().to_string(),
+            dir.join("b.log").display().to_string()
+        ]
+    );
+
+    let pattern = format!("{}/*.pdf", dir.display());
+    assert_eq!(
+        Command::parse(&format!("glide {} @bob", pattern))
+            .unwrap()
+            .expand_glob()
+            .unwrap_err(),
+        ParseError::NoGlobMatches(pattern)
+    );
+
+    // A plain path is left alone, whether or not it exists
+    assert_eq!(
+        Command::parse("glide missing.log @bob")
+            .unwrap()
+            .expand_glob()
+            .unwrap()
+            .len(),
+        1
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn peek_takes_only_ascii_digits() {
+    assert_eq!(
+        format!("{:?}", Command::parse("peek @bob a.txt 12").unwrap()),
+        r#"Peek { from: "bob", filename: "a.txt", bytes: 12 }"#
+    );
+    // Arabic-Indic digits are digits to `\d`, but not to `parse`
+    assert!(Command::parse("peek @bob a.txt ١٢").is_err());
+    assert!(Command::parse_with_trailer("peek @bob a.txt ١٢").is_err());
+}
+
+#[test]
+fn list_pages_take_only_ascii_digits() {
+    assert_eq!(
+        format!("{:?}", Command::parse("list -p 3 -n 20").unwrap()),
+        "List { filter: None, page: Some(3), page_size: Some(20) }"
+    );
+    for input in ["list -p ٣", "list -n ٣", "list bob -p 1 -n ٢٠"] {
+        assert!(Command::parse(input).is_err(), "{}", input);
+    }
+    // Where anything after the command is a message, the digits are one
+    assert_eq!(
+        with_trailer("list -p ٣"),
+        (
+            "List { filter: None, page: None, page_size: None }".to_string(),
+            Some("-p ٣".to_string())
+        )
+    );
+}
+
+#[test]
+fn nothing_much_is_refused_without_panicking() {
+    for input in [
+        "",
+        " ",
+        "glide",
+        "glide f.txt",
+        "glide f.txt @",
+        "@",
+        "ok @",
+        "no @",
+    ] {
+        assert!(Command::parse(input).is_err(), "{:?}", input);
+        assert!(Command::parse_with_trailer(input).is_err(), "{:?}", input);
+    }
+    assert_eq!(
+        Command::parse("").unwrap_err(),
+        ParseError::UnknownCommand(String::new())
+    );
+    assert!(matches!(
+        Command::parse("glide f.txt"),
+        Err(ParseError::MalformedGlide(_))
+    ));
+    assert_eq!(
+        Command::parse("@").unwrap_err(),
+        ParseError::MissingUsername
+    );
+}
+
+#[test]
+fn glide_as_names_the_file_for_the_recipient() {
+    let expected = r#"Glide { path: "dir/report.txt", to: "bob", as_name: Some("q3.txt") }"#;
+    assert_eq!(
+        format!(
+            "{:?}",
+            Command::parse("glide dir/report.txt @bob as q3.txt").unwrap()
+        ),
+        expected
+    );
+    assert_eq!(
+        with_trailer("glide dir/report.txt @bob as q3.txt"),
+        (expected.to_string(), None)
+    );
+}
+
+#[test]
+fn glide_as_an_unsafe_name_is_refused() {
+    for name in ["../escape.txt", "sub/dir.txt", "back\\slash.txt", "..", "."] {
+        let input = format!("glide report.txt @bob as {}", name);
+        assert!(
+            matches!(Command::parse(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+        assert!(
+            matches!(Command::parse_with_trailer(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn list_takes_a_filter_checked_if_its_a_glob() {
+    assert_eq!(
+        format!("{:?}", Command::parse("list ali").unwrap()),
+        r#"List { filter: Some("ali"), page: None, page_size: None }"#
+    );
+    assert_eq!(
+        with_trailer("list a?i* -p 2"),
+        (
+            r#"List { filter: Some("a?i*"), page: Some(2), page_size: None }"#.to_string(),
+            None
+        )
+    );
+    for input in ["list [ali", "list ali[", "list ["] {
+        assert!(
+            matches!(Command::parse(input), Err(ParseError::InvalidGlob(..))),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn nick_refuses_names_no_one_can_take() {
+    assert_eq!(
+        format!("{:?}", Command::parse("nick alice").unwrap()),
+        r#"SetName("alice")"#
+    );
+    for name in [
+        "admin",
+        "Server",
+        "..",
+        "bad/name",
+        "a_name_far_too_long_to_be_anyones_username",
+    ] {
+        let input = format!("nick {}", name);
+        assert!(
+            matches!(Command::parse(&input), Err(ParseError::InvalidUsername(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+        assert!(
+            matches!(Command::parse_with_trailer(&input), Err(ParseError::InvalidUsername(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn cancel_refuses_what_cant_be_a_staged_file() {
+    assert_eq!(
+        format!("{:?}", Command::parse("cancel report.txt").unwrap()),
+        r#"Cancel("report.txt")"#
+    );
+    for name in ["../x", "sub/dir.txt", "back\\slash.txt", ".."] {
+        let input = format!("cancel {}", name);
+        assert!(
+            matches!(Command::parse(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+        assert!(
+            matches!(Command::parse_with_trailer(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn ok_naming_an_unsafe_file_is_refused() {
+    assert_eq!(
+        format!("{:?}", Command::parse("ok @bob report.txt").unwrap()),
+        r#"Ok("bob", Some("report.txt"))"#
+    );
+    for name in ["../x", "sub/dir.txt", "back\\slash.txt", ".."] {
+        let input = format!("ok @bob {}", name);
+        assert!(
+            matches!(Command::parse(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+        assert!(
+            matches!(Command::parse_with_trailer(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+    }
+}
+
+#[test]
+fn no_naming_an_unsafe_file_is_refused() {
+    assert_eq!(
+        format!("{:?}", Command::parse("no @bob report.txt").unwrap()),
+        r#"No("bob", Some("report.txt"))"#
+    );
+    for name in ["../x", "sub/dir.txt", "back\\slash.txt", ".."] {
+        let input = format!("no @bob {}", name);
+        assert!(
+            matches!(Command::parse(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+        assert!(
+            matches!(Command::parse_with_trailer(&input), Err(ParseError::InvalidFilename(ref bad)) if bad == name),
+            "{}",
+            input
+        );
+    }
+}
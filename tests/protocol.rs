@@ -0,0 +1,414 @@
+mod common;
+
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::time::SystemTime;
+
+use tokio::io::AsyncReadExt;
+use utils::data::Request;
+use utils::error::GlideError;
+use utils::protocol::{ProtocolError, ProtocolVersion, Transmission};
+
+// Whatever follows the control byte: nothing, a little, or too much
+const PAYLOADS: &[&[u8]] = &[
+    &[],
+    &[0],
+    &[0xff],
+    &[0, 0, 0, 0],
+    &[0xff, 0xff, 0xff, 0xff],
+    &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+    b"name\0",
+    b"name",
+    &[0xc3, 0x28, 0],
+    &[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80],
+];
+
+// Any answer is fine, as long as decoding returns one
+async fn decode(bytes: Vec<u8>) {
+    let _ = Transmission::from_stream(&mut Cursor::new(bytes)).await;
+}
+
+#[tokio::test]
+async fn every_control_byte_decodes_without_panicking() {
+    for control in 0..=u8::MAX {
+        for second in 0..=u8::MAX {
+            decode(vec![control, second]).await;
+        }
+        for payload in PAYLOADS {
+            let mut bytes = vec![control];
+            bytes.extend_from_slice(payload);
+            decode(bytes).await;
+        }
+    }
+}
+
+#[tokio::test]
+async fn a_long_chain_of_tags_is_refused() {
+    let mut bytes = Vec::new();
+    for id in 0..100_000u32 {
+        bytes.push(0x1b);
+        bytes.extend_from_slice(&id.to_be_bytes());
+    }
+    assert!(Transmission::from_stream(&mut Cursor::new(bytes))
+        .await
+        .is_err());
+}
+
+// Writes `chunk` as a sender would, flips a byte of its data and reads it back
+async fn corrupted(chunk: Transmission, version: ProtocolVersion) -> GlideError {
+    let mut bytes = Vec::new();
+    chunk.write_to_for(&mut bytes, version).await.unwrap();
+    assert_eq!(bytes, chunk.to_bytes_for(version));
+
+    // The data sits just before the 4 byte checksum
+    let data_end = bytes.len() - 5;
+    bytes[data_end] ^= 0xff;
+    Transmission::from_stream_for(&mut Cursor::new(bytes), version)
+        .await
+        .unwrap_err()
+}
+
+#[tokio::test]
+async fn a_corrupted_chunk_is_refused() {
+    for version in [ProtocolVersion::V2, ProtocolVersion::V3] {
+        let chunk = Transmission::Chunk("a.txt".to_string(), b"hello".to_vec());
+        let err = corrupted(chunk, version).await;
+        assert!(
+            matches!(err, GlideError::ChecksumMismatch { .. }),
+            "{}",
+            err
+        );
+
+        let chunk = Transmission::ChunkAt("a.txt".to_string(), 1024, b"hello".to_vec());
+        let err = corrupted(chunk, version).await;
+        assert!(
+            matches!(err, GlideError::ChecksumMismatch { .. }),
+            "{}",
+            err
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_written_chunk_reads_back() {
+    let chunk = Transmission::Chunk("a.txt".to_string(), b"hello".to_vec());
+    let mut bytes = Vec::new();
+    chunk
+        .write_to_for(&mut bytes, ProtocolVersion::V2)
+        .await
+        .unwrap();
+    let read = Transmission::from_stream_for(&mut Cursor::new(bytes), ProtocolVersion::V2)
+        .await
+        .unwrap();
+    assert!(
+        matches!(read, Transmission::Chunk(ref name, ref data) if name == "a.txt" && data == b"hello")
+    );
+}
+
+#[tokio::test]
+async fn metadata_attributes_round_trip() {
+    let attributes = HashMap::from([
+        ("content-type".to_string(), "text/plain".to_string()),
+        ("description".to_string(), "quarterly numbers".to_string()),
+    ]);
+    for version in [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::V3,
+    ] {
+        let metadata = Transmission::Metadata("q3.txt".to_string(), 42, attributes.clone());
+        let bytes = metadata.to_bytes_for(version);
+        let read = Transmission::from_stream_for(&mut Cursor::new(bytes), version)
+            .await
+            .unwrap();
+        assert!(
+            matches!(read, Transmission::Metadata(ref name, 42, ref read) if name == "q3.txt" && *read == attributes),
+            "{:?}: {:?}",
+            version,
+            read
+        );
+    }
+}
+
+// Has no wildcard arm, so a new variant doesn't build until it's been
+// added here and to the shared list
+fn variant(transmission: &Transmission) -> usize {
+    match transmission {
+        Transmission::Username(_) => 0,
+        Transmission::UsernameOk => 1,
+        Transmission::UsernameTaken => 2,
+        Transmission::UsernameInvalid => 3,
+        Transmission::Command(_) => 4,
+        Transmission::GlideRequestSent => 5,
+        Transmission::Metadata(..) => 6,
+        Transmission::Chunk(..) => 7,
+        Transmission::ConnectedUsers(_) => 8,
+        Transmission::IncomingRequests(_) => 9,
+        Transmission::OkSuccess => 10,
+        Transmission::OkFailed => 11,
+        Transmission::NoSuccess => 12,
+        Transmission::ClientDisconnected => 13,
+        Transmission::RequestOutcome(..) => 14,
+        Transmission::Capabilities(_) => 15,
+        Transmission::Abort(_) => 16,
+        Transmission::GlideRefused(_) => 17,
+        Transmission::Error(_) => 18,
+        Transmission::PublicKey(..) => 19,
+        Transmission::Ping => 20,
+        Transmission::Pong => 21,
+        Transmission::ChunkAt(..) => 22,
+        Transmission::Purged(_) => 23,
+        Transmission::RangeRequest { .. } => 24,
+        Transmission::Tagged(..) => 25,
+        Transmission::Busy { .. } => 26,
+        Transmission::Version(_) => 27,
+        Transmission::Digest(..) => 28,
+        Transmission::ResumeFrom(..) => 29,
+        Transmission::TransferCancelled(_) => 30,
+        Transmission::GlideRequestsSent { .. } => 31,
+        Transmission::UserStatus { .. } => 32,
+        Transmission::Directory { .. } => 33,
+        Transmission::UsersPage { .. } => 34,
+    }
+}
+const VARIANTS: usize = 35;
+
+#[test]
+fn the_shared_list_has_every_kind_of_transmission() {
+    let covered: HashSet<usize> = common::every_transmission().iter().map(variant).collect();
+    assert_eq!(covered, (0..VARIANTS).collect());
+}
+
+#[test]
+fn the_control_byte_is_what_to_bytes_leads_with() {
+    for transmission in common::every_transmission() {
+        let bytes = transmission.to_bytes();
+        assert_eq!(transmission.control_byte(), bytes[0], "{:?}", transmission);
+        // Framed, it comes straight after the length
+        let framed = transmission.to_bytes_for(ProtocolVersion::V2);
+        assert_eq!(transmission.control_byte(), framed[4], "{:?}", transmission);
+        assert_eq!(
+            transmission.is_signal(),
+            bytes.len() == 1,
+            "{:?}",
+            transmission
+        );
+    }
+}
+
+#[tokio::test]
+async fn more_requests_than_the_count_can_say_still_decode() {
+    let request = Request {
+        sender: "alice".to_string(),
+        filename: "a.txt".to_string(),
+        size: 0,
+        offered_at: SystemTime::UNIX_EPOCH,
+    };
+    let requests = vec![request; u16::MAX as usize + 10];
+    let bytes = Transmission::IncomingRequests(requests).to_bytes();
+
+    let mut read = Cursor::new(bytes.clone());
+    let decoded = Transmission::from_stream(&mut read).await.unwrap();
+    assert!(
+        matches!(decoded, Transmission::IncomingRequests(ref requests) if requests.len() == u16::MAX as usize)
+    );
+    // With nothing left over to be taken for the next transmission
+    assert_eq!(read.position() as usize, bytes.len());
+}
+
+// Which field decoding says it was reading when `bytes` ran out
+async fn truncated_field(bytes: &[u8], version: ProtocolVersion) -> &'static str {
+    let err = Transmission::from_stream_for(&mut Cursor::new(bytes.to_vec()), version)
+        .await
+        .unwrap_err();
+    match err {
+        GlideError::Protocol(ProtocolError::Truncated(truncated)) => truncated.field,
+        other => panic!("{} bytes: {:?}", bytes.len(), other),
+    }
+}
+
+#[tokio::test]
+async fn a_frame_cut_short_names_the_field_it_was_in() {
+    // Control byte and "a.txt\0"
+    let name_end = 7;
+
+    let metadata = Transmission::Metadata("a.txt".to_string(), 3, HashMap::new()).to_bytes();
+    for cut in name_end..metadata.len() {
+        let field = truncated_field(&metadata[..cut], ProtocolVersion::V1).await;
+        assert_eq!(field, "metadata size");
+    }
+
+    let chunk = Transmission::Chunk("a.txt".to_string(), b"abc".to_vec()).to_bytes();
+    for cut in name_end..name_end + 2 {
+        let field = truncated_field(&chunk[..cut], ProtocolVersion::V1).await;
+        assert_eq!(field, "chunk size");
+    }
+    for cut in name_end + 2..chunk.len() {
+        let field = truncated_field(&chunk[..cut], ProtocolVersion::V1).await;
+        assert_eq!(field, "chunk data");
+    }
+
+    let outcome = Transmission::RequestOutcome("bob".to_string(), "a.txt".to_string(), true);
+    let outcome = outcome.to_bytes();
+    let field = truncated_field(&outcome[..outcome.len() - 1], ProtocolVersion::V1).await;
+    assert_eq!(field, "accepted flag");
+
+    let cases = [
+        (Transmission::Purged(2), "purge count"),
+        (Transmission::Version(3), "version"),
+        (Transmission::Tagged(1, Box::new(Transmission::Pong)), "tag"),
+        (
+            Transmission::Busy {
+                retry_after_secs: 5,
+            },
+            "retry delay",
+        ),
+    ];
+    for (transmission, expected) in cases {
+        let bytes = transmission.to_bytes();
+        let field = truncated_field(&bytes[..2], ProtocolVersion::V1).await;
+        assert_eq!(field, expected, "{:?}", transmission);
+    }
+
+    // A framed one can be cut off in its length or anywhere after it
+    let framed =
+        Transmission::Chunk("a.txt".to_string(), b"abc".to_vec()).to_bytes_for(ProtocolVersion::V2);
+    for cut in 1..4 {
+        let field = truncated_field(&framed[..cut], ProtocolVersion::V2).await;
+        assert_eq!(field, "frame length");
+    }
+    for cut in 4..framed.len() {
+        let field = truncated_field(&framed[..cut], ProtocolVersion::V2).await;
+        assert_eq!(field, "frame");
+    }
+    // Whereas ending before a frame starts is hanging up
+    assert!(matches!(
+        Transmission::from_stream_for(&mut Cursor::new(Vec::new()), ProtocolVersion::V2).await,
+        Err(GlideError::Protocol(ProtocolError::UnexpectedEof))
+    ));
+}
+
+#[tokio::test]
+async fn a_non_ascii_filename_reads_back_the_same() {
+    let filename = "café_日本語_📄.txt";
+    for version in [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::V3,
+    ] {
+        let metadata = Transmission::Metadata(filename.to_string(), 7, HashMap::new());
+        let bytes = metadata.to_bytes_for(version);
+        let read = Transmission::from_stream_for(&mut Cursor::new(bytes), version)
+            .await
+            .unwrap();
+        assert!(
+            matches!(read, Transmission::Metadata(ref name, 7, _) if name == filename),
+            "{:?}: {:?}",
+            version,
+            read
+        );
+    }
+}
+
+#[tokio::test]
+async fn a_filename_that_isnt_utf8_is_refused() {
+    for version in [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::V3,
+    ] {
+        // The same length, so a V2 frame's length still adds up
+        let mut bytes =
+            Transmission::Metadata("ok.txt".to_string(), 7, HashMap::new()).to_bytes_for(version);
+        let at = bytes.windows(2).position(|pair| pair == b"ok").unwrap();
+        bytes[at..at + 2].copy_from_slice(&[0xc3, 0x28]);
+
+        let err = Transmission::from_stream_for(&mut Cursor::new(bytes), version)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, GlideError::InvalidUtf8(ref invalid) if invalid.field == "filename"),
+            "{:?}: {:?}",
+            version,
+            err
+        );
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
+
+#[tokio::test]
+async fn a_field_longer_than_the_limit_is_refused_at_the_limit() {
+    let max_len = 16;
+    let control = Transmission::Metadata(String::new(), 0, HashMap::new()).to_bytes()[0];
+    let field = |len: usize| {
+        let mut bytes = vec![control];
+        bytes.extend(std::iter::repeat_n(b'a', len));
+        bytes
+    };
+
+    // Exactly the limit gets as far as the size after it
+    let mut bytes = field(max_len);
+    bytes.push(0);
+    let err = Transmission::from_stream_with_limit(&mut Cursor::new(bytes), max_len)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, GlideError::Protocol(ProtocolError::Truncated(_))),
+        "{:?}",
+        err
+    );
+
+    let mut bytes = field(max_len + 1);
+    bytes.push(0);
+    let err = Transmission::from_stream_with_limit(&mut Cursor::new(bytes), max_len)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            GlideError::Protocol(ProtocolError::FieldTooLong {
+                field: "filename",
+                max_len: 16
+            })
+        ),
+        "{:?}",
+        err
+    );
+
+    // A peer that never sends the terminator is cut off rather than read forever
+    let mut endless = Cursor::new(vec![control]).chain(tokio::io::repeat(b'a'));
+    let err = Transmission::from_stream_with_limit(&mut endless, max_len)
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(
+            err,
+            GlideError::Protocol(ProtocolError::FieldTooLong { .. })
+        ),
+        "{:?}",
+        err
+    );
+}
+
+#[tokio::test]
+async fn hundreds_of_connected_users_all_read_back() {
+    // Past what a single byte could count, and with a count whose low byte
+    // is a null
+    let users: Vec<String> = (0..512).map(|i| format!("user{}", i)).collect();
+    for version in [
+        ProtocolVersion::V1,
+        ProtocolVersion::V2,
+        ProtocolVersion::V3,
+    ] {
+        let bytes = Transmission::ConnectedUsers(users.clone()).to_bytes_for(version);
+        let read = Transmission::from_stream_for(&mut Cursor::new(bytes), version)
+            .await
+            .unwrap();
+        assert!(
+            matches!(read, Transmission::ConnectedUsers(ref read) if *read == users),
+            "{:?}: {:?}",
+            version,
+            read
+        );
+    }
+}
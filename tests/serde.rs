@@ -0,0 +1,44 @@
+#![cfg(feature = "serde")]
+
+use std::time::{Duration, SystemTime};
+
+use utils::data::{Request, UserData};
+
+fn request(sender: &str, filename: &str, size: u64, offered_at: SystemTime) -> Request {
+    Request {
+        sender: sender.to_string(),
+        filename: filename.to_string(),
+        size,
+        offered_at,
+    }
+}
+
+#[test]
+fn requests_round_trip_through_json() {
+    let requests = vec![
+        request("alice", "report.pdf", 5_000_000_000, SystemTime::UNIX_EPOCH),
+        request(
+            "bob",
+            "café_日本語.txt",
+            0,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+        ),
+    ];
+
+    let json = serde_json::to_string(&requests).unwrap();
+    let read: Vec<Request> = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", read), format!("{:?}", requests));
+}
+
+#[test]
+fn a_user_round_trips_through_json() {
+    let user = UserData {
+        socket: "127.0.0.1:4000".to_string(),
+        incoming_requests: vec![request("carol", "a.txt", 3, SystemTime::UNIX_EPOCH)],
+        public_key: Some([9; 32]),
+    };
+
+    let json = serde_json::to_string(&user).unwrap();
+    let read: UserData = serde_json::from_str(&json).unwrap();
+    assert_eq!(format!("{:?}", read), format!("{:?}", user));
+}
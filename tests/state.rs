@@ -0,0 +1,130 @@
+use utils::data::UserData;
+use utils::state;
+
+fn user(socket: &str) -> UserData {
+    UserData {
+        socket: socket.to_string(),
+        incoming_requests: Vec::new(),
+        public_key: None,
+    }
+}
+
+async fn socket_of(state: &state::SharedState, username: &str) -> Option<String> {
+    state::with_user(state, username, |client| client.socket.clone()).await
+}
+
+#[tokio::test]
+async fn rename_moves_the_user() {
+    let state = state::new_state();
+    assert!(state::insert_user(&state, "alice", user("a")).await);
+
+    assert!(state::rename_user(&state, "alice", "carol").await);
+    assert_eq!(socket_of(&state, "carol").await.as_deref(), Some("a"));
+    assert!(!state::contains_user(&state, "alice").await);
+
+    // Only the case changes
+    assert!(state::rename_user(&state, "carol", "Carol").await);
+    assert_eq!(socket_of(&state, "Carol").await.as_deref(), Some("a"));
+}
+
+#[tokio::test]
+async fn rename_to_a_taken_name_changes_nothing() {
+    let state = state::new_state();
+    assert!(state::insert_user(&state, "alice", user("a")).await);
+    assert!(state::insert_user(&state, "bob", user("b")).await);
+
+    assert!(!state::rename_user(&state, "alice", "BOB").await);
+    assert!(!state::rename_user(&state, "nobody", "dave").await);
+    assert_eq!(socket_of(&state, "alice").await.as_deref(), Some("a"));
+    assert_eq!(socket_of(&state, "bob").await.as_deref(), Some("b"));
+    assert!(!state::contains_user(&state, "dave").await);
+}
+
+// Whatever order they run in, every user ends up under exactly one name and
+// nobody's entry is lost or overwritten
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn racing_renames_never_lose_a_user() {
+    let state = state::new_state();
+    let names: Vec<String> = (0..32).map(|i| format!("user{}", i)).collect();
+    for name in &names {
+        assert!(state::insert_user(&state, name, user(name)).await);
+    }
+
+    let mut tasks = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let state = state.clone();
+        let name = name.clone();
+        // Everyone goes for the same few names, and some for a name that's
+        // still someone's original
+        let target = match i % 3 {
+            0 => format!("taken{}", i % 4),
+            1 => format!("user{}", (i + 1) % 32),
+            _ => format!("Taken{}", i % 4),
+        };
+        tasks.push(tokio::spawn(async move {
+            state::rename_user(&state, &name, &target).await
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let mut sockets = Vec::new();
+    for name in state::usernames(&state).await {
+        sockets.push(socket_of(&state, &name).await.unwrap());
+    }
+    sockets.sort();
+    let mut expected = names.clone();
+    expected.sort();
+    assert_eq!(sockets, expected);
+}
+
+// A failed rename mustn't put the old entry back over someone who's just
+// claimed the old name
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn failed_rename_never_overwrites_a_new_user() {
+    for _ in 0..200 {
+        let state = state::new_state();
+        assert!(state::insert_user(&state, "alice", user("a")).await);
+        assert!(state::insert_user(&state, "bob", user("b")).await);
+
+        let renaming = {
+            let state = state.clone();
+            tokio::spawn(async move { state::rename_user(&state, "alice", "bob").await })
+        };
+        let claiming = {
+            let state = state.clone();
+            tokio::spawn(async move { state::insert_user(&state, "alice", user("x")).await })
+        };
+        assert!(!renaming.await.unwrap());
+        let claimed = claiming.await.unwrap();
+
+        let expected = if claimed { "x" } else { "a" };
+        assert_eq!(socket_of(&state, "alice").await.as_deref(), Some(expected));
+        assert_eq!(socket_of(&state, "bob").await.as_deref(), Some("b"));
+    }
+}
+
+// However many connect at once, no more than the cap get in
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn racing_logins_stop_at_the_cap() {
+    let state = state::new_state();
+    let mut tasks = Vec::new();
+    for i in 0..64 {
+        let state = state.clone();
+        tasks.push(tokio::spawn(async move {
+            let name = format!("capped{}", i);
+            state::insert_user_capped(&state, &name, user(&name), 10).await
+        }));
+    }
+
+    let mut inserted = 0;
+    for task in tasks {
+        match task.await.unwrap() {
+            state::Inserted::Yes => inserted += 1,
+            other => assert_eq!(other, state::Inserted::Full),
+        }
+    }
+    assert_eq!(inserted, 10);
+    assert_eq!(state::usernames(&state).await.len(), 10);
+}
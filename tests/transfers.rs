@@ -0,0 +1,1185 @@
+mod common;
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{FutureExt, StreamExt};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use utils::cache::SentFiles;
+use utils::compression::{Deflater, Inflater};
+use utils::data::{
+    Compression, ExtensionPolicy, LineEnding, ReceivedFile, RetryPolicy, SymlinkPolicy, CHUNK_SIZE,
+    COMPRESSION_ATTRIBUTE, DIGEST_ATTRIBUTE, PARTIAL_SUFFIX, TEXT_ATTRIBUTE,
+};
+use utils::error::GlideError;
+use utils::protocol::Transmission;
+use utils::transfers::{self, ReceiveOptions, ScanVerdict, Scanner, SendOptions};
+
+#[tokio::test]
+async fn directory_with_too_many_files_is_refused_up_front() {
+    let save = common::scratch_dir("too-many");
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let header = Transmission::Directory {
+        name: "flood".to_string(),
+        files: 1_000_000,
+    };
+    sender.write_all(&header.to_bytes()).await.unwrap();
+
+    let options = ReceiveOptions {
+        max_dir_files: Some(10),
+        ..ReceiveOptions::default()
+    };
+    let result = transfers::receive_dir_with(&mut receiver, save.to_str().unwrap(), &options).await;
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::FileTooLarge);
+    assert_eq!(std::fs::read_dir(&save).unwrap().count(), 0);
+
+    // The sender's told to stop
+    assert!(matches!(
+        Transmission::from_stream(&mut sender).await.unwrap(),
+        Transmission::Abort(name) if name == "flood"
+    ));
+}
+
+#[tokio::test]
+async fn directory_over_the_byte_limit_is_refused() {
+    let source = common::scratch_dir("bytes-source").join("big");
+    std::fs::create_dir_all(&source).unwrap();
+    for name in ["a", "b", "c"] {
+        std::fs::write(source.join(name), [7u8; 100]).unwrap();
+    }
+    let save = common::scratch_dir("bytes-save");
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_dir(&mut sender, &path).await });
+
+    let options = ReceiveOptions {
+        max_dir_bytes: Some(250),
+        ..ReceiveOptions::default()
+    };
+    let result = transfers::receive_dir_with(&mut receiver, save.to_str().unwrap(), &options).await;
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::FileTooLarge);
+    drop(receiver);
+    // Small enough to have all been written before the receiver gave up
+    let _ = sending.await.unwrap();
+
+    // Only the files before the limit was reached arrived
+    let arrived = std::fs::read_dir(save.join("big")).unwrap().count();
+    assert_eq!(arrived, 2);
+}
+
+#[tokio::test]
+async fn directory_within_the_limits_arrives() {
+    let source = common::scratch_dir("within-source").join("small");
+    std::fs::create_dir_all(&source).unwrap();
+    for name in ["a", "b"] {
+        std::fs::write(source.join(name), [1u8; 10]).unwrap();
+    }
+    let save = common::scratch_dir("within-save");
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_dir(&mut sender, &path).await });
+
+    let options = ReceiveOptions {
+        max_dir_files: Some(2),
+        max_dir_bytes: Some(20),
+        ..ReceiveOptions::default()
+    };
+    let received = transfers::receive_dir_with(&mut receiver, save.to_str().unwrap(), &options)
+        .await
+        .unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(sending.await.unwrap().unwrap().len(), 2);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_file_whose_name_isnt_utf8_is_left_out_of_its_directory() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let source = common::scratch_dir("non-utf8-source").join("mixed");
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::write(source.join("good.txt"), "sent").unwrap();
+    std::fs::write(source.join(OsStr::from_bytes(b"bad\xff.txt")), "left out").unwrap();
+    let save = common::scratch_dir("non-utf8-save");
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_dir(&mut sender, &path).await });
+
+    let received = transfers::receive_dir(&mut receiver, save.to_str().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].filename, "mixed/good.txt");
+    assert_eq!(sending.await.unwrap().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn a_stray_chunk_is_refused_without_its_contents() {
+    let save = common::scratch_dir("stray");
+    let (mut sender, mut receiver) = tokio::io::duplex(1024);
+    let chunk = Transmission::Chunk("secret.txt".to_string(), b"hunter2".to_vec());
+    sender.write_all(&chunk.to_bytes()).await.unwrap();
+
+    let err = transfers::receive_file(&mut receiver, save.to_str().unwrap())
+        .await
+        .unwrap_err();
+    let message = format!("{} {:?}", err, err);
+    assert!(message.contains("expected Metadata"), "{}", message);
+    assert!(!message.contains("secret.txt"), "{}", message);
+    assert!(!message.contains("104, 117"), "{}", message);
+}
+
+#[tokio::test]
+async fn a_chunk_inflating_past_the_file_size_is_refused() {
+    let save = common::scratch_dir("bomb");
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let attributes = HashMap::from([(COMPRESSION_ATTRIBUTE.to_string(), "deflate".to_string())]);
+    let metadata = Transmission::Metadata("bomb.bin".to_string(), 10, attributes);
+    sender.write_all(&metadata.to_bytes()).await.unwrap();
+
+    // 16 MiB of zeros deflates to a few KiB
+    let mut deflater = Deflater::new();
+    let mut bomb = deflater.push(&vec![0u8; 16 << 20]);
+    bomb.extend(deflater.finish());
+    assert!(bomb.len() < u16::MAX as usize);
+    let chunk = Transmission::Chunk("bomb.bin".to_string(), bomb);
+    sender.write_all(&chunk.to_bytes()).await.unwrap();
+
+    let err = transfers::receive_file(&mut receiver, save.to_str().unwrap())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(matches!(
+        Transmission::from_stream(&mut sender).await.unwrap(),
+        Transmission::Abort(name) if name == "bomb.bin"
+    ));
+    assert!(!save.join("bomb.bin").exists());
+}
+
+#[test]
+fn inflating_stops_at_the_cap() {
+    let mut deflater = Deflater::new();
+    let mut stream = deflater.push(&[1u8; 1000]);
+    stream.extend(deflater.finish());
+
+    assert!(Inflater::new().push(&stream, 999).is_err());
+    let mut inflater = Inflater::new();
+    assert_eq!(inflater.push(&stream, 1000).unwrap(), vec![1u8; 1000]);
+    assert!(inflater.is_done());
+}
+
+#[tokio::test]
+async fn an_abort_stops_a_send_over_any_stream() {
+    let source = common::scratch_dir("abort-any").join("big.bin");
+    std::fs::write(&source, vec![3u8; 1 << 20]).unwrap();
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+
+    // The receiver gives up before reading anything
+    let abort = Transmission::Abort("big.bin".to_string());
+    receiver.write_all(&abort.to_bytes()).await.unwrap();
+
+    // A borrowed stream isn't 'static, and needn't be
+    let mut borrowed = &mut sender;
+    let sending = transfers::send_file(&mut borrowed, source.to_str().unwrap());
+    let err = tokio::time::timeout(std::time::Duration::from_secs(5), sending)
+        .await
+        .expect("the send should stop at its next chunk")
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+}
+
+// Sends a file the receiver aborts once the first chunk is in, returning
+// the send's error once it's checked the rest never came
+async fn abort_after_first_chunk<S, R>(name: &str, mut sender: S, mut receiver: R) -> GlideError
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    R: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let source = common::scratch_dir(name).join("big.bin");
+    let size = 8 << 20;
+    std::fs::write(&source, vec![5u8; size]).unwrap();
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_file(&mut sender, &path).await });
+
+    assert!(matches!(
+        Transmission::from_stream(&mut receiver).await.unwrap(),
+        Transmission::Metadata(..)
+    ));
+    let Transmission::Chunk(_, first) = Transmission::from_stream(&mut receiver).await.unwrap()
+    else {
+        panic!("Expected a chunk");
+    };
+    let abort = Transmission::Abort("big.bin".to_string());
+    receiver.write_all(&abort.to_bytes()).await.unwrap();
+
+    // Whatever was already on its way, until the sender hangs up
+    let mut through = first.len() as u64;
+    while let Ok(Transmission::Chunk(_, data)) = Transmission::from_stream(&mut receiver).await {
+        through += data.len() as u64;
+    }
+    let err = sending.await.unwrap().unwrap_err();
+    assert!(through < size as u64);
+    err
+}
+
+#[tokio::test]
+async fn a_receiver_aborting_after_the_first_chunk_stops_a_socket_send() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let sender = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (receiver, _) = listener.accept().await.unwrap();
+
+    let err = abort_after_first_chunk("abort-socket", sender, receiver).await;
+    assert!(matches!(err, GlideError::Aborted(_)), "{}", err);
+    assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+}
+
+#[tokio::test]
+async fn a_receiver_aborting_after_the_first_chunk_stops_a_duplex_send() {
+    let (sender, receiver) = tokio::io::duplex(1 << 16);
+
+    let err = abort_after_first_chunk("abort-duplex", sender, receiver).await;
+    assert!(matches!(err, GlideError::Aborted(_)), "{}", err);
+}
+
+// Sends `path` with `options` to nobody in particular, returning the hash
+// the sender gave for it
+async fn send_away(path: &str, options: &SendOptions) -> [u8; 32] {
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let draining =
+        tokio::spawn(async move { tokio::io::copy(&mut receiver, &mut tokio::io::sink()).await });
+    let hash = transfers::send_file_with(&mut sender, path, options)
+        .await
+        .unwrap();
+    drop(sender);
+    draining.await.unwrap().unwrap();
+    hash
+}
+
+#[tokio::test]
+async fn an_unchanged_file_isnt_hashed_again() {
+    let source = common::scratch_dir("cached").join("same.txt");
+    std::fs::write(&source, "unchanged").unwrap();
+    let path = source.to_str().unwrap();
+    let options = SendOptions {
+        sent_files: Some(SentFiles::default()),
+        ..SendOptions::default()
+    };
+
+    let hash = send_away(path, &options).await;
+    let modified = std::fs::metadata(&source).unwrap().modified().unwrap();
+    let sent_files = options.sent_files.as_ref().unwrap();
+    assert_eq!(sent_files.get(&source, modified), Some((9, hash)));
+
+    // Had it been hashed again, this wouldn't come back
+    let remembered = [1u8; 32];
+    sent_files.insert(&source, modified, 9, remembered);
+    assert_eq!(send_away(path, &options).await, remembered);
+
+    // Nor is it shared with a sender that has its own
+    assert_eq!(send_away(path, &SendOptions::default()).await, hash);
+}
+
+#[tokio::test]
+async fn inline_hashes_match_a_separate_pass_over_the_file() {
+    let dir = common::scratch_dir("inline-hash");
+    let source = dir.join("several.bin");
+    // A few chunks and a ragged end
+    let contents: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+    let save = dir.join("in");
+    std::fs::create_dir_all(&save).unwrap();
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_file(&mut sender, &path).await });
+    let received = transfers::receive_file(&mut receiver, save.to_str().unwrap())
+        .await
+        .unwrap();
+    let sent = sending.await.unwrap().unwrap();
+
+    let expected: [u8; 32] = Sha256::digest(&contents).into();
+    assert_eq!(sent, expected);
+    assert_eq!(received.hash, expected);
+    assert_eq!(std::fs::read(save.join("several.bin")).unwrap(), contents);
+}
+
+// Sends "slow.txt" a chunk at a time, stalling for `stall` before each chunk
+async fn send_haltingly(mut sender: tokio::io::DuplexStream, stall: Duration) {
+    let metadata = Transmission::Metadata("slow.txt".to_string(), 6, HashMap::new());
+    sender.write_all(&metadata.to_bytes()).await.unwrap();
+    for part in [b"abc", b"def"] {
+        tokio::time::sleep(stall).await;
+        let chunk = Transmission::Chunk("slow.txt".to_string(), part.to_vec());
+        sender.write_all(&chunk.to_bytes()).await.unwrap();
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn transient_read_timeouts_are_retried() {
+    let save = common::scratch_dir("retry");
+    let (sender, mut receiver) = tokio::io::duplex(1 << 16);
+    tokio::spawn(send_haltingly(sender, Duration::from_millis(250)));
+
+    // Each stall times out twice before the chunk turns up
+    let policy = RetryPolicy {
+        max_retries: 3,
+        backoff: Duration::from_millis(10),
+        read_timeout: Some(Duration::from_millis(100)),
+    };
+    let received =
+        transfers::receive_file_with_retry(&mut receiver, save.to_str().unwrap(), &policy)
+            .await
+            .unwrap();
+    assert_eq!(received.size, 6);
+    assert_eq!(std::fs::read(save.join("slow.txt")).unwrap(), b"abcdef");
+}
+
+#[tokio::test(start_paused = true)]
+async fn read_timeouts_past_the_retries_give_up() {
+    let save = common::scratch_dir("retry-exhausted");
+    let (sender, mut receiver) = tokio::io::duplex(1 << 16);
+    tokio::spawn(send_haltingly(sender, Duration::from_millis(250)));
+
+    let policy = RetryPolicy {
+        max_retries: 1,
+        backoff: Duration::from_millis(10),
+        read_timeout: Some(Duration::from_millis(100)),
+    };
+    let err = transfers::receive_file_with_retry(&mut receiver, save.to_str().unwrap(), &policy)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(!save.join("slow.txt").exists());
+}
+
+// Sends "torn.txt" with its one chunk stalling for `stall` half way through
+async fn send_torn(mut sender: tokio::io::DuplexStream, stall: Duration) {
+    let metadata = Transmission::Metadata("torn.txt".to_string(), 6, HashMap::new());
+    sender.write_all(&metadata.to_bytes()).await.unwrap();
+    let chunk = Transmission::Chunk("torn.txt".to_string(), b"abcdef".to_vec()).to_bytes();
+    let (start, end) = chunk.split_at(chunk.len() / 2);
+    sender.write_all(start).await.unwrap();
+    tokio::time::sleep(stall).await;
+    sender.write_all(end).await.unwrap();
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_stall_part_way_through_a_chunk_isnt_retried() {
+    let policy = RetryPolicy {
+        max_retries: 3,
+        backoff: Duration::from_millis(10),
+        read_timeout: Some(Duration::from_millis(100)),
+    };
+
+    // Within the timeout, the chunk arrives whole
+    let save = common::scratch_dir("torn-brief");
+    let (sender, mut receiver) = tokio::io::duplex(1 << 16);
+    tokio::spawn(send_torn(sender, Duration::from_millis(50)));
+    transfers::receive_file_with_retry(&mut receiver, save.to_str().unwrap(), &policy)
+        .await
+        .unwrap();
+    assert_eq!(std::fs::read(save.join("torn.txt")).unwrap(), b"abcdef");
+
+    // Past it, the transfer fails rather than reading on from the middle
+    let save = common::scratch_dir("torn-long");
+    let (sender, mut receiver) = tokio::io::duplex(1 << 16);
+    tokio::spawn(send_torn(sender, Duration::from_millis(250)));
+    let err = transfers::receive_file_with_retry(&mut receiver, save.to_str().unwrap(), &policy)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+    assert!(err.to_string().contains("part way"), "{}", err);
+    assert!(!save.join("torn.txt").exists());
+}
+
+#[tokio::test]
+async fn the_receiver_sees_the_senders_attributes() {
+    let dir = common::scratch_dir("attributes");
+    let source = dir.join("notes.txt");
+    std::fs::write(&source, "some notes").unwrap();
+    let save = dir.join("in");
+    std::fs::create_dir_all(&save).unwrap();
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move {
+        let attributes = HashMap::from([("description".to_string(), "for bob".to_string())]);
+        transfers::send_file_with_attributes(&mut sender, &path, &attributes).await
+    });
+    let received = transfers::receive_file(&mut receiver, save.to_str().unwrap())
+        .await
+        .unwrap();
+    sending.await.unwrap().unwrap();
+
+    assert_eq!(
+        received.attributes.get("description").map(String::as_str),
+        Some("for bob")
+    );
+}
+
+#[tokio::test]
+async fn a_source_truncated_mid_send_aborts_the_transfer() {
+    let source = common::scratch_dir("truncated").join("shrinking.bin");
+    let size = 8 << 20;
+    std::fs::write(&source, vec![9u8; size]).unwrap();
+    // Small enough that the sender is held up after a few chunks
+    let (mut sender, mut receiver) = tokio::io::duplex(4096);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_file(&mut sender, &path).await });
+
+    assert!(matches!(
+        Transmission::from_stream(&mut receiver).await.unwrap(),
+        Transmission::Metadata(_, promised, _) if promised as usize == size
+    ));
+    assert!(matches!(
+        Transmission::from_stream(&mut receiver).await.unwrap(),
+        Transmission::Chunk(..)
+    ));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&source)
+        .unwrap()
+        .set_len(0)
+        .unwrap();
+
+    // Whatever was read before the truncation, then the abort
+    let mut through = 0;
+    let aborted = loop {
+        match Transmission::from_stream(&mut receiver).await.unwrap() {
+            Transmission::Chunk(_, data) => through += data.len(),
+            other => break other,
+        }
+    };
+    assert!(
+        matches!(aborted, Transmission::Abort(ref name) if name == "shrinking.bin"),
+        "{:?}",
+        aborted
+    );
+    assert!(through < size);
+
+    let err = sending.await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof, "{}", err);
+}
+
+// Sends `contents` as `filename` and receives it into `save`, scanning it
+// with a scanner that rejects anything mentioning EICAR
+async fn receive_scanned(save: &Path, filename: &str, contents: &str) -> std::io::Result<()> {
+    let source = save.parent().unwrap().join(filename);
+    std::fs::write(&source, contents).unwrap();
+    let scan: &Scanner = &|path: &Path| {
+        let path = path.to_path_buf();
+        async move {
+            assert!(path.to_str().unwrap().ends_with(PARTIAL_SUFFIX));
+            match std::fs::read_to_string(&path)?.contains("EICAR") {
+                true => Ok(ScanVerdict::Rejected("test signature".to_string())),
+                false => Ok(ScanVerdict::Clean),
+            }
+        }
+        .boxed()
+    };
+    let options = ReceiveOptions {
+        scan: Some(scan),
+        ..ReceiveOptions::default()
+    };
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_file(&mut sender, &path).await });
+    let received =
+        transfers::receive_file_with(&mut receiver, save.to_str().unwrap(), &options).await;
+    sending.await.unwrap().unwrap();
+    received.map(drop).map_err(Into::into)
+}
+
+#[tokio::test]
+async fn a_file_the_scanner_rejects_isnt_kept() {
+    let save = common::scratch_dir("scanned").join("in");
+    std::fs::create_dir_all(&save).unwrap();
+
+    receive_scanned(&save, "clean.txt", "nothing to see")
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(save.join("clean.txt")).unwrap(),
+        "nothing to see"
+    );
+
+    let err = receive_scanned(&save, "bad.txt", "X5O!P%@AP EICAR-STANDARD-ANTIVIRUS-TEST")
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied, "{}", err);
+    assert!(err.to_string().contains("test signature"), "{}", err);
+    let left: Vec<_> = std::fs::read_dir(&save)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(left, ["clean.txt"]);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinks_are_sent_only_as_the_policy_allows() {
+    let dir = common::scratch_dir("symlinks");
+    let root = dir.join("root");
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("real.txt"), "inside").unwrap();
+    std::fs::write(dir.join("secret.txt"), "outside").unwrap();
+    let inside = root.join("inside.txt");
+    let outside = root.join("outside.txt");
+    std::os::unix::fs::symlink(root.join("real.txt"), &inside).unwrap();
+    std::os::unix::fs::symlink(dir.join("secret.txt"), &outside).unwrap();
+    let (inside, outside) = (inside.to_str().unwrap(), outside.to_str().unwrap());
+    let with = |symlinks: SymlinkPolicy| SendOptions {
+        symlinks,
+        ..SendOptions::default()
+    };
+
+    // Refused by default, wherever they point
+    for link in [inside, outside] {
+        let err = transfers::check_sendable(link, &SendOptions::default())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    }
+
+    let within = with(SymlinkPolicy::Within(root.clone()));
+    transfers::check_sendable(inside, &within).await.unwrap();
+    let err = transfers::check_sendable(outside, &within)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("links outside"), "{}", err);
+    // What's sent is what the link points at
+    assert_eq!(
+        send_away(inside, &within).await,
+        <[u8; 32]>::from(Sha256::digest(b"inside"))
+    );
+
+    let follow = with(SymlinkPolicy::Follow);
+    transfers::check_sendable(outside, &follow).await.unwrap();
+}
+
+#[tokio::test]
+async fn only_a_readable_file_is_sendable() {
+    let dir = common::scratch_dir("sendable");
+    let file = dir.join("fine.txt");
+    std::fs::write(&file, "fine").unwrap();
+    transfers::check_sendable(file.to_str().unwrap(), &SendOptions::default())
+        .await
+        .unwrap();
+
+    let missing = dir.join("missing.txt");
+    let err = transfers::check_sendable(missing.to_str().unwrap(), &SendOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(err.to_string().contains("missing.txt"), "{}", err);
+
+    let err = transfers::check_sendable(dir.to_str().unwrap(), &SendOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::IsADirectory);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_link_to_nothing_is_missing_even_when_followed() {
+    let dir = common::scratch_dir("dangling");
+    let link = dir.join("dangling.txt");
+    std::os::unix::fs::symlink(dir.join("gone.txt"), &link).unwrap();
+    let follow = SendOptions {
+        symlinks: SymlinkPolicy::Follow,
+        ..SendOptions::default()
+    };
+    let err = transfers::check_sendable(link.to_str().unwrap(), &follow)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn a_file_that_cant_be_read_isnt_sendable() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = common::scratch_dir("unreadable");
+    let file = dir.join("locked.txt");
+    std::fs::write(&file, "locked").unwrap();
+    std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o000)).unwrap();
+    // Root reads it regardless, so there's nothing to check
+    if std::fs::File::open(&file).is_ok() {
+        return;
+    }
+
+    let err = transfers::check_sendable(file.to_str().unwrap(), &SendOptions::default())
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::PermissionDenied);
+    assert!(err.to_string().contains("locked.txt"), "{}", err);
+}
+
+#[tokio::test]
+async fn one_read_of_a_file_reaches_every_recipient() {
+    let dir = common::scratch_dir("fanout");
+    let source = dir.join("broadcast.bin");
+    let contents: Vec<u8> = (0..20_000u32).map(|i| (i % 253) as u8).collect();
+    std::fs::write(&source, &contents).unwrap();
+
+    let (one, one_end) = tokio::io::duplex(1 << 16);
+    let (two, two_end) = tokio::io::duplex(1 << 16);
+    let receiving: Vec<_> = [("one", one_end), ("two", two_end)]
+        .into_iter()
+        .map(|(name, mut end)| {
+            let save = dir.join(name);
+            std::fs::create_dir_all(&save).unwrap();
+            tokio::spawn(async move {
+                transfers::receive_file(&mut end, save.to_str().unwrap())
+                    .await
+                    .unwrap()
+            })
+        })
+        .collect();
+
+    let mut streams = [one, two];
+    let sent = transfers::send_file_multi(&mut streams, source.to_str().unwrap())
+        .await
+        .unwrap();
+    drop(streams);
+
+    let expected: [u8; 32] = Sha256::digest(&contents).into();
+    for (result, receiving) in sent.into_iter().zip(receiving) {
+        assert_eq!(result.unwrap(), expected);
+        assert_eq!(receiving.await.unwrap().hash, expected);
+    }
+    for name in ["one", "two"] {
+        let received = std::fs::read(dir.join(name).join("broadcast.bin")).unwrap();
+        assert_eq!(received, contents);
+    }
+}
+
+#[tokio::test]
+async fn a_sparse_file_arrives_at_its_full_size() {
+    let dir = common::scratch_dir("sparse");
+    let source = dir.join("holey.bin");
+    // Data, a hole, more data, then a hole to the end
+    let size = 4 << 20;
+    let file = std::fs::File::create(&source).unwrap();
+    file.set_len(size).unwrap();
+    drop(file);
+    let mut contents = vec![0u8; size as usize];
+    contents[..5000].fill(1);
+    contents[2 << 20..(2 << 20) + 5000].fill(2);
+    {
+        use std::io::{Seek, Write};
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&source)
+            .unwrap();
+        file.write_all(&contents[..5000]).unwrap();
+        file.seek(std::io::SeekFrom::Start(2 << 20)).unwrap();
+        file.write_all(&contents[2 << 20..(2 << 20) + 5000])
+            .unwrap();
+    }
+    let save = dir.join("in");
+    std::fs::create_dir_all(&save).unwrap();
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move {
+        let options = SendOptions {
+            sparse: true,
+            ..SendOptions::default()
+        };
+        transfers::send_file_with(&mut sender, &path, &options).await
+    });
+    let received = transfers::receive_file(&mut receiver, save.to_str().unwrap())
+        .await
+        .unwrap();
+    let sent = sending.await.unwrap().unwrap();
+
+    assert_eq!(received.hash, sent);
+    let arrived = save.join("holey.bin");
+    assert_eq!(std::fs::metadata(&arrived).unwrap().len(), size);
+    assert_eq!(std::fs::read(&arrived).unwrap(), contents);
+}
+
+#[tokio::test]
+async fn a_file_too_big_for_the_wire_is_refused() {
+    let source = common::scratch_dir("too-big").join("huge.bin");
+    let file = std::fs::File::create(&source).unwrap();
+    // All hole, so it takes no room
+    file.set_len(u32::MAX as u64 + 1).unwrap();
+    drop(file);
+
+    let (mut sender, _receiver) = tokio::io::duplex(1 << 16);
+    for options in [
+        SendOptions::default(),
+        SendOptions {
+            sparse: true,
+            ..SendOptions::default()
+        },
+    ] {
+        let err = transfers::send_file_with(&mut sender, source.to_str().unwrap(), &options)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::FileTooLarge, "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn a_range_from_the_middle_of_a_file_is_those_bytes() {
+    let dir = common::scratch_dir("range");
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 241) as u8).collect();
+    std::fs::write(dir.join("whole.bin"), &contents).unwrap();
+    let save = dir.join("in");
+    std::fs::create_dir_all(&save).unwrap();
+
+    let (mut client, mut server) = tokio::io::duplex(1 << 16);
+    let served = dir.to_str().unwrap().to_string();
+    let serving = tokio::spawn(async move {
+        let Transmission::RangeRequest {
+            filename,
+            start,
+            len,
+        } = Transmission::from_stream(&mut server).await.unwrap()
+        else {
+            panic!("Expected a range request");
+        };
+        transfers::serve_range(&mut server, &served, &filename, start, len).await
+    });
+    let received = transfers::request_range(
+        &mut client,
+        "whole.bin",
+        30_000..70_000,
+        save.to_str().unwrap(),
+    )
+    .await
+    .unwrap();
+    serving.await.unwrap().unwrap();
+
+    // Written where it sits in the file, with nothing either side of it
+    assert_eq!(received.size, 40_000);
+    let arrived = std::fs::read(save.join("whole.bin")).unwrap();
+    assert_eq!(arrived.len(), 70_000);
+    assert!(arrived[..30_000].iter().all(|&byte| byte == 0));
+    assert_eq!(arrived[30_000..], contents[30_000..70_000]);
+}
+
+// Sends `contents` as `filename` and receives it into a fresh `save` with
+// `options`
+async fn round_trip(
+    name: &str,
+    filename: &str,
+    contents: &[u8],
+    options: &ReceiveOptions<'_>,
+) -> (PathBuf, Result<ReceivedFile, GlideError>) {
+    let dir = common::scratch_dir(name);
+    let source = dir.join(filename);
+    std::fs::write(&source, contents).unwrap();
+    let save = dir.join("in");
+    std::fs::create_dir_all(&save).unwrap();
+
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move { transfers::send_file(&mut sender, &path).await });
+    let received =
+        transfers::receive_file_with(&mut receiver, save.to_str().unwrap(), options).await;
+    drop(receiver);
+    let _ = sending.await.unwrap();
+    (save, received)
+}
+
+#[tokio::test]
+async fn an_empty_file_arrives_empty_whatever_the_limit() {
+    let options = ReceiveOptions {
+        max_file_size: Some(0),
+        ..ReceiveOptions::default()
+    };
+    let (save, received) = round_trip("empty", "empty.txt", b"", &options).await;
+    let received = received.unwrap();
+
+    assert_eq!(received.size, 0);
+    assert_eq!(received.hash, <[u8; 32]>::from(Sha256::digest(b"")));
+    assert_eq!(std::fs::read(save.join("empty.txt")).unwrap(), b"");
+}
+
+#[tokio::test]
+async fn a_file_over_the_size_limit_is_refused_before_its_written() {
+    let options = ReceiveOptions {
+        max_file_size: Some(100),
+        ..ReceiveOptions::default()
+    };
+    let (save, received) = round_trip("over-limit", "big.bin", &[4u8; 101], &options).await;
+
+    assert_eq!(received.unwrap_err().kind(), ErrorKind::FileTooLarge);
+    assert_eq!(std::fs::read_dir(&save).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn file_transmissions_are_what_send_file_sends() {
+    let dir = common::scratch_dir("transmissions");
+    let source = dir.join("listed.txt");
+    let contents = "a line of text\n".repeat(200);
+    std::fs::write(&source, &contents).unwrap();
+
+    let sent: Vec<Transmission> = transfers::file_transmissions(source.to_str().unwrap(), 1000)
+        .map(Result::unwrap)
+        .collect()
+        .await;
+    let [Transmission::Metadata(name, size, attributes), chunks @ .., Transmission::Digest(digest_name, digest)] =
+        sent.as_slice()
+    else {
+        panic!("{:?}", sent);
+    };
+    assert_eq!(
+        (name.as_str(), *size as usize),
+        ("listed.txt", contents.len())
+    );
+    assert_eq!(
+        attributes.get(TEXT_ATTRIBUTE).map(String::as_str),
+        Some("true")
+    );
+    assert_eq!(
+        attributes.get(DIGEST_ATTRIBUTE).map(String::as_str),
+        Some("sha256")
+    );
+    let lengths: Vec<usize> = chunks
+        .iter()
+        .map(|chunk| match chunk {
+            Transmission::Chunk(name, data) if name == "listed.txt" => data.len(),
+            other => panic!("{:?}", other),
+        })
+        .collect();
+    assert_eq!(lengths, [1000, 1000, 1000]);
+    assert_eq!(digest_name, "listed.txt");
+    assert_eq!(*digest, <[u8; 32]>::from(Sha256::digest(&contents)));
+}
+
+#[tokio::test]
+async fn file_transmissions_refuse_what_send_file_refuses() {
+    let dir = common::scratch_dir("transmissions-refused");
+    std::fs::write(dir.join("f.txt"), "f").unwrap();
+
+    let up = format!("{}/..", dir.display());
+    let zero = dir.join("f.txt").to_str().unwrap().to_string();
+    for (path, chunk_size) in [(up.as_str(), 1000), (zero.as_str(), 0)] {
+        let sent: Vec<_> = transfers::file_transmissions(path, chunk_size)
+            .collect()
+            .await;
+        assert!(matches!(sent.as_slice(), [Err(_)]), "{}: {:?}", path, sent);
+    }
+}
+
+// Sends `contents` compressed with `compression` and receives it, returning
+// how many bytes crossed the wire and what arrived
+async fn send_compressed(
+    name: &str,
+    contents: &[u8],
+    compression: Compression,
+) -> (usize, Vec<u8>) {
+    let dir = common::scratch_dir(name);
+    let source = dir.join("log.txt");
+    std::fs::write(&source, contents).unwrap();
+    let save = dir.join("in");
+    std::fs::create_dir_all(&save).unwrap();
+
+    let (mut sender, mut wire) = tokio::io::duplex(1 << 16);
+    let path = source.to_str().unwrap().to_string();
+    let sending = tokio::spawn(async move {
+        let options = SendOptions {
+            compression,
+            ..SendOptions::default()
+        };
+        transfers::send_file_with(&mut sender, &path, &options).await
+    });
+    let mut sent = Vec::new();
+    wire.read_to_end(&mut sent).await.unwrap();
+    sending.await.unwrap().unwrap();
+
+    // What was sent, replayed to a receiver
+    let wire_len = sent.len();
+    let (mut replay, mut receiver) = tokio::io::duplex(1 << 16);
+    let replaying = tokio::spawn(async move {
+        replay.write_all(&sent).await.unwrap();
+        replay
+    });
+    transfers::receive_file(&mut receiver, save.to_str().unwrap())
+        .await
+        .unwrap();
+    let _ = replaying.await.unwrap();
+    (wire_len, std::fs::read(save.join("log.txt")).unwrap())
+}
+
+// Lines much like each other, as logs are
+fn compressible(len: usize) -> Vec<u8> {
+    (0..)
+        .map(|i| {
+            format!(
+                "{} INFO request {} served in {}ms\n",
+                1_700_000_000 + i,
+                i % 97,
+                i % 13
+            )
+        })
+        .flat_map(String::into_bytes)
+        .take(len)
+        .collect()
+}
+
+#[tokio::test]
+async fn one_deflate_stream_beats_compressing_each_chunk() {
+    let contents = compressible(1 << 20);
+
+    let (deflated, arrived) =
+        send_compressed("whole-stream", &contents, Compression::Deflate).await;
+    assert_eq!(arrived, contents);
+    let (gzipped, arrived) = send_compressed("per-chunk", &contents, Compression::Gzip).await;
+    assert_eq!(arrived, contents);
+
+    assert!(deflated < gzipped, "{} vs {}", deflated, gzipped);
+    assert!(gzipped < contents.len());
+}
+
+#[tokio::test]
+async fn compressed_chunks_arrive_intact_in_fewer_bytes() {
+    let contents = compressible(256 << 10);
+    let (plain, _) = send_compressed("codec-none", &contents, Compression::None).await;
+
+    let codecs = [
+        ("codec-gzip", Compression::Gzip),
+        #[cfg(feature = "zstd")]
+        ("codec-zstd", Compression::Zstd),
+    ];
+    for (name, compression) in codecs {
+        let (sent, arrived) = send_compressed(name, &contents, compression).await;
+        assert_eq!(arrived, contents, "{:?}", compression);
+        assert!(sent < plain / 2, "{:?}: {} of {}", compression, sent, plain);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn a_throttled_send_takes_as_long_as_its_rate_says() {
+    let source = common::scratch_dir("throttled").join("slow.bin");
+    let size = 100_000;
+    std::fs::write(&source, vec![6u8; size]).unwrap();
+    let rate = 10_000;
+    let options = SendOptions {
+        max_bytes_per_sec: Some(rate),
+        ..SendOptions::default()
+    };
+
+    let started = tokio::time::Instant::now();
+    send_away(source.to_str().unwrap(), &options).await;
+    let elapsed = started.elapsed();
+    let expected = Duration::from_secs((size as u64) / rate);
+    assert!(elapsed >= expected, "{:?}", elapsed);
+    assert!(elapsed < expected + Duration::from_secs(2), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn a_resumed_file_ends_up_the_same_as_its_source() {
+    let dir = common::scratch_dir("resume");
+    let contents: Vec<u8> = (0..150_000u32).map(|i| (i % 239) as u8).collect();
+    std::fs::write(dir.join("resumed.bin"), &contents).unwrap();
+    let save = dir.join("in");
+    std::fs::create_dir_all(&save).unwrap();
+    // What a first try got through before the connection dropped
+    let partial = transfers::partial_path(save.join("resumed.bin").to_str().unwrap());
+    std::fs::write(&partial, &contents[..60_000]).unwrap();
+
+    let (mut client, mut server) = tokio::io::duplex(1 << 16);
+    let served = dir.to_str().unwrap().to_string();
+    let serving = tokio::spawn(async move {
+        let Transmission::ResumeFrom(filename, offset) =
+            Transmission::from_stream(&mut server).await.unwrap()
+        else {
+            panic!("Expected a resume");
+        };
+        assert_eq!(offset, 60_000);
+        transfers::serve_resume(&mut server, &served, &filename, offset).await
+    });
+    let received = transfers::resume_file(&mut client, "resumed.bin", save.to_str().unwrap())
+        .await
+        .unwrap();
+    let sent = serving.await.unwrap().unwrap();
+
+    assert_eq!(received.hash, sent);
+    assert_eq!(std::fs::read(save.join("resumed.bin")).unwrap(), contents);
+    assert!(!Path::new(&partial).exists());
+}
+
+#[tokio::test]
+async fn a_text_file_gets_the_line_endings_asked_for() {
+    let options = ReceiveOptions {
+        line_endings: Some(LineEnding::CrLf),
+        ..ReceiveOptions::default()
+    };
+    // A CRLF split across two chunks is still one line ending, and a lone CR
+    // is left alone
+    let mut contents = "a".repeat(CHUNK_SIZE - 1).into_bytes();
+    contents.extend(b"\r\nunix\nlone\rcr\n");
+    let (save, received) = round_trip("crlf-text", "notes.txt", &contents, &options).await;
+    let received = received.unwrap();
+    assert_eq!(received.attributes[TEXT_ATTRIBUTE], "true");
+
+    let mut expected = "a".repeat(CHUNK_SIZE - 1).into_bytes();
+    expected.extend(b"\r\nunix\r\nlone\rcr\r\n");
+    assert_eq!(std::fs::read(save.join("notes.txt")).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn a_binary_file_arrives_byte_for_byte_whatever_the_line_endings() {
+    let options = ReceiveOptions {
+        line_endings: Some(LineEnding::CrLf),
+        ..ReceiveOptions::default()
+    };
+    let contents = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR\n\xff\xfe".to_vec();
+    let (save, received) = round_trip("crlf-binary", "image.png", &contents, &options).await;
+    assert_eq!(received.unwrap().attributes[TEXT_ATTRIBUTE], "false");
+    assert_eq!(std::fs::read(save.join("image.png")).unwrap(), contents);
+}
+
+#[test]
+fn extensions_match_the_end_of_the_name_whatever_the_case() {
+    let allow = ExtensionPolicy::Allow(vec!["txt".to_string(), ".tar.gz".to_string()]);
+    for filename in ["notes.txt", "NOTES.TXT", "backup.tar.gz", "backup.TAR.GZ"] {
+        assert!(allow.permits(filename), "{}", filename);
+    }
+    for filename in ["notes", "notestxt", "notes.txt.exe", "backup.gz", ".txt.sh"] {
+        assert!(!allow.permits(filename), "{}", filename);
+    }
+
+    let deny = ExtensionPolicy::Deny(vec!["exe".to_string()]);
+    assert!(deny.permits("notes.txt"));
+    assert!(deny.permits("exe"));
+    // Windows drops trailing dots and spaces, so these all run as exes
+    for filename in ["run.exe", "run.EXE", "run.exe.", "run.exe ", "run.exe. . "] {
+        assert!(!deny.permits(filename), "{:?}", filename);
+    }
+
+    assert!(ExtensionPolicy::Any.permits("anything.at.all"));
+}
+
+#[tokio::test]
+async fn a_file_of_a_kind_not_accepted_isnt_saved() {
+    let policy = ExtensionPolicy::Allow(vec!["txt".to_string()]);
+    let options = ReceiveOptions {
+        extensions: Some(&policy),
+        ..ReceiveOptions::default()
+    };
+
+    let (save, received) = round_trip("denied-kind", "run.sh", b"#!/bin/sh", &options).await;
+    assert_eq!(received.unwrap_err().kind(), ErrorKind::PermissionDenied);
+    assert_eq!(std::fs::read_dir(&save).unwrap().count(), 0);
+
+    let (save, received) = round_trip("allowed-kind", "notes.TXT", b"fine", &options).await;
+    received.unwrap();
+    assert_eq!(std::fs::read(save.join("notes.TXT")).unwrap(), b"fine");
+}
+
+#[tokio::test]
+async fn a_file_with_a_non_ascii_name_arrives_under_that_name() {
+    let filename = "café_日本語.txt";
+    let (save, received) = round_trip(
+        "non-ascii",
+        filename,
+        b"bonjour",
+        &ReceiveOptions::default(),
+    )
+    .await;
+    assert_eq!(received.unwrap().filename, filename);
+    assert_eq!(std::fs::read(save.join(filename)).unwrap(), b"bonjour");
+}
+
+// Gives `good` bytes, then fails as a disk or pipe going away does
+struct FailsAfter {
+    good: usize,
+}
+
+impl AsyncRead for FailsAfter {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.good == 0 {
+            return Poll::Ready(Err(std::io::Error::other("the disk went away")));
+        }
+        let n = self.good.min(buf.remaining());
+        buf.put_slice(&vec![b'x'; n]);
+        self.good -= n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[tokio::test]
+async fn a_reader_that_fails_part_way_fails_the_send() {
+    let save = common::scratch_dir("failing-reader");
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let receiving = {
+        let save = save.clone();
+        tokio::spawn(
+            async move { transfers::receive_file(&mut receiver, save.to_str().unwrap()).await },
+        )
+    };
+
+    let mut reader = FailsAfter {
+        good: CHUNK_SIZE + 100,
+    };
+    let err = transfers::send_stream(&mut sender, &mut reader, "half.bin", 4 * CHUNK_SIZE as u32)
+        .await
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::Other);
+    assert!(err.to_string().contains("the disk went away"), "{}", err);
+
+    // The other end is told, rather than left waiting for the rest, and
+    // keeps what it got only to resume from
+    assert!(receiving.await.unwrap().is_err());
+    assert!(!save.join("half.bin").exists());
+}
+
+#[tokio::test]
+async fn a_file_only_appears_under_its_name_once_its_all_there() {
+    let save = common::scratch_dir("staged-part");
+    let (final_path, part_path) = (
+        save.join("whole.bin"),
+        save.join(format!("whole.bin{}", PARTIAL_SUFFIX)),
+    );
+    let (mut sender, mut receiver) = tokio::io::duplex(1 << 16);
+    let receiving = {
+        let save = save.clone();
+        tokio::spawn(
+            async move { transfers::receive_file(&mut receiver, save.to_str().unwrap()).await },
+        )
+    };
+    // What's sent comes through here, a piece at a time
+    let (mut source, mut reader) = tokio::io::duplex(1 << 16);
+    let size = 2 * CHUNK_SIZE;
+    let sending = tokio::spawn(async move {
+        transfers::send_stream(&mut sender, &mut reader, "whole.bin", size as u32).await
+    });
+
+    source.write_all(&vec![b'a'; CHUNK_SIZE]).await.unwrap();
+    while std::fs::metadata(&part_path).map_or(true, |m| m.len() < CHUNK_SIZE as u64) {
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert!(!final_path.exists());
+
+    source.write_all(&vec![b'b'; CHUNK_SIZE]).await.unwrap();
+    sending.await.unwrap().unwrap();
+    receiving.await.unwrap().unwrap();
+    assert_eq!(std::fs::read(&final_path).unwrap().len(), size);
+    assert!(!part_path.exists());
+}
@@ -0,0 +1,262 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use utils::commands::Command;
+use utils::data::{Request, ServerConfig};
+use utils::protocol::{self, ProtocolVersion, Transmission};
+use utils::relay::{self, RelayOptions};
+use utils::state::{self, SharedState};
+
+// Serves every connection to the address it returns until the test ends
+async fn server(config: ServerConfig) -> (SocketAddr, SharedState) {
+    let state = state::new_state();
+    let addr = common::serve(&state, &config).await;
+    (addr, state)
+}
+
+// Relays every connection to the address it returns on to `backend`
+async fn relayed(backend: SocketAddr, options: RelayOptions) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (client, _) = listener.accept().await.unwrap();
+            let options = options.clone();
+            tokio::spawn(async move {
+                let _ = relay::relay(client, backend, &options).await;
+            });
+        }
+    });
+    addr
+}
+
+// Logs in as `username` in `version`, returning the server's answer
+async fn log_in(stream: &mut TcpStream, username: &str, version: ProtocolVersion) -> Transmission {
+    let login = Transmission::Username(username.to_string()).to_bytes_for(version);
+    stream.write_all(&login).await.unwrap();
+    Transmission::from_stream_for(stream, version)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_client_that_offers_a_version_speaks_it() {
+    let (addr, _) = server(ServerConfig::default()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    let agreed = protocol::negotiate_version(&mut client, &[1, 2, 3])
+        .await
+        .unwrap();
+    assert_eq!(agreed, 3);
+    let answer = log_in(&mut client, "offers_v3", ProtocolVersion::V3).await;
+    assert!(matches!(answer, Transmission::UsernameOk));
+}
+
+#[tokio::test]
+async fn the_lower_version_wins() {
+    let config = ServerConfig {
+        max_version: ProtocolVersion::V2,
+        ..ServerConfig::default()
+    };
+    let (addr, _) = server(config).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    let agreed = protocol::negotiate_version(&mut client, &[1, 2, 3])
+        .await
+        .unwrap();
+    assert_eq!(agreed, 2);
+    let answer = log_in(&mut client, "lower_v2", ProtocolVersion::V2).await;
+    assert!(matches!(answer, Transmission::UsernameOk));
+}
+
+#[tokio::test]
+async fn a_client_without_a_version_speaks_version_1() {
+    let (addr, _) = server(ServerConfig::default()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    let answer = log_in(&mut client, "no_version", ProtocolVersion::V1).await;
+    assert!(matches!(answer, Transmission::UsernameOk));
+}
+
+#[tokio::test]
+async fn an_unsupported_version_closes_the_connection() {
+    let (addr, _) = server(ServerConfig::default()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    let agreed = protocol::negotiate_version(&mut client, &[0])
+        .await
+        .unwrap();
+    assert_eq!(agreed, 0);
+    assert!(Transmission::from_stream(&mut client).await.is_err());
+}
+
+#[tokio::test]
+async fn a_version_after_logging_in_is_refused() {
+    let (addr, _) = server(ServerConfig::default()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    let answer = log_in(&mut client, "late_version", ProtocolVersion::V1).await;
+    assert!(matches!(answer, Transmission::UsernameOk));
+    client
+        .write_all(&Transmission::Version(3).to_bytes())
+        .await
+        .unwrap();
+    assert!(Transmission::from_stream(&mut client).await.is_err());
+}
+
+// Sends `command` in version 3, returning the answer
+async fn run_v3(stream: &mut TcpStream, command: Command) -> Transmission {
+    let command = Transmission::Command(command).to_bytes_for(ProtocolVersion::V3);
+    stream.write_all(&command).await.unwrap();
+    Transmission::from_stream_for(stream, ProtocolVersion::V3)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn a_relay_passes_the_handshake_through() {
+    let (backend, _) = server(ServerConfig::default()).await;
+    let addr = relayed(backend, RelayOptions::default()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    let agreed = protocol::negotiate_version(&mut client, &[1, 2, 3])
+        .await
+        .unwrap();
+    assert_eq!(agreed, 3);
+    let answer = log_in(&mut client, "relayed_v3", ProtocolVersion::V3).await;
+    assert!(matches!(answer, Transmission::UsernameOk));
+
+    // And then commands and their answers, as they were sent
+    let mut direct = TcpStream::connect(backend).await.unwrap();
+    log_in(&mut direct, "relayed_peer", ProtocolVersion::V1).await;
+    let list = Command::List {
+        filter: Some("relayed".to_string()),
+        page: None,
+        page_size: None,
+    };
+    let answer = run_v3(&mut client, list.clone()).await;
+    assert!(
+        matches!(answer, Transmission::UsersPage { total: 1, ref users } if users == &["relayed_peer"]),
+        "{:?}",
+        answer
+    );
+
+    // Unless the relay won't let them through
+    let options = RelayOptions {
+        allowed_commands: Some(vec!["list".to_string()]),
+        ..RelayOptions::default()
+    };
+    let addr = relayed(backend, options).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    protocol::negotiate_version(&mut client, &[3])
+        .await
+        .unwrap();
+    log_in(&mut client, "relayed_limited", ProtocolVersion::V3).await;
+    let answer = run_v3(&mut client, Command::Requests).await;
+    assert!(
+        matches!(answer, Transmission::Error(ref message) if message.contains("'reqs'")),
+        "{:?}",
+        answer
+    );
+    let answer = run_v3(&mut client, list).await;
+    assert!(
+        matches!(answer, Transmission::UsersPage { total: 2, .. }),
+        "{:?}",
+        answer
+    );
+}
+
+#[tokio::test]
+async fn requests_carry_their_size_and_time_in_version_3() {
+    let (addr, state) = server(ServerConfig::default()).await;
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let offered_at = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+
+    protocol::negotiate_version(&mut client, &[1, 2, 3])
+        .await
+        .unwrap();
+    log_in(&mut client, "reqs_v3", ProtocolVersion::V3).await;
+    state::with_user(&state, "reqs_v3", |client| {
+        client.incoming_requests.push(Request {
+            sender: "someone".to_string(),
+            filename: "big.bin".to_string(),
+            size: 5_000_000_000,
+            offered_at,
+        })
+    })
+    .await
+    .unwrap();
+
+    let reqs = Transmission::Command(Command::Requests).to_bytes_for(ProtocolVersion::V3);
+    client.write_all(&reqs).await.unwrap();
+    let answer = Transmission::from_stream_for(&mut client, ProtocolVersion::V3)
+        .await
+        .unwrap();
+    let Transmission::IncomingRequests(requests) = answer else {
+        panic!("Expected requests, got {:?}", answer);
+    };
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].sender, "someone");
+    assert_eq!(requests[0].filename, "big.bin");
+    assert_eq!(requests[0].size, 5_000_000_000);
+    assert_eq!(requests[0].offered_at, offered_at);
+}
+
+// Logs in over a server offering up to `server_version` and a client offering
+// `client`, then lists users, all in the version they agree on
+async fn pair(server_version: ProtocolVersion, client: &[u16], expected: ProtocolVersion) {
+    let config = ServerConfig {
+        max_version: server_version,
+        ..ServerConfig::default()
+    };
+    let (addr, _) = server(config).await;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+
+    let agreed = match client {
+        [] => ProtocolVersion::V1,
+        offered => {
+            let agreed = protocol::negotiate_version(&mut stream, offered)
+                .await
+                .unwrap();
+            ProtocolVersion::from_number(agreed).unwrap()
+        }
+    };
+    assert_eq!(agreed, expected);
+
+    let username = format!("pair_{}_{}", server_version.number(), client.len());
+    let answer = log_in(&mut stream, &username, agreed).await;
+    assert!(matches!(answer, Transmission::UsernameOk));
+
+    let list = Transmission::Command(Command::List {
+        filter: None,
+        page: None,
+        page_size: None,
+    });
+    stream.write_all(&list.to_bytes_for(agreed)).await.unwrap();
+    let answer = Transmission::from_stream_for(&mut stream, agreed)
+        .await
+        .unwrap();
+    // Alone on its own server, so nobody else to list
+    assert!(matches!(answer, Transmission::ConnectedUsers(ref users) if users.is_empty()));
+}
+
+#[tokio::test]
+async fn a_v1_server_and_a_v1_client_pair() {
+    pair(ProtocolVersion::V1, &[1], ProtocolVersion::V1).await;
+}
+
+#[tokio::test]
+async fn a_v2_server_and_a_v2_client_pair() {
+    pair(ProtocolVersion::V2, &[1, 2], ProtocolVersion::V2).await;
+}
+
+#[tokio::test]
+async fn a_v2_server_and_a_v1_client_pair() {
+    pair(ProtocolVersion::V2, &[1], ProtocolVersion::V1).await;
+    // One that predates versions altogether
+    pair(ProtocolVersion::V2, &[], ProtocolVersion::V1).await;
+}